@@ -5,14 +5,15 @@ use crate::{
     services::auth_service::AuthService,
 };
 use axum::{
-    extract::{Extension, State},
+    extract::{Extension, Path, State},
     http::StatusCode,
     response::Json,
-    routing::{get, post},
+    routing::{delete, get, post},
     Router,
 };
 use serde::Serialize;
 use std::sync::Arc;
+use uuid::Uuid;
 
 pub fn routes() -> Router<Arc<AuthService>> {
     Router::new()
@@ -24,6 +25,10 @@ pub fn protected_routes() -> Router<Arc<AuthService>> {
         .route("/logout", post(logout))
         .route("/me", get(get_current_user))
         .route("/register", post(register)) // For admin to create users
+        .route("/api-keys", get(list_api_keys).post(create_api_key))
+        .route("/api-keys/:id", delete(revoke_api_key))
+        .route("/access-keys", get(list_access_keys).post(create_access_key))
+        .route("/access-keys/:id", delete(revoke_access_key))
 }
 
 #[derive(Serialize)]
@@ -78,3 +83,73 @@ async fn register(
     let user = auth_service.create_user(request).await?;
     Ok((StatusCode::CREATED, Json(user)))
 }
+
+/// List the current user's API keys
+async fn list_api_keys(
+    State(auth_service): State<Arc<AuthService>>,
+    Extension(auth_context): Extension<AuthContext>,
+) -> Result<Json<Vec<ApiKeyInfo>>, ApiError> {
+    let keys = auth_service.list_api_keys(auth_context.user_id).await?;
+    Ok(Json(keys))
+}
+
+/// Create an API key for the current user
+async fn create_api_key(
+    State(auth_service): State<Arc<AuthService>>,
+    Extension(auth_context): Extension<AuthContext>,
+    Json(request): Json<CreateApiKeyRequest>,
+) -> Result<(StatusCode, Json<CreatedApiKey>), ApiError> {
+    let key = auth_service
+        .create_api_key(auth_context.user_id, &request.name)
+        .await?;
+    Ok((StatusCode::CREATED, Json(key)))
+}
+
+/// Revoke one of the current user's API keys
+async fn revoke_api_key(
+    State(auth_service): State<Arc<AuthService>>,
+    Extension(auth_context): Extension<AuthContext>,
+    Path(id): Path<Uuid>,
+) -> Result<Json<MessageResponse>, ApiError> {
+    auth_service.revoke_api_key(auth_context.user_id, id).await?;
+
+    Ok(Json(MessageResponse {
+        message: "API key revoked".to_string(),
+    }))
+}
+
+/// List the current user's S3 access keys
+async fn list_access_keys(
+    State(auth_service): State<Arc<AuthService>>,
+    Extension(auth_context): Extension<AuthContext>,
+) -> Result<Json<Vec<AccessKeyInfo>>, ApiError> {
+    let keys = auth_service.list_access_keys(auth_context.user_id).await?;
+    Ok(Json(keys))
+}
+
+/// Create an S3 access key pair for the current user
+async fn create_access_key(
+    State(auth_service): State<Arc<AuthService>>,
+    Extension(auth_context): Extension<AuthContext>,
+    Json(request): Json<CreateAccessKeyRequest>,
+) -> Result<(StatusCode, Json<CreatedAccessKey>), ApiError> {
+    let key = auth_service
+        .create_access_key(auth_context.user_id, &request.name)
+        .await?;
+    Ok((StatusCode::CREATED, Json(key)))
+}
+
+/// Revoke one of the current user's S3 access keys
+async fn revoke_access_key(
+    State(auth_service): State<Arc<AuthService>>,
+    Extension(auth_context): Extension<AuthContext>,
+    Path(access_key_id): Path<String>,
+) -> Result<Json<MessageResponse>, ApiError> {
+    auth_service
+        .revoke_access_key(auth_context.user_id, &access_key_id)
+        .await?;
+
+    Ok(Json(MessageResponse {
+        message: "Access key revoked".to_string(),
+    }))
+}
@@ -0,0 +1,294 @@
+use crate::{
+    errors::ApiError,
+    middleware::AuthContext,
+    services::{normalize, ChangeEvent, FileChange, EVENT_TICKET_TTL},
+    AppState,
+};
+use axum::{
+    extract::{Extension, Query, State},
+    http::HeaderMap,
+    response::sse::{Event, KeepAlive, Sse},
+    routing::{get, post},
+    Json, Router,
+};
+use futures::Stream;
+use serde::Serialize;
+use std::{convert::Infallible, time::Duration};
+use tokio::sync::{broadcast, mpsc};
+use tokio_stream::wrappers::ReceiverStream;
+
+/// How often a long-lived stream re-checks that its token is still valid
+const TOKEN_RECHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+pub fn routes() -> Router<AppState> {
+    Router::new()
+        .route("/", get(stream_events))
+        .route("/ticket", post(create_ticket))
+}
+
+/// The paths one client is interested in
+struct Subscription {
+    /// Normalized subscribed paths; "" is the whole tree
+    paths: Vec<String>,
+    /// Include changes at any depth, not just direct children
+    recursive: bool,
+}
+
+impl Subscription {
+    fn from_query(query: &[(String, String)]) -> Result<Self, ApiError> {
+        let mut paths = Vec::new();
+        let mut recursive = true;
+        for (key, value) in query {
+            match key.as_str() {
+                "path" => paths.push(normalize(value)?),
+                "recursive" => {
+                    recursive = value.parse().map_err(|_| ApiError::BadRequest {
+                        message: format!("Invalid value for recursive: {}", value),
+                    })?
+                }
+                _ => {}
+            }
+        }
+        if paths.is_empty() {
+            paths.push(String::new());
+        }
+        Ok(Self { paths, recursive })
+    }
+
+    fn matches(&self, change: &FileChange) -> bool {
+        std::iter::once(change.path.as_str())
+            .chain(change.from.as_deref())
+            .any(|path| self.paths.iter().any(|subscribed| self.covers(subscribed, path)))
+    }
+
+    fn covers(&self, subscribed: &str, path: &str) -> bool {
+        if path == subscribed {
+            return true;
+        }
+        let inside = if subscribed.is_empty() {
+            Some(path)
+        } else {
+            path.strip_prefix(subscribed).and_then(|rest| rest.strip_prefix('/'))
+        };
+        match inside {
+            Some(rest) => self.recursive || !rest.contains('/'),
+            None => false,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct TicketResponse {
+    ticket: String,
+    /// Seconds the ticket can be used to open the stream for
+    expires_in: u64,
+}
+
+/// A ticket to pass as `ticket` when opening the stream from a browser's `EventSource`,
+/// which can't send the token in a header
+async fn create_ticket(
+    State(app_state): State<AppState>,
+    Extension(auth_context): Extension<AuthContext>,
+) -> Json<TicketResponse> {
+    Json(TicketResponse {
+        ticket: app_state.auth_service.create_event_ticket(&auth_context.token),
+        expires_in: EVENT_TICKET_TTL.as_secs(),
+    })
+}
+
+#[derive(Serialize)]
+struct RescanEvent {
+    message: &'static str,
+}
+
+/// Server-Sent Events stream of changes below the subscribed paths.
+///
+/// Query: `path` (repeatable, default "/"), `recursive` (default true) and `last_event_id`,
+/// which is also read from the `Last-Event-ID` header browsers send on reconnect.
+async fn stream_events(
+    State(app_state): State<AppState>,
+    Extension(auth_context): Extension<AuthContext>,
+    headers: HeaderMap,
+    Query(query): Query<Vec<(String, String)>>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, ApiError> {
+    let subscription = Subscription::from_query(&query)?;
+
+    let last_event_id = headers
+        .get("last-event-id")
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string)
+        .or_else(|| {
+            query
+                .iter()
+                .find(|(key, _)| key == "last_event_id")
+                .map(|(_, value)| value.clone())
+        })
+        .map(|value| {
+            value.trim().parse::<u64>().map_err(|_| ApiError::BadRequest {
+                message: format!("Invalid last event id: {}", value),
+            })
+        })
+        .transpose()?;
+
+    let (sender, receiver) = mpsc::channel(64);
+    let feed = app_state.change_feed.clone();
+    let auth_service = app_state.auth_service.clone();
+
+    tokio::spawn(async move {
+        let mut changes = match last_event_id {
+            Some(last_event_id) => {
+                let (replay, changes) = feed.subscribe_from(last_event_id);
+                if replay.missed && sender.send(rescan_event(None)).await.is_err() {
+                    return;
+                }
+                for event in replay.events {
+                    for sse_event in to_sse_events(&event, &subscription) {
+                        if sender.send(sse_event).await.is_err() {
+                            return;
+                        }
+                    }
+                }
+                changes
+            }
+            None => feed.subscribe(),
+        };
+
+        let mut recheck = tokio::time::interval(TOKEN_RECHECK_INTERVAL);
+        recheck.tick().await;
+        loop {
+            tokio::select! {
+                event = changes.recv() => {
+                    let sse_events = match event {
+                        Ok(event) => to_sse_events(&event, &subscription),
+                        // Without an id the client resumes from the last event it did get,
+                        // which may still be replayable
+                        Err(broadcast::error::RecvError::Lagged(_)) => vec![rescan_event(None)],
+                        Err(broadcast::error::RecvError::Closed) => return,
+                    };
+                    for sse_event in sse_events {
+                        if sender.send(sse_event).await.is_err() {
+                            return;
+                        }
+                    }
+                }
+                _ = recheck.tick() => {
+                    // End the stream once the token expires or the user logs out
+                    if auth_service.validate_token(&auth_context.token).await.is_err() {
+                        return;
+                    }
+                }
+                _ = sender.closed() => return,
+            }
+        }
+    });
+
+    Ok(Sse::new(ReceiverStream::new(receiver)).keep_alive(KeepAlive::default()))
+}
+
+fn to_sse_events(event: &ChangeEvent, subscription: &Subscription) -> Vec<Result<Event, Infallible>> {
+    match event {
+        ChangeEvent::Changed(changes) => changes
+            .iter()
+            .filter(|change| subscription.matches(change))
+            .map(|change| {
+                Ok(Event::default()
+                    .id(change.id.to_string())
+                    .event(change.kind.as_str())
+                    .json_data(change)
+                    .unwrap_or_default())
+            })
+            .collect(),
+        ChangeEvent::Rescan { id } => vec![rescan_event(Some(*id))],
+    }
+}
+
+/// Tells the client that changes were missed and its view should be reloaded
+fn rescan_event(id: Option<u64>) -> Result<Event, Infallible> {
+    let mut event = Event::default().event("rescan");
+    if let Some(id) = id {
+        event = event.id(id.to_string());
+    }
+    Ok(event
+        .json_data(RescanEvent {
+            message: "Changes were missed, reload the current view",
+        })
+        .unwrap_or_default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{services::ChangeKind, test_app};
+    use axum::{
+        body::{Body, HttpBody},
+        http::{Request, StatusCode},
+    };
+    use tower::ServiceExt;
+
+    fn change(path: &str, from: Option<&str>) -> FileChange {
+        FileChange {
+            id: 1,
+            path: path.to_string(),
+            kind: ChangeKind::Created,
+            from: from.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn test_subscription_matches_paths() {
+        let query = vec![
+            ("path".to_string(), "/docs".to_string()),
+            ("recursive".to_string(), "false".to_string()),
+        ];
+        let subscription = Subscription::from_query(&query).unwrap();
+        assert!(subscription.matches(&change("docs", None)));
+        assert!(subscription.matches(&change("docs/a.txt", None)));
+        assert!(!subscription.matches(&change("docs/nested/b.txt", None)));
+        assert!(!subscription.matches(&change("docs-old/a.txt", None)));
+        assert!(subscription.matches(&change("elsewhere.txt", Some("docs/a.txt"))));
+
+        let everything = Subscription::from_query(&[]).unwrap();
+        assert!(everything.matches(&change("a/b/c/d.txt", None)));
+    }
+
+    #[tokio::test]
+    async fn test_stream_opens_with_a_ticket() {
+        let dir = tempfile::tempdir().unwrap();
+        let (app, token) = test_app(dir.path()).await;
+        let post = |uri: &str, token: Option<&str>, body: &'static str| {
+            let mut request = Request::post(uri).header("content-type", "application/json");
+            if let Some(token) = token {
+                request = request.header("authorization", format!("Bearer {}", token));
+            }
+            request.body(Body::from(body)).unwrap()
+        };
+        let open = |uri: String| Request::get(uri).body(Body::empty()).unwrap();
+
+        let response = app.clone().oneshot(post("/api/events/ticket", Some(&token), "")).await.unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let ticket: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        let ticket = ticket["ticket"].as_str().unwrap().to_string();
+
+        let response = app.clone().oneshot(open(format!("/api/events?path=/docs&ticket={}", ticket))).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let mut stream = response.into_body();
+        let mkdir = post("/api/files/mkdir", Some(&token), r#"{"path":"/docs"}"#);
+        assert_eq!(app.clone().oneshot(mkdir).await.unwrap().status(), StatusCode::OK);
+        let event = tokio::time::timeout(Duration::from_secs(5), stream.data()).await.unwrap().unwrap().unwrap();
+        assert!(String::from_utf8_lossy(&event).contains("event:created"));
+
+        // Tickets don't stand in for the token anywhere else, and stop with its session
+        for (uri, status) in [
+            ("/api/events".to_string(), StatusCode::UNAUTHORIZED),
+            ("/api/events?ticket=unknown".to_string(), StatusCode::UNAUTHORIZED),
+            (format!("/api/files?ticket={}", ticket), StatusCode::UNAUTHORIZED),
+        ] {
+            assert_eq!(app.clone().oneshot(open(uri.clone())).await.unwrap().status(), status, "{}", uri);
+        }
+        let renew = post(&format!("/api/events/ticket?ticket={}", ticket), None, "");
+        assert_eq!(app.clone().oneshot(renew).await.unwrap().status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(app.clone().oneshot(post("/api/auth/logout", Some(&token), "")).await.unwrap().status(), StatusCode::OK);
+        let response = app.oneshot(open(format!("/api/events?ticket={}", ticket))).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+}
@@ -1,58 +1,200 @@
 use crate::{
     errors::ApiError,
     middleware::AuthContext,
-    services::{FileService, FileInfo},
+    services::{
+        normalize, parent_of, split_archive_path, ArchiveCompression, ArchiveFormat, ArchiveService, AttributeService,
+        ChangeKind,
+        ChecksumAlgorithm, DuplicateAction, DuplicateService, ExpectedChecksum, FileDetails, FileService, FileInfo, Job,
+        JobContext, Ownership, SearchQuery, SearchResponse, SearchService,
+    },
+    utils::{
+        preview::{self, Preview},
+        range::parse_byte_range,
+    },
     AppState,
 };
 use axum::{
+    body::StreamBody,
     extract::{Extension, Multipart, Path, Query, State, DefaultBodyLimit},
-    http::{header, StatusCode},
+    http::{header, HeaderMap, StatusCode, Uri},
     response::{IntoResponse, Response},
     routing::{delete, get, post, put},
     Json, Router,
 };
-use serde::{Deserialize, Serialize};
-use tokio::io::{AsyncWriteExt, BufWriter};
-use tokio::fs::File;
-use futures::StreamExt;
+use bytes::Bytes;
 use chrono::{DateTime, Utc};
+use percent_encoding::percent_decode_str;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use tokio::io::AsyncReadExt;
+use tokio_util::io::ReaderStream;
 
 pub fn routes() -> Router<AppState> {
     Router::new()
         .route("/", get(list_files))
         .route("/upload", post(upload_files))
         .route("/upload-folder", post(upload_folder))
+        .route("/search", get(search_files).delete(delete_file))
         .route("/mkdir", post(create_directory))
         .route("/rename", put(rename_file))
+        .route("/by-hash", put(link_file).delete(delete_file))
+        .route("/extract", post(extract_archive).delete(delete_file))
+        .route("/compress", post(compress_files).delete(delete_file))
+        .route("/copy", post(copy_file).delete(delete_file))
+        .route("/duplicates", post(find_duplicates).delete(delete_file))
+        .route("/duplicates/resolve", post(resolve_duplicates).delete(delete_file))
         .route("/*path", delete(delete_file))
-        .route("/download/*path", get(download_file))
+        .route("/download/archive", post(download_archive).get(download_file_named_archive).delete(delete_file))
+        .route("/download/*path", get(download_file).delete(delete_file))
+        .route("/preview/*path", get(preview_file).delete(delete_file))
+        .route("/thumbnail/*path", get(thumbnail).delete(delete_file))
+        .route("/checksum/*path", get(checksum).delete(delete_file))
+        .route("/stat/*path", get(stat_file).delete(delete_file))
+        .route("/chmod", post(change_mode).delete(delete_file))
+        .route("/chown", post(change_owner).delete(delete_file))
+        .route("/times", post(set_times).delete(delete_file))
         .layer(DefaultBodyLimit::max(1000 * 1024 * 1024 * 1024)) 
 }
 
 #[derive(Deserialize)]
 struct ListQuery {
     path: Option<String>,
+    /// Report the size of everything below each directory instead of its own
+    #[serde(default)]
+    sizes: bool,
+    /// Comma-separated algorithms to add the checksums of files in
+    checksums: Option<String>,
+    #[serde(default)]
+    detail: Detail,
+}
+
+/// How much to tell about each listed entry
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+enum Detail {
+    #[default]
+    Basic,
+    /// Add what the local disk records about it, as `GET /stat` does
+    Full,
+}
+
+#[derive(Deserialize)]
+struct DownloadQuery {
+    /// Used when the path is a directory
+    #[serde(default)]
+    format: ArchiveFormat,
+    #[serde(default)]
+    compression: ArchiveCompression,
+}
+
+#[derive(Deserialize)]
+struct ArchiveRequest {
+    paths: Vec<String>,
+    #[serde(default)]
+    format: ArchiveFormat,
+    #[serde(default)]
+    compression: ArchiveCompression,
+}
+
+#[derive(Deserialize)]
+struct ExtractRequest {
+    path: String,
+    /// Directory to extract into, by default one named after the archive next to it
+    destination: Option<String>,
+    #[serde(default)]
+    overwrite: bool,
+}
+
+#[derive(Deserialize)]
+struct CompressRequest {
+    paths: Vec<String>,
+    /// Path of the archive to write
+    destination: String,
+    /// Taken from the extension of `destination` when not given
+    format: Option<ArchiveFormat>,
+    #[serde(default)]
+    compression: ArchiveCompression,
+    #[serde(default)]
+    overwrite: bool,
+}
+
+#[derive(Serialize)]
+struct ListedFile {
+    #[serde(flatten)]
+    info: FileInfo,
+    /// Files below a directory, when `sizes` is requested
+    #[serde(skip_serializing_if = "Option::is_none")]
+    file_count: Option<u64>,
+    /// Of a file, when `checksums` are requested
+    #[serde(skip_serializing_if = "Option::is_none")]
+    checksums: Option<BTreeMap<ChecksumAlgorithm, String>>,
+    #[serde(flatten)]
+    details: Option<FileDetails>,
 }
 
 #[derive(Serialize)]
 struct ListResponse {
-    files: Vec<FileInfo>,
+    files: Vec<ListedFile>,
     path: String,
 }
 
 async fn list_files(
     State(app_state): State<AppState>,
-    Extension(_auth_context): Extension<AuthContext>,
+    Extension(auth_context): Extension<AuthContext>,
     Query(query): Query<ListQuery>,
 ) -> Result<Json<ListResponse>, ApiError> {
     let path = query.path.unwrap_or_else(|| "/".to_string());
-    let file_service = FileService::new(app_state.config.as_ref().clone());
+    let algorithms = ChecksumAlgorithm::parse_list(query.checksums.as_deref().unwrap_or_default())?;
+    let file_service = app_state.file_service(&auth_context.email)
+        .with_archives(app_state.archive_browser.clone());
     
-    let files = file_service.list_files(&path).await?;
+    let infos = file_service.list_files(&path).await?;
+    let mut details = match query.detail {
+        Detail::Full => file_service.details(&infos).await?.into_iter().map(Some).collect(),
+        Detail::Basic => Vec::new(),
+    }
+    .into_iter();
+
+    let mut files = Vec::new();
+    for mut info in infos {
+        let in_archive = split_archive_path(&info.path).is_some();
+        let mut file_count = None;
+        if query.sizes && info.is_directory && !in_archive {
+            let size = app_state.disk_usage_service.size(&info.path).await?;
+            info.size = size.bytes;
+            file_count = Some(size.files);
+        }
+        let mut checksums = None;
+        if !algorithms.is_empty() && !info.is_directory && !in_archive {
+            checksums = Some(app_state.checksum_service.checksums(&info.path, &algorithms).await?.1);
+        }
+        files.push(ListedFile {
+            info,
+            file_count,
+            checksums,
+            details: details.next().flatten(),
+        });
+    }
     
     Ok(Json(ListResponse { files, path }))
 }
 
+async fn search_files(
+    State(app_state): State<AppState>,
+    Extension(_auth_context): Extension<AuthContext>,
+    Query(query): Query<SearchQuery>,
+) -> Result<Json<SearchResponse>, ApiError> {
+    let search_service = SearchService::new(
+        app_state.config.as_ref().clone(),
+        app_state.index_service.clone(),
+        app_state.storage.clone(),
+    );
+    
+    let response = search_service.search(query).await?;
+    
+    Ok(Json(response))
+}
+
 #[derive(Deserialize)]
 struct CreateDirectoryRequest {
     path: String,
@@ -68,13 +210,14 @@ struct CreateDirectoryResponse {
 
 async fn create_directory(
     State(app_state): State<AppState>,
-    Extension(_auth_context): Extension<AuthContext>,
+    Extension(auth_context): Extension<AuthContext>,
     Json(request): Json<CreateDirectoryRequest>,
 ) -> Result<Json<CreateDirectoryResponse>, ApiError> {
-    let file_service = FileService::new(app_state.config.as_ref().clone());
+    let file_service = app_state.file_service(&auth_context.email);
     let recursive = request.recursive.unwrap_or(true);
     
     let file_info = file_service.create_directory(&request.path, recursive).await?;
+    app_state.change_feed.publish(&request.path, ChangeKind::Created);
     
     Ok(Json(CreateDirectoryResponse {
         message: "Directory created successfully".to_string(),
@@ -85,13 +228,13 @@ async fn create_directory(
 
 #[derive(Serialize)]
 struct UploadResponse {
-    uploaded: Vec<FileInfo>,
+    uploaded: Vec<UploadedFile>,
     failed: Vec<UploadError>,
 }
 
 #[derive(Serialize)]
 struct FolderUploadResponse {
-    uploaded: Vec<FileInfo>,
+    uploaded: Vec<UploadedFile>,
     failed: Vec<UploadError>,
     folders_created: Vec<String>,
     total_files: usize,
@@ -99,6 +242,51 @@ struct FolderUploadResponse {
     failed_files: usize,
 }
 
+#[derive(Serialize)]
+struct UploadedFile {
+    #[serde(flatten)]
+    info: FileInfo,
+    /// Checksums the contents were verified against
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    checksums: BTreeMap<ChecksumAlgorithm, String>,
+}
+
+impl UploadedFile {
+    fn new(info: FileInfo, verified: &[ExpectedChecksum]) -> Self {
+        let checksums = verified
+            .iter()
+            .map(|expected| (expected.algorithm, hex::encode(&expected.digest)))
+            .collect();
+        Self { info, checksums }
+    }
+}
+
+/// Checksums the contents of a request or multipart part must have, from its `Digest` and
+/// `Content-MD5` headers
+pub(crate) fn expected_checksums(headers: &HeaderMap) -> Result<Vec<ExpectedChecksum>, ApiError> {
+    let text = |value: &header::HeaderValue| {
+        value.to_str().map(str::to_string).map_err(|_| ApiError::BadRequest {
+            message: "Checksum headers must be ASCII".to_string(),
+        })
+    };
+    let mut expected = Vec::new();
+    for value in headers.get_all("digest") {
+        expected.extend(ExpectedChecksum::from_digest_header(&text(value)?)?);
+    }
+    if let Some(value) = headers.get("content-md5") {
+        expected.push(ExpectedChecksum::from_content_md5(&text(value)?)?);
+    }
+    Ok(expected)
+}
+
+/// Read a `checksum` form field, which applies to the file that follows it
+async fn checksum_field(field: axum::extract::multipart::Field<'_>) -> Result<ExpectedChecksum, ApiError> {
+    let value = field.text().await.map_err(|e| ApiError::BadRequest {
+        message: format!("Failed to read checksum field: {}", e),
+    })?;
+    ExpectedChecksum::parse(&value)
+}
+
 #[derive(Serialize)]
 struct UploadError {
     filename: String,
@@ -107,12 +295,18 @@ struct UploadError {
 
 async fn upload_files(
     State(app_state): State<AppState>,
-    Extension(_auth_context): Extension<AuthContext>,
+    Extension(auth_context): Extension<AuthContext>,
+    headers: HeaderMap,
     mut multipart: Multipart,
 ) -> Result<Json<UploadResponse>, ApiError> {
+    let file_service = app_state.file_service(&auth_context.email);
     let mut uploaded = Vec::new();
     let mut failed = Vec::new();
     let mut target_path = "/".to_string();
+    // Checksum headers on the request can only describe a single file
+    let request_checksums = expected_checksums(&headers)?;
+    let mut next_checksums = Vec::new();
+    let mut files_seen = 0;
 
     let start_time = std::time::Instant::now();
 
@@ -132,20 +326,38 @@ async fn upload_files(
                 }
             })?;
             target_path = String::from_utf8_lossy(&data).to_string();
+        } else if name == "checksum" {
+            next_checksums.push(checksum_field(field).await?);
         } else if name == "file" {
             // Extract filename
             let filename = field
                 .file_name()
                 .unwrap_or("unnamed_file")
                 .to_string();
+            files_seen += 1;
+            let mut expected = std::mem::take(&mut next_checksums);
             
             // Stream file data directly to disk
             let upload_start = std::time::Instant::now();
             
-            match stream_upload_file(&app_state.config, &target_path, &filename, field).await {
+            let result = async {
+                expected.extend(expected_checksums(field.headers())?);
+                if !request_checksums.is_empty() {
+                    if files_seen > 1 {
+                        return Err(ApiError::BadRequest {
+                            message: "Checksum headers on the request only apply to uploads of one file".to_string(),
+                        });
+                    }
+                    expected.extend(request_checksums.iter().cloned());
+                }
+                stream_upload_file(&file_service, &target_path, &filename, field, &expected).await
+            }
+            .await;
+            match result {
                 Ok(file_info) => {
                     let _upload_duration = upload_start.elapsed();
-                    uploaded.push(file_info);
+                    app_state.change_feed.publish(&file_info.path, ChangeKind::Created);
+                    uploaded.push(UploadedFile::new(file_info, &expected));
                 },
                 Err(e) => {
                     let _upload_duration = upload_start.elapsed();
@@ -165,14 +377,16 @@ async fn upload_files(
 
 async fn upload_folder(
     State(app_state): State<AppState>,
-    Extension(_auth_context): Extension<AuthContext>,
+    Extension(auth_context): Extension<AuthContext>,
     mut multipart: Multipart,
 ) -> Result<Json<FolderUploadResponse>, ApiError> {
+    let file_service = app_state.file_service(&auth_context.email);
     let mut uploaded = Vec::new();
     let mut failed = Vec::new();
     let mut folders_created = Vec::new();
     let mut target_path = "/".to_string();
     let mut created_dirs = std::collections::HashSet::new();
+    let mut next_checksums = Vec::new();
 
     // Collect all files first to determine which are large files
     let mut files_to_process = Vec::new();
@@ -196,12 +410,16 @@ async fn upload_folder(
                 }
             })?;
             target_path = String::from_utf8_lossy(&data).to_string();
+        } else if name == "checksum" {
+            next_checksums.push(checksum_field(field).await?);
         } else if name == "file" {
             // Extract filename and file data
             let filename = field
                 .file_name()
                 .unwrap_or("unnamed_file")
                 .to_string();
+            let mut expected = std::mem::take(&mut next_checksums);
+            expected.extend(expected_checksums(field.headers())?);
             
             // Read the entire file data to determine size and decide processing method
             let data = field.bytes().await.map_err(|e| {
@@ -210,7 +428,7 @@ async fn upload_folder(
                 }
             })?;
             
-            files_to_process.push((filename, data.to_vec()));
+            files_to_process.push((filename, data, expected));
         }
     }
 
@@ -221,11 +439,11 @@ async fn upload_folder(
     let mut large_files = Vec::new();
     let mut small_files = Vec::new();
     
-    for (filename, data) in files_to_process {
+    for (filename, data, expected) in files_to_process {
         if data.len() > large_file_threshold {
-            large_files.push((filename, data));
+            large_files.push((filename, data, expected));
         } else {
-            small_files.push((filename, data));
+            small_files.push((filename, data, expected));
         }
     }
 
@@ -236,7 +454,7 @@ async fn upload_folder(
              total_files, large_files.len(), small_files.len());
 
     // Process large files individually using the same logic as upload_files
-    for (i, (filename, data)) in large_files.iter().enumerate() {
+    for (i, (filename, data, expected)) in large_files.iter().enumerate() {
         let upload_start = std::time::Instant::now();
         
         println!("Uploading large file ({}/{}): {} ({}MB)", 
@@ -260,10 +478,10 @@ async fn upload_folder(
         };
 
         // Use the same streaming logic but with the data we already have
-        match upload_large_file_data(&app_state.config, &dir_path, file_name, &data, &mut created_dirs).await {
+        match upload_large_file_data(&file_service, &dir_path, file_name, data, expected, &mut created_dirs).await {
             Ok((file_info, created_dir)) => {
                 let upload_duration = upload_start.elapsed();
-                uploaded.push(file_info);
+                uploaded.push(UploadedFile::new(file_info, expected));
                 if let Some(dir) = created_dir {
                     if !folders_created.contains(&dir) {
                         folders_created.push(dir);
@@ -286,7 +504,7 @@ async fn upload_folder(
     if !small_files.is_empty() {
         println!("Processing {} small files in batches", small_files.len());
         
-        for (i, (filename, data)) in small_files.iter().enumerate() {
+        for (i, (filename, data, expected)) in small_files.iter().enumerate() {
             let upload_start = std::time::Instant::now();
             
             // Progress reporting every 100 files for long uploads
@@ -297,10 +515,10 @@ async fn upload_folder(
             println!("Uploading small file ({}/{}): {} ({}KB)", 
                     i + 1 + large_files.len(), total_files, filename, data.len() as f64 / 1024.0);
             
-            match upload_small_file_data(&app_state.config, &target_path, &filename, &data, &mut created_dirs).await {
+            match upload_small_file_data(&file_service, &target_path, filename, data, expected, &mut created_dirs).await {
                 Ok((file_info, created_dir)) => {
                     let upload_duration = upload_start.elapsed();
-                    uploaded.push(file_info);
+                    uploaded.push(UploadedFile::new(file_info, expected));
                     if let Some(dir) = created_dir {
                         if !folders_created.contains(&dir) {
                             folders_created.push(dir);
@@ -320,6 +538,9 @@ async fn upload_folder(
         }
     }
 
+    // One change to the target covers every file and folder created by this upload
+    app_state.change_feed.publish(&target_path, ChangeKind::Modified);
+    
     let total_duration = start_time.elapsed();
     let total_files = uploaded.len() + failed.len();
     let successful_files = uploaded.len();
@@ -338,228 +559,520 @@ async fn upload_folder(
     }))
 }
 
-async fn stream_upload_file_with_structure(
-    config: &crate::config::Config, 
+async fn stream_upload_file(
+    file_service: &FileService,
     target_path: &str, 
-    relative_path: &str, 
-    mut field: axum::extract::multipart::Field<'_>,
-    created_dirs: &mut std::collections::HashSet<String>,
-) -> Result<(FileInfo, Option<String>), ApiError> {
-    use crate::utils::security::resolve_path;
-    use std::path::Path;
+    filename: &str, 
+    field: axum::extract::multipart::Field<'_>,
+    expected: &[ExpectedChecksum],
+) -> Result<FileInfo, ApiError> {
+    // Ensure target directory exists
+    file_service.ensure_directory(target_path).await?;
     
-    // Get storage directory from config
-    let storage_path = &config.storage.home_directory;
+    // Stream data in chunks; the file only appears once it has been written in full
+    let path = format!("{}/{}", target_path.trim_end_matches('/'), filename);
+    let (file_info, _) = file_service.write_file_verified(&path, field, expected).await?;
     
-    // Parse the relative path to extract directory structure and filename
-    let path_obj = Path::new(relative_path);
-    let filename = path_obj.file_name()
-        .and_then(|name| name.to_str())
-        .unwrap_or("unnamed_file");
+    Ok(file_info)
+}
+
+async fn download_file(
+    State(app_state): State<AppState>,
+    Extension(auth_context): Extension<AuthContext>,
+    Path(path): Path<String>,
+    Query(query): Query<DownloadQuery>,
+) -> Result<Response, ApiError> {
+    let file_service = app_state.file_service(&auth_context.email)
+        .with_archives(app_state.archive_browser.clone());
+    let info = file_service.get_info(&path).await?;
+    if info.is_directory && split_archive_path(&path).is_none() {
+        return archive_response(&app_state, &[path], query.format, query.compression).await;
+    }
+    let (data, filename) = file_service.download_file(&path).await?;
     
-    // Get the directory path within the relative structure
-    let dir_path = if let Some(parent) = path_obj.parent() {
-        if parent.as_os_str().is_empty() {
-            target_path.to_string()
-        } else {
-            format!("{}/{}", target_path.trim_end_matches('/'), parent.to_string_lossy())
-        }
-    } else {
-        target_path.to_string()
+    let headers = [
+        (header::CONTENT_TYPE, "application/octet-stream"),
+        (
+            header::CONTENT_DISPOSITION,
+            &format!("attachment; filename=\"{}\"", filename),
+        ),
+    ];
+
+    Ok((StatusCode::OK, headers, data).into_response())
+}
+
+/// Extract an archive stored on the server in the background
+async fn extract_archive(
+    State(app_state): State<AppState>,
+    Extension(auth_context): Extension<AuthContext>,
+    Json(request): Json<ExtractRequest>,
+) -> Result<(StatusCode, Json<Job>), ApiError> {
+    let file_service = app_state.file_service(&auth_context.email);
+    let info = file_service.get_info(&request.path).await?;
+    let (stem, _) = ArchiveFormat::split_name(&info.name)
+        .filter(|_| !info.is_directory)
+        .ok_or_else(|| ApiError::BadRequest {
+            message: format!("{} is not a zip, tar, tar.gz or tar.zst archive", request.path),
+        })?;
+    let destination = match request.destination {
+        Some(destination) => destination,
+        None => match parent_of(&normalize(&request.path)?) {
+            parent if parent.is_empty() => stem.to_string(),
+            parent => format!("{}/{}", parent, stem),
+        },
     };
-    
-    // Resolve the full target path
-    let full_target_path = resolve_path(storage_path, &dir_path).map_err(|e| {
-        ApiError::BadRequest {
-            message: format!("Invalid target path: {}", e),
-        }
-    })?;
-    
-    // Track directory creation for response
-    let created_dir = if !created_dirs.contains(&dir_path) && dir_path != target_path {
-        created_dirs.insert(dir_path.clone());
-        Some(dir_path.clone())
-    } else {
-        None
+
+    let params = ExtractJob {
+        path: request.path,
+        destination,
+        overwrite: request.overwrite,
     };
-    
-    // Ensure target directory exists
-    tokio::fs::create_dir_all(&full_target_path).await.map_err(|e| {
-        ApiError::InternalServerError {
-            message: format!("Failed to create target directory: {}", e),
+    let job = app_state.job_service.submit("extract", &auth_context.email, &params).await?;
+    Ok((StatusCode::ACCEPTED, Json(job)))
+}
+
+/// Write an archive of files and directories to the server in the background
+async fn compress_files(
+    State(app_state): State<AppState>,
+    Extension(auth_context): Extension<AuthContext>,
+    Json(request): Json<CompressRequest>,
+) -> Result<(StatusCode, Json<Job>), ApiError> {
+    let file_service = app_state.file_service(&auth_context.email);
+    check_destination(&file_service, &request.destination, request.overwrite).await?;
+    let archive_service = ArchiveService::new(app_state.config.clone(), app_state.storage.clone());
+    archive_service.resolve(&request.paths).await?;
+
+    let params = CompressJob {
+        format: request
+            .format
+            .or_else(|| ArchiveFormat::from_name(&request.destination))
+            .unwrap_or_default(),
+        paths: request.paths,
+        destination: request.destination,
+        compression: request.compression,
+        overwrite: request.overwrite,
+    };
+    let job = app_state.job_service.submit("compress", &auth_context.email, &params).await?;
+    Ok((StatusCode::ACCEPTED, Json(job)))
+}
+
+/// Copy a file or directory in the background
+async fn copy_file(
+    State(app_state): State<AppState>,
+    Extension(auth_context): Extension<AuthContext>,
+    Json(request): Json<CopyJob>,
+) -> Result<(StatusCode, Json<Job>), ApiError> {
+    let file_service = app_state.file_service(&auth_context.email);
+    file_service.get_info(&request.from).await?;
+    check_destination(&file_service, &request.to, false).await?;
+
+    let job = app_state.job_service.submit("copy", &auth_context.email, &request).await?;
+    Ok((StatusCode::ACCEPTED, Json(job)))
+}
+
+/// Look for files with the same contents below a directory in the background
+async fn find_duplicates(
+    State(app_state): State<AppState>,
+    Extension(auth_context): Extension<AuthContext>,
+    Json(request): Json<DuplicatesJob>,
+) -> Result<(StatusCode, Json<Job>), ApiError> {
+    let info = app_state.file_service(&auth_context.email).get_info(&request.path).await?;
+    if !info.is_directory {
+        return Err(ApiError::BadRequest {
+            message: format!("{} is not a directory", request.path),
+        });
+    }
+
+    let job = app_state.job_service.submit("duplicates", &auth_context.email, &request).await?;
+    Ok((StatusCode::ACCEPTED, Json(job)))
+}
+
+#[derive(Deserialize)]
+struct ResolveDuplicatesRequest {
+    action: DuplicateAction,
+    /// The copy that stays as it is
+    keep: String,
+    /// Copies of it to trash or link to it
+    paths: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct ResolveDuplicatesResponse {
+    resolved: Vec<FileInfo>,
+    failed: Vec<ResolveError>,
+}
+
+#[derive(Serialize)]
+struct ResolveError {
+    path: String,
+    error: String,
+}
+
+/// Move duplicates of a file to the trash or replace them with hard links to it. Each is
+/// checked to still have the same contents first, and fails or succeeds on its own.
+async fn resolve_duplicates(
+    State(app_state): State<AppState>,
+    Extension(auth_context): Extension<AuthContext>,
+    Json(request): Json<ResolveDuplicatesRequest>,
+) -> Result<Json<ResolveDuplicatesResponse>, ApiError> {
+    let file_service = app_state.file_service(&auth_context.email);
+    let duplicates = DuplicateService::new(
+        app_state.config.clone(),
+        app_state.storage.clone(),
+        app_state.checksum_service.clone(),
+    );
+    file_service.get_info(&request.keep).await?;
+
+    let mut resolved = Vec::new();
+    let mut failed = Vec::new();
+    for path in request.paths {
+        match duplicates.resolve(&file_service, &request.keep, &path, request.action).await {
+            Ok(info) => {
+                match request.action {
+                    DuplicateAction::Trash => app_state.change_feed.publish_rename(&path, &info.path),
+                    DuplicateAction::Hardlink => app_state.change_feed.publish(&path, ChangeKind::Modified),
+                }
+                resolved.push(info);
+            }
+            Err(e) => failed.push(ResolveError {
+                path,
+                error: e.to_string(),
+            }),
         }
-    })?;
-    
-    let file_path = full_target_path.join(filename);
-    
-    // Create file and buffered writer
-    let file = File::create(&file_path).await.map_err(|e| {
-        ApiError::InternalServerError {
-            message: format!("Failed to create file: {}", e),
+    }
+    Ok(Json(ResolveDuplicatesResponse { resolved, failed }))
+}
+
+/// Fail with `FileExists` when something is at `destination` and it isn't to be overwritten
+async fn check_destination(file_service: &FileService, destination: &str, overwrite: bool) -> Result<(), ApiError> {
+    if !overwrite && file_service.get_info(destination).await.is_ok() {
+        return Err(ApiError::FileExists {
+            path: destination.to_string(),
+        });
+    }
+    Ok(())
+}
+
+/// Parameters of an `extract` job
+#[derive(Serialize, Deserialize)]
+struct ExtractJob {
+    path: String,
+    destination: String,
+    overwrite: bool,
+}
+
+/// Parameters of a `compress` job
+#[derive(Serialize, Deserialize)]
+struct CompressJob {
+    paths: Vec<String>,
+    destination: String,
+    format: ArchiveFormat,
+    compression: ArchiveCompression,
+    overwrite: bool,
+}
+
+/// Parameters of a `copy` job, and the request for one
+#[derive(Serialize, Deserialize)]
+struct CopyJob {
+    from: String,
+    to: String,
+}
+
+/// Parameters of a `delete` job
+#[derive(Serialize, Deserialize)]
+struct DeleteJob {
+    path: String,
+}
+
+/// Parameters of a `duplicates` job, and the request for one
+#[derive(Serialize, Deserialize)]
+struct DuplicatesJob {
+    /// Directory to look below, by default the root
+    #[serde(default)]
+    path: String,
+    /// Smallest file to compare, in bytes
+    #[serde(default)]
+    min_size: u64,
+}
+
+/// Run the jobs started by these routes on behalf of whoever started them
+pub fn register_jobs(app_state: &AppState) {
+    let jobs = &app_state.job_service;
+
+    let state = app_state.clone();
+    jobs.register("extract", move |params: ExtractJob, context: JobContext| {
+        let state = state.clone();
+        async move {
+            let archive_service = ArchiveService::new(state.config.clone(), state.storage.clone());
+            let file_service = state.file_service(&context.owner);
+            archive_service
+                .extract(
+                    file_service,
+                    state.change_feed.clone(),
+                    &params.path,
+                    &params.destination,
+                    params.overwrite,
+                    context.reporter,
+                )
+                .await
         }
-    })?;
-    
-    let mut writer = BufWriter::new(file);
-    let mut total_bytes = 0u64;
-    let mut _chunk_count = 0u64;
-    let stream_start = std::time::Instant::now();
-    
-    // Stream data in chunks
-    while let Some(chunk) = field.next().await {
-        let chunk = chunk.map_err(|e| {
-            ApiError::BadRequest {
-                message: format!("Failed to read file chunk: {}", e),
-            }
-        })?;
-        
-        writer.write_all(&chunk).await.map_err(|e| {
-            ApiError::InternalServerError {
-                message: format!("Failed to write file chunk: {}", e),
+    });
+
+    let state = app_state.clone();
+    jobs.register("compress", move |params: CompressJob, context: JobContext| {
+        let state = state.clone();
+        async move {
+            let file_service = state.file_service(&context.owner);
+            check_destination(&file_service, &params.destination, params.overwrite).await?;
+            let archive_service = ArchiveService::new(state.config.clone(), state.storage.clone());
+            let roots = archive_service.resolve(&params.paths).await?;
+
+            let mut total = 0;
+            for root in &roots {
+                total += match root.info.is_directory {
+                    true => state.disk_usage_service.size(&root.info.path).await?.bytes,
+                    false => root.info.size,
+                };
             }
-        })?;
-        
-        total_bytes += chunk.len() as u64;
-        _chunk_count += 1;
-    }
-    
-    // Flush and close file
-    writer.flush().await.map_err(|e| {
-        ApiError::InternalServerError {
-            message: format!("Failed to flush file: {}", e),
+            context.reporter.set_total(Some(total));
+
+            let info = archive_service
+                .create(&file_service, roots, &params.destination, params.format, params.compression, context.reporter)
+                .await?;
+            state.change_feed.publish(&info.path, ChangeKind::Created);
+            Ok(info)
         }
-    })?;
-    
-    let final_duration = stream_start.elapsed();
-    let final_mb = total_bytes as f64 / (1024.0 * 1024.0);
-    let _final_speed = final_mb / final_duration.as_secs_f64();
-    
-    // Get file metadata and create FileInfo
-    let metadata = tokio::fs::metadata(&file_path).await.map_err(|e| {
-        ApiError::InternalServerError {
-            message: format!("Failed to get file metadata: {}", e),
+    });
+
+    let state = app_state.clone();
+    jobs.register("copy", move |params: CopyJob, context: JobContext| {
+        let state = state.clone();
+        async move {
+            let file_service = state.file_service(&context.owner);
+            let info = file_service.copy_path(&params.from, &params.to).await?;
+            state.change_feed.publish(&params.to, ChangeKind::Created);
+            Ok(info)
         }
-    })?;
-    
-    let file_info = FileInfo {
-        name: filename.to_string(),
-        path: format!("{}/{}", dir_path.trim_end_matches('/'), filename),
-        size: metadata.len(),
-        is_directory: false,
-        modified: metadata.modified()
-            .map(|t| DateTime::<Utc>::from(t))
-            .unwrap_or_else(|_| Utc::now()),
-        mime_type: Some(mime_guess::from_path(filename).first_or_octet_stream().to_string()),
-    };
-    
-    Ok((file_info, created_dir))
-}
+    });
 
-async fn stream_upload_file(
-    config: &crate::config::Config, 
-    target_path: &str, 
-    filename: &str, 
-    mut field: axum::extract::multipart::Field<'_>
-) -> Result<FileInfo, ApiError> {
-    use crate::utils::security::resolve_path;
-    
-    // Get storage directory from config
-    let storage_path = &config.storage.home_directory;
-    
-    // Resolve the full target path
-    let full_target_path = resolve_path(storage_path, target_path).map_err(|e| {
-        ApiError::BadRequest {
-            message: format!("Invalid target path: {}", e),
+    let state = app_state.clone();
+    jobs.register("delete", move |params: DeleteJob, context: JobContext| {
+        let state = state.clone();
+        async move {
+            let file_service = state.file_service(&context.owner);
+            file_service.delete_file(&params.path).await?;
+            state.change_feed.publish(&params.path, ChangeKind::Deleted);
+            Ok(DeleteResponse {
+                message: "File deleted successfully".to_string(),
+                path: params.path,
+            })
         }
-    })?;
-    
-    // Ensure target directory exists
-    tokio::fs::create_dir_all(&full_target_path).await.map_err(|e| {
-        ApiError::InternalServerError {
-            message: format!("Failed to create target directory: {}", e),
+    });
+
+    let state = app_state.clone();
+    jobs.register("duplicates", move |params: DuplicatesJob, context: JobContext| {
+        let state = state.clone();
+        async move {
+            let duplicates =
+                DuplicateService::new(state.config.clone(), state.storage.clone(), state.checksum_service.clone());
+            duplicates.find(&params.path, params.min_size, &context.reporter).await
         }
-    })?;
-    
-    let file_path = full_target_path.join(filename);
-    
-    // Create file and buffered writer
-    let file = File::create(&file_path).await.map_err(|e| {
-        ApiError::InternalServerError {
-            message: format!("Failed to create file: {}", e),
+    });
+}
+
+/// `GET /download/archive` is a file called "archive" at the root, not the bulk download
+async fn download_file_named_archive(
+    state: State<AppState>,
+    auth_context: Extension<AuthContext>,
+    query: Query<DownloadQuery>,
+) -> Result<Response, ApiError> {
+    download_file(state, auth_context, Path("archive".to_string()), query).await
+}
+
+/// Download several files and directories as one archive
+async fn download_archive(
+    State(app_state): State<AppState>,
+    Extension(_auth_context): Extension<AuthContext>,
+    Json(request): Json<ArchiveRequest>,
+) -> Result<Response, ApiError> {
+    archive_response(&app_state, &request.paths, request.format, request.compression).await
+}
+
+/// Stream an archive of `paths` as it is built. Entries that can't be read are listed in
+/// a manifest at the end of the archive instead of failing the download.
+async fn archive_response(
+    app_state: &AppState,
+    paths: &[String],
+    format: ArchiveFormat,
+    compression: ArchiveCompression,
+) -> Result<Response, ApiError> {
+    let archive_service = ArchiveService::new(app_state.config.clone(), app_state.storage.clone());
+    let roots = archive_service.resolve(paths).await?;
+    let filename = ArchiveService::file_name(&roots, format);
+
+    let headers = [
+        (header::CONTENT_TYPE, format.content_type().to_string()),
+        (header::CONTENT_DISPOSITION, format!("attachment; filename=\"{}\"", filename)),
+    ];
+    let body = StreamBody::new(archive_service.stream(roots, format, compression));
+    Ok((StatusCode::OK, headers, body).into_response())
+}
+
+/// Serve a file to be viewed in the browser rather than saved. Text is sent as UTF-8, and
+/// types that could run script in our origin are still downloaded.
+async fn preview_file(
+    State(app_state): State<AppState>,
+    Extension(auth_context): Extension<AuthContext>,
+    Path(path): Path<String>,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    let file_service = app_state.file_service(&auth_context.email)
+        .with_archives(app_state.archive_browser.clone());
+    let info = file_service.get_info(&path).await?;
+    if info.is_directory {
+        return Err(ApiError::BadRequest {
+            message: "Cannot preview a directory".to_string(),
+        });
+    }
+
+    let etag = info.etag();
+    let if_none_match = headers.get(header::IF_NONE_MATCH).and_then(|value| value.to_str().ok());
+    if if_none_match.is_some_and(|value| value.split(',').any(|tag| tag.trim() == etag)) {
+        return Ok((StatusCode::NOT_MODIFIED, [(header::ETAG, etag)]).into_response());
+    }
+
+    let mut kind = preview::classify(info.mime_type.as_deref());
+    if kind == Preview::Unknown {
+        let mut head = Vec::new();
+        if info.size > 0 {
+            let length = info.size.min(preview::SNIFF_LENGTH as u64);
+            let (mut reader, _) = file_service.open_file(&path, Some(0..length)).await?;
+            reader.read_to_end(&mut head).await?;
         }
-    })?;
-    
-    let mut writer = BufWriter::new(file);
-    let mut total_bytes = 0u64;
-    let mut _chunk_count = 0u64;
-    let stream_start = std::time::Instant::now();
-    
-    // Stream data in chunks
-    while let Some(chunk) = field.next().await {
-        let chunk = chunk.map_err(|e| {
-            ApiError::BadRequest {
-                message: format!("Failed to read file chunk: {}", e),
-            }
-        })?;
-        
-        writer.write_all(&chunk).await.map_err(|e| {
-            ApiError::InternalServerError {
-                message: format!("Failed to write file chunk: {}", e),
-            }
-        })?;
-        
-        total_bytes += chunk.len() as u64;
-        _chunk_count += 1;
+        kind = match preview::looks_like_text(&head) {
+            true => Preview::Text,
+            false => Preview::Attachment,
+        };
     }
-    
-    // Flush and close file
-    writer.flush().await.map_err(|e| {
-        ApiError::InternalServerError {
-            message: format!("Failed to flush file: {}", e),
+
+    let response = Response::builder()
+        .header(header::ETAG, &etag)
+        .header(header::X_CONTENT_TYPE_OPTIONS, "nosniff");
+    match kind {
+        Preview::Text => {
+            let max_size = app_state.config.preview.max_text_size;
+            if info.size > max_size {
+                return Err(ApiError::FileTooLarge { size: info.size });
+            }
+            let (mut reader, _) = file_service.open_file(&path, None).await?;
+            let mut data = Vec::with_capacity(info.size as usize);
+            reader.read_to_end(&mut data).await?;
+            let text = String::from_utf8_lossy(&data).into_owned();
+
+            let response = response
+                .header(header::CONTENT_TYPE, "text/plain; charset=utf-8")
+                .header(header::CONTENT_DISPOSITION, preview::content_disposition("inline", &info.name))
+                .header(header::CONTENT_SECURITY_POLICY, preview::SANDBOX_POLICY)
+                .body(axum::body::boxed(axum::body::Full::from(text)))
+                .map_err(response_error)?;
+            Ok(response)
         }
-    })?;
-    
-    let final_duration = stream_start.elapsed();
-    let final_mb = total_bytes as f64 / (1024.0 * 1024.0);
-    let _final_speed = final_mb / final_duration.as_secs_f64();
-    
-    // Get file metadata and create FileInfo
-    let metadata = tokio::fs::metadata(&file_path).await.map_err(|e| {
-        ApiError::InternalServerError {
-            message: format!("Failed to get file metadata: {}", e),
+        Preview::Inline(content_type) => {
+            let policy = match content_type.as_str() {
+                "application/pdf" => preview::PDF_POLICY,
+                _ => preview::SANDBOX_POLICY,
+            };
+            let response = response
+                .header(header::CONTENT_TYPE, content_type)
+                .header(header::CONTENT_DISPOSITION, preview::content_disposition("inline", &info.name))
+                .header(header::CONTENT_SECURITY_POLICY, policy);
+            stream_file(&file_service, &path, &info, &headers, response).await
         }
-    })?;
-    
-    let file_info = FileInfo {
-        name: filename.to_string(),
-        path: format!("{}/{}", target_path.trim_end_matches('/'), filename),
-        size: metadata.len(),
-        is_directory: false,
-        modified: metadata.modified()
-            .map(|t| DateTime::<Utc>::from(t))
-            .unwrap_or_else(|_| Utc::now()),
-        mime_type: Some(mime_guess::from_path(filename).first_or_octet_stream().to_string()),
+        Preview::Attachment | Preview::Unknown => {
+            let response = response
+                .header(header::CONTENT_TYPE, "application/octet-stream")
+                .header(header::CONTENT_DISPOSITION, preview::content_disposition("attachment", &info.name))
+                .header(header::CONTENT_SECURITY_POLICY, preview::SANDBOX_POLICY);
+            stream_file(&file_service, &path, &info, &headers, response).await
+        }
+    }
+}
+
+/// Send a file, or the part of it asked for in a `Range` header, so that audio and video
+/// can be seeked
+async fn stream_file(
+    file_service: &FileService,
+    path: &str,
+    info: &FileInfo,
+    headers: &HeaderMap,
+    mut response: axum::http::response::Builder,
+) -> Result<Response, ApiError> {
+    let range = match headers.get(header::RANGE).and_then(|value| value.to_str().ok()) {
+        Some(value) => match parse_byte_range(value, info.size) {
+            Some(range) => range,
+            None => {
+                return Ok((
+                    StatusCode::RANGE_NOT_SATISFIABLE,
+                    [(header::CONTENT_RANGE, format!("bytes */{}", info.size))],
+                )
+                    .into_response())
+            }
+        },
+        None => None,
     };
-    
-    Ok(file_info)
+
+    response = response.header(header::ACCEPT_RANGES, "bytes");
+    let (start, length) = match range {
+        Some((start, end)) => {
+            response = response
+                .status(StatusCode::PARTIAL_CONTENT)
+                .header(header::CONTENT_RANGE, format!("bytes {}-{}/{}", start, end, info.size));
+            (start, end - start + 1)
+        }
+        None => (0, info.size),
+    };
+    response = response.header(header::CONTENT_LENGTH, length);
+
+    let (file, _) = file_service.open_file(path, Some(start..start + length)).await?;
+    let stream = StreamBody::new(ReaderStream::new(file));
+    response.body(axum::body::boxed(stream)).map_err(response_error)
 }
 
-async fn download_file(
+/// A response that couldn't be built, from a header value we failed to make valid
+fn response_error(e: axum::http::Error) -> ApiError {
+    ApiError::InternalServerError {
+        message: format!("Failed to build response: {}", e),
+    }
+}
+
+#[derive(Deserialize)]
+struct ThumbnailQuery {
+    /// Longest edge in pixels, rounded up to one of the configured sizes
+    size: Option<u32>,
+}
+
+async fn thumbnail(
     State(app_state): State<AppState>,
     Extension(_auth_context): Extension<AuthContext>,
     Path(path): Path<String>,
+    Query(query): Query<ThumbnailQuery>,
+    headers: HeaderMap,
 ) -> Result<Response, ApiError> {
-    let file_service = FileService::new(app_state.config.as_ref().clone());
-    let (data, filename) = file_service.download_file(&path).await?;
-    
-    let headers = [
-        (header::CONTENT_TYPE, "application/octet-stream"),
-        (
-            header::CONTENT_DISPOSITION,
-            &format!("attachment; filename=\"{}\"", filename),
-        ),
+    let thumbnail = app_state.thumbnail_service.thumbnail(&path, query.size).await?;
+    let cache_headers = [
+        (header::ETAG, thumbnail.etag.clone()),
+        (header::CACHE_CONTROL, "private, max-age=86400".to_string()),
     ];
+    let not_modified = headers
+        .get(header::IF_NONE_MATCH)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.split(',').any(|tag| tag.trim() == thumbnail.etag));
+    if not_modified {
+        return Ok((StatusCode::NOT_MODIFIED, cache_headers).into_response());
+    }
 
-    Ok((StatusCode::OK, headers, data).into_response())
+    let content_type = [(header::CONTENT_TYPE, thumbnail.content_type)];
+    Ok((StatusCode::OK, content_type, cache_headers, thumbnail.data).into_response())
 }
 
 #[derive(Serialize)]
@@ -582,27 +1095,59 @@ struct RenameResponse {
     file_info: FileInfo,
 }
 
+#[derive(Deserialize)]
+struct DeleteQuery {
+    /// Delete as a job and answer straight away, for large directories
+    #[serde(default)]
+    background: bool,
+}
+
+/// Delete the file at the request path. Besides `DELETE /*path` it is routed on every other
+/// route here: a path matching a route that lacks the method gets 405 rather than falling
+/// through to the wildcard, so files named like an endpoint couldn't be deleted otherwise.
 async fn delete_file(
     State(app_state): State<AppState>,
-    Extension(_auth_context): Extension<AuthContext>,
-    Path(path): Path<String>,
-) -> Result<Json<DeleteResponse>, ApiError> {
-    let file_service = FileService::new(app_state.config.as_ref().clone());
+    Extension(auth_context): Extension<AuthContext>,
+    uri: Uri,
+    Query(query): Query<DeleteQuery>,
+) -> Result<Response, ApiError> {
+    let path = request_path(&uri)?;
+    let file_service = app_state.file_service(&auth_context.email);
+    if query.background {
+        file_service.get_info(&path).await?;
+        let job = app_state.job_service.submit("delete", &auth_context.email, &DeleteJob { path }).await?;
+        return Ok((StatusCode::ACCEPTED, Json(job)).into_response());
+    }
+
     file_service.delete_file(&path).await?;
+    app_state.change_feed.publish(&path, ChangeKind::Deleted);
     
     Ok(Json(DeleteResponse {
         message: "File deleted successfully".to_string(),
         path,
-    }))
+    })
+    .into_response())
+}
+
+/// The storage path a request addresses: its URI path within this router, decoded
+fn request_path(uri: &Uri) -> Result<String, ApiError> {
+    let raw = uri.path().trim_start_matches('/');
+    percent_decode_str(raw)
+        .decode_utf8()
+        .map(|path| path.into_owned())
+        .map_err(|_| ApiError::InvalidPath {
+            path: raw.to_string(),
+        })
 }
 
 async fn rename_file(
     State(app_state): State<AppState>,
-    Extension(_auth_context): Extension<AuthContext>,
+    Extension(auth_context): Extension<AuthContext>,
     Json(request): Json<RenameRequest>,
 ) -> Result<Json<RenameResponse>, ApiError> {
-    let file_service = FileService::new(app_state.config.as_ref().clone());
+    let file_service = app_state.file_service(&auth_context.email);
     let file_info = file_service.rename_file(&request.from, &request.to).await?;
+    app_state.change_feed.publish_rename(&request.from, &request.to);
     
     Ok(Json(RenameResponse {
         message: "File renamed successfully".to_string(),
@@ -612,25 +1157,239 @@ async fn rename_file(
     }))
 }
 
+#[derive(Deserialize)]
+struct LinkRequest {
+    path: String,
+    sha256: String,
+}
+
+#[derive(Serialize)]
+struct LinkResponse {
+    message: String,
+    file_info: FileInfo,
+}
+
+/// Create a file from contents the server already has, skipping the upload. Answers 404
+/// when it doesn't have them, and the file has to be uploaded instead.
+async fn link_file(
+    State(app_state): State<AppState>,
+    Extension(auth_context): Extension<AuthContext>,
+    Json(request): Json<LinkRequest>,
+) -> Result<Response, ApiError> {
+    let file_service = app_state.file_service(&auth_context.email);
+    let (file_info, created) = file_service.link_file(&request.path, &request.sha256).await?;
+
+    let (status, kind) = match created {
+        true => (StatusCode::CREATED, ChangeKind::Created),
+        false => (StatusCode::OK, ChangeKind::Modified),
+    };
+    app_state.change_feed.publish(&request.path, kind);
+
+    let response = LinkResponse {
+        message: "File created from existing contents".to_string(),
+        file_info,
+    };
+    Ok((status, Json(response)).into_response())
+}
+
+#[derive(Deserialize)]
+struct ChecksumQuery {
+    /// Comma-separated; SHA-256 when not given
+    algorithms: Option<String>,
+}
+
+#[derive(Serialize)]
+struct ChecksumResponse {
+    #[serde(flatten)]
+    info: FileInfo,
+    checksums: BTreeMap<ChecksumAlgorithm, String>,
+}
+
+/// Checksums of a file, computed unless cached for its current size and modification time
+async fn checksum(
+    State(app_state): State<AppState>,
+    Extension(_auth_context): Extension<AuthContext>,
+    Path(path): Path<String>,
+    Query(query): Query<ChecksumQuery>,
+) -> Result<Json<ChecksumResponse>, ApiError> {
+    let mut algorithms = ChecksumAlgorithm::parse_list(query.algorithms.as_deref().unwrap_or_default())?;
+    if algorithms.is_empty() {
+        algorithms.push(ChecksumAlgorithm::Sha256);
+    }
+    let (info, checksums) = app_state.checksum_service.checksums(&path, &algorithms).await?;
+    Ok(Json(ChecksumResponse { info, checksums }))
+}
+
+#[derive(Serialize)]
+struct StatResponse {
+    #[serde(flatten)]
+    info: FileInfo,
+    #[serde(flatten)]
+    details: FileDetails,
+}
+
+/// A file or directory with its mode, owner, times, inode and link count, as the local disk
+/// records them
+async fn stat_file(
+    State(app_state): State<AppState>,
+    Extension(auth_context): Extension<AuthContext>,
+    Path(path): Path<String>,
+) -> Result<Json<StatResponse>, ApiError> {
+    let file_service = app_state.file_service(&auth_context.email);
+    let (info, details) = file_service.get_details(&path).await?;
+    Ok(Json(StatResponse { info, details }))
+}
+
+fn check_attribute_access(auth_context: &AuthContext) -> Result<(), ApiError> {
+    if !auth_context.is_admin() {
+        return Err(ApiError::Forbidden {
+            message: "Admin access required to change file attributes".to_string(),
+        });
+    }
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct AttributesResponse {
+    /// Files and directories changed
+    changed: u64,
+    file: StatResponse,
+}
+
+async fn attributes_response(
+    file_service: &FileService,
+    path: &str,
+    changed: u64,
+) -> Result<Json<AttributesResponse>, ApiError> {
+    let (info, details) = file_service.get_details(path).await?;
+    Ok(Json(AttributesResponse {
+        changed,
+        file: StatResponse { info, details },
+    }))
+}
+
+#[derive(Deserialize)]
+struct ChangeModeRequest {
+    path: String,
+    /// Octal, like `644`
+    mode: String,
+    /// For directories, when it differs from `mode`
+    directory_mode: Option<String>,
+    #[serde(default)]
+    recursive: bool,
+}
+
+/// Set the permission bits of a file or directory, and optionally of everything below it
+async fn change_mode(
+    State(app_state): State<AppState>,
+    Extension(auth_context): Extension<AuthContext>,
+    Json(request): Json<ChangeModeRequest>,
+) -> Result<Json<AttributesResponse>, ApiError> {
+    check_attribute_access(&auth_context)?;
+    let mode = AttributeService::parse_mode(&request.mode)?;
+    let directory_mode = request.directory_mode.as_deref().map(AttributeService::parse_mode).transpose()?;
+    let path = normalize(&request.path)?;
+
+    let attributes = AttributeService::new(app_state.config.clone());
+    let changed = attributes.chmod(&path, mode, directory_mode, request.recursive).await?;
+    tracing::info!(
+        target: "audit",
+        "{} set the mode of {} to {:04o}{}{}",
+        auth_context.email,
+        path,
+        mode,
+        directory_mode.map(|mode| format!(" ({:04o} for directories)", mode)).unwrap_or_default(),
+        if request.recursive { " recursively" } else { "" }
+    );
+    app_state.change_feed.publish(&path, ChangeKind::Modified);
+    attributes_response(&app_state.file_service(&auth_context.email), &path, changed).await
+}
+
+#[derive(Deserialize)]
+struct ChangeOwnerRequest {
+    path: String,
+    /// Name or numeric id
+    user: Option<String>,
+    /// Name or numeric id
+    group: Option<String>,
+    #[serde(default)]
+    recursive: bool,
+}
+
+/// Give a file or directory, and optionally everything below it, to another user or group
+async fn change_owner(
+    State(app_state): State<AppState>,
+    Extension(auth_context): Extension<AuthContext>,
+    Json(request): Json<ChangeOwnerRequest>,
+) -> Result<Json<AttributesResponse>, ApiError> {
+    check_attribute_access(&auth_context)?;
+    if request.user.is_none() && request.group.is_none() {
+        return Err(ApiError::BadRequest {
+            message: "Give a user, a group or both".to_string(),
+        });
+    }
+    let owner = Ownership::parse(request.user.as_deref(), request.group.as_deref())?;
+    let path = normalize(&request.path)?;
+
+    let attributes = AttributeService::new(app_state.config.clone());
+    let changed = attributes.chown(&path, owner, request.recursive).await?;
+    tracing::info!(
+        target: "audit",
+        "{} set the owner of {} to {}:{}{}",
+        auth_context.email,
+        path,
+        owner.uid.map(|uid| uid.to_string()).unwrap_or_default(),
+        owner.gid.map(|gid| gid.to_string()).unwrap_or_default(),
+        if request.recursive { " recursively" } else { "" }
+    );
+    app_state.change_feed.publish(&path, ChangeKind::Modified);
+    attributes_response(&app_state.file_service(&auth_context.email), &path, changed).await
+}
+
+#[derive(Deserialize)]
+struct SetTimesRequest {
+    path: String,
+    modified: Option<DateTime<Utc>>,
+    accessed: Option<DateTime<Utc>>,
+}
+
+/// Set the modification and access times of a file or directory
+async fn set_times(
+    State(app_state): State<AppState>,
+    Extension(auth_context): Extension<AuthContext>,
+    Json(request): Json<SetTimesRequest>,
+) -> Result<Json<AttributesResponse>, ApiError> {
+    check_attribute_access(&auth_context)?;
+    if request.modified.is_none() && request.accessed.is_none() {
+        return Err(ApiError::BadRequest {
+            message: "Give a modified time, an accessed time or both".to_string(),
+        });
+    }
+    let path = normalize(&request.path)?;
+
+    let attributes = AttributeService::new(app_state.config.clone());
+    attributes.set_times(&path, request.modified, request.accessed).await?;
+    tracing::info!(
+        target: "audit",
+        "{} set the times of {} to modified {}, accessed {}",
+        auth_context.email,
+        path,
+        request.modified.map(|time| time.to_rfc3339()).unwrap_or_else(|| "unchanged".to_string()),
+        request.accessed.map(|time| time.to_rfc3339()).unwrap_or_else(|| "unchanged".to_string())
+    );
+    app_state.change_feed.publish(&path, ChangeKind::Modified);
+    attributes_response(&app_state.file_service(&auth_context.email), &path, 1).await
+}
+
 // Helper function to upload large files using pre-loaded data
 async fn upload_large_file_data(
-    config: &crate::config::Config,
+    file_service: &FileService,
     target_path: &str,
     filename: &str,
-    data: &[u8],
+    data: &Bytes,
+    expected: &[ExpectedChecksum],
     created_dirs: &mut std::collections::HashSet<String>,
 ) -> Result<(FileInfo, Option<String>), ApiError> {
-    use crate::utils::security::resolve_path;
-    
-    let storage_path = &config.storage.home_directory;
-    
-    // Resolve the full target path
-    let full_target_path = resolve_path(storage_path, target_path).map_err(|e| {
-        ApiError::BadRequest {
-            message: format!("Invalid target path: {}", e),
-        }
-    })?;
-    
     // Track directory creation for response
     let created_dir = if !created_dirs.contains(target_path) && target_path != "/" {
         created_dirs.insert(target_path.to_string());
@@ -639,56 +1398,22 @@ async fn upload_large_file_data(
         None
     };
     
-    // Ensure target directory exists
-    tokio::fs::create_dir_all(&full_target_path).await.map_err(|e| {
-        ApiError::InternalServerError {
-            message: format!("Failed to create target directory: {}", e),
-        }
-    })?;
-    
-    let file_path = full_target_path.join(filename);
-    
-    // Write file data directly (since we already have it in memory)
-    tokio::fs::write(&file_path, data).await.map_err(|e| {
-        ApiError::InternalServerError {
-            message: format!("Failed to write file: {}", e),
-        }
-    })?;
-    
-    // Get file metadata and create FileInfo
-    let metadata = tokio::fs::metadata(&file_path).await.map_err(|e| {
-        ApiError::InternalServerError {
-            message: format!("Failed to get file metadata: {}", e),
-        }
-    })?;
-    
-    let file_info = FileInfo {
-        name: filename.to_string(),
-        path: format!("{}/{}", target_path.trim_end_matches('/'), filename),
-        size: metadata.len(),
-        is_directory: false,
-        modified: metadata.modified()
-            .map(|t| DateTime::<Utc>::from(t))
-            .unwrap_or_else(|_| Utc::now()),
-        mime_type: Some(mime_guess::from_path(filename).first_or_octet_stream().to_string()),
-    };
+    let file_info = write_file_data(file_service, target_path, filename, data, expected).await?;
     
     Ok((file_info, created_dir))
 }
 
 // Helper function to upload small files using pre-loaded data with folder structure
 async fn upload_small_file_data(
-    config: &crate::config::Config,
+    file_service: &FileService,
     target_path: &str,
     relative_path: &str,
-    data: &[u8],
+    data: &Bytes,
+    expected: &[ExpectedChecksum],
     created_dirs: &mut std::collections::HashSet<String>,
 ) -> Result<(FileInfo, Option<String>), ApiError> {
-    use crate::utils::security::resolve_path;
     use std::path::Path;
     
-    let storage_path = &config.storage.home_directory;
-    
     // Parse the relative path to extract directory structure and filename
     let path_obj = Path::new(relative_path);
     let filename = path_obj.file_name()
@@ -706,13 +1431,6 @@ async fn upload_small_file_data(
         target_path.to_string()
     };
     
-    // Resolve the full target path
-    let full_target_path = resolve_path(storage_path, &dir_path).map_err(|e| {
-        ApiError::BadRequest {
-            message: format!("Invalid target path: {}", e),
-        }
-    })?;
-    
     // Track directory creation for response
     let created_dir = if !created_dirs.contains(&dir_path) && dir_path != target_path {
         created_dirs.insert(dir_path.clone());
@@ -721,39 +1439,129 @@ async fn upload_small_file_data(
         None
     };
     
-    // Ensure target directory exists
-    tokio::fs::create_dir_all(&full_target_path).await.map_err(|e| {
-        ApiError::InternalServerError {
-            message: format!("Failed to create target directory: {}", e),
-        }
-    })?;
-    
-    let file_path = full_target_path.join(filename);
+    let file_info = write_file_data(file_service, &dir_path, filename, data, expected).await?;
     
-    // Write file data directly
-    tokio::fs::write(&file_path, data).await.map_err(|e| {
-        ApiError::InternalServerError {
-            message: format!("Failed to write file: {}", e),
-        }
-    })?;
+    Ok((file_info, created_dir))
+}
+
+// Write file data we already have in memory, creating its directory first
+async fn write_file_data(
+    file_service: &FileService,
+    dir_path: &str,
+    filename: &str,
+    data: &Bytes,
+    expected: &[ExpectedChecksum],
+) -> Result<FileInfo, ApiError> {
+    file_service.ensure_directory(dir_path).await?;
     
-    // Get file metadata and create FileInfo
-    let metadata = tokio::fs::metadata(&file_path).await.map_err(|e| {
-        ApiError::InternalServerError {
-            message: format!("Failed to get file metadata: {}", e),
-        }
-    })?;
+    let path = format!("{}/{}", dir_path.trim_end_matches('/'), filename);
+    let body = futures::stream::iter([Ok::<_, std::convert::Infallible>(data.clone())]);
+    let (file_info, _) = file_service.write_file_verified(&path, body, expected).await?;
     
-    let file_info = FileInfo {
-        name: filename.to_string(),
-        path: format!("{}/{}", dir_path.trim_end_matches('/'), filename),
-        size: metadata.len(),
-        is_directory: false,
-        modified: metadata.modified()
-            .map(|t| DateTime::<Utc>::from(t))
-            .unwrap_or_else(|_| Utc::now()),
-        mime_type: Some(mime_guess::from_path(filename).first_or_octet_stream().to_string()),
+    Ok(file_info)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_app;
+    use axum::{
+        body::Body,
+        http::{Method, Request, StatusCode},
     };
-    
-    Ok((file_info, created_dir))
+    use tower::ServiceExt;
+
+    fn get(uri: &str, token: &str) -> Request<Body> {
+        Request::get(uri)
+            .header("authorization", format!("Bearer {}", token))
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    fn delete(uri: &str, token: &str) -> Request<Body> {
+        Request::builder()
+            .method(Method::DELETE)
+            .uri(uri)
+            .header("authorization", format!("Bearer {}", token))
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_search_filters_and_paginates() {
+        let dir = tempfile::tempdir().unwrap();
+        let (app, token) = test_app(dir.path()).await;
+        let home = dir.path().join("files");
+        std::fs::create_dir_all(home.join("docs/deep/deeper")).unwrap();
+        for (path, size) in [("a.txt", 1), ("docs/b.txt", 5), ("docs/c.md", 5), ("docs/deep/d.txt", 50), ("docs/deep/deeper/e.txt", 5)] {
+            std::fs::write(home.join(path), "x".repeat(size)).unwrap();
+        }
+
+        let search = |query: &str| {
+            let request = get(&format!("/api/files/search?source=walk&{}", query), &token);
+            let app = app.clone();
+            async move {
+                let response = app.oneshot(request).await.unwrap();
+                assert_eq!(response.status(), StatusCode::OK);
+                let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+                let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+                let paths: Vec<String> = body["results"]
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .map(|hit| hit["path"].as_str().unwrap().to_string())
+                    .collect();
+                (paths, body["pagination"]["has_more"].as_bool().unwrap())
+            }
+        };
+
+        let (paths, has_more) = search("q=*.txt&mode=glob&type=file&path=/docs&max_size=10&limit=1").await;
+        assert_eq!((paths, has_more), (vec!["docs/b.txt".to_string()], true));
+        let (paths, has_more) = search("q=*.txt&mode=glob&type=file&path=/docs&max_size=10&limit=1&offset=1").await;
+        assert_eq!((paths, has_more), (vec!["docs/deep/deeper/e.txt".to_string()], false));
+        let (paths, _) = search("q=^d&mode=regex&max_depth=2").await;
+        assert_eq!(paths, ["docs", "docs/deep"]);
+        let (paths, _) = search("type=directory&q=deep").await;
+        assert_eq!(paths, ["docs/deep", "docs/deep/deeper"]);
+        let (paths, _) = search("mime_type=text/markdown").await;
+        assert_eq!(paths, ["docs/c.md"]);
+
+        // A file called "search" is still a file
+        std::fs::write(home.join("search"), "s").unwrap();
+        assert_eq!(app.clone().oneshot(delete("/api/files/search", &token)).await.unwrap().status(), StatusCode::OK);
+        assert!(!home.join("search").exists());
+    }
+
+    #[tokio::test]
+    async fn test_files_named_like_endpoints_can_be_deleted() {
+        let dir = tempfile::tempdir().unwrap();
+        let (app, token) = test_app(dir.path()).await;
+        let home = dir.path().join("files");
+        for path in ["thumbnail/a b.txt", "by-hash", "preview/a.txt", "download/archive", "download/a.txt", "extract", "compress", "copy", "checksum/a.txt", "duplicates/resolve", "stat/a.txt", "chmod", "chown", "times"] {
+            let file = home.join(path);
+            std::fs::create_dir_all(file.parent().unwrap()).unwrap();
+            std::fs::write(&file, "a").unwrap();
+
+            let request = delete(&format!("/api/files/{}", path.replace(' ', "%20")), &token);
+            assert_eq!(app.clone().oneshot(request).await.unwrap().status(), StatusCode::OK, "{}", path);
+            assert!(!file.exists());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_preview_names_any_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let (app, token) = test_app(dir.path()).await;
+        let home = dir.path().join("files");
+        let cases = [
+            ("café.txt", "caf%C3%A9.txt", "inline; filename=\"caf_.txt\"; filename*=UTF-8''caf%C3%A9.txt"),
+            ("a\nb.txt", "a%0Ab.txt", "inline; filename=\"a_b.txt\"; filename*=UTF-8''a%0Ab.txt"),
+            ("x\u{1}.png", "x%01.png", "inline; filename=\"x_.png\"; filename*=UTF-8''x%01.png"),
+        ];
+        for (name, uri_name, expected) in cases {
+            std::fs::write(home.join(name), "preview").unwrap();
+            let response = app.clone().oneshot(get(&format!("/api/files/preview/{}", uri_name), &token)).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK, "{:?}", name);
+            assert_eq!(response.headers()["content-disposition"], expected);
+        }
+    }
 }
@@ -0,0 +1,139 @@
+use crate::{
+    errors::ApiError,
+    middleware::AuthContext,
+    services::{Job, JobStatus},
+    AppState,
+};
+use axum::{
+    extract::{Extension, Path, Query, State},
+    http::StatusCode,
+    response::sse::{Event, KeepAlive, Sse},
+    routing::{get, post},
+    Json, Router,
+};
+use futures::Stream;
+use serde::{Deserialize, Serialize};
+use std::{convert::Infallible, time::Duration};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+
+/// Least time between two updates of a watched job
+const WATCH_INTERVAL: Duration = Duration::from_millis(500);
+const DEFAULT_LIMIT: u32 = 100;
+const MAX_LIMIT: u32 = 1000;
+
+pub fn routes() -> Router<AppState> {
+    Router::new()
+        .route("/", get(list_jobs))
+        .route("/:id", get(get_job))
+        .route("/:id/events", get(watch_job))
+        .route("/:id/cancel", post(cancel_job))
+        .route("/:id/retry", post(retry_job))
+}
+
+#[derive(Deserialize)]
+struct ListQuery {
+    status: Option<JobStatus>,
+    limit: Option<u32>,
+}
+
+#[derive(Serialize)]
+struct JobsResponse {
+    jobs: Vec<Job>,
+}
+
+/// The caller's jobs, newest first; admins see everyone's
+async fn list_jobs(
+    State(app_state): State<AppState>,
+    Extension(auth_context): Extension<AuthContext>,
+    Query(query): Query<ListQuery>,
+) -> Result<Json<JobsResponse>, ApiError> {
+    let owner = (!auth_context.is_admin()).then_some(auth_context.email.as_str());
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+    let jobs = app_state.job_service.list(owner, query.status, limit).await?;
+    Ok(Json(JobsResponse { jobs }))
+}
+
+/// The job `id`, if the caller may see it
+async fn find_job(app_state: &AppState, auth_context: &AuthContext, id: String) -> Result<Job, ApiError> {
+    app_state
+        .job_service
+        .get(&id)
+        .await?
+        .filter(|job| auth_context.is_admin() || job.owner == auth_context.email)
+        .ok_or(ApiError::NotFound {
+            resource: "Job".to_string(),
+            id,
+        })
+}
+
+/// A job with its progress, and its result or error once finished
+async fn get_job(
+    State(app_state): State<AppState>,
+    Extension(auth_context): Extension<AuthContext>,
+    Path(id): Path<String>,
+) -> Result<Json<Job>, ApiError> {
+    find_job(&app_state, &auth_context, id).await.map(Json)
+}
+
+/// Server-Sent Events stream of a job's state, as a `job` event whenever it changes. The
+/// stream ends once the job has finished.
+async fn watch_job(
+    State(app_state): State<AppState>,
+    Extension(auth_context): Extension<AuthContext>,
+    Path(id): Path<String>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, ApiError> {
+    let job = find_job(&app_state, &auth_context, id).await?;
+    let updates = app_state.job_service.watch(&job.id);
+    let job_service = app_state.job_service.clone();
+
+    let (sender, receiver) = mpsc::channel(4);
+    tokio::spawn(async move {
+        let Some(mut updates) = updates else {
+            let _ = sender.send(job_event(&job)).await;
+            return;
+        };
+        loop {
+            let job = updates.borrow_and_update().clone();
+            if sender.send(job_event(&job)).await.is_err() || job.status.is_finished() {
+                return;
+            }
+            tokio::time::sleep(WATCH_INTERVAL).await;
+            if updates.changed().await.is_err() {
+                // Finished while we weren't looking
+                if let Ok(Some(job)) = job_service.get(&job.id).await {
+                    let _ = sender.send(job_event(&job)).await;
+                }
+                return;
+            }
+        }
+    });
+
+    Ok(Sse::new(ReceiverStream::new(receiver)).keep_alive(KeepAlive::default()))
+}
+
+fn job_event(job: &Job) -> Result<Event, Infallible> {
+    Ok(Event::default().event("job").json_data(job).unwrap_or_default())
+}
+
+/// Stop a queued or running job. It is `cancelled` once it has stopped.
+async fn cancel_job(
+    State(app_state): State<AppState>,
+    Extension(auth_context): Extension<AuthContext>,
+    Path(id): Path<String>,
+) -> Result<(StatusCode, Json<Job>), ApiError> {
+    let job = find_job(&app_state, &auth_context, id).await?;
+    let job = app_state.job_service.cancel(&job.id).await?;
+    Ok((StatusCode::ACCEPTED, Json(job)))
+}
+
+/// Run a failed or cancelled job again, as a new job
+async fn retry_job(
+    State(app_state): State<AppState>,
+    Extension(auth_context): Extension<AuthContext>,
+    Path(id): Path<String>,
+) -> Result<(StatusCode, Json<Job>), ApiError> {
+    let job = find_job(&app_state, &auth_context, id).await?;
+    let job = app_state.job_service.retry(&job.id).await?;
+    Ok((StatusCode::ACCEPTED, Json(job)))
+}
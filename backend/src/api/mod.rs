@@ -1,5 +1,21 @@
 pub mod files;
 pub mod auth;
+pub mod search;
+pub mod events;
+pub mod webdav;
+pub mod s3;
+pub mod storage;
+pub mod versions;
+pub mod quota;
+pub mod jobs;
 
-pub use files::routes as files_routes;
+pub use files::{routes as files_routes, register_jobs as register_files_jobs};
 pub use auth::{routes as auth_routes, protected_routes as auth_protected_routes};
+pub use search::{routes as search_routes, register_jobs as register_search_jobs};
+pub use events::routes as events_routes;
+pub use webdav::routes as webdav_routes;
+pub use s3::routes as s3_routes;
+pub use storage::routes as storage_routes;
+pub use versions::routes as versions_routes;
+pub use quota::routes as quota_routes;
+pub use jobs::routes as jobs_routes;
@@ -0,0 +1,51 @@
+use crate::{
+    errors::ApiError,
+    middleware::AuthContext,
+    services::{QuotaOverview, QuotaStatus, Usage},
+    AppState,
+};
+use axum::{
+    extract::{Extension, State},
+    routing::get,
+    Json, Router,
+};
+use serde::Serialize;
+
+pub fn routes() -> Router<AppState> {
+    Router::new()
+        .route("/", get(my_quota))
+        .route("/overview", get(quota_overview))
+}
+
+#[derive(Serialize)]
+struct QuotaResponse {
+    user: String,
+    usage: Usage,
+    quotas: Vec<QuotaStatus>,
+}
+
+/// What the caller stores and the quotas that apply to them
+async fn my_quota(
+    State(app_state): State<AppState>,
+    Extension(auth_context): Extension<AuthContext>,
+) -> Result<Json<QuotaResponse>, ApiError> {
+    let (usage, quotas) = app_state.quota_service.user_quotas(&auth_context.email).await?;
+    Ok(Json(QuotaResponse {
+        user: auth_context.email,
+        usage,
+        quotas,
+    }))
+}
+
+/// Every quota and every user's usage
+async fn quota_overview(
+    State(app_state): State<AppState>,
+    Extension(auth_context): Extension<AuthContext>,
+) -> Result<Json<QuotaOverview>, ApiError> {
+    if !auth_context.is_admin() {
+        return Err(ApiError::Forbidden {
+            message: "Admin access required to view all quotas".to_string(),
+        });
+    }
+    Ok(Json(app_state.quota_service.overview().await?))
+}
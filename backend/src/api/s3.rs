@@ -0,0 +1,1029 @@
+use crate::{
+    db::models::{UserInfo, UserRole},
+    errors::ApiError,
+    services::{normalize, ChangeKind, FileInfo, FileService, StagedFile},
+    utils::{
+        range::parse_byte_range,
+        sigv4::{uri_encode, ChunkedDecoder, PayloadHash, SignedRequest},
+        xml,
+    },
+    AppState,
+};
+use axum::{
+    body::{Body, Bytes, StreamBody},
+    extract::State,
+    http::{header, HeaderMap, Method, Request, StatusCode},
+    response::{IntoResponse, Response},
+    routing::any,
+    Router,
+};
+use base64::Engine;
+use chrono::{DateTime, Utc};
+use futures::{Stream, StreamExt};
+use hyper::body::HttpBody;
+use percent_encoding::percent_decode_str;
+use quick_xml::escape::escape;
+use std::{
+    path::{Component, Path},
+    pin::Pin,
+};
+use tokio_util::io::ReaderStream;
+
+/// Where the S3-compatible API is mounted; clients use it as their endpoint URL
+pub const S3_PREFIX: &str = "/s3";
+
+const S3_NS: &str = "http://s3.amazonaws.com/doc/2006-03-01/";
+
+/// How far a request's `X-Amz-Date` may be from the server clock
+const MAX_CLOCK_SKEW_SECONDS: i64 = 15 * 60;
+
+const MAX_KEYS: usize = 1000;
+const MAX_PART_NUMBER: u32 = 10000;
+
+/// Largest CompleteMultipartUpload body we accept
+const MAX_XML_BODY: usize = 1024 * 1024;
+
+/// Routes are registered under `S3_PREFIX` rather than nested, so that `/s3/` itself
+/// (ListBuckets) is routed too
+pub fn routes() -> Router<AppState> {
+    Router::new()
+        .route(S3_PREFIX, any(handle))
+        .route(&format!("{}/", S3_PREFIX), any(handle))
+        .route(&format!("{}/*path", S3_PREFIX), any(handle))
+}
+
+/// An error in the shape S3 clients expect
+#[derive(Debug)]
+struct S3Error {
+    status: StatusCode,
+    code: &'static str,
+    message: String,
+}
+
+impl S3Error {
+    fn new(status: StatusCode, code: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            code,
+            message: message.into(),
+        }
+    }
+
+    fn no_such_bucket(bucket: &str) -> Self {
+        Self::new(StatusCode::NOT_FOUND, "NoSuchBucket", format!("The bucket {} does not exist", bucket))
+    }
+
+    fn no_such_key() -> Self {
+        Self::new(StatusCode::NOT_FOUND, "NoSuchKey", "The specified key does not exist")
+    }
+
+    fn no_such_upload() -> Self {
+        Self::new(StatusCode::NOT_FOUND, "NoSuchUpload", "The specified multipart upload does not exist")
+    }
+
+    fn malformed_xml(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, "MalformedXML", message)
+    }
+}
+
+impl IntoResponse for S3Error {
+    fn into_response(self) -> Response {
+        let body = format!(
+            "<Error><Code>{}</Code><Message>{}</Message></Error>",
+            self.code,
+            escape(&self.message)
+        );
+        xml_response(self.status, body)
+    }
+}
+
+impl From<ApiError> for S3Error {
+    fn from(error: ApiError) -> Self {
+        let message = error.to_string();
+        match error {
+            ApiError::FileNotFound { .. } | ApiError::NotFound { .. } => Self::no_such_key(),
+            ApiError::InvalidPath { .. } | ApiError::InvalidFileType { .. } => {
+                Self::new(StatusCode::BAD_REQUEST, "InvalidArgument", message)
+            }
+            ApiError::FileTooLarge { .. } => Self::new(StatusCode::BAD_REQUEST, "EntityTooLarge", message),
+            ApiError::QuotaExceeded { .. } => Self::new(StatusCode::INSUFFICIENT_STORAGE, "QuotaExceeded", message),
+            ApiError::BadRequest { .. } => Self::new(StatusCode::BAD_REQUEST, "InvalidRequest", message),
+            ApiError::FileExists { .. } | ApiError::Conflict { .. } => {
+                Self::new(StatusCode::CONFLICT, "InvalidRequest", message)
+            }
+            ApiError::AccessDenied | ApiError::Forbidden { .. } | ApiError::Unauthorized { .. } => {
+                Self::new(StatusCode::FORBIDDEN, "AccessDenied", message)
+            }
+            _ => {
+                tracing::error!("S3 request failed: {}", message);
+                Self::new(StatusCode::INTERNAL_SERVER_ERROR, "InternalError", "We encountered an internal error")
+            }
+        }
+    }
+}
+
+type S3Result = Result<Response, S3Error>;
+
+async fn handle(State(app_state): State<AppState>, request: Request<Body>) -> Response {
+    let (parts, body) = request.into_parts();
+
+    let result = async {
+        let path = percent_decode_str(parts.uri.path())
+            .decode_utf8()
+            .map_err(|_| S3Error::new(StatusCode::BAD_REQUEST, "InvalidURI", "Invalid request path"))?
+            .to_string();
+        let query = parse_query(parts.uri.query().unwrap_or_default());
+
+        let auth = authenticate(&app_state, &parts.method, &path, &query, &parts.headers).await?;
+
+        let rest = path.strip_prefix(S3_PREFIX).unwrap_or_default().trim_start_matches('/');
+        let (bucket, key) = rest.split_once('/').unwrap_or((rest, ""));
+        let request = S3Request {
+            app_state,
+            auth,
+            headers: parts.headers,
+            query,
+            bucket: bucket.to_string(),
+            key: key.to_string(),
+        };
+        request.dispatch(&parts.method, body).await
+    }
+    .await;
+
+    result.unwrap_or_else(IntoResponse::into_response)
+}
+
+/// A verified request and the user it was signed by
+struct Authenticated {
+    user: UserInfo,
+    request: SignedRequest,
+    secret: String,
+}
+
+async fn authenticate(
+    app_state: &AppState,
+    method: &Method,
+    path: &str,
+    query: &[(String, String)],
+    headers: &HeaderMap,
+) -> Result<Authenticated, S3Error> {
+    let request = SignedRequest::parse(headers, query)
+        .map_err(|message| S3Error::new(StatusCode::BAD_REQUEST, "AuthorizationHeaderMalformed", message))?
+        .ok_or_else(|| S3Error::new(StatusCode::FORBIDDEN, "AccessDenied", "Anonymous access is not allowed"))?;
+
+    if !request.is_current(Utc::now(), MAX_CLOCK_SKEW_SECONDS) {
+        return Err(if request.expires_in.is_some() {
+            S3Error::new(StatusCode::FORBIDDEN, "AccessDenied", "Request has expired")
+        } else {
+            S3Error::new(
+                StatusCode::FORBIDDEN,
+                "RequestTimeTooSkewed",
+                "The difference between the request time and the server's time is too large",
+            )
+        });
+    }
+
+    let (secret, user) = app_state
+        .auth_service
+        .access_key_secret(&request.access_key_id)
+        .await?
+        .ok_or_else(|| {
+            S3Error::new(
+                StatusCode::FORBIDDEN,
+                "InvalidAccessKeyId",
+                "The access key ID you provided does not exist in our records",
+            )
+        })?;
+
+    if !request.verify(&secret, method, path, query, headers) {
+        return Err(S3Error::new(
+            StatusCode::FORBIDDEN,
+            "SignatureDoesNotMatch",
+            "The request signature we calculated does not match the signature you provided",
+        ));
+    }
+    app_state.auth_service.touch_access_key(&request.access_key_id).await?;
+    // The file API is admin-only, and so is this one
+    if !matches!(user.role, UserRole::Admin) {
+        return Err(S3Error::new(StatusCode::FORBIDDEN, "AccessDenied", "Admin access required"));
+    }
+
+    Ok(Authenticated { user, request, secret })
+}
+
+/// One S3 request against a bucket (top-level directory) and key (path below it)
+struct S3Request {
+    app_state: AppState,
+    auth: Authenticated,
+    headers: HeaderMap,
+    query: Vec<(String, String)>,
+    bucket: String,
+    key: String,
+}
+
+impl S3Request {
+    async fn dispatch(&self, method: &Method, body: Body) -> S3Result {
+        if self.bucket.is_empty() {
+            return match *method {
+                Method::GET => self.list_buckets().await,
+                _ => Err(not_implemented()),
+            };
+        }
+        if self.key.is_empty() {
+            return match *method {
+                Method::HEAD => self.head_bucket().await,
+                Method::GET if self.has_param("location") => self.bucket_location().await,
+                Method::GET if !self.has_param("uploads") => self.list_objects().await,
+                _ => Err(not_implemented()),
+            };
+        }
+
+        let multipart = self.has_param("uploadId");
+        match *method {
+            Method::GET if multipart => self.list_parts().await,
+            Method::GET => self.get_object(true).await,
+            Method::HEAD => self.get_object(false).await,
+            Method::PUT if multipart => self.upload_part(body).await,
+            Method::PUT if self.headers.contains_key("x-amz-copy-source") => self.copy_object().await,
+            Method::PUT => self.put_object(body).await,
+            Method::POST if self.has_param("uploads") => self.create_multipart_upload().await,
+            Method::POST if multipart => self.complete_multipart_upload(body).await,
+            Method::DELETE if multipart => self.abort_multipart_upload().await,
+            Method::DELETE => self.delete_object().await,
+            _ => Err(not_implemented()),
+        }
+    }
+
+    fn file_service(&self) -> FileService {
+        self.app_state.file_service(&self.auth.user.email)
+    }
+
+    fn param(&self, name: &str) -> Option<&str> {
+        self.query
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn has_param(&self, name: &str) -> bool {
+        self.param(name).is_some()
+    }
+
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).and_then(|value| value.to_str().ok())
+    }
+
+    /// Check that `bucket` names an existing top-level directory
+    async fn check_bucket(&self, bucket: &str) -> Result<(), S3Error> {
+        if normalize(bucket).ok().as_deref() != Some(bucket) {
+            return Err(S3Error::new(StatusCode::BAD_REQUEST, "InvalidBucketName", "The specified bucket is not valid"));
+        }
+        match self.file_service().get_info(bucket).await {
+            Ok(info) if info.is_directory => Ok(()),
+            Ok(_) | Err(ApiError::FileNotFound { .. }) => Err(S3Error::no_such_bucket(bucket)),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// The storage path of `key` in `bucket`; keys can't point outside their bucket
+    fn object_path(bucket: &str, key: &str) -> Result<String, S3Error> {
+        let path = normalize(&format!("{}/{}", bucket, key))?;
+        match path.strip_prefix(bucket) {
+            Some(rest) if rest.len() > 1 && rest.starts_with('/') => Ok(path),
+            _ => Err(S3Error::new(StatusCode::BAD_REQUEST, "InvalidArgument", "Invalid object key")),
+        }
+    }
+
+    async fn info(&self, path: &str) -> Result<Option<FileInfo>, S3Error> {
+        match self.file_service().get_info(path).await {
+            Ok(info) => Ok(Some(info)),
+            Err(ApiError::FileNotFound { .. }) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// The request body with any `aws-chunked` framing removed and chunk signatures checked
+    fn payload(&self, body: Body) -> Pin<Box<dyn Stream<Item = Result<Bytes, String>> + Send>> {
+        let decoder = match self.auth.request.payload {
+            PayloadHash::Streaming { signed } => Some(ChunkedDecoder::new(if signed {
+                self.auth.request.chunk_signer(&self.auth.secret)
+            } else {
+                None
+            })),
+            _ => None,
+        };
+
+        Box::pin(futures::stream::unfold(Some((body, decoder)), |state| async move {
+            let (mut body, mut decoder) = state?;
+            loop {
+                let Some(chunk) = body.data().await else {
+                    let finished = decoder.as_ref().map_or(Ok(()), ChunkedDecoder::finish);
+                    return finished.err().map(|e| (Err(e), None));
+                };
+                let chunk = match chunk {
+                    Ok(chunk) => chunk,
+                    Err(e) => return Some((Err(e.to_string()), None)),
+                };
+                let Some(decoder_ref) = decoder.as_mut() else {
+                    return Some((Ok(chunk), Some((body, decoder))));
+                };
+                match decoder_ref.push(&chunk) {
+                    Ok(decoded) if decoded.is_empty() => continue,
+                    Ok(decoded) => return Some((Ok(Bytes::from(decoded)), Some((body, decoder)))),
+                    Err(e) => return Some((Err(e), None)),
+                }
+            }
+        }))
+    }
+
+    /// Stage the request body, checking it against the signed payload hash and length
+    async fn stage_body(&self, body: Body) -> Result<StagedFile, S3Error> {
+        let staged = self.app_state.s3_service.stage(self.payload(body)).await?;
+
+        if let PayloadHash::Sha256(expected) = &self.auth.request.payload {
+            if *expected != staged.sha256 {
+                return Err(S3Error::new(
+                    StatusCode::BAD_REQUEST,
+                    "XAmzContentSHA256Mismatch",
+                    "The provided 'x-amz-content-sha256' header does not match what was computed",
+                ));
+            }
+        }
+        if let Some(length) = self.header("x-amz-decoded-content-length") {
+            if length.parse::<u64>().ok() != Some(staged.size) {
+                return Err(S3Error::new(
+                    StatusCode::BAD_REQUEST,
+                    "IncompleteBody",
+                    "You did not provide the number of bytes specified by the x-amz-decoded-content-length header",
+                ));
+            }
+        }
+        Ok(staged)
+    }
+
+    async fn read_xml_body(&self, body: Body) -> Result<xml::XmlElement, S3Error> {
+        let mut payload = self.payload(body);
+        let mut bytes = Vec::new();
+        while let Some(chunk) = payload.next().await {
+            let chunk = chunk.map_err(|message| S3Error::new(StatusCode::BAD_REQUEST, "IncompleteBody", message))?;
+            if bytes.len() + chunk.len() > MAX_XML_BODY {
+                return Err(S3Error::malformed_xml("Request body is too large"));
+            }
+            bytes.extend_from_slice(&chunk);
+        }
+        if let PayloadHash::Sha256(expected) = &self.auth.request.payload {
+            if *expected != hex::encode(<sha2::Sha256 as sha2::Digest>::digest(&bytes)) {
+                return Err(S3Error::new(
+                    StatusCode::BAD_REQUEST,
+                    "XAmzContentSHA256Mismatch",
+                    "The provided 'x-amz-content-sha256' header does not match what was computed",
+                ));
+            }
+        }
+        xml::parse(&bytes).map_err(S3Error::malformed_xml)
+    }
+
+    /// Move a staged upload into place and announce it
+    async fn install(&self, path: &str, staged: StagedFile) -> Result<FileInfo, S3Error> {
+        let (info, created) = self.file_service().install_file(path, staged.path()).await?;
+        let kind = if created { ChangeKind::Created } else { ChangeKind::Modified };
+        self.app_state.change_feed.publish(path, kind);
+        Ok(info)
+    }
+
+    async fn list_buckets(&self) -> S3Result {
+        let buckets: String = self
+            .file_service()
+            .list_files("")
+            .await?
+            .iter()
+            .filter(|entry| entry.is_directory)
+            .map(|entry| {
+                format!(
+                    "<Bucket><Name>{}</Name><CreationDate>{}</CreationDate></Bucket>",
+                    escape(&entry.name),
+                    timestamp(entry.modified)
+                )
+            })
+            .collect();
+
+        Ok(xml_response(
+            StatusCode::OK,
+            format!(
+                "<ListAllMyBucketsResult xmlns=\"{}\"><Owner><ID>{}</ID><DisplayName>{}</DisplayName></Owner>\
+                 <Buckets>{}</Buckets></ListAllMyBucketsResult>",
+                S3_NS,
+                self.auth.user.id,
+                escape(&self.auth.user.email),
+                buckets
+            ),
+        ))
+    }
+
+    async fn head_bucket(&self) -> S3Result {
+        match self.check_bucket(&self.bucket).await {
+            Ok(()) => Ok(StatusCode::OK.into_response()),
+            // HEAD responses have no body
+            Err(e) => Ok(e.status.into_response()),
+        }
+    }
+
+    async fn bucket_location(&self) -> S3Result {
+        self.check_bucket(&self.bucket).await?;
+        Ok(xml_response(
+            StatusCode::OK,
+            format!("<LocationConstraint xmlns=\"{}\"/>", S3_NS),
+        ))
+    }
+
+    /// ListObjectsV2, or ListObjects (v1) when `list-type=2` is missing
+    async fn list_objects(&self) -> S3Result {
+        self.check_bucket(&self.bucket).await?;
+
+        let v2 = self.param("list-type") == Some("2");
+        let prefix = self.param("prefix").unwrap_or_default().to_string();
+        let delimiter = self.param("delimiter").filter(|delimiter| !delimiter.is_empty()).map(str::to_string);
+        let max_keys = match self.param("max-keys") {
+            Some(value) => value
+                .parse::<usize>()
+                .map_err(|_| S3Error::new(StatusCode::BAD_REQUEST, "InvalidArgument", "Invalid max-keys"))?
+                .min(MAX_KEYS),
+            None => MAX_KEYS,
+        };
+        let after = if v2 {
+            match self.param("continuation-token") {
+                Some(token) => Some(decode_continuation_token(token)?),
+                None => self.param("start-after").map(str::to_string),
+            }
+        } else {
+            self.param("marker").map(str::to_string)
+        };
+
+        let max_depth = self.app_state.config.search.max_depth;
+        let entries = list_entries(&self.file_service(), &self.bucket, &prefix, delimiter.as_deref(), max_depth).await?;
+
+        let url_encoded = self.param("encoding-type") == Some("url");
+        let encode = |value: &str| {
+            if url_encoded {
+                uri_encode(value, true)
+            } else {
+                escape(value).to_string()
+            }
+        };
+
+        let mut page: Vec<&ListEntry> = entries
+            .iter()
+            .filter(|entry| after.as_deref().is_none_or(|after| entry.key() > after))
+            .take(max_keys + 1)
+            .collect();
+        let truncated = page.len() > max_keys;
+        page.truncate(max_keys);
+
+        let mut body = format!("<ListBucketResult xmlns=\"{}\"><Name>{}</Name>", S3_NS, escape(&self.bucket));
+        body.push_str(&format!("<Prefix>{}</Prefix>", encode(&prefix)));
+        if let Some(delimiter) = &delimiter {
+            body.push_str(&format!("<Delimiter>{}</Delimiter>", encode(delimiter)));
+        }
+        body.push_str(&format!(
+            "<MaxKeys>{}</MaxKeys><IsTruncated>{}</IsTruncated>",
+            max_keys, truncated
+        ));
+        if url_encoded {
+            body.push_str("<EncodingType>url</EncodingType>");
+        }
+        let last_key = page.last().map(|entry| entry.key().to_string());
+        if v2 {
+            body.push_str(&format!("<KeyCount>{}</KeyCount>", page.len()));
+            if let Some(token) = self.param("continuation-token") {
+                body.push_str(&format!("<ContinuationToken>{}</ContinuationToken>", escape(token)));
+            }
+            if let Some(start_after) = self.param("start-after") {
+                body.push_str(&format!("<StartAfter>{}</StartAfter>", encode(start_after)));
+            }
+            if let (true, Some(last_key)) = (truncated, &last_key) {
+                body.push_str(&format!(
+                    "<NextContinuationToken>{}</NextContinuationToken>",
+                    base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(last_key)
+                ));
+            }
+        } else {
+            body.push_str(&format!("<Marker>{}</Marker>", encode(self.param("marker").unwrap_or_default())));
+            if let (true, Some(last_key)) = (truncated, &last_key) {
+                body.push_str(&format!("<NextMarker>{}</NextMarker>", encode(last_key)));
+            }
+        }
+
+        for entry in page {
+            match entry {
+                ListEntry::Object { key, info } => body.push_str(&format!(
+                    "<Contents><Key>{}</Key><LastModified>{}</LastModified><ETag>{}</ETag>\
+                     <Size>{}</Size><StorageClass>STANDARD</StorageClass></Contents>",
+                    encode(key),
+                    timestamp(info.modified),
+                    escape(&info.etag()),
+                    if info.is_directory { 0 } else { info.size }
+                )),
+                ListEntry::Prefix(prefix) => body.push_str(&format!(
+                    "<CommonPrefixes><Prefix>{}</Prefix></CommonPrefixes>",
+                    encode(prefix)
+                )),
+            }
+        }
+        body.push_str("</ListBucketResult>");
+        Ok(xml_response(StatusCode::OK, body))
+    }
+
+    async fn get_object(&self, with_body: bool) -> S3Result {
+        self.check_bucket(&self.bucket).await?;
+        let path = Self::object_path(&self.bucket, &self.key)?;
+        let info = self.info(&path).await?.ok_or_else(S3Error::no_such_key)?;
+
+        // Keys ending in a slash are the directories themselves, as empty objects
+        let is_marker = self.key.ends_with('/');
+        if info.is_directory != is_marker {
+            return Err(S3Error::no_such_key());
+        }
+
+        let etag = info.etag();
+        if let Some(if_match) = self.header("if-match") {
+            if !etag_matches(if_match, &etag) {
+                return Err(S3Error::new(
+                    StatusCode::PRECONDITION_FAILED,
+                    "PreconditionFailed",
+                    "At least one of the pre-conditions you specified did not hold",
+                ));
+            }
+        }
+        if self.header("if-none-match").is_some_and(|value| etag_matches(value, &etag)) {
+            return Ok((StatusCode::NOT_MODIFIED, [(header::ETAG, etag)]).into_response());
+        }
+
+        let size = if is_marker { 0 } else { info.size };
+        let range = match self.header("range") {
+            Some(value) => parse_byte_range(value, size).ok_or_else(|| {
+                S3Error::new(
+                    StatusCode::RANGE_NOT_SATISFIABLE,
+                    "InvalidRange",
+                    "The requested range is not satisfiable",
+                )
+            })?,
+            None => None,
+        };
+
+        let content_type = if is_marker {
+            "application/x-directory"
+        } else {
+            info.mime_type.as_deref().unwrap_or("application/octet-stream")
+        };
+        let mut response = Response::builder()
+            .header(header::CONTENT_TYPE, content_type)
+            .header(header::ETAG, &etag)
+            .header(header::LAST_MODIFIED, httpdate::fmt_http_date(info.modified.into()))
+            .header(header::ACCEPT_RANGES, "bytes");
+        let (start, length) = match range {
+            Some((start, end)) => {
+                response = response
+                    .status(StatusCode::PARTIAL_CONTENT)
+                    .header(header::CONTENT_RANGE, format!("bytes {}-{}/{}", start, end, size));
+                (start, end - start + 1)
+            }
+            None => (0, size),
+        };
+        response = response.header(header::CONTENT_LENGTH, length);
+
+        if !with_body || is_marker {
+            return Ok(response.body(axum::body::boxed(Body::empty())).unwrap());
+        }
+
+        let (file, _) = self.file_service().open_file(&path, Some(start..start + length)).await?;
+        let stream = StreamBody::new(ReaderStream::new(file));
+        Ok(response.body(axum::body::boxed(stream)).unwrap())
+    }
+
+    async fn put_object(&self, body: Body) -> S3Result {
+        self.check_bucket(&self.bucket).await?;
+        let path = Self::object_path(&self.bucket, &self.key)?;
+
+        let info = if self.key.ends_with('/') {
+            // A "folder" created by a console or sync tool
+            let info = self.file_service().create_directory(&path, true).await?;
+            self.app_state.change_feed.publish(&path, ChangeKind::Created);
+            info
+        } else {
+            let staged = self.stage_body(body).await?;
+            self.install(&path, staged).await?
+        };
+
+        Ok((StatusCode::OK, [(header::ETAG, info.etag())]).into_response())
+    }
+
+    async fn copy_object(&self) -> S3Result {
+        self.check_bucket(&self.bucket).await?;
+        let path = Self::object_path(&self.bucket, &self.key)?;
+
+        let source = self.header("x-amz-copy-source").unwrap_or_default();
+        let source = source.split_once("?versionId=").map_or(source, |(source, _)| source);
+        let source = percent_decode_str(source)
+            .decode_utf8()
+            .map_err(|_| S3Error::new(StatusCode::BAD_REQUEST, "InvalidArgument", "Invalid copy source"))?;
+        let (source_bucket, source_key) = source
+            .trim_start_matches('/')
+            .split_once('/')
+            .ok_or_else(|| S3Error::new(StatusCode::BAD_REQUEST, "InvalidArgument", "Invalid copy source"))?;
+        self.check_bucket(source_bucket).await?;
+        let source_path = Self::object_path(source_bucket, source_key)?;
+
+        let source_info = self.info(&source_path).await?.ok_or_else(S3Error::no_such_key)?;
+        if source_info.is_directory {
+            return Err(S3Error::no_such_key());
+        }
+
+        // Copy through the staging directory so that copying an object onto itself works
+        let (file, _) = self.file_service().open_file(&source_path, None).await?;
+        let staged = self.app_state.s3_service.stage(ReaderStream::new(file)).await?;
+        let info = self.install(&path, staged).await?;
+
+        Ok(xml_response(
+            StatusCode::OK,
+            format!(
+                "<CopyObjectResult xmlns=\"{}\"><LastModified>{}</LastModified><ETag>{}</ETag></CopyObjectResult>",
+                S3_NS,
+                timestamp(info.modified),
+                escape(&info.etag())
+            ),
+        ))
+    }
+
+    async fn delete_object(&self) -> S3Result {
+        self.check_bucket(&self.bucket).await?;
+        let path = Self::object_path(&self.bucket, &self.key)?;
+
+        // Deleting a key that doesn't exist succeeds, and a directory only goes once it's empty
+        let file_service = self.file_service();
+        let deletable = match self.info(&path).await? {
+            Some(info) if info.is_directory => {
+                self.key.ends_with('/') && file_service.list_files(&path).await?.is_empty()
+            }
+            Some(_) => !self.key.ends_with('/'),
+            None => false,
+        };
+        if deletable {
+            file_service.delete_file(&path).await?;
+            self.app_state.change_feed.publish(&path, ChangeKind::Deleted);
+        }
+        Ok(StatusCode::NO_CONTENT.into_response())
+    }
+
+    async fn create_multipart_upload(&self) -> S3Result {
+        self.check_bucket(&self.bucket).await?;
+        Self::object_path(&self.bucket, &self.key)?;
+
+        let upload = self
+            .app_state
+            .s3_service
+            .create_multipart_upload(&self.bucket, &self.key)
+            .await?;
+        Ok(xml_response(
+            StatusCode::OK,
+            format!(
+                "<InitiateMultipartUploadResult xmlns=\"{}\"><Bucket>{}</Bucket><Key>{}</Key><UploadId>{}</UploadId>\
+                 </InitiateMultipartUploadResult>",
+                S3_NS,
+                escape(&self.bucket),
+                escape(&self.key),
+                upload.upload_id
+            ),
+        ))
+    }
+
+    /// The upload named by `uploadId`, which must be for this bucket and key
+    async fn upload_id(&self) -> Result<String, S3Error> {
+        let upload_id = self.param("uploadId").unwrap_or_default();
+        match self.app_state.s3_service.multipart_upload(upload_id).await? {
+            Some(upload) if upload.bucket == self.bucket && upload.key == self.key => Ok(upload.upload_id),
+            _ => Err(S3Error::no_such_upload()),
+        }
+    }
+
+    async fn upload_part(&self, body: Body) -> S3Result {
+        let part_number = self
+            .param("partNumber")
+            .and_then(|value| value.parse::<u32>().ok())
+            .filter(|number| (1..=MAX_PART_NUMBER).contains(number))
+            .ok_or_else(|| {
+                S3Error::new(
+                    StatusCode::BAD_REQUEST,
+                    "InvalidArgument",
+                    "Part number must be an integer between 1 and 10000",
+                )
+            })?;
+        let upload_id = self.upload_id().await?;
+
+        let staged = self.stage_body(body).await?;
+        let part = self.app_state.s3_service.put_part(&upload_id, part_number, staged).await?;
+        Ok((StatusCode::OK, [(header::ETAG, part.etag)]).into_response())
+    }
+
+    async fn list_parts(&self) -> S3Result {
+        let upload_id = self.upload_id().await?;
+        let parts: String = self
+            .app_state
+            .s3_service
+            .list_parts(&upload_id)
+            .await?
+            .iter()
+            .map(|part| {
+                format!(
+                    "<Part><PartNumber>{}</PartNumber><LastModified>{}</LastModified><ETag>{}</ETag><Size>{}</Size></Part>",
+                    part.part_number,
+                    timestamp(part.uploaded_at),
+                    escape(&part.etag),
+                    part.size
+                )
+            })
+            .collect();
+
+        Ok(xml_response(
+            StatusCode::OK,
+            format!(
+                "<ListPartsResult xmlns=\"{}\"><Bucket>{}</Bucket><Key>{}</Key><UploadId>{}</UploadId>\
+                 <IsTruncated>false</IsTruncated>{}</ListPartsResult>",
+                S3_NS,
+                escape(&self.bucket),
+                escape(&self.key),
+                upload_id,
+                parts
+            ),
+        ))
+    }
+
+    async fn complete_multipart_upload(&self, body: Body) -> S3Result {
+        let upload_id = self.upload_id().await?;
+        let path = Self::object_path(&self.bucket, &self.key)?;
+
+        let root = self.read_xml_body(body).await?;
+        if root.name != "CompleteMultipartUpload" {
+            return Err(S3Error::malformed_xml("Expected a CompleteMultipartUpload element"));
+        }
+        let parts = root
+            .children
+            .iter()
+            .filter(|child| child.name == "Part")
+            .map(|part| {
+                let text = |name: &str| {
+                    part.children
+                        .iter()
+                        .find(|child| child.name == name)
+                        .map(|child| child.text.trim().to_string())
+                };
+                let number = text("PartNumber").and_then(|number| number.parse::<u32>().ok());
+                match (number, text("ETag")) {
+                    (Some(number), Some(etag)) => Ok((number, etag)),
+                    _ => Err(S3Error::malformed_xml("Each Part needs a PartNumber and an ETag")),
+                }
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let s3_service = &self.app_state.s3_service;
+        let staged = s3_service
+            .assemble_parts(&upload_id, &parts)
+            .await
+            .map_err(|e| match e {
+                ApiError::NotFound { .. } => S3Error::new(
+                    StatusCode::BAD_REQUEST,
+                    "InvalidPart",
+                    "One or more of the specified parts could not be found",
+                ),
+                ApiError::BadRequest { message } => S3Error::new(StatusCode::BAD_REQUEST, "InvalidPartOrder", message),
+                e => e.into(),
+            })?;
+        let info = self.install(&path, staged).await?;
+        s3_service.remove_upload(&upload_id).await?;
+
+        Ok(xml_response(
+            StatusCode::OK,
+            format!(
+                "<CompleteMultipartUploadResult xmlns=\"{}\"><Location>{}/{}/{}</Location><Bucket>{}</Bucket>\
+                 <Key>{}</Key><ETag>{}</ETag></CompleteMultipartUploadResult>",
+                S3_NS,
+                S3_PREFIX,
+                escape(&uri_encode(&self.bucket, false)),
+                escape(&uri_encode(&self.key, true)),
+                escape(&self.bucket),
+                escape(&self.key),
+                escape(&info.etag())
+            ),
+        ))
+    }
+
+    async fn abort_multipart_upload(&self) -> S3Result {
+        let upload_id = self.upload_id().await?;
+        self.app_state.s3_service.remove_upload(&upload_id).await?;
+        Ok(StatusCode::NO_CONTENT.into_response())
+    }
+}
+
+/// A listed key: an object, or with a delimiter, a group of keys sharing a prefix
+enum ListEntry {
+    Object { key: String, info: FileInfo },
+    Prefix(String),
+}
+
+impl ListEntry {
+    fn key(&self) -> &str {
+        match self {
+            ListEntry::Object { key, .. } | ListEntry::Prefix(key) => key,
+        }
+    }
+}
+
+/// List the keys of `bucket` starting with `prefix`, sorted. Files are objects and empty
+/// directories are `dir/` objects. With the `/` delimiter only one directory is read,
+/// otherwise directories up to `max_depth` below the prefix are walked.
+async fn list_entries(
+    file_service: &FileService,
+    bucket: &str,
+    prefix: &str,
+    delimiter: Option<&str>,
+    max_depth: usize,
+) -> Result<Vec<ListEntry>, ApiError> {
+    // Only the directory part of the prefix narrows down where to look
+    let base = &prefix[..prefix.rfind('/').map_or(0, |index| index + 1)];
+    let base_is_normal = Path::new(base)
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)))
+        && !base.contains("//");
+    if !base_is_normal || base.starts_with('/') {
+        return Ok(Vec::new());
+    }
+    let dir = format!("{}/{}", bucket, base.trim_end_matches('/'));
+    match file_service.get_info(dir.trim_end_matches('/')).await {
+        Ok(info) if info.is_directory => {}
+        Ok(_) | Err(ApiError::FileNotFound { .. }) => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    }
+
+    let mut entries = Vec::new();
+    if delimiter == Some("/") {
+        let children = file_service.list_files(dir.trim_end_matches('/')).await?;
+        // An empty directory is its own marker object, as it is when listed from above
+        if children.is_empty() && !base.is_empty() {
+            let info = file_service.get_info(dir.trim_end_matches('/')).await?;
+            entries.push(ListEntry::Object { key: base.to_string(), info });
+        }
+        for child in children {
+            let key = format!("{}{}", base, child.name);
+            if child.is_directory {
+                entries.push(ListEntry::Prefix(format!("{}/", key)));
+            } else {
+                entries.push(ListEntry::Object { key, info: child });
+            }
+        }
+    } else {
+        // (storage path, key prefix of its children, depth)
+        let mut pending = vec![(dir.trim_end_matches('/').to_string(), base.to_string(), 0)];
+        while let Some((dir, key_prefix, depth)) = pending.pop() {
+            let children = match file_service.list_files(&dir).await {
+                Ok(children) => children,
+                // Removed while we were listing
+                Err(ApiError::FileNotFound { .. }) => continue,
+                Err(e) => return Err(e),
+            };
+            if children.is_empty() && !key_prefix.is_empty() {
+                let info = file_service.get_info(&dir).await?;
+                entries.push(ListEntry::Object { key: key_prefix, info });
+                continue;
+            }
+
+            for child in children {
+                let key = format!("{}{}", key_prefix, child.name);
+                if !child.is_directory {
+                    entries.push(ListEntry::Object { key, info: child });
+                    continue;
+                }
+                let key = format!("{}/", key);
+                let could_match = key.starts_with(prefix) || prefix.starts_with(&key);
+                if could_match && depth < max_depth {
+                    pending.push((child.path, key, depth + 1));
+                }
+            }
+        }
+
+        // Roll keys up to the first delimiter after the prefix
+        if let Some(delimiter) = delimiter {
+            entries = entries
+                .into_iter()
+                .map(|entry| {
+                    let rest = entry.key().strip_prefix(prefix).unwrap_or_default();
+                    match rest.find(delimiter) {
+                        Some(index) => ListEntry::Prefix(format!("{}{}", prefix, &rest[..index + delimiter.len()])),
+                        None => entry,
+                    }
+                })
+                .collect();
+        }
+    }
+
+    entries.retain(|entry| entry.key().starts_with(prefix));
+    entries.sort_by(|a, b| a.key().cmp(b.key()));
+    entries.dedup_by(|a, b| a.key() == b.key());
+    Ok(entries)
+}
+
+/// Decode a query string without treating `+` as a space, as SigV4 clients encode spaces as `%20`
+fn parse_query(query: &str) -> Vec<(String, String)> {
+    let decode = |value: &str| percent_decode_str(value).decode_utf8_lossy().to_string();
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (decode(key), decode(value))
+        })
+        .collect()
+}
+
+fn decode_continuation_token(token: &str) -> Result<String, S3Error> {
+    base64::engine::general_purpose::URL_SAFE_NO_PAD
+        .decode(token)
+        .ok()
+        .and_then(|bytes| String::from_utf8(bytes).ok())
+        .ok_or_else(|| S3Error::new(StatusCode::BAD_REQUEST, "InvalidArgument", "Invalid continuation token"))
+}
+
+fn etag_matches(header: &str, etag: &str) -> bool {
+    header.split(',').map(str::trim).any(|candidate| candidate == "*" || candidate == etag)
+}
+
+fn timestamp(time: DateTime<Utc>) -> String {
+    time.format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string()
+}
+
+fn not_implemented() -> S3Error {
+    S3Error::new(
+        StatusCode::NOT_IMPLEMENTED,
+        "NotImplemented",
+        "A header or operation you provided implies functionality that is not implemented",
+    )
+}
+
+fn xml_response(status: StatusCode, body: String) -> Response {
+    (
+        status,
+        [(header::CONTENT_TYPE, "application/xml")],
+        format!("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n{}", body),
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+
+    #[tokio::test]
+    async fn test_list_entries_with_and_without_delimiter() {
+        let config = crate::config::Config::for_tests(".");
+        let file_service = FileService::new(config, std::sync::Arc::new(MemoryStorage::new()));
+        file_service.ensure_directory("bucket/photos/2024").await.unwrap();
+        file_service.ensure_directory("bucket/empty").await.unwrap();
+        for (path, data) in [("bucket/photos/2024/a.jpg", "a"), ("bucket/photos/b.jpg", "b"), ("bucket/photos.txt", "c")] {
+            let body = futures::stream::iter([Ok::<_, std::io::Error>(Bytes::from(data))]);
+            file_service.write_file(path, body).await.unwrap();
+        }
+
+        let keys = |prefix: &'static str, delimiter: Option<&'static str>| {
+            let file_service = &file_service;
+            async move {
+                list_entries(file_service, "bucket", prefix, delimiter, 10)
+                    .await
+                    .unwrap()
+                    .iter()
+                    .map(|entry| match entry {
+                        ListEntry::Object { key, .. } => key.clone(),
+                        ListEntry::Prefix(prefix) => format!("[{}]", prefix),
+                    })
+                    .collect::<Vec<_>>()
+            }
+        };
+
+        assert_eq!(
+            keys("", None).await,
+            vec!["empty/", "photos.txt", "photos/2024/a.jpg", "photos/b.jpg"]
+        );
+        assert_eq!(keys("photos", Some("/")).await, vec!["photos.txt", "[photos/]"]);
+        assert_eq!(keys("photos/", Some("/")).await, vec!["[photos/2024/]", "photos/b.jpg"]);
+        assert_eq!(keys("", Some("o")).await, vec!["empty/", "[pho]"]);
+        assert_eq!(keys("empty/", Some("/")).await, vec!["empty/"]);
+        assert_eq!(keys("empty/", None).await, vec!["empty/"]);
+        assert!(keys("../", None).await.is_empty());
+    }
+
+    #[test]
+    fn test_object_path_stays_inside_bucket() {
+        assert_eq!(S3Request::object_path("b", "a/c.txt").unwrap(), "b/a/c.txt");
+        assert_eq!(S3Request::object_path("b", "dir/").unwrap(), "b/dir");
+        assert!(S3Request::object_path("b", "../other/x").is_err());
+        assert!(S3Request::object_path("b", "").is_err());
+    }
+}
@@ -0,0 +1,161 @@
+use crate::{
+    errors::ApiError,
+    middleware::AuthContext,
+    services::{IndexStatus, Job, JobContext, ScanSummary, SearchQuery, SearchResponse, SearchService},
+    AppState,
+};
+use axum::{
+    extract::{Extension, Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use serde::{Deserialize, Serialize};
+
+pub fn routes() -> Router<AppState> {
+    Router::new()
+        .route("/", get(search))
+        .route("/index/status", get(index_status))
+        .route("/index/rebuild", post(rebuild_index))
+        .route("/index/update", post(update_index))
+}
+
+async fn search(
+    State(app_state): State<AppState>,
+    Extension(_auth_context): Extension<AuthContext>,
+    Query(query): Query<SearchQuery>,
+) -> Result<Json<SearchResponse>, ApiError> {
+    let search_service = SearchService::new(
+        app_state.config.as_ref().clone(),
+        app_state.index_service.clone(),
+        app_state.storage.clone(),
+    );
+
+    let response = search_service.search(query).await?;
+
+    Ok(Json(response))
+}
+
+async fn index_status(
+    State(app_state): State<AppState>,
+    Extension(_auth_context): Extension<AuthContext>,
+) -> Result<Json<IndexStatus>, ApiError> {
+    let status = app_state.index_service.status().await?;
+    Ok(Json(status))
+}
+
+#[derive(Deserialize, Default)]
+struct IndexScanRequest {
+    /// Return immediately and scan as a job (default: true)
+    background: Option<bool>,
+    /// Subtrees to scan; the whole tree when omitted
+    paths: Option<Vec<String>>,
+}
+
+#[derive(Serialize)]
+struct IndexScanResponse {
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    summaries: Option<Vec<ScanSummary>>,
+    /// The job scanning in the background
+    #[serde(skip_serializing_if = "Option::is_none")]
+    job: Option<Job>,
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum ScanKind {
+    Rebuild,
+    Update,
+}
+
+/// Parameters of an `index_scan` job
+#[derive(Serialize, Deserialize)]
+struct IndexScanJob {
+    kind: ScanKind,
+    paths: Vec<String>,
+}
+
+/// Run index scans started in the background as jobs
+pub fn register_jobs(app_state: &AppState) {
+    let index_service = app_state.index_service.clone();
+    app_state.job_service.register("index_scan", move |params: IndexScanJob, _: JobContext| {
+        let index_service = index_service.clone();
+        async move {
+            match params.kind {
+                ScanKind::Rebuild => index_service.rebuild(&params.paths).await,
+                ScanKind::Update => index_service.update(&params.paths).await,
+            }
+        }
+    });
+}
+
+async fn rebuild_index(
+    State(app_state): State<AppState>,
+    Extension(auth_context): Extension<AuthContext>,
+    request: Option<Json<IndexScanRequest>>,
+) -> Result<Response, ApiError> {
+    run_index_scan(app_state, auth_context, request, ScanKind::Rebuild).await
+}
+
+async fn update_index(
+    State(app_state): State<AppState>,
+    Extension(auth_context): Extension<AuthContext>,
+    request: Option<Json<IndexScanRequest>>,
+) -> Result<Response, ApiError> {
+    run_index_scan(app_state, auth_context, request, ScanKind::Update).await
+}
+
+async fn run_index_scan(
+    app_state: AppState,
+    auth_context: AuthContext,
+    request: Option<Json<IndexScanRequest>>,
+    kind: ScanKind,
+) -> Result<Response, ApiError> {
+    if !auth_context.is_admin() {
+        return Err(ApiError::Forbidden {
+            message: "Admin access required to manage the search index".to_string(),
+        });
+    }
+
+    let request = request.map(|Json(request)| request).unwrap_or_default();
+    let paths = request.paths.unwrap_or_default();
+    let index_service = app_state.index_service.clone();
+
+    if !index_service.is_enabled() {
+        return Err(ApiError::BadRequest {
+            message: "The search index is disabled".to_string(),
+        });
+    }
+
+    if index_service.is_scanning() {
+        return Err(ApiError::Conflict {
+            message: "An index scan is already running".to_string(),
+        });
+    }
+
+    if request.background.unwrap_or(true) {
+        let params = IndexScanJob { kind, paths };
+        let job = app_state.job_service.submit("index_scan", &auth_context.email, &params).await?;
+
+        let response = IndexScanResponse {
+            message: "Index scan started".to_string(),
+            summaries: None,
+            job: Some(job),
+        };
+        return Ok((StatusCode::ACCEPTED, Json(response)).into_response());
+    }
+
+    let summaries = match kind {
+        ScanKind::Rebuild => index_service.rebuild(&paths).await?,
+        ScanKind::Update => index_service.update(&paths).await?,
+    };
+
+    let response = IndexScanResponse {
+        message: "Index scan completed".to_string(),
+        summaries: Some(summaries),
+        job: None,
+    };
+    Ok(Json(response).into_response())
+}
@@ -0,0 +1,70 @@
+use crate::{
+    errors::ApiError,
+    middleware::AuthContext,
+    services::DiskUsageReport,
+    storage::{dedup, CollectedGarbage},
+    AppState,
+};
+use axum::{
+    extract::{Extension, Query, State},
+    routing::{get, post},
+    Json, Router,
+};
+use serde::Deserialize;
+
+pub fn routes() -> Router<AppState> {
+    Router::new()
+        .route("/usage", get(disk_usage))
+        .route("/dedup", get(dedup_report))
+        .route("/dedup/gc", post(collect_garbage))
+}
+
+#[derive(Deserialize)]
+struct UsageQuery {
+    path: Option<String>,
+    /// How many directories, files and types to list
+    limit: Option<usize>,
+}
+
+/// What takes up the space below a directory
+async fn disk_usage(
+    State(app_state): State<AppState>,
+    Extension(_auth_context): Extension<AuthContext>,
+    Query(query): Query<UsageQuery>,
+) -> Result<Json<DiskUsageReport>, ApiError> {
+    let path = query.path.unwrap_or_else(|| "/".to_string());
+    let limit = query.limit.unwrap_or(20);
+    Ok(Json(app_state.disk_usage_service.report(&path, limit).await?))
+}
+
+fn check_dedup_access(app_state: &AppState, auth_context: &AuthContext) -> Result<(), ApiError> {
+    if !auth_context.is_admin() {
+        return Err(ApiError::Forbidden {
+            message: "Admin access required to manage deduplicated storage".to_string(),
+        });
+    }
+    if !app_state.config.storage.dedup_in_use() {
+        return Err(ApiError::BadRequest {
+            message: "Deduplication is not enabled".to_string(),
+        });
+    }
+    Ok(())
+}
+
+/// How much space deduplication saves
+async fn dedup_report(
+    State(app_state): State<AppState>,
+    Extension(auth_context): Extension<AuthContext>,
+) -> Result<Json<dedup::DedupReport>, ApiError> {
+    check_dedup_access(&app_state, &auth_context)?;
+    Ok(Json(dedup::report(&app_state.db).await?))
+}
+
+/// Delete stored contents no file refers to any more, without waiting for the next scheduled run
+async fn collect_garbage(
+    State(app_state): State<AppState>,
+    Extension(auth_context): Extension<AuthContext>,
+) -> Result<Json<CollectedGarbage>, ApiError> {
+    check_dedup_access(&app_state, &auth_context)?;
+    Ok(Json(app_state.storage.collect_garbage().await?))
+}
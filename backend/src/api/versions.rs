@@ -0,0 +1,171 @@
+use crate::{
+    errors::ApiError,
+    middleware::AuthContext,
+    services::{normalize, ChangeKind, FileInfo, FileVersion, VersionDiff},
+    AppState,
+};
+use axum::{
+    body::StreamBody,
+    extract::{Extension, Path, Query, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use serde::{Deserialize, Serialize};
+use tokio_util::io::ReaderStream;
+
+pub fn routes() -> Router<AppState> {
+    Router::new()
+        .route("/", get(list_versions).delete(prune_versions))
+        .route("/:id", get(get_version).delete(delete_version))
+        .route("/:id/download", get(download_version))
+        .route("/:id/diff", get(diff_version))
+        .route("/:id/restore", post(restore_version))
+}
+
+#[derive(Deserialize)]
+struct VersionsQuery {
+    path: String,
+}
+
+#[derive(Serialize)]
+struct VersionsResponse {
+    path: String,
+    versions: Vec<FileVersion>,
+}
+
+/// Earlier contents of a file, newest first; those of a deleted file are still listed
+async fn list_versions(
+    State(app_state): State<AppState>,
+    Extension(_auth_context): Extension<AuthContext>,
+    Query(query): Query<VersionsQuery>,
+) -> Result<Json<VersionsResponse>, ApiError> {
+    let versions = app_state.version_service.list(&normalize(&query.path)?).await?;
+    Ok(Json(VersionsResponse {
+        path: query.path,
+        versions,
+    }))
+}
+
+#[derive(Deserialize)]
+struct PruneQuery {
+    path: String,
+    /// How many of the newest versions to keep
+    #[serde(default)]
+    keep: usize,
+}
+
+#[derive(Serialize)]
+struct PruneResponse {
+    message: String,
+    deleted: usize,
+}
+
+/// Delete the older versions of a file
+async fn prune_versions(
+    State(app_state): State<AppState>,
+    Extension(_auth_context): Extension<AuthContext>,
+    Query(query): Query<PruneQuery>,
+) -> Result<Json<PruneResponse>, ApiError> {
+    let deleted = app_state
+        .version_service
+        .prune(&normalize(&query.path)?, query.keep)
+        .await?;
+    Ok(Json(PruneResponse {
+        message: "Versions deleted successfully".to_string(),
+        deleted,
+    }))
+}
+
+async fn get_version(
+    State(app_state): State<AppState>,
+    Extension(_auth_context): Extension<AuthContext>,
+    Path(id): Path<String>,
+) -> Result<Json<FileVersion>, ApiError> {
+    Ok(Json(app_state.version_service.get(&id).await?))
+}
+
+#[derive(Serialize)]
+struct DeleteVersionResponse {
+    message: String,
+    id: String,
+}
+
+async fn delete_version(
+    State(app_state): State<AppState>,
+    Extension(_auth_context): Extension<AuthContext>,
+    Path(id): Path<String>,
+) -> Result<Json<DeleteVersionResponse>, ApiError> {
+    app_state.version_service.delete(&id).await?;
+    Ok(Json(DeleteVersionResponse {
+        message: "Version deleted successfully".to_string(),
+        id,
+    }))
+}
+
+async fn download_version(
+    State(app_state): State<AppState>,
+    Extension(_auth_context): Extension<AuthContext>,
+    Path(id): Path<String>,
+) -> Result<Response, ApiError> {
+    let (reader, version) = app_state.version_service.open(&id).await?;
+    let filename = version.path.rsplit('/').next().unwrap_or("download").to_string();
+
+    let headers = [
+        (header::CONTENT_TYPE, "application/octet-stream".to_string()),
+        (header::CONTENT_LENGTH, version.size.to_string()),
+        (header::CONTENT_DISPOSITION, format!("attachment; filename=\"{}\"", filename)),
+    ];
+    Ok((StatusCode::OK, headers, StreamBody::new(ReaderStream::new(reader))).into_response())
+}
+
+#[derive(Deserialize)]
+struct DiffQuery {
+    /// Another version to compare with; the file as it is now by default
+    against: Option<String>,
+}
+
+async fn diff_version(
+    State(app_state): State<AppState>,
+    Extension(_auth_context): Extension<AuthContext>,
+    Path(id): Path<String>,
+    Query(query): Query<DiffQuery>,
+) -> Result<Json<VersionDiff>, ApiError> {
+    Ok(Json(app_state.version_service.diff(&id, query.against.as_deref()).await?))
+}
+
+#[derive(Serialize)]
+struct RestoreResponse {
+    message: String,
+    file_info: FileInfo,
+}
+
+/// Put a version back in place. The contents it replaces become a version in turn, so a
+/// restore can itself be undone.
+async fn restore_version(
+    State(app_state): State<AppState>,
+    Extension(auth_context): Extension<AuthContext>,
+    Path(id): Path<String>,
+) -> Result<Response, ApiError> {
+    let version = app_state.version_service.get(&id).await?;
+    let quotas = &app_state.quota_service;
+    quotas.check_write(&auth_context.email, &version.path, Some(version.size)).await?;
+
+    let (file_info, created) = app_state.version_service.restore(&id, &auth_context.email).await?;
+    if let Err(e) = quotas.record_write(&file_info.path, &auth_context.email, file_info.size).await {
+        tracing::warn!("Failed to record quota usage of {}: {}", file_info.path, e);
+    }
+
+    let (status, kind) = match created {
+        true => (StatusCode::CREATED, ChangeKind::Created),
+        false => (StatusCode::OK, ChangeKind::Modified),
+    };
+    app_state.change_feed.publish(&file_info.path, kind);
+
+    let response = RestoreResponse {
+        message: "Version restored successfully".to_string(),
+        file_info,
+    };
+    Ok((status, Json(response)).into_response())
+}
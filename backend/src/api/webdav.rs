@@ -0,0 +1,919 @@
+use crate::{
+    api::files::expected_checksums,
+    errors::ApiError,
+    middleware::AuthContext,
+    services::{
+        normalize, parent_of, ChangeKind, DavLock, DeadProperty, FileInfo, FileService,
+        PropertyUpdate, MAX_LOCK_TIMEOUT,
+    },
+    utils::{
+        range::parse_byte_range,
+        xml::{self, XmlElement},
+    },
+    AppState,
+};
+use axum::{
+    body::{Body, Bytes, StreamBody},
+    extract::State,
+    http::{header, HeaderMap, HeaderValue, Request, StatusCode},
+    response::{IntoResponse, Response},
+    routing::any,
+    Router,
+};
+use hyper::body::HttpBody;
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use std::time::Duration;
+use tokio_util::io::ReaderStream;
+
+/// Where the WebDAV tree is mounted; used to build and parse hrefs
+pub const DAV_PREFIX: &str = "/dav";
+
+const DAV_NS: &str = "DAV:";
+
+/// Largest PROPFIND/PROPPATCH/LOCK body we accept
+const MAX_XML_BODY: usize = 1024 * 1024;
+
+const ALLOWED_METHODS: &str =
+    "OPTIONS, GET, HEAD, PUT, DELETE, MKCOL, COPY, MOVE, PROPFIND, PROPPATCH, LOCK, UNLOCK";
+
+const LIVE_PROPERTIES: &[&str] = &[
+    "creationdate",
+    "displayname",
+    "getcontentlength",
+    "getcontenttype",
+    "getetag",
+    "getlastmodified",
+    "lockdiscovery",
+    "resourcetype",
+    "supportedlock",
+];
+
+/// Characters escaped in href path segments
+const SEGMENT: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'_')
+    .remove(b'.')
+    .remove(b'~');
+
+/// Routes are registered under `DAV_PREFIX` rather than nested, so that `/dav/` itself
+/// (the root collection clients mount) is routed too
+pub fn routes() -> Router<AppState> {
+    Router::new()
+        .route(DAV_PREFIX, any(handle))
+        .route(&format!("{}/", DAV_PREFIX), any(handle))
+        .route(&format!("{}/*path", DAV_PREFIX), any(handle))
+}
+
+async fn handle(State(app_state): State<AppState>, request: Request<Body>) -> Response {
+    let (parts, body) = request.into_parts();
+
+    let path = match request_path(parts.uri.path().strip_prefix(DAV_PREFIX).unwrap_or_default()) {
+        Ok(path) => path,
+        Err(e) => return e.into_response(),
+    };
+    let user = parts
+        .extensions
+        .get::<AuthContext>()
+        .map(|auth_context| auth_context.email.clone())
+        .unwrap_or_default();
+    let dav = DavRequest {
+        app_state,
+        path,
+        headers: parts.headers,
+        user,
+    };
+
+    let result = match parts.method.as_str() {
+        "OPTIONS" => Ok(options()),
+        "PROPFIND" => dav.propfind(body).await,
+        "PROPPATCH" => dav.proppatch(body).await,
+        "GET" => dav.get(true).await,
+        "HEAD" => dav.get(false).await,
+        "PUT" => dav.put(body).await,
+        "DELETE" => dav.delete().await,
+        "MKCOL" => dav.mkcol(body).await,
+        "COPY" => dav.copy_or_move(false).await,
+        "MOVE" => dav.copy_or_move(true).await,
+        "LOCK" => dav.lock(body).await,
+        "UNLOCK" => dav.unlock().await,
+        _ => Ok(status(StatusCode::METHOD_NOT_ALLOWED)),
+    };
+
+    result.unwrap_or_else(|e| e.into_response())
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Depth {
+    Zero,
+    One,
+    Infinity,
+}
+
+enum PropfindKind {
+    AllProp,
+    PropName,
+    Prop(Vec<(String, String)>),
+}
+
+/// One WebDAV request against a normalized storage path
+struct DavRequest {
+    app_state: AppState,
+    path: String,
+    headers: HeaderMap,
+    /// Email of the authenticated user, who the changes are made for
+    user: String,
+}
+
+impl DavRequest {
+    fn file_service(&self) -> FileService {
+        self.app_state.file_service(&self.user)
+    }
+
+    async fn info(&self, path: &str) -> Result<Option<FileInfo>, ApiError> {
+        match self.file_service().get_info(path).await {
+            Ok(info) => Ok(Some(info)),
+            Err(ApiError::FileNotFound { .. }) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn parent_exists(&self, path: &str) -> Result<bool, ApiError> {
+        Ok(self
+            .info(&parent_of(path))
+            .await?
+            .is_some_and(|info| info.is_directory))
+    }
+
+    /// Lock tokens the client submitted in the `If` header
+    fn submitted_tokens(&self) -> Vec<String> {
+        let Some(value) = self.headers.get("if").and_then(|value| value.to_str().ok()) else {
+            return Vec::new();
+        };
+        value
+            .split('<')
+            .filter_map(|part| part.split_once('>').map(|(token, _)| token))
+            .filter(|token| token.starts_with("opaquelocktoken:"))
+            .map(str::to_string)
+            .collect()
+    }
+
+    /// Whether a lock the client didn't submit a token for blocks modifying `path`
+    fn is_locked(&self, path: &str, subtree: bool) -> bool {
+        let tokens = self.submitted_tokens();
+        !self.app_state.webdav_service.can_modify(path, subtree, &tokens)
+    }
+
+    fn depth(&self, default: Depth) -> Result<Depth, ApiError> {
+        match self.headers.get("depth").map(|value| value.to_str().unwrap_or("")) {
+            None => Ok(default),
+            Some("0") => Ok(Depth::Zero),
+            Some("1") => Ok(Depth::One),
+            Some(value) if value.eq_ignore_ascii_case("infinity") => Ok(Depth::Infinity),
+            Some(value) => Err(ApiError::BadRequest {
+                message: format!("Invalid Depth header: {}", value),
+            }),
+        }
+    }
+
+    async fn propfind(&self, body: Body) -> Result<Response, ApiError> {
+        let depth = self.depth(Depth::Infinity)?;
+        if depth == Depth::Infinity {
+            return Ok(error_response(StatusCode::FORBIDDEN, "propfind-finite-depth"));
+        }
+
+        let body = read_xml_body(body).await?;
+        let kind = if body.is_empty() {
+            PropfindKind::AllProp
+        } else {
+            let root = parse_body(&body)?;
+            if !root.is(DAV_NS, "propfind") {
+                return Err(bad_xml("Expected a propfind element"));
+            }
+            if root.child(DAV_NS, "propname").is_some() {
+                PropfindKind::PropName
+            } else if let Some(prop) = root.child(DAV_NS, "prop") {
+                PropfindKind::Prop(
+                    prop.children
+                        .iter()
+                        .map(|child| (child.namespace.clone(), child.name.clone()))
+                        .collect(),
+                )
+            } else {
+                PropfindKind::AllProp
+            }
+        };
+
+        let Some(info) = self.info(&self.path).await? else {
+            return Ok(status(StatusCode::NOT_FOUND));
+        };
+
+        let mut resources = Vec::new();
+        if depth == Depth::One && info.is_directory {
+            for child in self.file_service().list_files(&self.path).await? {
+                resources.push((join(&self.path, &child.name), child));
+            }
+        }
+        resources.insert(0, (self.path.clone(), info));
+
+        let mut multistatus = Multistatus::new();
+        for (path, info) in &resources {
+            let dead = self.app_state.webdav_service.properties(path).await?;
+            let propstats = match &kind {
+                PropfindKind::AllProp => {
+                    let mut found: Vec<String> = LIVE_PROPERTIES
+                        .iter()
+                        .filter_map(|name| self.live_property(path, info, name))
+                        .collect();
+                    found.extend(dead.iter().map(render_dead));
+                    vec![(StatusCode::OK, found)]
+                }
+                PropfindKind::PropName => {
+                    let mut names: Vec<String> = LIVE_PROPERTIES
+                        .iter()
+                        .filter(|name| self.live_property(path, info, name).is_some())
+                        .map(|name| xml::element(DAV_NS, name, None))
+                        .collect();
+                    names.extend(
+                        dead.iter()
+                            .map(|property| xml::element(&property.namespace, &property.name, None)),
+                    );
+                    vec![(StatusCode::OK, names)]
+                }
+                PropfindKind::Prop(requested) => {
+                    let mut found = Vec::new();
+                    let mut missing = Vec::new();
+                    for (namespace, name) in requested {
+                        let value = if namespace == DAV_NS {
+                            self.live_property(path, info, name)
+                        } else {
+                            None
+                        }
+                        .or_else(|| {
+                            dead.iter()
+                                .find(|property| &property.namespace == namespace && &property.name == name)
+                                .map(render_dead)
+                        });
+                        match value {
+                            Some(value) => found.push(value),
+                            None => missing.push(xml::element(namespace, name, None)),
+                        }
+                    }
+                    vec![(StatusCode::OK, found), (StatusCode::NOT_FOUND, missing)]
+                }
+            };
+            multistatus.propstat_response(&href(path, info.is_directory), &propstats);
+        }
+
+        Ok(multistatus.finish())
+    }
+
+    fn live_property(&self, path: &str, info: &FileInfo, name: &str) -> Option<String> {
+        let value = match name {
+            "creationdate" => escape(&info.modified.to_rfc3339()),
+            "displayname" => escape(&info.name),
+            "getcontentlength" if !info.is_directory => info.size.to_string(),
+            "getcontenttype" if !info.is_directory => escape(content_type(info)),
+            "getetag" if !info.is_directory => escape(&info.etag()),
+            "getlastmodified" => escape(&last_modified(info)),
+            "resourcetype" if info.is_directory => xml::element(DAV_NS, "collection", None),
+            "resourcetype" => String::new(),
+            "supportedlock" => ["exclusive", "shared"]
+                .iter()
+                .map(|scope| {
+                    format!(
+                        "<D:lockentry><D:lockscope><D:{}/></D:lockscope><D:locktype><D:write/></D:locktype></D:lockentry>",
+                        scope
+                    )
+                })
+                .collect(),
+            "lockdiscovery" => self
+                .app_state
+                .webdav_service
+                .locks_on(path)
+                .iter()
+                .map(render_lock)
+                .collect(),
+            _ => return None,
+        };
+        Some(xml::element(DAV_NS, name, Some(&value)))
+    }
+
+    async fn proppatch(&self, body: Body) -> Result<Response, ApiError> {
+        if self.is_locked(&self.path, false) {
+            return Ok(status(StatusCode::LOCKED));
+        }
+        let Some(info) = self.info(&self.path).await? else {
+            return Ok(status(StatusCode::NOT_FOUND));
+        };
+
+        let body = read_xml_body(body).await?;
+        let root = parse_body(&body)?;
+        if !root.is(DAV_NS, "propertyupdate") {
+            return Err(bad_xml("Expected a propertyupdate element"));
+        }
+
+        // Instructions are applied in document order
+        let mut updates = Vec::new();
+        for instruction in &root.children {
+            let set = if instruction.is(DAV_NS, "set") {
+                true
+            } else if instruction.is(DAV_NS, "remove") {
+                false
+            } else {
+                continue;
+            };
+            for prop in instruction.children.iter().filter(|child| child.is(DAV_NS, "prop")) {
+                for property in &prop.children {
+                    updates.push(if set {
+                        PropertyUpdate::Set(DeadProperty {
+                            namespace: property.namespace.clone(),
+                            name: property.name.clone(),
+                            value: property.inner_xml(),
+                        })
+                    } else {
+                        PropertyUpdate::Remove {
+                            namespace: property.namespace.clone(),
+                            name: property.name.clone(),
+                        }
+                    });
+                }
+            }
+        }
+
+        let name_of = |update: &PropertyUpdate| match update {
+            PropertyUpdate::Set(property) => xml::element(&property.namespace, &property.name, None),
+            PropertyUpdate::Remove { namespace, name } => xml::element(namespace, name, None),
+        };
+        let is_protected = |update: &PropertyUpdate| match update {
+            PropertyUpdate::Set(property) => property.namespace == DAV_NS,
+            PropertyUpdate::Remove { namespace, .. } => namespace == DAV_NS,
+        };
+
+        // Live properties can't be changed, and a single failure fails the whole request
+        let (protected, allowed): (Vec<_>, Vec<_>) = updates.iter().partition(|update| is_protected(update));
+        let propstats = if protected.is_empty() {
+            self.app_state
+                .webdav_service
+                .update_properties(&self.path, &updates)
+                .await?;
+            vec![(StatusCode::OK, updates.iter().map(name_of).collect())]
+        } else {
+            vec![
+                (StatusCode::FORBIDDEN, protected.into_iter().map(name_of).collect()),
+                (StatusCode::FAILED_DEPENDENCY, allowed.into_iter().map(name_of).collect()),
+            ]
+        };
+
+        let mut multistatus = Multistatus::new();
+        multistatus.propstat_response(&href(&self.path, info.is_directory), &propstats);
+        Ok(multistatus.finish())
+    }
+
+    async fn get(&self, with_body: bool) -> Result<Response, ApiError> {
+        let Some(info) = self.info(&self.path).await? else {
+            return Ok(status(StatusCode::NOT_FOUND));
+        };
+        if info.is_directory {
+            return self.directory_listing(with_body).await;
+        }
+
+        let etag = info.etag();
+        let if_none_match = self.headers.get(header::IF_NONE_MATCH).and_then(|value| value.to_str().ok());
+        if if_none_match.is_some_and(|value| value.split(',').any(|tag| tag.trim() == etag || tag.trim() == "*")) {
+            return Ok((StatusCode::NOT_MODIFIED, [(header::ETAG, etag)]).into_response());
+        }
+
+        let range = match self.headers.get(header::RANGE).and_then(|value| value.to_str().ok()) {
+            Some(value) => match parse_byte_range(value, info.size) {
+                Some(range) => range,
+                None => {
+                    return Ok((
+                        StatusCode::RANGE_NOT_SATISFIABLE,
+                        [(header::CONTENT_RANGE, format!("bytes */{}", info.size))],
+                    )
+                        .into_response())
+                }
+            },
+            None => None,
+        };
+
+        let mut response = Response::builder()
+            .header(header::CONTENT_TYPE, content_type(&info))
+            .header(header::ETAG, &etag)
+            .header(header::LAST_MODIFIED, last_modified(&info))
+            .header(header::ACCEPT_RANGES, "bytes");
+        let (start, length) = match range {
+            Some((start, end)) => {
+                response = response
+                    .status(StatusCode::PARTIAL_CONTENT)
+                    .header(header::CONTENT_RANGE, format!("bytes {}-{}/{}", start, end, info.size));
+                (start, end - start + 1)
+            }
+            None => (0, info.size),
+        };
+        response = response.header(header::CONTENT_LENGTH, length);
+
+        if !with_body {
+            return Ok(response.body(axum::body::boxed(Body::empty())).unwrap());
+        }
+
+        let (file, _) = self.file_service().open_file(&self.path, Some(start..start + length)).await?;
+        let stream = StreamBody::new(ReaderStream::new(file));
+        Ok(response.body(axum::body::boxed(stream)).unwrap())
+    }
+
+    async fn directory_listing(&self, with_body: bool) -> Result<Response, ApiError> {
+        let headers = [(header::CONTENT_TYPE, "text/html; charset=utf-8")];
+        if !with_body {
+            return Ok(headers.into_response());
+        }
+
+        let mut html = format!(
+            "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>/{}</title></head><body><ul>\n",
+            escape(&self.path)
+        );
+        if !self.path.is_empty() {
+            html.push_str(&format!("<li><a href=\"{}\">../</a></li>\n", href(&parent_of(&self.path), true)));
+        }
+        for child in self.file_service().list_files(&self.path).await? {
+            let path = join(&self.path, &child.name);
+            let suffix = if child.is_directory { "/" } else { "" };
+            html.push_str(&format!(
+                "<li><a href=\"{}\">{}{}</a></li>\n",
+                href(&path, child.is_directory),
+                escape(&child.name),
+                suffix
+            ));
+        }
+        html.push_str("</ul></body></html>\n");
+        Ok((headers, html).into_response())
+    }
+
+    async fn put(&self, body: Body) -> Result<Response, ApiError> {
+        if self.is_locked(&self.path, false) {
+            return Ok(status(StatusCode::LOCKED));
+        }
+        if self.path.is_empty() || self.info(&self.path).await?.is_some_and(|info| info.is_directory) {
+            return Ok(status(StatusCode::METHOD_NOT_ALLOWED));
+        }
+        if !self.parent_exists(&self.path).await? {
+            return Ok(status(StatusCode::CONFLICT));
+        }
+
+        let expected = expected_checksums(&self.headers)?;
+        let (info, created) = self.file_service().write_file_verified(&self.path, body, &expected).await?;
+        let kind = if created { ChangeKind::Created } else { ChangeKind::Modified };
+        self.app_state.change_feed.publish(&self.path, kind);
+
+        let code = if created { StatusCode::CREATED } else { StatusCode::NO_CONTENT };
+        Ok((code, [(header::ETAG, info.etag())]).into_response())
+    }
+
+    async fn delete(&self) -> Result<Response, ApiError> {
+        if self.path.is_empty() {
+            return Ok(status(StatusCode::FORBIDDEN));
+        }
+        if self.is_locked(&self.path, true) {
+            return Ok(status(StatusCode::LOCKED));
+        }
+        if self.info(&self.path).await?.is_none() {
+            return Ok(status(StatusCode::NOT_FOUND));
+        }
+
+        self.file_service().delete_file(&self.path).await?;
+        self.forget(&self.path).await?;
+        self.app_state.change_feed.publish(&self.path, ChangeKind::Deleted);
+        Ok(status(StatusCode::NO_CONTENT))
+    }
+
+    /// Drop the locks and properties of a path that no longer exists
+    async fn forget(&self, path: &str) -> Result<(), ApiError> {
+        self.app_state.webdav_service.release_subtree(path);
+        self.app_state.webdav_service.delete_properties(path).await
+    }
+
+    async fn mkcol(&self, body: Body) -> Result<Response, ApiError> {
+        if !read_xml_body(body).await?.is_empty() {
+            return Ok(status(StatusCode::UNSUPPORTED_MEDIA_TYPE));
+        }
+        if self.path.is_empty() || self.info(&self.path).await?.is_some() {
+            return Ok(status(StatusCode::METHOD_NOT_ALLOWED));
+        }
+        if !self.parent_exists(&self.path).await? {
+            return Ok(status(StatusCode::CONFLICT));
+        }
+        if self.is_locked(&self.path, false) {
+            return Ok(status(StatusCode::LOCKED));
+        }
+
+        self.file_service().create_directory(&self.path, false).await?;
+        self.app_state.change_feed.publish(&self.path, ChangeKind::Created);
+        Ok(status(StatusCode::CREATED))
+    }
+
+    async fn copy_or_move(&self, is_move: bool) -> Result<Response, ApiError> {
+        let destination = self.destination()?;
+        let overwrite = !self
+            .headers
+            .get("overwrite")
+            .is_some_and(|value| value.as_bytes().eq_ignore_ascii_case(b"F"));
+
+        let Some(info) = self.info(&self.path).await? else {
+            return Ok(status(StatusCode::NOT_FOUND));
+        };
+        if self.path.is_empty() && is_move {
+            return Ok(status(StatusCode::FORBIDDEN));
+        }
+        if destination == self.path || destination.starts_with(&format!("{}/", self.path)) || destination.is_empty() {
+            return Ok(status(StatusCode::FORBIDDEN));
+        }
+
+        let depth = self.depth(Depth::Infinity)?;
+        if depth == Depth::One || (is_move && depth != Depth::Infinity) {
+            return Err(ApiError::BadRequest {
+                message: "Depth must be infinity (or 0 for COPY)".to_string(),
+            });
+        }
+
+        if !self.parent_exists(&destination).await? {
+            return Ok(status(StatusCode::CONFLICT));
+        }
+        if (is_move && self.is_locked(&self.path, true)) || self.is_locked(&destination, true) {
+            return Ok(status(StatusCode::LOCKED));
+        }
+
+        let file_service = self.file_service();
+        let replaced = self.info(&destination).await?;
+        if let Some(replaced) = &replaced {
+            if !overwrite {
+                return Ok(status(StatusCode::PRECONDITION_FAILED));
+            }
+            if !replaced.is_directory {
+                file_service.preserve_version(&destination).await?;
+            }
+            file_service.delete_file(&destination).await?;
+            self.forget(&destination).await?;
+        }
+
+        let dav = &self.app_state.webdav_service;
+        if is_move {
+            file_service.rename_file(&self.path, &destination).await?;
+            dav.move_properties(&self.path, &destination).await?;
+            dav.release_subtree(&self.path);
+            self.app_state.change_feed.publish_rename(&self.path, &destination);
+        } else {
+            if info.is_directory && depth == Depth::Zero {
+                file_service.create_directory(&destination, false).await?;
+                let properties = dav.properties(&self.path).await?;
+                let updates: Vec<_> = properties.into_iter().map(PropertyUpdate::Set).collect();
+                dav.update_properties(&destination, &updates).await?;
+            } else {
+                file_service.copy_path(&self.path, &destination).await?;
+                dav.copy_properties(&self.path, &destination).await?;
+            }
+            let kind = if replaced.is_some() { ChangeKind::Modified } else { ChangeKind::Created };
+            self.app_state.change_feed.publish(&destination, kind);
+        }
+
+        Ok(status(if replaced.is_some() { StatusCode::NO_CONTENT } else { StatusCode::CREATED }))
+    }
+
+    /// The normalized target of a COPY or MOVE
+    fn destination(&self) -> Result<String, ApiError> {
+        let value = self
+            .headers
+            .get("destination")
+            .and_then(|value| value.to_str().ok())
+            .ok_or_else(|| ApiError::BadRequest {
+                message: "Missing Destination header".to_string(),
+            })?;
+
+        // Absolute URI or absolute path; only the path matters
+        let path = match value.split_once("://") {
+            Some((_, rest)) => rest.find('/').map(|index| &rest[index..]).unwrap_or("/"),
+            None => value,
+        };
+        request_path(path.strip_prefix(DAV_PREFIX).filter(|rest| rest.is_empty() || rest.starts_with('/')).ok_or_else(
+            || ApiError::BadRequest {
+                message: "Destination must be inside the WebDAV tree".to_string(),
+            },
+        )?)
+    }
+
+    async fn lock(&self, body: Body) -> Result<Response, ApiError> {
+        let timeout = self.timeout();
+        let body = read_xml_body(body).await?;
+        let dav = &self.app_state.webdav_service;
+
+        if body.is_empty() {
+            // Refresh an existing lock
+            return Ok(match dav.refresh(&self.path, &self.submitted_tokens(), timeout) {
+                Some(lock) => lock_response(StatusCode::OK, &lock),
+                None => status(StatusCode::PRECONDITION_FAILED),
+            });
+        }
+
+        let root = parse_body(&body)?;
+        if !root.is(DAV_NS, "lockinfo") {
+            return Err(bad_xml("Expected a lockinfo element"));
+        }
+        let exclusive = match root.child(DAV_NS, "lockscope") {
+            Some(scope) if scope.child(DAV_NS, "exclusive").is_some() => true,
+            Some(scope) if scope.child(DAV_NS, "shared").is_some() => false,
+            _ => return Err(bad_xml("Missing or unknown lockscope")),
+        };
+        if root
+            .child(DAV_NS, "locktype")
+            .and_then(|locktype| locktype.child(DAV_NS, "write"))
+            .is_none()
+        {
+            return Err(bad_xml("Only write locks are supported"));
+        }
+        let owner = root.child(DAV_NS, "owner").map(XmlElement::inner_xml);
+
+        let deep = match self.depth(Depth::Infinity)? {
+            Depth::Zero => false,
+            Depth::Infinity => true,
+            Depth::One => {
+                return Err(ApiError::BadRequest {
+                    message: "Depth must be 0 or infinity".to_string(),
+                })
+            }
+        };
+
+        let exists = self.info(&self.path).await?.is_some();
+        if !exists && !self.parent_exists(&self.path).await? {
+            return Ok(status(StatusCode::CONFLICT));
+        }
+
+        let Some(lock) = dav.lock(&self.path, exclusive, deep, owner, timeout) else {
+            return Ok(status(StatusCode::LOCKED));
+        };
+
+        // Locking an unmapped URL creates an empty resource
+        if !exists {
+            let empty = futures::stream::empty::<Result<Bytes, std::io::Error>>();
+            if let Err(e) = self.file_service().write_file(&self.path, empty).await {
+                dav.unlock(&self.path, &lock.token);
+                return Err(e);
+            }
+            self.app_state.change_feed.publish(&self.path, ChangeKind::Created);
+        }
+
+        let code = if exists { StatusCode::OK } else { StatusCode::CREATED };
+        let mut response = lock_response(code, &lock);
+        if let Ok(value) = HeaderValue::from_str(&format!("<{}>", lock.token)) {
+            response.headers_mut().insert("lock-token", value);
+        }
+        Ok(response)
+    }
+
+    async fn unlock(&self) -> Result<Response, ApiError> {
+        let token = self
+            .headers
+            .get("lock-token")
+            .and_then(|value| value.to_str().ok())
+            .map(|value| value.trim().trim_start_matches('<').trim_end_matches('>'))
+            .ok_or_else(|| ApiError::BadRequest {
+                message: "Missing Lock-Token header".to_string(),
+            })?;
+
+        if self.app_state.webdav_service.unlock(&self.path, token) {
+            Ok(status(StatusCode::NO_CONTENT))
+        } else {
+            Ok(error_response(StatusCode::CONFLICT, "lock-token-matches-request-uri"))
+        }
+    }
+
+    /// The first timeout in the `Timeout` header we understand, capped by `MAX_LOCK_TIMEOUT`
+    fn timeout(&self) -> Duration {
+        self.headers
+            .get("timeout")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| {
+                value.split(',').map(str::trim).find_map(|timeout| {
+                    if timeout.eq_ignore_ascii_case("infinite") {
+                        Some(MAX_LOCK_TIMEOUT)
+                    } else {
+                        timeout
+                            .strip_prefix("Second-")
+                            .and_then(|seconds| seconds.parse().ok())
+                            .map(Duration::from_secs)
+                    }
+                })
+            })
+            .unwrap_or(MAX_LOCK_TIMEOUT)
+            .min(MAX_LOCK_TIMEOUT)
+    }
+}
+
+/// Builds a 207 Multi-Status body
+struct Multistatus {
+    body: String,
+}
+
+impl Multistatus {
+    fn new() -> Self {
+        Self {
+            body: "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<D:multistatus xmlns:D=\"DAV:\">\n".to_string(),
+        }
+    }
+
+    fn propstat_response(&mut self, href: &str, propstats: &[(StatusCode, Vec<String>)]) {
+        self.body.push_str(&format!("<D:response><D:href>{}</D:href>", escape(href)));
+        for (code, properties) in propstats.iter().filter(|(_, properties)| !properties.is_empty()) {
+            self.body.push_str(&format!(
+                "<D:propstat><D:prop>{}</D:prop><D:status>{}</D:status></D:propstat>",
+                properties.concat(),
+                status_line(*code)
+            ));
+        }
+        self.body.push_str("</D:response>\n");
+    }
+
+    fn finish(mut self) -> Response {
+        self.body.push_str("</D:multistatus>\n");
+        (
+            StatusCode::MULTI_STATUS,
+            [(header::CONTENT_TYPE, "application/xml; charset=utf-8")],
+            self.body,
+        )
+            .into_response()
+    }
+}
+
+fn options() -> Response {
+    (
+        StatusCode::OK,
+        [
+            ("dav", "1, 2"),
+            ("allow", ALLOWED_METHODS),
+            ("ms-author-via", "DAV"),
+        ],
+    )
+        .into_response()
+}
+
+fn status(code: StatusCode) -> Response {
+    code.into_response()
+}
+
+/// An RFC 4918 error body naming the failed precondition
+fn error_response(code: StatusCode, condition: &str) -> Response {
+    let body = format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<D:error xmlns:D=\"DAV:\"><D:{}/></D:error>\n",
+        condition
+    );
+    (code, [(header::CONTENT_TYPE, "application/xml; charset=utf-8")], body).into_response()
+}
+
+fn lock_response(code: StatusCode, lock: &DavLock) -> Response {
+    let body = format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<D:prop xmlns:D=\"DAV:\"><D:lockdiscovery>{}</D:lockdiscovery></D:prop>\n",
+        render_lock(lock)
+    );
+    (code, [(header::CONTENT_TYPE, "application/xml; charset=utf-8")], body).into_response()
+}
+
+fn render_lock(lock: &DavLock) -> String {
+    format!(
+        "<D:activelock><D:locktype><D:write/></D:locktype><D:lockscope><D:{}/></D:lockscope>\
+         <D:depth>{}</D:depth>{}<D:timeout>Second-{}</D:timeout>\
+         <D:locktoken><D:href>{}</D:href></D:locktoken><D:lockroot><D:href>{}</D:href></D:lockroot></D:activelock>",
+        if lock.exclusive { "exclusive" } else { "shared" },
+        if lock.deep { "infinity" } else { "0" },
+        lock.owner
+            .as_deref()
+            .map(|owner| xml::element(DAV_NS, "owner", Some(owner)))
+            .unwrap_or_default(),
+        lock.remaining().as_secs(),
+        escape(&lock.token),
+        escape(&href(&lock.path, false)),
+    )
+}
+
+fn render_dead(property: &DeadProperty) -> String {
+    xml::element(&property.namespace, &property.name, Some(&property.value))
+}
+
+fn status_line(code: StatusCode) -> String {
+    format!("HTTP/1.1 {} {}", code.as_u16(), code.canonical_reason().unwrap_or(""))
+}
+
+/// Turn the percent-encoded request path (relative to the mount) into a storage path
+fn request_path(raw: &str) -> Result<String, ApiError> {
+    let decoded = percent_decode_str(raw)
+        .decode_utf8()
+        .map_err(|_| ApiError::InvalidPath {
+            path: raw.to_string(),
+        })?;
+    normalize(&decoded)
+}
+
+/// The href of a storage path; collections end with a slash
+fn href(path: &str, is_directory: bool) -> String {
+    let mut href = DAV_PREFIX.to_string();
+    for segment in path.split('/').filter(|segment| !segment.is_empty()) {
+        href.push('/');
+        href.extend(utf8_percent_encode(segment, SEGMENT));
+    }
+    if is_directory || path.is_empty() {
+        href.push('/');
+    }
+    href
+}
+
+fn join(parent: &str, name: &str) -> String {
+    if parent.is_empty() {
+        name.to_string()
+    } else {
+        format!("{}/{}", parent, name)
+    }
+}
+
+fn escape(text: &str) -> String {
+    quick_xml::escape::escape(text).to_string()
+}
+
+fn content_type(info: &FileInfo) -> &str {
+    info.mime_type.as_deref().unwrap_or("application/octet-stream")
+}
+
+fn last_modified(info: &FileInfo) -> String {
+    httpdate::fmt_http_date(info.modified.into())
+}
+
+async fn read_xml_body(mut body: Body) -> Result<Vec<u8>, ApiError> {
+    let mut bytes = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|e| ApiError::BadRequest {
+            message: format!("Failed to read request body: {}", e),
+        })?;
+        if bytes.len() + chunk.len() > MAX_XML_BODY {
+            return Err(ApiError::FileTooLarge {
+                size: (bytes.len() + chunk.len()) as u64,
+            });
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+    Ok(bytes)
+}
+
+fn parse_body(body: &[u8]) -> Result<XmlElement, ApiError> {
+    xml::parse(body).map_err(|message| ApiError::BadRequest { message })
+}
+
+fn bad_xml(message: &str) -> ApiError {
+    ApiError::BadRequest {
+        message: message.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_app;
+    use axum::{body::Body, http::Request};
+    use base64::Engine;
+    use tower::ServiceExt;
+
+    #[test]
+    fn test_href_round_trips_through_request_path() {
+        assert_eq!(href("", true), "/dav/");
+        assert_eq!(href("my docs/ä+b.txt", false), "/dav/my%20docs/%C3%A4%2Bb.txt");
+        assert_eq!(request_path("/my%20docs/%C3%A4%2Bb.txt").unwrap(), "my docs/ä+b.txt");
+        // Traversal can't climb above the mount
+        assert_eq!(request_path("/a/%2E%2E/%2E%2E/etc").unwrap(), "etc");
+    }
+
+    #[tokio::test]
+    async fn test_api_key_logins_need_the_owners_email() {
+        let dir = tempfile::tempdir().unwrap();
+        let (app, token) = test_app(dir.path()).await;
+        let request = Request::post("/api/auth/api-keys")
+            .header("authorization", format!("Bearer {}", token))
+            .header("content-type", "application/json")
+            .body(Body::from(r#"{"name":"dav"}"#))
+            .unwrap();
+        let body = hyper::body::to_bytes(app.clone().oneshot(request).await.unwrap().into_body()).await.unwrap();
+        let key: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        let key = key["key"].as_str().unwrap();
+
+        for (username, status) in [
+            ("admin@filedash.local", StatusCode::MULTI_STATUS),
+            ("Admin@FileDash.local", StatusCode::MULTI_STATUS),
+            ("someone@example.com", StatusCode::UNAUTHORIZED),
+            ("", StatusCode::UNAUTHORIZED),
+        ] {
+            let credentials = base64::engine::general_purpose::STANDARD.encode(format!("{}:{}", username, key));
+            let request = Request::builder()
+                .method("PROPFIND")
+                .uri("/dav/")
+                .header("authorization", format!("Basic {}", credentials))
+                .header("depth", "0")
+                .body(Body::empty())
+                .unwrap();
+            assert_eq!(app.clone().oneshot(request).await.unwrap().status(), status, "{}", username);
+        }
+    }
+}
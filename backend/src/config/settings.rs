@@ -7,6 +7,28 @@ pub struct Config {
     pub storage: StorageConfig,
     pub database: DatabaseConfig,
     pub auth: AuthConfig,
+    #[serde(default)]
+    pub search: SearchConfig,
+    #[serde(default)]
+    pub watcher: WatcherConfig,
+    #[serde(default)]
+    pub s3: S3Config,
+    #[serde(default)]
+    pub versioning: VersioningConfig,
+    #[serde(default)]
+    pub quotas: QuotaConfig,
+    #[serde(default)]
+    pub disk_usage: DiskUsageConfig,
+    #[serde(default)]
+    pub thumbnails: ThumbnailConfig,
+    #[serde(default)]
+    pub preview: PreviewConfig,
+    #[serde(default)]
+    pub archives: ArchiveConfig,
+    #[serde(default)]
+    pub jobs: JobConfig,
+    #[serde(default)]
+    pub attributes: AttributeConfig,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -24,6 +46,188 @@ pub struct StorageConfig {
     pub max_upload_size: u64,
     #[serde(default = "default_frontend_dist_path")]
     pub frontend_dist_path: PathBuf,
+    /// Where file contents are kept; `home_directory` unless configured otherwise
+    #[serde(default)]
+    pub backend: StorageBackendConfig,
+    /// Named storage roots shown as the top-level directories. When any are configured,
+    /// `home_directory` and `backend` are no longer used for files.
+    #[serde(default)]
+    pub mounts: Vec<MountConfig>,
+    #[serde(default)]
+    pub encryption: EncryptionConfig,
+    #[serde(default)]
+    pub dedup: DedupConfig,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct EncryptionConfig {
+    /// Encrypt file contents before they reach the backend; mounts can override this
+    #[serde(default)]
+    pub enabled: bool,
+    /// Base64 of 32 random bytes. Every file key is derived from it.
+    #[serde(default)]
+    pub master_key: Option<String>,
+    /// Earlier master keys, still accepted for reading. Files written with them are
+    /// re-encrypted under `master_key` in the background.
+    #[serde(default)]
+    pub previous_keys: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MountConfig {
+    /// The top-level directory the mount appears as
+    pub name: String,
+    /// Directory holding the mount's files, for the local backend
+    #[serde(default)]
+    pub path: PathBuf,
+    #[serde(default)]
+    pub read_only: bool,
+    /// Overrides `storage.allowed_extensions` for this mount
+    #[serde(default)]
+    pub allowed_extensions: Option<Vec<String>>,
+    /// Overrides `storage.max_upload_size` for this mount
+    #[serde(default)]
+    pub max_upload_size: Option<u64>,
+    #[serde(default)]
+    pub backend: StorageBackendConfig,
+    /// Overrides `storage.encryption.enabled` for this mount
+    #[serde(default)]
+    pub encrypted: Option<bool>,
+    /// Overrides `storage.dedup.enabled` for this mount
+    #[serde(default)]
+    pub deduplicated: Option<bool>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DedupConfig {
+    /// Keep each distinct file content once, by SHA-256, with paths referring to it;
+    /// mounts can override this
+    #[serde(default)]
+    pub enabled: bool,
+    /// How often contents no longer referred to by any path are deleted (0 disables)
+    #[serde(default = "default_dedup_gc_interval_seconds")]
+    pub gc_interval_seconds: u64,
+}
+
+/// A directory on the local disk and the storage path its contents appear under
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LocalRoot {
+    /// `""` when there are no mounts, otherwise the mount name
+    pub path: String,
+    pub directory: PathBuf,
+    /// Whether files in it are stored encrypted
+    pub encrypted: bool,
+}
+
+impl StorageConfig {
+    /// The mount a normalized storage path belongs to
+    pub fn mount(&self, path: &str) -> Option<&MountConfig> {
+        let name = path.split('/').next().unwrap_or_default();
+        self.mounts.iter().find(|mount| mount.name == name)
+    }
+
+    pub fn allowed_extensions_for(&self, path: &str) -> &[String] {
+        self.mount(path)
+            .and_then(|mount| mount.allowed_extensions.as_deref())
+            .unwrap_or(&self.allowed_extensions)
+    }
+
+    pub fn max_upload_size_for(&self, path: &str) -> u64 {
+        self.mount(path)
+            .and_then(|mount| mount.max_upload_size)
+            .unwrap_or(self.max_upload_size)
+    }
+
+    /// Whether files at a normalized storage path are stored encrypted
+    pub fn encrypted_at(&self, path: &str) -> bool {
+        self.mount(path)
+            .and_then(|mount| mount.encrypted)
+            .unwrap_or(self.encryption.enabled)
+    }
+
+    /// Whether anything is stored encrypted
+    pub fn encryption_in_use(&self) -> bool {
+        if self.mounts.is_empty() {
+            return self.encryption.enabled;
+        }
+        self.mounts
+            .iter()
+            .any(|mount| mount.encrypted.unwrap_or(self.encryption.enabled))
+    }
+
+    /// Whether anything is stored deduplicated
+    pub fn dedup_in_use(&self) -> bool {
+        if self.mounts.is_empty() {
+            return self.dedup.enabled;
+        }
+        self.mounts
+            .iter()
+            .any(|mount| mount.deduplicated.unwrap_or(self.dedup.enabled))
+    }
+
+    /// The largest upload any path accepts
+    pub fn largest_upload_size(&self) -> u64 {
+        self.mounts
+            .iter()
+            .filter_map(|mount| mount.max_upload_size)
+            .fold(self.max_upload_size, u64::max)
+    }
+
+    /// The directories on the local disk that hold files, for the services that read the
+    /// disk directly rather than through the storage backend
+    pub fn local_roots(&self) -> Vec<LocalRoot> {
+        // Deduplicated files are only on the disk by content, not by path
+        if self.mounts.is_empty() && self.dedup.enabled {
+            return Vec::new();
+        }
+        if self.mounts.is_empty() {
+            if !matches!(self.backend, StorageBackendConfig::Local) {
+                return Vec::new();
+            }
+            return vec![LocalRoot {
+                path: String::new(),
+                directory: self.home_directory.clone(),
+                encrypted: self.encryption.enabled,
+            }];
+        }
+        self.mounts
+            .iter()
+            .filter(|mount| matches!(mount.backend, StorageBackendConfig::Local))
+            .filter(|mount| !mount.deduplicated.unwrap_or(self.dedup.enabled))
+            .map(|mount| LocalRoot {
+                path: mount.name.clone(),
+                directory: mount.path.clone(),
+                encrypted: mount.encrypted.unwrap_or(self.encryption.enabled),
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum StorageBackendConfig {
+    /// Files in `home_directory` on the local disk
+    #[default]
+    Local,
+    /// Objects in an S3-compatible bucket, such as AWS S3 or MinIO
+    S3(ObjectStoreConfig),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ObjectStoreConfig {
+    /// Base URL of the S3 API, such as `http://localhost:9000`; buckets are addressed by path
+    pub endpoint: String,
+    pub bucket: String,
+    #[serde(default = "default_object_store_region")]
+    pub region: String,
+    pub access_key_id: String,
+    pub secret_access_key: String,
+    /// Key prefix files are kept under, so the bucket can hold other data too
+    #[serde(default)]
+    pub prefix: String,
+    /// Files larger than this are uploaded in parts of this size; S3 needs at least 5 MiB
+    #[serde(default = "default_object_store_part_size")]
+    pub part_size: u64,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -43,10 +247,363 @@ pub struct AuthConfig {
     pub enable_auth: bool,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SearchConfig {
+    #[serde(default = "default_search_max_depth")]
+    pub max_depth: usize,
+    #[serde(default = "default_search_timeout_seconds")]
+    pub timeout_seconds: u64,
+    #[serde(default = "default_search_limit")]
+    pub default_limit: usize,
+    #[serde(default = "default_search_max_limit")]
+    pub max_limit: usize,
+    #[serde(default = "default_index_enabled")]
+    pub index_enabled: bool,
+    #[serde(default = "default_reconcile_interval_seconds")]
+    pub reconcile_interval_seconds: u64,
+    #[serde(default)]
+    pub content: ContentIndexConfig,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ContentIndexConfig {
+    #[serde(default = "default_content_enabled")]
+    pub enabled: bool,
+    /// Files larger than this are indexed by name only
+    #[serde(default = "default_content_max_file_size")]
+    pub max_file_size: u64,
+    #[serde(default = "default_content_workers")]
+    pub workers: usize,
+    #[serde(default = "default_content_queue_size")]
+    pub queue_size: usize,
+    /// Directory prefixes whose contents are indexed; empty means everywhere
+    #[serde(default)]
+    pub include_paths: Vec<String>,
+    /// Directory prefixes whose contents are never indexed
+    #[serde(default)]
+    pub exclude_paths: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WatcherConfig {
+    /// Watch the storage directory for changes made outside FileDash
+    #[serde(default = "default_watcher_enabled")]
+    pub enabled: bool,
+    /// How long to collect filesystem events before publishing them as one batch
+    #[serde(default = "default_watcher_debounce_ms")]
+    pub debounce_ms: u64,
+    /// Raw events buffered before the watcher gives up and asks for a rescan
+    #[serde(default = "default_watcher_queue_size")]
+    pub queue_size: usize,
+    /// Recent change batches kept so event stream clients can resume after reconnecting
+    #[serde(default = "default_watcher_history_size")]
+    pub history_size: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct S3Config {
+    /// Serve the S3-compatible API under `/s3`
+    #[serde(default = "default_s3_enabled")]
+    pub enabled: bool,
+    /// Where uploads are assembled before being moved into place; keep it on the same
+    /// filesystem as the storage directory so the move is a rename
+    #[serde(default = "default_s3_staging_dir")]
+    pub staging_dir: PathBuf,
+    /// Multipart uploads neither completed nor aborted within this time are discarded
+    #[serde(default = "default_s3_multipart_expiry_hours")]
+    pub multipart_expiry_hours: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VersioningConfig {
+    /// Keep the previous contents of a file whenever it is overwritten
+    #[serde(default = "default_versioning_enabled")]
+    pub enabled: bool,
+    /// Where previous contents are kept, encrypted when storage encryption is in use
+    #[serde(default = "default_versioning_directory")]
+    pub directory: PathBuf,
+    /// Versions kept per file; the oldest are deleted first (0 keeps all)
+    #[serde(default = "default_versioning_max_versions")]
+    pub max_versions: usize,
+    /// Versions older than this are deleted (0 keeps them regardless of age)
+    #[serde(default)]
+    pub max_age_days: u64,
+    /// Total size of all versions; the oldest are deleted first to stay under it (0 is unlimited)
+    #[serde(default)]
+    pub max_total_size: u64,
+    /// How often versions past their age are deleted (0 only checks when a new version is kept)
+    #[serde(default = "default_versioning_cleanup_interval_seconds")]
+    pub cleanup_interval_seconds: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QuotaConfig {
+    /// Limits on storage use; usage is only tracked when there are any
+    #[serde(default)]
+    pub rules: Vec<QuotaRule>,
+    /// Named sets of users that quota rules can apply to together
+    #[serde(default)]
+    pub groups: Vec<QuotaGroup>,
+    /// How often tracked usage is checked against what storage actually holds (0 disables)
+    #[serde(default = "default_quota_reconcile_interval_seconds")]
+    pub reconcile_interval_seconds: u64,
+}
+
+/// A limit on the files of one user, the members of a group together, or a directory tree.
+/// Exactly one of `user`, `group` and `path` is set.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QuotaRule {
+    /// A user's email, or `"*"` for every user without a rule of their own
+    #[serde(default)]
+    pub user: Option<String>,
+    #[serde(default)]
+    pub group: Option<String>,
+    #[serde(default)]
+    pub path: Option<String>,
+    #[serde(default)]
+    pub max_bytes: Option<u64>,
+    #[serde(default)]
+    pub max_files: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QuotaGroup {
+    pub name: String,
+    /// Emails of the users in the group
+    #[serde(default)]
+    pub members: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DiskUsageConfig {
+    /// How long computed directory sizes are reused before being listed again, in case a
+    /// change wasn't reported (0 keeps them until a change is)
+    #[serde(default = "default_disk_usage_cache_ttl_seconds")]
+    pub cache_ttl_seconds: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ThumbnailConfig {
+    #[serde(default = "default_thumbnails_enabled")]
+    pub enabled: bool,
+    /// Where generated thumbnails are cached, encrypted when storage encryption is in use
+    #[serde(default = "default_thumbnails_directory")]
+    pub directory: PathBuf,
+    /// Sizes generated, as the longest edge in pixels; requests are rounded up to one of them
+    #[serde(default = "default_thumbnail_sizes")]
+    pub sizes: Vec<u32>,
+    /// Larger images are not decoded
+    #[serde(default = "default_thumbnail_max_source_size")]
+    pub max_source_size: u64,
+    /// Thumbnails generated at the same time
+    #[serde(default = "default_thumbnail_workers")]
+    pub workers: usize,
+    /// Requests waiting for a worker before more are turned away with 503
+    #[serde(default = "default_thumbnail_queue_size")]
+    pub queue_size: usize,
+    /// Quality of thumbnails of opaque images, which are JPEG; those with transparency are
+    /// lossless WebP
+    #[serde(default = "default_thumbnail_jpeg_quality")]
+    pub jpeg_quality: u8,
+    /// Total size of the cache; the oldest thumbnails are deleted first to stay under it
+    /// (0 is unlimited)
+    #[serde(default = "default_thumbnail_max_cache_size")]
+    pub max_cache_size: u64,
+    /// How often the cache is trimmed to `max_cache_size`
+    #[serde(default = "default_thumbnail_cleanup_interval_seconds")]
+    pub cleanup_interval_seconds: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PreviewConfig {
+    /// Largest text file shown in the browser; larger ones have to be downloaded
+    #[serde(default = "default_preview_max_text_size")]
+    pub max_text_size: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ArchiveConfig {
+    /// Archives whose listing is kept in memory for browsing
+    #[serde(default = "default_archive_cache_size")]
+    pub cache_size: usize,
+    /// Archives with more entries than this can't be browsed or extracted
+    #[serde(default = "default_archive_max_entries")]
+    pub max_entries: usize,
+    /// Most bytes written when extracting an archive
+    #[serde(default = "default_archive_max_extracted_size")]
+    pub max_extracted_size: u64,
+    /// Most bytes written when extracting an archive, as a multiple of its size, to stop
+    /// zip bombs (0 is unlimited)
+    #[serde(default = "default_archive_max_compression_ratio")]
+    pub max_compression_ratio: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JobConfig {
+    /// Background jobs run at the same time; the rest wait their turn
+    #[serde(default = "default_job_workers")]
+    pub workers: usize,
+    /// Days finished jobs are kept
+    #[serde(default = "default_job_retention_days")]
+    pub retention_days: u32,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct AttributeConfig {
+    /// Let admins set the setuid and setgid bits on files, which make them run as their
+    /// owner or group; directories can always have setgid
+    #[serde(default)]
+    pub allow_setid_files: bool,
+}
+
+impl Default for SearchConfig {
+    fn default() -> Self {
+        Self {
+            max_depth: default_search_max_depth(),
+            timeout_seconds: default_search_timeout_seconds(),
+            default_limit: default_search_limit(),
+            max_limit: default_search_max_limit(),
+            index_enabled: default_index_enabled(),
+            reconcile_interval_seconds: default_reconcile_interval_seconds(),
+            content: ContentIndexConfig::default(),
+        }
+    }
+}
+
+impl Default for ContentIndexConfig {
+    fn default() -> Self {
+        Self {
+            enabled: default_content_enabled(),
+            max_file_size: default_content_max_file_size(),
+            workers: default_content_workers(),
+            queue_size: default_content_queue_size(),
+            include_paths: Vec::new(),
+            exclude_paths: Vec::new(),
+        }
+    }
+}
+
+impl Default for WatcherConfig {
+    fn default() -> Self {
+        Self {
+            enabled: default_watcher_enabled(),
+            debounce_ms: default_watcher_debounce_ms(),
+            queue_size: default_watcher_queue_size(),
+            history_size: default_watcher_history_size(),
+        }
+    }
+}
+
+impl Default for DedupConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            gc_interval_seconds: default_dedup_gc_interval_seconds(),
+        }
+    }
+}
+
+impl Default for S3Config {
+    fn default() -> Self {
+        Self {
+            enabled: default_s3_enabled(),
+            staging_dir: default_s3_staging_dir(),
+            multipart_expiry_hours: default_s3_multipart_expiry_hours(),
+        }
+    }
+}
+
+impl Default for VersioningConfig {
+    fn default() -> Self {
+        Self {
+            enabled: default_versioning_enabled(),
+            directory: default_versioning_directory(),
+            max_versions: default_versioning_max_versions(),
+            max_age_days: 0,
+            max_total_size: 0,
+            cleanup_interval_seconds: default_versioning_cleanup_interval_seconds(),
+        }
+    }
+}
+
+impl Default for DiskUsageConfig {
+    fn default() -> Self {
+        Self {
+            cache_ttl_seconds: default_disk_usage_cache_ttl_seconds(),
+        }
+    }
+}
+
+impl Default for ThumbnailConfig {
+    fn default() -> Self {
+        Self {
+            enabled: default_thumbnails_enabled(),
+            directory: default_thumbnails_directory(),
+            sizes: default_thumbnail_sizes(),
+            max_source_size: default_thumbnail_max_source_size(),
+            workers: default_thumbnail_workers(),
+            queue_size: default_thumbnail_queue_size(),
+            jpeg_quality: default_thumbnail_jpeg_quality(),
+            max_cache_size: default_thumbnail_max_cache_size(),
+            cleanup_interval_seconds: default_thumbnail_cleanup_interval_seconds(),
+        }
+    }
+}
+
+impl Default for PreviewConfig {
+    fn default() -> Self {
+        Self {
+            max_text_size: default_preview_max_text_size(),
+        }
+    }
+}
+
+impl Default for ArchiveConfig {
+    fn default() -> Self {
+        Self {
+            cache_size: default_archive_cache_size(),
+            max_entries: default_archive_max_entries(),
+            max_extracted_size: default_archive_max_extracted_size(),
+            max_compression_ratio: default_archive_max_compression_ratio(),
+        }
+    }
+}
+
+impl Default for JobConfig {
+    fn default() -> Self {
+        Self {
+            workers: default_job_workers(),
+            retention_days: default_job_retention_days(),
+        }
+    }
+}
+
+impl Default for QuotaConfig {
+    fn default() -> Self {
+        Self {
+            rules: Vec::new(),
+            groups: Vec::new(),
+            reconcile_interval_seconds: default_quota_reconcile_interval_seconds(),
+        }
+    }
+}
+
 fn default_frontend_dist_path() -> PathBuf {
     PathBuf::from("frontend_dist")
 }
 
+fn default_object_store_region() -> String {
+    "us-east-1".to_string()
+}
+
+fn default_object_store_part_size() -> u64 {
+    8 * 1024 * 1024
+}
+
+fn default_dedup_gc_interval_seconds() -> u64 {
+    3600
+}
+
 fn default_database_url() -> String {
     "sqlite://filedash.db".to_string()
 }
@@ -70,3 +627,184 @@ fn default_request_timeout_seconds() -> u64 {
         .and_then(|v| v.parse().ok())
         .unwrap_or(86400) // 24 hours default timeout for folder uploads with multiple files
 }
+
+fn default_search_max_depth() -> usize {
+    32
+}
+
+fn default_search_timeout_seconds() -> u64 {
+    30
+}
+
+fn default_search_limit() -> usize {
+    50
+}
+
+fn default_search_max_limit() -> usize {
+    1000
+}
+
+fn default_index_enabled() -> bool {
+    true
+}
+
+fn default_reconcile_interval_seconds() -> u64 {
+    3600
+}
+
+fn default_content_enabled() -> bool {
+    true
+}
+
+fn default_content_max_file_size() -> u64 {
+    10 * 1024 * 1024
+}
+
+fn default_content_workers() -> usize {
+    2
+}
+
+fn default_content_queue_size() -> usize {
+    1024
+}
+
+fn default_watcher_enabled() -> bool {
+    true
+}
+
+fn default_watcher_debounce_ms() -> u64 {
+    500
+}
+
+fn default_watcher_queue_size() -> usize {
+    4096
+}
+
+fn default_watcher_history_size() -> usize {
+    1024
+}
+
+fn default_s3_enabled() -> bool {
+    true
+}
+
+fn default_s3_staging_dir() -> PathBuf {
+    PathBuf::from("data/s3-staging")
+}
+
+fn default_s3_multipart_expiry_hours() -> u64 {
+    24
+}
+
+fn default_versioning_enabled() -> bool {
+    true
+}
+
+fn default_versioning_directory() -> PathBuf {
+    PathBuf::from("data/versions")
+}
+
+fn default_versioning_max_versions() -> usize {
+    20
+}
+
+fn default_versioning_cleanup_interval_seconds() -> u64 {
+    3600
+}
+
+fn default_quota_reconcile_interval_seconds() -> u64 {
+    3600
+}
+
+fn default_disk_usage_cache_ttl_seconds() -> u64 {
+    3600
+}
+
+fn default_thumbnails_enabled() -> bool {
+    true
+}
+
+fn default_thumbnails_directory() -> PathBuf {
+    PathBuf::from("data/thumbnails")
+}
+
+fn default_thumbnail_sizes() -> Vec<u32> {
+    vec![64, 128, 256, 512]
+}
+
+fn default_thumbnail_max_source_size() -> u64 {
+    50 * 1024 * 1024
+}
+
+fn default_thumbnail_workers() -> usize {
+    2
+}
+
+fn default_thumbnail_queue_size() -> usize {
+    256
+}
+
+fn default_thumbnail_jpeg_quality() -> u8 {
+    80
+}
+
+fn default_thumbnail_max_cache_size() -> u64 {
+    1024 * 1024 * 1024
+}
+
+fn default_thumbnail_cleanup_interval_seconds() -> u64 {
+    3600
+}
+
+fn default_preview_max_text_size() -> u64 {
+    5 * 1024 * 1024
+}
+
+fn default_archive_cache_size() -> usize {
+    32
+}
+
+fn default_archive_max_entries() -> usize {
+    100_000
+}
+
+fn default_archive_max_extracted_size() -> u64 {
+    10 * 1024 * 1024 * 1024
+}
+
+fn default_archive_max_compression_ratio() -> u64 {
+    100
+}
+
+fn default_job_workers() -> usize {
+    4
+}
+
+fn default_job_retention_days() -> u32 {
+    7
+}
+
+#[cfg(test)]
+impl Config {
+    /// Settings for tests: files in `home_directory`, any file type and up to 10 MiB, and
+    /// everything else at its default
+    pub(crate) fn for_tests(home_directory: impl Into<PathBuf>) -> Self {
+        let mut config: Self = toml::from_str(
+            r#"
+            [server]
+            host = "127.0.0.1"
+            port = 0
+            [storage]
+            home_directory = "."
+            allowed_extensions = ["*"]
+            max_upload_size = 10485760
+            [database]
+            [auth]
+            jwt_secret = "test"
+            "#,
+        )
+        .unwrap();
+        config.storage.home_directory = home_directory.into();
+        config
+    }
+}
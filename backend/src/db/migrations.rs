@@ -51,6 +51,370 @@ pub async fn run_migrations(pool: &SqlitePool) -> Result<(), sqlx::Error> {
         .execute(pool)
         .await?;
 
+    // Long-lived keys for clients that can't log in interactively, e.g. WebDAV mounts
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS api_keys (
+            id TEXT PRIMARY KEY NOT NULL,
+            user_id TEXT NOT NULL,
+            name TEXT NOT NULL,
+            key_hash TEXT UNIQUE NOT NULL,
+            prefix TEXT NOT NULL,
+            created_at TEXT NOT NULL DEFAULT (datetime('now')),
+            last_used_at TEXT,
+            FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE
+        )
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    sqlx::query("CREATE INDEX IF NOT EXISTS idx_api_keys_user_id ON api_keys(user_id)")
+        .execute(pool)
+        .await?;
+
+    // S3 access keys. SigV4 signs with the secret itself, so unlike API keys it is stored as-is.
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS s3_access_keys (
+            access_key_id TEXT PRIMARY KEY NOT NULL,
+            user_id TEXT NOT NULL,
+            name TEXT NOT NULL,
+            secret_key TEXT NOT NULL,
+            created_at TEXT NOT NULL DEFAULT (datetime('now')),
+            last_used_at TEXT,
+            FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE
+        )
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    sqlx::query("CREATE INDEX IF NOT EXISTS idx_s3_access_keys_user_id ON s3_access_keys(user_id)")
+        .execute(pool)
+        .await?;
+
+    // In-progress S3 multipart uploads; parts are staged on disk under `s3.staging_dir`
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS s3_multipart_uploads (
+            upload_id TEXT PRIMARY KEY NOT NULL,
+            bucket TEXT NOT NULL,
+            key TEXT NOT NULL,
+            created_at TEXT NOT NULL DEFAULT (datetime('now'))
+        )
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS s3_multipart_parts (
+            upload_id TEXT NOT NULL,
+            part_number INTEGER NOT NULL,
+            etag TEXT NOT NULL,
+            size INTEGER NOT NULL,
+            uploaded_at TEXT NOT NULL DEFAULT (datetime('now')),
+            PRIMARY KEY (upload_id, part_number),
+            FOREIGN KEY (upload_id) REFERENCES s3_multipart_uploads (upload_id) ON DELETE CASCADE
+        )
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    // Create file metadata index used by search
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS file_index (
+            path TEXT PRIMARY KEY NOT NULL,
+            parent TEXT NOT NULL,
+            name TEXT NOT NULL,
+            size INTEGER NOT NULL,
+            modified INTEGER NOT NULL,
+            is_directory BOOLEAN NOT NULL,
+            mime_type TEXT,
+            indexed_at TEXT NOT NULL DEFAULT (datetime('now'))
+        )
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    // Trigram FTS over names so LIKE and GLOB name queries can use the index
+    sqlx::query(
+        r#"
+        CREATE VIRTUAL TABLE IF NOT EXISTS file_index_fts USING fts5(
+            name,
+            content='file_index',
+            content_rowid='rowid',
+            tokenize='trigram'
+        )
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    sqlx::query(
+        r#"
+        CREATE TRIGGER IF NOT EXISTS file_index_ai AFTER INSERT ON file_index BEGIN
+            INSERT INTO file_index_fts(rowid, name) VALUES (new.rowid, new.name);
+        END
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    sqlx::query(
+        r#"
+        CREATE TRIGGER IF NOT EXISTS file_index_ad AFTER DELETE ON file_index BEGIN
+            INSERT INTO file_index_fts(file_index_fts, rowid, name) VALUES ('delete', old.rowid, old.name);
+        END
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    sqlx::query(
+        r#"
+        CREATE TRIGGER IF NOT EXISTS file_index_au AFTER UPDATE OF name ON file_index BEGIN
+            INSERT INTO file_index_fts(file_index_fts, rowid, name) VALUES ('delete', old.rowid, old.name);
+            INSERT INTO file_index_fts(rowid, name) VALUES (new.rowid, new.name);
+        END
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS index_meta (
+            key TEXT PRIMARY KEY NOT NULL,
+            value TEXT NOT NULL
+        )
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    // Extracted text of text-like files, one row per file that has been through extraction
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS file_content (
+            path TEXT PRIMARY KEY NOT NULL,
+            size INTEGER NOT NULL,
+            modified INTEGER NOT NULL,
+            indexed_at TEXT NOT NULL DEFAULT (datetime('now'))
+        )
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    sqlx::query(
+        r#"
+        CREATE VIRTUAL TABLE IF NOT EXISTS file_content_fts USING fts5(
+            body,
+            tokenize='unicode61 remove_diacritics 2'
+        )
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    sqlx::query(
+        r#"
+        CREATE TRIGGER IF NOT EXISTS file_content_ad AFTER DELETE ON file_content BEGIN
+            DELETE FROM file_content_fts WHERE rowid = old.rowid;
+        END
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    // Content goes away together with the index entry it belongs to
+    sqlx::query(
+        r#"
+        CREATE TRIGGER IF NOT EXISTS file_index_content_ad AFTER DELETE ON file_index BEGIN
+            DELETE FROM file_content WHERE path = old.path;
+        END
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    sqlx::query("CREATE INDEX IF NOT EXISTS idx_file_index_parent ON file_index(parent)")
+        .execute(pool)
+        .await?;
+
+    sqlx::query("CREATE INDEX IF NOT EXISTS idx_file_index_modified ON file_index(modified)")
+        .execute(pool)
+        .await?;
+
+    // Dead properties set through WebDAV PROPPATCH
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS dav_properties (
+            path TEXT NOT NULL,
+            namespace TEXT NOT NULL,
+            name TEXT NOT NULL,
+            value TEXT NOT NULL,
+            PRIMARY KEY (path, namespace, name)
+        )
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    // Deduplicated storage: each root's tree, with files referring to contents by SHA-256
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS dedup_entries (
+            root TEXT NOT NULL,
+            path TEXT NOT NULL,
+            parent TEXT NOT NULL,
+            is_directory BOOLEAN NOT NULL,
+            size INTEGER NOT NULL DEFAULT 0,
+            modified INTEGER NOT NULL,
+            hash TEXT,
+            PRIMARY KEY (root, path)
+        )
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    sqlx::query("CREATE INDEX IF NOT EXISTS idx_dedup_entries_parent ON dedup_entries(root, parent)")
+        .execute(pool)
+        .await?;
+
+    sqlx::query("CREATE INDEX IF NOT EXISTS idx_dedup_entries_hash ON dedup_entries(root, hash)")
+        .execute(pool)
+        .await?;
+
+    // Stored contents and how many paths refer to them; unreferenced ones await collection
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS dedup_blobs (
+            root TEXT NOT NULL,
+            hash TEXT NOT NULL,
+            size INTEGER NOT NULL,
+            refcount INTEGER NOT NULL,
+            created INTEGER NOT NULL,
+            PRIMARY KEY (root, hash)
+        )
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    // Earlier contents of overwritten files, kept in the versions store under their id
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS file_versions (
+            id TEXT PRIMARY KEY,
+            path TEXT NOT NULL,
+            size INTEGER NOT NULL,
+            sha256 TEXT NOT NULL,
+            modified INTEGER NOT NULL,
+            replaced_at INTEGER NOT NULL,
+            replaced_by TEXT NOT NULL
+        )
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    sqlx::query("CREATE INDEX IF NOT EXISTS idx_file_versions_path ON file_versions(path, replaced_at)")
+        .execute(pool)
+        .await?;
+
+    sqlx::query("CREATE INDEX IF NOT EXISTS idx_file_versions_replaced_at ON file_versions(replaced_at)")
+        .execute(pool)
+        .await?;
+
+    // Who owns each file and how large it is, for quotas; `owner` is empty for files
+    // written outside FileDash
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS quota_files (
+            path TEXT PRIMARY KEY,
+            owner TEXT NOT NULL,
+            size INTEGER NOT NULL,
+            updated INTEGER NOT NULL
+        )
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    sqlx::query("CREATE INDEX IF NOT EXISTS idx_quota_files_owner ON quota_files(owner)")
+        .execute(pool)
+        .await?;
+
+    // Running totals of quota_files per user and per directory with a quota
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS quota_usage (
+            scope TEXT NOT NULL,
+            name TEXT NOT NULL,
+            bytes INTEGER NOT NULL,
+            files INTEGER NOT NULL,
+            PRIMARY KEY (scope, name)
+        )
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    // Checksums computed on demand, valid while the file keeps the same size and mtime
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS file_checksums (
+            path TEXT NOT NULL,
+            algorithm TEXT NOT NULL,
+            size INTEGER NOT NULL,
+            modified INTEGER NOT NULL,
+            value TEXT NOT NULL,
+            PRIMARY KEY (path, algorithm)
+        )
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    // Background jobs. `params` is what the job was started with, so that it can be retried.
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS jobs (
+            id TEXT PRIMARY KEY,
+            kind TEXT NOT NULL,
+            owner TEXT NOT NULL,
+            params TEXT NOT NULL,
+            status TEXT NOT NULL,
+            done INTEGER NOT NULL DEFAULT 0,
+            total INTEGER,
+            result TEXT,
+            error TEXT,
+            retry_of TEXT,
+            created_at INTEGER NOT NULL,
+            started_at INTEGER,
+            finished_at INTEGER
+        )
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    sqlx::query("CREATE INDEX IF NOT EXISTS idx_jobs_owner ON jobs(owner, created_at)")
+        .execute(pool)
+        .await?;
+
+    sqlx::query("CREATE INDEX IF NOT EXISTS idx_jobs_status ON jobs(status)")
+        .execute(pool)
+        .await?;
+
     // Create default admin user if none exists
     create_default_admin_user(pool).await?;
 
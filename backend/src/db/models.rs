@@ -73,7 +73,7 @@ pub struct LoginResponse {
     pub expires_at: DateTime<Utc>,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Serialize)]
 pub struct UserInfo {
     pub id: Uuid,
     pub email: String,
@@ -93,3 +93,50 @@ impl From<User> for UserInfo {
         }
     }
 }
+
+#[derive(Debug, Deserialize)]
+pub struct CreateApiKeyRequest {
+    pub name: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ApiKeyInfo {
+    pub id: Uuid,
+    pub name: String,
+    /// The first characters of the key, enough to recognise it
+    pub prefix: String,
+    pub created_at: DateTime<Utc>,
+    pub last_used_at: Option<DateTime<Utc>>,
+}
+
+/// A newly created API key; the only time the full key is returned
+#[derive(Debug, Serialize)]
+pub struct CreatedApiKey {
+    pub id: Uuid,
+    pub name: String,
+    pub key: String,
+    pub prefix: String,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateAccessKeyRequest {
+    pub name: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AccessKeyInfo {
+    pub access_key_id: String,
+    pub name: String,
+    pub created_at: DateTime<Utc>,
+    pub last_used_at: Option<DateTime<Utc>>,
+}
+
+/// A newly created S3 access key; the only time the secret is returned
+#[derive(Debug, Serialize)]
+pub struct CreatedAccessKey {
+    pub access_key_id: String,
+    pub secret_access_key: String,
+    pub name: String,
+    pub created_at: DateTime<Utc>,
+}
@@ -26,6 +26,21 @@ pub enum ApiError {
     #[error("Invalid file type: {file_type}")]
     InvalidFileType { file_type: String },
     
+    #[error("Quota exceeded for {quota}: {} remaining", describe_remaining(.remaining_bytes, .remaining_files))]
+    QuotaExceeded {
+        quota: String,
+        /// `None` when the quota doesn't limit this
+        remaining_bytes: Option<u64>,
+        remaining_files: Option<u64>,
+    },
+    
+    #[error("Checksum mismatch: expected {algorithm} {expected}, got {actual}")]
+    ChecksumMismatch {
+        algorithm: String,
+        expected: String,
+        actual: String,
+    },
+    
     #[error("IO error: {0}")]
     IoError(#[from] std::io::Error),
     
@@ -51,10 +66,22 @@ pub enum ApiError {
     #[error("Internal server error: {message}")]
     InternalServerError { message: String },
     
+    #[error("Service unavailable: {message}")]
+    ServiceUnavailable { message: String },
+    
     #[error("Database error: {0}")]
     Database(#[from] sqlx::Error),
 }
 
+fn describe_remaining(bytes: &Option<u64>, files: &Option<u64>) -> String {
+    match (bytes, files) {
+        (Some(bytes), Some(files)) => format!("{} bytes and {} files", bytes, files),
+        (Some(bytes), None) => format!("{} bytes", bytes),
+        (None, Some(files)) => format!("{} files", files),
+        (None, None) => "nothing".to_string(),
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 struct ErrorResponse {
     error: String,
@@ -102,6 +129,26 @@ impl IntoResponse for ApiError {
                 self.to_string(),
                 Some(serde_json::json!({ "file_type": file_type })),
             ),
+            ApiError::QuotaExceeded { quota, remaining_bytes, remaining_files } => (
+                StatusCode::INSUFFICIENT_STORAGE,
+                "quota_exceeded",
+                self.to_string(),
+                Some(serde_json::json!({
+                    "quota": quota,
+                    "remaining_bytes": remaining_bytes,
+                    "remaining_files": remaining_files,
+                })),
+            ),
+            ApiError::ChecksumMismatch { algorithm, expected, actual } => (
+                StatusCode::BAD_REQUEST,
+                "checksum_mismatch",
+                self.to_string(),
+                Some(serde_json::json!({
+                    "algorithm": algorithm,
+                    "expected": expected,
+                    "actual": actual,
+                })),
+            ),
             ApiError::IoError(_) => (
                 StatusCode::INTERNAL_SERVER_ERROR,
                 "io_error",
@@ -150,6 +197,12 @@ impl IntoResponse for ApiError {
                 message.clone(),
                 None,
             ),
+            ApiError::ServiceUnavailable { message } => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                "service_unavailable",
+                message.clone(),
+                None,
+            ),
             ApiError::Database(_) => (
                 StatusCode::INTERNAL_SERVER_ERROR,
                 "database_error",
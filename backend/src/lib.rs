@@ -20,17 +20,45 @@ pub mod db;
 pub mod errors;
 pub mod middleware;
 pub mod services;
+pub mod storage;
 pub mod utils;
 
 use config::Config;
 use db::Database;
-use services::AuthService;
+use services::{
+    ArchiveBrowser, AuthService, ChangeFeed, ChecksumService, JobService, DiskUsageService, FileService, IndexService, QuotaService, S3Service, ThumbnailService, VersionService, WebDavService,
+};
+use storage::StorageBackend;
 
 #[derive(Clone)]
 pub struct AppState {
     pub config: Arc<Config>,
     pub db: Database,
+    pub storage: Arc<dyn StorageBackend>,
     pub auth_service: Arc<AuthService>,
+    pub index_service: Arc<IndexService>,
+    pub change_feed: Arc<ChangeFeed>,
+    pub webdav_service: Arc<WebDavService>,
+    pub s3_service: Arc<S3Service>,
+    pub version_service: Arc<VersionService>,
+    pub quota_service: Arc<QuotaService>,
+    pub disk_usage_service: Arc<DiskUsageService>,
+    pub thumbnail_service: Arc<ThumbnailService>,
+    pub archive_browser: Arc<ArchiveBrowser>,
+    pub job_service: Arc<JobService>,
+    pub checksum_service: Arc<ChecksumService>,
+}
+
+impl AppState {
+    /// A file service making changes for `user`: it keeps versions of what it overwrites and
+    /// holds writes to the quotas
+    pub fn file_service(&self, user: &str) -> FileService {
+        FileService::new(self.config.as_ref().clone(), self.storage.clone())
+            .as_user(user)
+            .with_versions(self.version_service.clone())
+            .with_quotas(self.quota_service.clone())
+            .with_checksums(self.checksum_service.clone())
+    }
 }
 
 pub async fn create_app(config: Arc<Config>) -> Result<Router, Box<dyn std::error::Error>> {
@@ -38,6 +66,16 @@ pub async fn create_app(config: Arc<Config>) -> Result<Router, Box<dyn std::erro
     let database_url = config.database.url.clone();
     let db = Database::new(&database_url).await?;
     
+    // Every file operation goes through the storage backend
+    let storage = storage::from_config(&config.storage, &db)?;
+    if config.storage.encryption_in_use() {
+        // Files stored unencrypted or under an earlier key are rewritten under the current one
+        storage::encrypted::start_reencryption(storage.clone());
+    }
+    if config.storage.dedup_in_use() {
+        storage::dedup::start_garbage_collection(storage.clone(), config.storage.dedup.gc_interval_seconds);
+    }
+    
     // Initialize auth service
     let auth_service = Arc::new(AuthService::new(
         db.clone(),
@@ -45,24 +83,130 @@ pub async fn create_app(config: Arc<Config>) -> Result<Router, Box<dyn std::erro
         Some(config.auth.token_expiration_hours),
     ));
     
+    // Watch the storage directory so changes made by other tools are picked up
+    let change_feed = Arc::new(ChangeFeed::new(config.clone()));
+    
+    // Initialize search index and keep it reconciled in the background
+    let index_service = Arc::new(IndexService::new(db.clone(), config.clone(), storage.clone()));
+    index_service.start_background_tasks(change_feed.subscribe());
+
+    // WebDAV locks and properties; properties of entries deleted elsewhere are dropped
+    let webdav_service = Arc::new(WebDavService::new(db.clone()));
+    webdav_service.start_background_tasks(change_feed.subscribe());
+
+    // Recursive directory sizes, dropped as the directories below them change
+    let disk_usage_service = Arc::new(DiskUsageService::new(config.clone(), storage.clone()));
+    disk_usage_service.start_background_tasks(change_feed.subscribe());
+
+    // Checksums computed on demand; those of deleted and moved files are dropped
+    let checksum_service = Arc::new(ChecksumService::new(db.clone(), storage.clone()));
+    checksum_service.start_background_tasks(change_feed.subscribe());
+    change_feed.start();
+
+    // Staged uploads for the S3-compatible API; abandoned multipart uploads expire
+    let s3_service = Arc::new(S3Service::new(db.clone(), config.clone()));
+    if config.s3.enabled {
+        s3_service.start_background_tasks();
+    }
+
+    // Earlier contents of overwritten files; those past the retention policy are deleted
+    let version_service = Arc::new(VersionService::new(db.clone(), config.clone(), storage.clone())?);
+    version_service.start_background_tasks();
+    if config.versioning.enabled && config.storage.encryption_in_use() {
+        storage::encrypted::start_reencryption(version_service.store());
+    }
+
+    // Storage quotas; usage is reconciled with what storage holds on startup and periodically
+    let quota_service = Arc::new(QuotaService::new(db.clone(), &config, storage.clone())?);
+    quota_service.start_background_tasks();
+
+    // Image thumbnails, cached on disk and trimmed to the configured size
+    let thumbnail_service = Arc::new(ThumbnailService::new(config.clone(), storage.clone())?);
+    thumbnail_service.start_background_tasks();
+    if config.thumbnails.enabled && config.storage.encryption_in_use() {
+        storage::encrypted::start_reencryption(thumbnail_service.store());
+    }
+
+    // Listings of archives browsed as directories, kept until the archive changes
+    let archive_browser = Arc::new(ArchiveBrowser::new(config.clone(), storage.clone()));
+
+    // Long operations such as archive extraction, run outside the request that starts them;
+    // jobs cut short by a restart are failed, and finished ones kept for a while
+    let job_service = Arc::new(JobService::new(db.clone(), config.clone()));
+    let interrupted = job_service.recover().await?;
+    if interrupted > 0 {
+        tracing::warn!("{} jobs were interrupted by a restart and have been marked failed", interrupted);
+    }
+    job_service.start_background_tasks();
+    
     // Create shared state
     let state = AppState {
         config: config.clone(),
         db: db.clone(),
+        storage: storage.clone(),
         auth_service: auth_service.clone(),
+        index_service: index_service.clone(),
+        change_feed: change_feed.clone(),
+        webdav_service: webdav_service.clone(),
+        s3_service: s3_service.clone(),
+        version_service: version_service.clone(),
+        quota_service: quota_service.clone(),
+        disk_usage_service: disk_usage_service.clone(),
+        thumbnail_service: thumbnail_service.clone(),
+        archive_browser: archive_browser.clone(),
+        job_service: job_service.clone(),
+        checksum_service: checksum_service.clone(),
     };
+
+    // Each kind of job is run by the API that starts it
+    api::register_files_jobs(&state);
+    api::register_search_jobs(&state);
     
     // Build protected API routes (require authentication)
     let protected_files_routes = Router::new()
         .nest("/files", api::files_routes())
         .with_state(state.clone());
         
+    let protected_search_routes = Router::new()
+        .nest("/search", api::search_routes())
+        .with_state(state.clone());
+        
+    // The change feed also takes a ticket in the URL, for browsers' EventSource
+    let events_routes = Router::new()
+        .nest("/events", api::events_routes())
+        .route_layer(from_fn_with_state(
+            auth_service.clone(),
+            middleware::auth::event_stream_middleware,
+        ))
+        .with_state(state.clone());
+        
+    let protected_storage_routes = Router::new()
+        .nest("/storage", api::storage_routes())
+        .with_state(state.clone());
+        
+    let protected_versions_routes = Router::new()
+        .nest("/versions", api::versions_routes())
+        .with_state(state.clone());
+        
+    let protected_quota_routes = Router::new()
+        .nest("/quota", api::quota_routes())
+        .with_state(state.clone());
+        
+    let protected_jobs_routes = Router::new()
+        .nest("/jobs", api::jobs_routes())
+        .with_state(state.clone());
+        
     let protected_auth_routes = Router::new()
         .nest("/auth", api::auth_protected_routes())
         .with_state(auth_service.clone());
         
     let protected_routes = Router::new()
         .merge(protected_files_routes)
+        .merge(protected_search_routes)
+        .merge(protected_storage_routes)
+        .merge(protected_versions_routes)
+        .merge(protected_quota_routes)
+        .merge(protected_jobs_routes)
         .merge(protected_auth_routes)
         .route_layer(from_fn_with_state(
             auth_service.clone(),
@@ -77,8 +221,24 @@ pub async fn create_app(config: Arc<Config>) -> Result<Router, Box<dyn std::erro
     // Build API routes
     let api_routes = Router::new()
         .merge(auth_routes)
+        .merge(events_routes)
         .merge(protected_routes);
     
+    // WebDAV accepts Basic credentials and API keys so desktop clients can mount it
+    let webdav_routes = api::webdav_routes()
+        .route_layer(from_fn_with_state(
+            auth_service.clone(),
+            middleware::auth::basic_or_key_middleware,
+        ))
+        .with_state(state.clone());
+    
+    // The S3 API authenticates each request by its SigV4 signature
+    let s3_routes = if config.s3.enabled {
+        api::s3_routes().with_state(state.clone())
+    } else {
+        Router::new()
+    };
+    
     // Build main application
     let frontend_dir = Path::new(&config.storage.frontend_dist_path);
     let index_file = frontend_dir.join("index.html");
@@ -90,10 +250,13 @@ pub async fn create_app(config: Arc<Config>) -> Result<Router, Box<dyn std::erro
         .nest_service("/", ServeDir::new(&config.storage.frontend_dist_path)
             .not_found_service(ServeFile::new(&index_file)))
         .with_state(state)
+        .merge(s3_routes)
+        .layer(CorsLayer::permissive())
+        // Added after CORS: it would answer the plain OPTIONS requests WebDAV clients send
+        .merge(webdav_routes)
         .layer(
             ServiceBuilder::new()
                 .layer(TraceLayer::new_for_http())
-                .layer(CorsLayer::permissive())
                 .layer(TimeoutLayer::new(Duration::from_secs(config.server.request_timeout_seconds))) // Configurable timeout
         );
     
@@ -103,3 +266,30 @@ pub async fn create_app(config: Arc<Config>) -> Result<Router, Box<dyn std::erro
 async fn health_check() -> &'static str {
     "OK"
 }
+
+/// The whole app storing files in `dir/files`, and a token for the default admin
+#[cfg(test)]
+pub(crate) async fn test_app(dir: &Path) -> (Router, String) {
+    use tower::ServiceExt;
+
+    let mut config = Config::for_tests(dir.join("files"));
+    std::fs::create_dir_all(&config.storage.home_directory).unwrap();
+    config.database.url = format!("sqlite:{}", dir.join("test.db").display());
+    config.watcher.enabled = false;
+    config.s3.staging_dir = dir.join("s3-staging");
+    config.versioning.directory = dir.join("versions");
+    config.thumbnails.directory = dir.join("thumbnails");
+    let app = create_app(Arc::new(config)).await.unwrap();
+
+    let login = axum::http::Request::post("/api/auth/login")
+        .header("content-type", "application/json")
+        .body(axum::body::Body::from(r#"{"email":"admin@filedash.local","password":"admin123"}"#))
+        .unwrap();
+    let response = app.clone().oneshot(login).await.unwrap();
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let token = serde_json::from_slice::<serde_json::Value>(&body).unwrap()["token"]
+        .as_str()
+        .unwrap()
+        .to_string();
+    (app, token)
+}
@@ -20,12 +20,22 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let port = config.server.port;
     let host = config.server.host.clone();
     
-    // Create files directory if it doesn't exist
-    let files_dir = &config.storage.home_directory;
-    if !files_dir.exists() {
-        std::fs::create_dir_all(files_dir)
-            .expect("Failed to create file storage directory");
-        tracing::info!("Created storage directory: {:?}", files_dir);
+    // Create the files directories if they don't exist
+    for root in config.storage.local_roots() {
+        let files_dir = &root.directory;
+        if !files_dir.exists() {
+            std::fs::create_dir_all(files_dir)
+                .expect("Failed to create file storage directory");
+            tracing::info!("Created storage directory: {:?}", files_dir);
+        }
+    }
+    if config.versioning.enabled && !config.versioning.directory.exists() {
+        std::fs::create_dir_all(&config.versioning.directory)
+            .expect("Failed to create versions directory");
+    }
+    if config.thumbnails.enabled && !config.thumbnails.directory.exists() {
+        std::fs::create_dir_all(&config.thumbnails.directory)
+            .expect("Failed to create thumbnails directory");
     }
     
     // Build application with routes (async now due to database initialization)
@@ -5,11 +5,12 @@ use crate::{
 };
 use axum::{
     body::Body,
-    extract::State,
-    http::{Request, header::AUTHORIZATION},
+    extract::{Query, State},
+    http::{Method, Request, header::{AUTHORIZATION, WWW_AUTHENTICATE}, HeaderValue},
     middleware::Next,
-    response::Response,
+    response::{IntoResponse, Response},
 };
+use base64::Engine;
 use std::sync::Arc;
 use uuid::Uuid;
 
@@ -108,6 +109,31 @@ pub async fn admin_middleware(
     Ok(next.run(request).await)
 }
 
+/// Middleware for the change feed. Browsers open it with `EventSource`, which can't send an
+/// `Authorization` header, so reading it also takes a ticket from `POST /api/events/ticket`
+/// in the `ticket` query parameter. Otherwise it's the same as `admin_middleware`.
+pub async fn event_stream_middleware(
+    State(auth_service): State<Arc<AuthService>>,
+    mut request: Request<Body>,
+    next: Next<Body>,
+) -> Result<Response, ApiError> {
+    let ticket = match request.method() {
+        &Method::GET => Query::<Vec<(String, String)>>::try_from_uri(request.uri())
+            .ok()
+            .and_then(|Query(query)| query.into_iter().find(|(key, _)| key == "ticket"))
+            .map(|(_, ticket)| ticket),
+        _ => None,
+    };
+    if let Some(ticket) = ticket {
+        let token = auth_service.redeem_event_ticket(&ticket)?;
+        let header = HeaderValue::from_str(&format!("Bearer {}", token)).map_err(|_| ApiError::Unauthorized {
+            message: "Invalid or expired ticket".to_string(),
+        })?;
+        request.headers_mut().insert(AUTHORIZATION, header);
+    }
+    admin_middleware(State(auth_service), request, next).await
+}
+
 /// Optional auth middleware - doesn't fail if no token provided
 pub async fn optional_auth_middleware(
     State(auth_service): State<Arc<AuthService>>,
@@ -134,6 +160,119 @@ pub async fn optional_auth_middleware(
     next.run(request).await
 }
 
+/// Middleware for clients that can't log in interactively, such as WebDAV mounts.
+///
+/// Accepts HTTP Basic with an email and either the password or an API key, or a Bearer
+/// JWT or API key. Like the rest of the file API, it requires an admin account.
+pub async fn basic_or_key_middleware(
+    State(auth_service): State<Arc<AuthService>>,
+    mut request: Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    let auth_context = match authenticate_basic_or_key(&auth_service, &request).await {
+        Ok(auth_context) => auth_context,
+        Err(e @ ApiError::Unauthorized { .. }) => {
+            // Ask clients like file managers to prompt for credentials
+            let mut response = e.into_response();
+            response.headers_mut().insert(
+                WWW_AUTHENTICATE,
+                HeaderValue::from_static("Basic realm=\"FileDash\", charset=\"UTF-8\""),
+            );
+            return response;
+        }
+        Err(e) => return e.into_response(),
+    };
+
+    if !auth_context.is_admin() {
+        return ApiError::Forbidden {
+            message: "Admin access required".to_string(),
+        }
+        .into_response();
+    }
+
+    request.extensions_mut().insert(auth_context);
+    next.run(request).await
+}
+
+async fn authenticate_basic_or_key(
+    auth_service: &AuthService,
+    request: &Request<Body>,
+) -> Result<AuthContext, ApiError> {
+    let auth_header = request
+        .headers()
+        .get(AUTHORIZATION)
+        .ok_or_else(|| ApiError::Unauthorized {
+            message: "Missing Authorization header".to_string(),
+        })?
+        .to_str()
+        .map_err(|_| ApiError::Unauthorized {
+            message: "Invalid Authorization header format".to_string(),
+        })?;
+
+    if let Some(encoded) = auth_header.strip_prefix("Basic ") {
+        let decoded = base64::engine::general_purpose::STANDARD
+            .decode(encoded.trim())
+            .ok()
+            .and_then(|bytes| String::from_utf8(bytes).ok())
+            .ok_or_else(|| ApiError::Unauthorized {
+                message: "Invalid Basic credentials".to_string(),
+            })?;
+        let (email, secret) = decoded.split_once(':').ok_or_else(|| ApiError::Unauthorized {
+            message: "Invalid Basic credentials".to_string(),
+        })?;
+
+        let user = if auth_service.is_api_key(secret) {
+            // The username has to name the key's owner, so a leaked key alone can't be
+            // presented as anyone's
+            let user = auth_service.authenticate_api_key(secret).await?;
+            if !user.email.eq_ignore_ascii_case(email) {
+                return Err(ApiError::Unauthorized {
+                    message: "Invalid Basic credentials".to_string(),
+                });
+            }
+            user
+        } else {
+            auth_service.verify_credentials_cached(email, secret).await?
+        };
+        return Ok(AuthContext {
+            user_id: user.id,
+            email: user.email,
+            role: user.role,
+            token: String::new(),
+        });
+    }
+
+    if let Some(token) = auth_header.strip_prefix("Bearer ") {
+        if auth_service.is_api_key(token) {
+            let user = auth_service.authenticate_api_key(token).await?;
+            return Ok(AuthContext {
+                user_id: user.id,
+                email: user.email,
+                role: user.role,
+                token: String::new(),
+            });
+        }
+
+        let claims = auth_service.validate_token(token).await?;
+        let user_id = Uuid::parse_str(&claims.sub).map_err(|_| ApiError::Unauthorized {
+            message: "Invalid user ID in token".to_string(),
+        })?;
+        let role: UserRole = claims.role.parse().map_err(|_| ApiError::Unauthorized {
+            message: "Invalid role in token".to_string(),
+        })?;
+        return Ok(AuthContext {
+            user_id,
+            email: claims.email,
+            role,
+            token: token.to_string(),
+        });
+    }
+
+    Err(ApiError::Unauthorized {
+        message: "Authorization must be Basic or Bearer".to_string(),
+    })
+}
+
 fn extract_token_from_header(request: &Request<Body>) -> Result<String, ApiError> {
     let auth_header = request
         .headers()
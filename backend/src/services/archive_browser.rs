@@ -0,0 +1,558 @@
+use crate::{
+    config::Config,
+    errors::ApiError,
+    services::{decompress, index_service, ArchiveFormat, ChannelWriter, FileInfo},
+    storage::{StorageBackend, StorageReader},
+};
+use chrono::{DateTime, TimeZone, Utc};
+use std::{
+    collections::BTreeMap,
+    io::{self, BufReader, Read, Seek, SeekFrom},
+    ops::Range,
+    sync::{Arc, Mutex},
+};
+use tokio::{runtime::Handle, sync::mpsc};
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_util::io::{StreamReader, SyncIoBridge};
+use zip::ZipArchive;
+
+/// Separates the path of an archive from the path of an entry inside it
+pub const ARCHIVE_SEPARATOR: &str = "!/";
+
+/// Split `bundle.zip!/inner/path` into the archive and the path inside it. `bundle.zip!`
+/// is the top of the archive. Paths not ending in an archive's extension aren't split.
+pub fn split_archive_path(path: &str) -> Option<(&str, &str)> {
+    let (archive, inner) = match path.split_once(ARCHIVE_SEPARATOR) {
+        Some(parts) => parts,
+        None => (path.strip_suffix('!')?, ""),
+    };
+    ArchiveFormat::from_name(archive)?;
+    Some((archive, inner))
+}
+
+/// Where the contents of an entry are found
+#[derive(Debug, Clone, Copy)]
+enum Location {
+    Directory,
+    /// Index in the central directory
+    Zip(usize),
+    /// Offset of the data in an uncompressed tar
+    Tar(u64),
+    /// Only found by decompressing from the start
+    Compressed,
+}
+
+#[derive(Debug, Clone)]
+struct ArchiveEntry {
+    size: u64,
+    modified: DateTime<Utc>,
+    location: Location,
+}
+
+/// The entries of one archive, by normalized path, as of `modified`
+struct Listing {
+    modified: DateTime<Utc>,
+    size: u64,
+    format: ArchiveFormat,
+    entries: BTreeMap<String, ArchiveEntry>,
+    /// Central directory of a zip, cloned for every read of its members
+    zip: Option<Mutex<ZipArchive<StorageFile>>>,
+}
+
+/// Lists and reads the contents of zip and tar archives without extracting them. The
+/// listing of recently browsed archives is kept until they change.
+pub struct ArchiveBrowser {
+    config: Arc<Config>,
+    storage: Arc<dyn StorageBackend>,
+    /// Most recently used last
+    cache: Mutex<Vec<(String, Arc<Listing>)>>,
+}
+
+impl ArchiveBrowser {
+    pub fn new(config: Arc<Config>, storage: Arc<dyn StorageBackend>) -> Self {
+        Self {
+            config,
+            storage,
+            cache: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Information about an entry, or the top of the archive, as a file or directory
+    pub async fn stat(&self, path: &str) -> Result<FileInfo, ApiError> {
+        let (archive, inner) = split(path)?;
+        let listing = self.listing(archive).await?;
+        let inner = index_service::normalize(inner)?;
+        let entry = find(&listing, path, &inner)?;
+        Ok(entry_info(&display_path(archive, &inner), &inner, entry))
+    }
+
+    /// The entries directly inside a directory of an archive, directories first. A file is
+    /// listed on its own.
+    pub async fn list(&self, path: &str) -> Result<Vec<FileInfo>, ApiError> {
+        let (archive, inner) = split(path)?;
+        let listing = self.listing(archive).await?;
+        let inner = index_service::normalize(inner)?;
+        let entry = find(&listing, path, &inner)?;
+        if !matches!(entry.location, Location::Directory) {
+            return Ok(vec![entry_info(&display_path(archive, &inner), &inner, entry)]);
+        }
+
+        let below: Box<dyn Iterator<Item = (&String, &ArchiveEntry)>> = match inner.is_empty() {
+            true => Box::new(listing.entries.iter()),
+            false => {
+                let (start, end) = index_service::subtree_bounds(&inner);
+                Box::new(listing.entries.range(start..end))
+            }
+        };
+        let mut files: Vec<FileInfo> = below
+            .filter(|(key, _)| !key.is_empty() && index_service::parent_of(key) == inner)
+            .map(|(key, entry)| entry_info(&display_path(archive, key), key, entry))
+            .collect();
+        files.sort_by(|a, b| b.is_directory.cmp(&a.is_directory).then_with(|| a.name.cmp(&b.name)));
+        Ok(files)
+    }
+
+    /// Read a file inside an archive, or only the bytes of it in `range`
+    pub async fn open(&self, path: &str, range: Option<Range<u64>>) -> Result<(StorageReader, FileInfo), ApiError> {
+        let (archive, inner) = split(path)?;
+        let listing = self.listing(archive).await?;
+        let inner = index_service::normalize(inner)?;
+        let entry = find(&listing, path, &inner)?.clone();
+        let info = entry_info(&display_path(archive, &inner), &inner, &entry);
+        let range = range.unwrap_or(0..entry.size);
+        let (skip, length) = (range.start, range.end.saturating_sub(range.start));
+
+        let reader: StorageReader = match entry.location {
+            Location::Directory => {
+                return Err(ApiError::BadRequest {
+                    message: "Cannot download a directory".to_string(),
+                })
+            }
+            Location::Tar(offset) => {
+                let key = index_service::normalize(archive)?;
+                self.storage.open_read(&key, Some(offset + skip..offset + skip + length)).await?
+            }
+            Location::Zip(index) => {
+                let zip = listing.zip.as_ref().expect("zip listing without its central directory");
+                let mut zip = zip.lock().unwrap().clone();
+                spawn_reader(move |out| {
+                    let mut member = zip.by_index(index).map_err(io::Error::other)?;
+                    io::copy(&mut (&mut member).take(skip), &mut io::sink())?;
+                    io::copy(&mut member.take(length), out).map(|_| ())
+                })
+            }
+            Location::Compressed => {
+                let source = self.reader(archive).await?;
+                let format = listing.format;
+                spawn_reader(move |out| {
+                    let mut tar = tar::Archive::new(decompress(format, source)?);
+                    for member in tar.entries()? {
+                        let mut member = member?;
+                        if member_path(&member.path_bytes()).as_deref() == Some(inner.as_str()) {
+                            io::copy(&mut (&mut member).take(skip), &mut io::sink())?;
+                            return io::copy(&mut member.take(length), out).map(|_| ());
+                        }
+                    }
+                    Err(io::Error::new(io::ErrorKind::NotFound, "archive changed while being read"))
+                })
+            }
+        };
+        Ok((reader, info))
+    }
+
+    /// The listing of the archive at `archive`, read again if it changed since it was cached
+    async fn listing(&self, archive: &str) -> Result<Arc<Listing>, ApiError> {
+        let key = index_service::normalize(archive)?;
+        let info = self.storage.stat(&key).await?;
+        let format = match ArchiveFormat::from_name(&key) {
+            Some(format) if !info.is_directory => format,
+            _ => {
+                return Err(ApiError::BadRequest {
+                    message: format!("{} is not an archive", archive),
+                })
+            }
+        };
+
+        {
+            let mut cache = self.cache.lock().unwrap();
+            if let Some(i) = cache.iter().position(|(cached, _)| *cached == key) {
+                let (_, listing) = cache.remove(i);
+                if listing.modified == info.modified && listing.size == info.size {
+                    cache.push((key, listing.clone()));
+                    return Ok(listing);
+                }
+            }
+        }
+
+        let listing = Arc::new(self.read_listing(&key, &info, format).await?);
+        let mut cache = self.cache.lock().unwrap();
+        cache.retain(|(cached, _)| *cached != key);
+        cache.push((key, listing.clone()));
+        let excess = cache.len().saturating_sub(self.config.archives.cache_size);
+        cache.drain(..excess);
+        Ok(listing)
+    }
+
+    async fn read_listing(&self, key: &str, info: &FileInfo, format: ArchiveFormat) -> Result<Listing, ApiError> {
+        let max_entries = self.config.archives.max_entries;
+        let too_many = || ApiError::BadRequest {
+            message: format!("{} has more than {} entries to browse", key, max_entries),
+        };
+        let unreadable = |e: io::Error| ApiError::BadRequest {
+            message: format!("{} could not be read as an archive: {}", key, e),
+        };
+        // Entries without a time of their own get the archive's
+        let archive_modified = info.modified;
+        let mut listing = Listing {
+            modified: info.modified,
+            size: info.size,
+            format,
+            entries: BTreeMap::new(),
+            zip: None,
+        };
+
+        match format {
+            ArchiveFormat::Zip => {
+                let file = StorageFile::new(self.storage.clone(), key.to_string(), info.size);
+                let (zip, entries) = tokio::task::spawn_blocking(move || -> io::Result<_> {
+                    let mut zip = ZipArchive::new(file).map_err(invalid_archive)?;
+                    if zip.len() > max_entries {
+                        return Ok((zip, None));
+                    }
+                    let mut entries = Vec::with_capacity(zip.len());
+                    for index in 0..zip.len() {
+                        let member = zip.by_index_raw(index).map_err(invalid_archive)?;
+                        let Some(path) = member_path(member.name_raw()) else {
+                            continue;
+                        };
+                        let modified = member
+                            .last_modified()
+                            .and_then(|time| {
+                                Utc.with_ymd_and_hms(
+                                    time.year().into(),
+                                    time.month().into(),
+                                    time.day().into(),
+                                    time.hour().into(),
+                                    time.minute().into(),
+                                    time.second().into(),
+                                )
+                                .single()
+                            })
+                            .unwrap_or(archive_modified);
+                        let location = match member.is_dir() {
+                            true => Location::Directory,
+                            false => Location::Zip(index),
+                        };
+                        entries.push((path, ArchiveEntry { size: member.size(), modified, location }));
+                    }
+                    Ok((zip, Some(entries)))
+                })
+                .await
+                .map_err(|e| ApiError::Internal(e.into()))?
+                .map_err(unreadable)?;
+
+                let entries = entries.ok_or_else(too_many)?;
+                listing.entries.extend(entries);
+                listing.zip = Some(Mutex::new(zip));
+            }
+            ArchiveFormat::Tar | ArchiveFormat::TarGz | ArchiveFormat::TarZst => {
+                let source = self.reader(key).await?;
+                let entries = tokio::task::spawn_blocking(move || -> io::Result<_> {
+                    let mut tar = tar::Archive::new(decompress(format, source)?);
+                    let mut entries = Vec::new();
+                    for member in tar.entries()? {
+                        let member = member?;
+                        let Some(path) = member_path(&member.path_bytes()) else {
+                            continue;
+                        };
+                        let location = match member.header().entry_type() {
+                            tar::EntryType::Directory => Location::Directory,
+                            kind if !kind.is_file() => continue,
+                            _ if format == ArchiveFormat::Tar => Location::Tar(member.raw_file_position()),
+                            _ => Location::Compressed,
+                        };
+                        let modified = member
+                            .header()
+                            .mtime()
+                            .ok()
+                            .and_then(|seconds| Utc.timestamp_opt(seconds as i64, 0).single())
+                            .unwrap_or(archive_modified);
+                        entries.push((path, ArchiveEntry { size: member.size(), modified, location }));
+                        if entries.len() > max_entries {
+                            return Ok(None);
+                        }
+                    }
+                    Ok(Some(entries))
+                })
+                .await
+                .map_err(|e| ApiError::Internal(e.into()))?
+                .map_err(unreadable)?;
+
+                listing.entries.extend(entries.ok_or_else(too_many)?);
+            }
+        }
+
+        // Archives needn't have entries for the directories their files are in
+        let directory = ArchiveEntry {
+            size: 0,
+            modified: info.modified,
+            location: Location::Directory,
+        };
+        let mut missing = vec![String::new()];
+        for path in listing.entries.keys() {
+            let mut parent = index_service::parent_of(path);
+            while !parent.is_empty() && !listing.entries.contains_key(&parent) {
+                let next = index_service::parent_of(&parent);
+                missing.push(parent);
+                parent = next;
+            }
+        }
+        for path in missing {
+            listing.entries.entry(path).or_insert_with(|| directory.clone());
+        }
+        Ok(listing)
+    }
+
+    /// A blocking reader of the whole archive, for use on a blocking thread
+    async fn reader(&self, archive: &str) -> Result<BufReader<SyncIoBridge<StorageReader>>, ApiError> {
+        let key = index_service::normalize(archive)?;
+        let reader = self.storage.open_read(&key, None).await?;
+        Ok(BufReader::new(SyncIoBridge::new(reader)))
+    }
+}
+
+fn split(path: &str) -> Result<(&str, &str), ApiError> {
+    split_archive_path(path).ok_or_else(|| ApiError::InvalidPath {
+        path: path.to_string(),
+    })
+}
+
+fn find<'a>(listing: &'a Listing, path: &str, inner: &str) -> Result<&'a ArchiveEntry, ApiError> {
+    listing.entries.get(inner).ok_or_else(|| ApiError::FileNotFound {
+        path: path.to_string(),
+    })
+}
+
+/// The path of an entry as the user sees it: `bundle.zip!/inner/path`
+fn display_path(archive: &str, inner: &str) -> String {
+    format!("{}{}{}", archive.trim_end_matches('/'), ARCHIVE_SEPARATOR, inner)
+}
+
+fn entry_info(path: &str, inner: &str, entry: &ArchiveEntry) -> FileInfo {
+    let name = inner.rsplit('/').next().unwrap_or_default().to_string();
+    let is_directory = matches!(entry.location, Location::Directory);
+    FileInfo {
+        mime_type: match is_directory {
+            true => None,
+            false => mime_guess::from_path(&name).first().map(|mime| mime.to_string()),
+        },
+        name,
+        path: path.to_string(),
+        size: entry.size,
+        modified: entry.modified,
+        is_directory,
+    }
+}
+
+/// The normalized path of an archive member, or `None` for names that would climb out of
+/// the archive. Windows tools may separate with backslashes.
+pub(crate) fn member_path(name: &[u8]) -> Option<String> {
+    let name = String::from_utf8_lossy(name).replace('\\', "/");
+    let mut parts = Vec::new();
+    for part in name.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => return None,
+            part => parts.push(part),
+        }
+    }
+    match parts.is_empty() {
+        true => None,
+        false => Some(parts.join("/")),
+    }
+}
+
+fn invalid_archive(e: zip::result::ZipError) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+/// Run `write` on a blocking thread, reading what it writes as it is written
+fn spawn_reader<F>(write: F) -> StorageReader
+where
+    F: FnOnce(&mut ChannelWriter) -> io::Result<()> + Send + 'static,
+{
+    let (sender, receiver) = mpsc::channel(4);
+    tokio::task::spawn_blocking(move || {
+        let mut out = ChannelWriter::new(sender.clone());
+        if let Err(e) = write(&mut out).and_then(|_| io::Write::flush(&mut out)) {
+            if e.kind() != io::ErrorKind::BrokenPipe {
+                let _ = sender.blocking_send(Err(e));
+            }
+        }
+    });
+    Box::new(StreamReader::new(ReceiverStream::new(receiver)))
+}
+
+/// Bytes read from storage at a time by `StorageFile`
+const READ_AHEAD: usize = 64 * 1024;
+
+/// A stored file read and seeked through on a blocking thread, as the zip reader needs.
+/// Reads go through a window of the file; seeking a little way forward reads on to it
+/// and only seeking further opens the file again.
+pub(crate) struct StorageFile {
+    storage: Arc<dyn StorageBackend>,
+    key: String,
+    size: u64,
+    handle: Handle,
+    position: u64,
+    reader: Option<SyncIoBridge<StorageReader>>,
+    /// Where the next read from `reader` starts
+    reader_position: u64,
+    buffer: Vec<u8>,
+    buffer_start: u64,
+}
+
+impl StorageFile {
+    pub(crate) fn new(storage: Arc<dyn StorageBackend>, key: String, size: u64) -> Self {
+        Self {
+            storage,
+            key,
+            size,
+            handle: Handle::current(),
+            position: 0,
+            reader: None,
+            reader_position: 0,
+            buffer: Vec::new(),
+            buffer_start: 0,
+        }
+    }
+
+    /// Fill the window from `position`
+    fn fill(&mut self) -> io::Result<()> {
+        let skip = self
+            .position
+            .checked_sub(self.reader_position)
+            .filter(|skip| self.reader.is_some() && *skip <= READ_AHEAD as u64);
+        let reader = match (skip, &mut self.reader) {
+            (Some(skip), Some(reader)) => {
+                io::copy(&mut reader.take(skip), &mut io::sink())?;
+                reader
+            }
+            _ => {
+                let range = self.position..self.size;
+                let reader = self
+                    .handle
+                    .block_on(self.storage.open_read(&self.key, Some(range)))
+                    .map_err(io::Error::other)?;
+                self.reader.insert(SyncIoBridge::new_with_handle(reader, self.handle.clone()))
+            }
+        };
+
+        self.buffer.clear();
+        reader.take(READ_AHEAD as u64).read_to_end(&mut self.buffer)?;
+        if self.buffer.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "archive shrank while being read"));
+        }
+        self.buffer_start = self.position;
+        self.reader_position = self.position + self.buffer.len() as u64;
+        Ok(())
+    }
+}
+
+impl Clone for StorageFile {
+    fn clone(&self) -> Self {
+        Self {
+            storage: self.storage.clone(),
+            key: self.key.clone(),
+            size: self.size,
+            handle: self.handle.clone(),
+            position: self.position,
+            reader: None,
+            reader_position: 0,
+            buffer: Vec::new(),
+            buffer_start: 0,
+        }
+    }
+}
+
+impl Read for StorageFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position >= self.size {
+            return Ok(0);
+        }
+        let buffer_end = self.buffer_start + self.buffer.len() as u64;
+        if !(self.buffer_start..buffer_end).contains(&self.position) {
+            self.fill()?;
+        }
+        let offset = (self.position - self.buffer_start) as usize;
+        let n = buf.len().min(self.buffer.len() - offset);
+        buf[..n].copy_from_slice(&self.buffer[offset..offset + n]);
+        self.position += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for StorageFile {
+    fn seek(&mut self, position: SeekFrom) -> io::Result<u64> {
+        self.position = match position {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.size.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        }
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "seek before the start of the file"))?;
+        Ok(self.position)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        services::{ArchiveCompression, ArchiveService},
+        storage::MemoryStorage,
+    };
+    use futures::StreamExt;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_browse_archives() {
+        let config = Config::for_tests(".");
+        let storage: Arc<dyn StorageBackend> = Arc::new(MemoryStorage::new());
+        storage.mkdir("docs/sub", true).await.unwrap();
+        let mut writer = storage.open_write("docs/sub/notes.txt").await.unwrap();
+        writer.write_all(b"hello from inside").await.unwrap();
+        writer.commit().await.unwrap();
+
+        // Archive the tree in each format, then browse it
+        let config = Arc::new(config);
+        let archives = ArchiveService::new(config.clone(), storage.clone());
+        let browser = ArchiveBrowser::new(config, storage.clone());
+        for format in [ArchiveFormat::Zip, ArchiveFormat::Tar, ArchiveFormat::TarGz, ArchiveFormat::TarZst] {
+            let roots = archives.resolve(&["docs".to_string()]).await.unwrap();
+            let mut stream = Box::pin(archives.stream(roots, format, ArchiveCompression::Fast));
+            let path = format!("bundle.{}", format.extension());
+            let mut writer = storage.open_write(&path).await.unwrap();
+            while let Some(chunk) = stream.next().await {
+                writer.write_all(&chunk.unwrap()).await.unwrap();
+            }
+            writer.commit().await.unwrap();
+
+            let top = browser.list(&format!("/{}!", path)).await.unwrap();
+            assert_eq!(top.len(), 1);
+            assert_eq!(top[0].path, format!("/{}!/docs", path));
+            assert!(top[0].is_directory);
+
+            let files = browser.list(&format!("/{}!/docs/sub", path)).await.unwrap();
+            assert_eq!(files[0].name, "notes.txt");
+            assert_eq!(files[0].size, 17);
+
+            let (mut reader, _) = browser.open(&files[0].path, Some(6..10)).await.unwrap();
+            let mut text = String::new();
+            reader.read_to_string(&mut text).await.unwrap();
+            assert_eq!(text, "from");
+        }
+
+        assert_eq!(split_archive_path("a/b.tgz!/c"), Some(("a/b.tgz", "c")));
+        assert_eq!(split_archive_path("a/b.txt!/c"), None);
+    }
+}
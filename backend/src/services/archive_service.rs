@@ -0,0 +1,961 @@
+use crate::{
+    config::Config,
+    errors::ApiError,
+    services::{index_service, member_path, ChangeFeed, ChangeKind, FileInfo, FileService, JobReporter, StorageFile},
+    storage::StorageBackend,
+};
+use bytes::Bytes;
+use chrono::{Datelike, Timelike};
+use flate2::{read::GzDecoder, write::GzEncoder};
+use futures::{Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashSet,
+    io::{self, BufRead, BufReader, Read, Write},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+use tokio::{runtime::Handle, sync::mpsc};
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_util::io::SyncIoBridge;
+use zip::{
+    write::{SimpleFileOptions, StreamWriter},
+    CompressionMethod, ZipArchive, ZipWriter,
+};
+
+/// Bytes collected before a chunk is handed to the response
+const CHUNK_SIZE: usize = 64 * 1024;
+/// Chunks buffered ahead of a slow client
+const CHANNEL_CAPACITY: usize = 8;
+/// Entry added at the end of an archive listing what was left out of it
+pub const MANIFEST_NAME: &str = "filedash-manifest.txt";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub enum ArchiveFormat {
+    #[default]
+    #[serde(rename = "zip")]
+    Zip,
+    #[serde(rename = "tar")]
+    Tar,
+    #[serde(rename = "tar.gz", alias = "tgz")]
+    TarGz,
+    #[serde(rename = "tar.zst", alias = "tzst")]
+    TarZst,
+}
+
+/// Extensions of archives and their formats, longest first
+const EXTENSIONS: [(&str, ArchiveFormat); 6] = [
+    (".tar.zst", ArchiveFormat::TarZst),
+    (".tar.gz", ArchiveFormat::TarGz),
+    (".tzst", ArchiveFormat::TarZst),
+    (".tgz", ArchiveFormat::TarGz),
+    (".tar", ArchiveFormat::Tar),
+    (".zip", ArchiveFormat::Zip),
+];
+
+impl ArchiveFormat {
+    /// The format of an archive, judging by its file name
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::split_name(name).map(|(_, format)| format)
+    }
+
+    /// An archive's name without its extension, and its format
+    pub fn split_name(name: &str) -> Option<(&str, Self)> {
+        EXTENSIONS.iter().find_map(|(extension, format)| {
+            let start = name.len().checked_sub(extension.len())?;
+            let suffix = name.get(start..)?;
+            suffix.eq_ignore_ascii_case(extension).then(|| (&name[..start], *format))
+        })
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            ArchiveFormat::Zip => "zip",
+            ArchiveFormat::Tar => "tar",
+            ArchiveFormat::TarGz => "tar.gz",
+            ArchiveFormat::TarZst => "tar.zst",
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            ArchiveFormat::Zip => "application/zip",
+            ArchiveFormat::Tar => "application/x-tar",
+            ArchiveFormat::TarGz => "application/gzip",
+            ArchiveFormat::TarZst => "application/zstd",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ArchiveCompression {
+    None,
+    #[default]
+    Fast,
+    Best,
+}
+
+/// A selected file or directory and the name it is stored under in the archive
+#[derive(Debug, Clone)]
+pub struct ArchiveRoot {
+    pub name: String,
+    pub info: FileInfo,
+}
+
+pub struct ArchiveService {
+    config: Arc<Config>,
+    storage: Arc<dyn StorageBackend>,
+}
+
+impl ArchiveService {
+    pub fn new(config: Arc<Config>, storage: Arc<dyn StorageBackend>) -> Self {
+        Self { config, storage }
+    }
+
+    /// Check that every selected path exists and give each a distinct top-level name.
+    /// Selecting the root puts its contents at the top of the archive.
+    pub async fn resolve(&self, paths: &[String]) -> Result<Vec<ArchiveRoot>, ApiError> {
+        if paths.is_empty() {
+            return Err(ApiError::BadRequest {
+                message: "No paths selected".to_string(),
+            });
+        }
+
+        let mut roots = Vec::with_capacity(paths.len());
+        let mut seen = HashSet::new();
+        let mut names = HashSet::new();
+        for path in paths {
+            let key = index_service::normalize(path)?;
+            if !seen.insert(key.clone()) {
+                continue;
+            }
+            let info = self.storage.stat(&key).await?;
+            let name = if key.is_empty() {
+                String::new()
+            } else {
+                unique_name(&info.name, &mut names)
+            };
+            roots.push(ArchiveRoot { name, info });
+        }
+        Ok(roots)
+    }
+
+    /// Name offered to the browser for an archive of these roots
+    pub fn file_name(roots: &[ArchiveRoot], format: ArchiveFormat) -> String {
+        let stem = match roots {
+            [root] if !root.name.is_empty() => root.name.as_str(),
+            _ => "download",
+        };
+        format!("{}.{}", stem, format.extension())
+    }
+
+    /// Build the archive on a blocking thread, yielding it in chunks as it is written.
+    /// Stops early when the receiving end is dropped.
+    pub fn stream(
+        &self,
+        roots: Vec<ArchiveRoot>,
+        format: ArchiveFormat,
+        compression: ArchiveCompression,
+    ) -> impl Stream<Item = io::Result<Bytes>> {
+        self.spawn_archive(roots, format, compression, Arc::default())
+    }
+
+    /// As `stream`, adding the bytes of the files archived so far to `read`
+    fn spawn_archive(
+        &self,
+        roots: Vec<ArchiveRoot>,
+        format: ArchiveFormat,
+        compression: ArchiveCompression,
+        read: Arc<AtomicU64>,
+    ) -> ReceiverStream<io::Result<Bytes>> {
+        let (sender, receiver) = mpsc::channel(CHANNEL_CAPACITY);
+        let storage = self.storage.clone();
+        let handle = Handle::current();
+
+        tokio::task::spawn_blocking(move || {
+            let writer = ChannelWriter::new(sender.clone());
+            let written = builder(format, compression, writer)
+                .and_then(|builder| write_archive(&handle, storage.as_ref(), roots, builder, &read));
+            if let Err(e) = written {
+                if e.kind() != io::ErrorKind::BrokenPipe {
+                    tracing::warn!("Archive download failed: {}", e);
+                    let _ = sender.blocking_send(Err(e));
+                }
+            }
+        });
+
+        ReceiverStream::new(receiver)
+    }
+
+    /// Write an archive of `roots` to `destination` through `file_service`, reporting the
+    /// bytes archived so far as progress
+    pub async fn create(
+        &self,
+        file_service: &FileService,
+        roots: Vec<ArchiveRoot>,
+        destination: &str,
+        format: ArchiveFormat,
+        compression: ArchiveCompression,
+        reporter: JobReporter,
+    ) -> Result<FileInfo, ApiError> {
+        let read = Arc::new(AtomicU64::new(0));
+        let chunks = self
+            .spawn_archive(roots, format, compression, read.clone())
+            .inspect(|_| reporter.progress(read.load(Ordering::Relaxed)));
+        let (info, _) = file_service.write_file(destination, chunks).await?;
+        Ok(info)
+    }
+
+    /// Extract the archive at `archive` into the directory `destination` through
+    /// `file_service`, reporting the bytes of the archive read so far as progress.
+    /// Entries that would land outside `destination`, links and special files are skipped;
+    /// the extraction stops once it writes more than the configured limits allow.
+    pub async fn extract(
+        &self,
+        file_service: FileService,
+        change_feed: Arc<ChangeFeed>,
+        archive: &str,
+        destination: &str,
+        overwrite: bool,
+        reporter: JobReporter,
+    ) -> Result<ExtractionResult, ApiError> {
+        let key = index_service::normalize(archive)?;
+        let info = self.storage.stat(&key).await?;
+        let format = match ArchiveFormat::from_name(&key) {
+            Some(format) if !info.is_directory => format,
+            _ => {
+                return Err(ApiError::BadRequest {
+                    message: format!("{} is not a zip, tar, tar.gz or tar.zst archive", archive),
+                })
+            }
+        };
+        let destination = index_service::normalize(destination)?;
+        file_service.ensure_directory(&destination).await?;
+        reporter.set_total(Some(info.size));
+
+        let limits = &self.config.archives;
+        let max_bytes = match limits.max_compression_ratio {
+            0 => limits.max_extracted_size,
+            ratio => limits.max_extracted_size.min(info.size.saturating_mul(ratio)),
+        };
+        let mut extractor = Extractor {
+            handle: Handle::current(),
+            file_service,
+            change_feed,
+            destination,
+            overwrite,
+            max_entries: limits.max_entries,
+            max_bytes,
+            entries: 0,
+            directories: HashSet::new(),
+            result: ExtractionResult::default(),
+        };
+
+        let storage = self.storage.clone();
+        tokio::task::spawn_blocking(move || -> Result<ExtractionResult, ApiError> {
+            match format {
+                ArchiveFormat::Zip => {
+                    let file = StorageFile::new(storage, key, info.size);
+                    let mut zip = ZipArchive::new(file).map_err(|e| unreadable(e.to_string()))?;
+                    let declared: u64 = (0..zip.len())
+                        .filter_map(|index| zip.by_index_raw(index).ok().map(|member| member.size()))
+                        .sum();
+                    if declared > extractor.max_bytes {
+                        return Err(extractor.too_large());
+                    }
+
+                    let mut read = 0;
+                    for index in 0..zip.len() {
+                        let name = zip.name_for_index(index).unwrap_or_default().to_string();
+                        let mut member = match zip.by_index(index) {
+                            Ok(member) => member,
+                            Err(e) => {
+                                extractor.skip(&name, e.to_string());
+                                continue;
+                            }
+                        };
+                        let kind = if member.is_dir() {
+                            EntryKind::Directory
+                        } else if member.is_symlink() {
+                            EntryKind::Link
+                        } else if member.is_file() {
+                            EntryKind::File
+                        } else {
+                            EntryKind::Other
+                        };
+                        let name = member.name_raw().to_vec();
+                        read += member.compressed_size();
+                        extractor.entry(&name, kind, &mut member)?;
+                        reporter.progress(read);
+                        reporter.check_cancelled()?;
+                    }
+                    // Headers and the central directory make up the rest
+                    reporter.progress(info.size);
+                }
+                _ => {
+                    let read = Arc::new(AtomicU64::new(0));
+                    let source = extractor.handle.block_on(storage.open_read(&key, None))?;
+                    let source = CountingReader {
+                        inner: SyncIoBridge::new(source),
+                        read: read.clone(),
+                    };
+                    let mut tar = tar::Archive::new(decompress(format, BufReader::new(source))?);
+                    for member in tar.entries().map_err(|e| unreadable(e.to_string()))? {
+                        let mut member = member.map_err(|e| unreadable(e.to_string()))?;
+                        let kind = match member.header().entry_type() {
+                            tar::EntryType::XGlobalHeader | tar::EntryType::XHeader => continue,
+                            tar::EntryType::Directory => EntryKind::Directory,
+                            tar::EntryType::Symlink | tar::EntryType::Link => EntryKind::Link,
+                            kind if kind.is_file() => EntryKind::File,
+                            _ => EntryKind::Other,
+                        };
+                        let name = member.path_bytes().into_owned();
+                        extractor.entry(&name, kind, &mut member)?;
+                        reporter.progress(read.load(Ordering::Relaxed));
+                        reporter.check_cancelled()?;
+                    }
+                }
+            }
+            Ok(extractor.result)
+        })
+        .await
+        .map_err(|e| ApiError::Internal(e.into()))?
+    }
+}
+
+/// What an extraction did
+#[derive(Debug, Default, Serialize)]
+pub struct ExtractionResult {
+    pub files: u64,
+    pub directories: u64,
+    pub bytes: u64,
+    /// Entries left out and why, up to `MAX_SKIPPED_REPORTED` of them
+    pub skipped: Vec<SkippedEntry>,
+    pub skipped_count: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SkippedEntry {
+    pub path: String,
+    pub reason: String,
+}
+
+/// Skipped entries listed in an extraction's result; the rest are only counted
+const MAX_SKIPPED_REPORTED: usize = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum EntryKind {
+    File,
+    Directory,
+    /// Symbolic or hard link, which could point outside the destination
+    Link,
+    Other,
+}
+
+/// Writes the entries of an archive, on a blocking thread, as they are read
+struct Extractor {
+    handle: Handle,
+    file_service: FileService,
+    change_feed: Arc<ChangeFeed>,
+    destination: String,
+    overwrite: bool,
+    max_entries: usize,
+    /// Most bytes written before the archive is taken for a zip bomb
+    max_bytes: u64,
+    entries: usize,
+    /// Directories known to exist
+    directories: HashSet<String>,
+    result: ExtractionResult,
+}
+
+impl Extractor {
+    fn skip(&mut self, path: &str, reason: impl Into<String>) {
+        self.result.skipped_count += 1;
+        if self.result.skipped.len() < MAX_SKIPPED_REPORTED {
+            self.result.skipped.push(SkippedEntry {
+                path: path.to_string(),
+                reason: reason.into(),
+            });
+        }
+    }
+
+    /// Skip an entry and go on to the next
+    fn skipped(&mut self, path: &str, reason: impl Into<String>) -> Result<(), ApiError> {
+        self.skip(path, reason);
+        Ok(())
+    }
+
+    fn too_large(&self) -> ApiError {
+        ApiError::BadRequest {
+            message: format!(
+                "Extraction stopped: the archive expands to more than {} bytes, the most allowed for it",
+                self.max_bytes
+            ),
+        }
+    }
+
+    /// Extract one entry. Entries that can't be extracted are skipped; errors reading the
+    /// archive and exceeded limits or quotas end the extraction.
+    fn entry(&mut self, name: &[u8], kind: EntryKind, contents: &mut dyn Read) -> Result<(), ApiError> {
+        self.entries += 1;
+        if self.entries > self.max_entries {
+            return Err(ApiError::BadRequest {
+                message: format!("Extraction stopped: the archive has more than {} entries", self.max_entries),
+            });
+        }
+
+        let shown = String::from_utf8_lossy(name).into_owned();
+        let Some(relative) = safe_member_path(name) else {
+            return self.skipped(&shown, "path leads outside the destination");
+        };
+        match kind {
+            EntryKind::Link => return self.skipped(&shown, "links are not extracted"),
+            EntryKind::Other => return self.skipped(&shown, "not a regular file or directory"),
+            EntryKind::File | EntryKind::Directory => {}
+        }
+        let target = match self.destination.is_empty() {
+            true => relative,
+            false => format!("{}/{}", self.destination, relative),
+        };
+        let key = match index_service::normalize(&target) {
+            Ok(key) if self.destination.is_empty() || key.starts_with(&format!("{}/", self.destination)) => key,
+            Ok(_) => return self.skipped(&shown, "path leads outside the destination"),
+            Err(e) => return self.skipped(&shown, e.to_string()),
+        };
+
+        if kind == EntryKind::Directory {
+            if let Err(e) = self.make_directory(&key) {
+                self.skip(&shown, e.to_string());
+            }
+            return Ok(());
+        }
+        if let Err(e) = self.make_directory(&index_service::parent_of(&key)) {
+            return self.skipped(&shown, e.to_string());
+        }
+        if !self.overwrite && self.handle.block_on(self.file_service.get_info(&key)).is_ok() {
+            return self.skipped(&shown, "already exists");
+        }
+
+        let mut chunks = Chunks {
+            contents,
+            written: self.result.bytes,
+            max_bytes: self.max_bytes,
+            failure: None,
+        };
+        let written = self
+            .handle
+            .block_on(self.file_service.write_file(&key, futures::stream::iter(&mut chunks)));
+        match written {
+            Ok((_, created)) => {
+                self.result.files += 1;
+                self.result.bytes = chunks.written;
+                let change = if created { ChangeKind::Created } else { ChangeKind::Modified };
+                self.change_feed.publish(&key, change);
+                Ok(())
+            }
+            Err(e) => match chunks.failure {
+                Some(Failure::TooLarge) => Err(self.too_large()),
+                Some(Failure::Unreadable(e)) => Err(unreadable(e)),
+                None if matches!(e, ApiError::QuotaExceeded { .. }) => Err(e),
+                None => self.skipped(&shown, e.to_string()),
+            },
+        }
+    }
+
+    /// Create `key` and the directories above it unless they are known to exist
+    fn make_directory(&mut self, key: &str) -> Result<(), ApiError> {
+        if key.is_empty() || self.directories.contains(key) {
+            return Ok(());
+        }
+        let existed = self.handle.block_on(self.file_service.get_info(key)).is_ok();
+        self.handle.block_on(self.file_service.ensure_directory(key))?;
+        if !existed {
+            self.result.directories += 1;
+            self.change_feed.publish(key, ChangeKind::Created);
+        }
+        let mut path = key.to_string();
+        while !path.is_empty() && self.directories.insert(path.clone()) {
+            path = index_service::parent_of(&path);
+        }
+        Ok(())
+    }
+}
+
+enum Failure {
+    TooLarge,
+    Unreadable(String),
+}
+
+/// The contents of an entry in chunks, stopping once more than `max_bytes` have been
+/// written in all
+struct Chunks<'a> {
+    contents: &'a mut dyn Read,
+    written: u64,
+    max_bytes: u64,
+    failure: Option<Failure>,
+}
+
+impl Iterator for Chunks<'_> {
+    type Item = io::Result<Bytes>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failure.is_some() {
+            return None;
+        }
+        let mut buffer = vec![0; CHUNK_SIZE];
+        match self.contents.read(&mut buffer) {
+            Ok(0) => None,
+            Ok(n) => {
+                self.written += n as u64;
+                if self.written > self.max_bytes {
+                    self.failure = Some(Failure::TooLarge);
+                    return Some(Err(io::Error::other("extraction limit exceeded")));
+                }
+                buffer.truncate(n);
+                Some(Ok(Bytes::from(buffer)))
+            }
+            Err(e) => {
+                self.failure = Some(Failure::Unreadable(e.to_string()));
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+fn unreadable(e: impl std::fmt::Display) -> ApiError {
+    ApiError::BadRequest {
+        message: format!("The archive could not be read: {}", e),
+    }
+}
+
+/// The path of a member relative to where the archive is extracted, or `None` for absolute
+/// paths and those climbing out with `..`
+fn safe_member_path(name: &[u8]) -> Option<String> {
+    let absolute = matches!(name.first(), Some(b'/' | b'\\')) || name.get(1) == Some(&b':');
+    match absolute {
+        true => None,
+        false => member_path(name),
+    }
+}
+
+/// Counts the bytes read through it, for progress
+struct CountingReader<R> {
+    inner: R,
+    read: Arc<AtomicU64>,
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.read.fetch_add(n as u64, Ordering::Relaxed);
+        Ok(n)
+    }
+}
+
+/// The tar stream inside an archive of `format`
+pub(crate) fn decompress<R: BufRead + Send + 'static>(format: ArchiveFormat, source: R) -> io::Result<Box<dyn Read + Send>> {
+    Ok(match format {
+        ArchiveFormat::TarGz => Box::new(GzDecoder::new(source)),
+        ArchiveFormat::TarZst => Box::new(zstd::stream::read::Decoder::with_buffer(source)?),
+        _ => Box::new(source),
+    })
+}
+
+/// Add " (2)", " (3)"... before the extension of names already taken
+fn unique_name(name: &str, taken: &mut HashSet<String>) -> String {
+    let mut candidate = name.to_string();
+    let (stem, extension) = match name.rfind('.') {
+        Some(i) if i > 0 => name.split_at(i),
+        _ => (name, ""),
+    };
+    let mut n = 2;
+    while !taken.insert(candidate.clone()) {
+        candidate = format!("{} ({}){}", stem, n, extension);
+        n += 1;
+    }
+    candidate
+}
+
+/// Walk the roots depth first, adding everything readable and noting the rest in the manifest
+fn write_archive(
+    handle: &Handle,
+    storage: &dyn StorageBackend,
+    roots: Vec<ArchiveRoot>,
+    mut builder: Box<dyn ArchiveBuilder>,
+    read: &AtomicU64,
+) -> io::Result<()> {
+    let mut skipped = Vec::new();
+    let mut pending: Vec<(String, FileInfo)> =
+        roots.into_iter().rev().map(|root| (root.name, root.info)).collect();
+
+    while let Some((name, info)) = pending.pop() {
+        if info.is_directory {
+            if !name.is_empty() {
+                builder.add_directory(&name, &info)?;
+            }
+            match handle.block_on(storage.list(&info.path)) {
+                Ok(mut children) => {
+                    children.sort_by(|a, b| b.name.cmp(&a.name));
+                    for child in children {
+                        let child_name = if name.is_empty() {
+                            child.name.clone()
+                        } else {
+                            format!("{}/{}", name, child.name)
+                        };
+                        pending.push((child_name, child));
+                    }
+                }
+                Err(e) => skipped.push(format!("{}/: {}", name, e)),
+            }
+            continue;
+        }
+
+        let reader = match handle.block_on(storage.open_read(&info.path, None)) {
+            Ok(reader) => reader,
+            Err(e) => {
+                skipped.push(format!("{}: {}", name, e));
+                continue;
+            }
+        };
+        let mut contents = Contents {
+            inner: SyncIoBridge::new_with_handle(reader, handle.clone()),
+            remaining: info.size,
+            read: 0,
+            error: None,
+            progress: read,
+        };
+        builder.add_file(&name, &info, &mut contents)?;
+        if let Some(e) = contents.error {
+            skipped.push(format!(
+                "{}: read failed after {} bytes, the rest is zero-filled: {}",
+                name, contents.read, e
+            ));
+        }
+    }
+
+    if !skipped.is_empty() {
+        let mut manifest = String::from("These entries could not be added to the archive:\n\n");
+        for line in skipped {
+            manifest.push_str(&line);
+            manifest.push('\n');
+        }
+        let info = FileInfo {
+            name: MANIFEST_NAME.to_string(),
+            path: MANIFEST_NAME.to_string(),
+            size: manifest.len() as u64,
+            modified: chrono::Utc::now(),
+            is_directory: false,
+            mime_type: Some("text/plain".to_string()),
+        };
+        builder.add_file(MANIFEST_NAME, &info, &mut manifest.as_bytes())?;
+    }
+
+    builder.finish()
+}
+
+/// Reads exactly the size recorded for a file, since tar headers and zip64 flags are written
+/// before its data. Growth is cut off and read errors or truncation are padded with zeros.
+struct Contents<'a, R> {
+    inner: R,
+    remaining: u64,
+    read: u64,
+    error: Option<io::Error>,
+    /// Bytes read from every file so far
+    progress: &'a AtomicU64,
+}
+
+impl<R: Read> Read for Contents<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let limit = buf.len().min(usize::try_from(self.remaining).unwrap_or(usize::MAX));
+        if limit == 0 {
+            return Ok(0);
+        }
+
+        if self.error.is_none() {
+            match self.inner.read(&mut buf[..limit]) {
+                Ok(0) => {
+                    self.error = Some(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "file shrank while being read",
+                    ))
+                }
+                Ok(n) => {
+                    self.remaining -= n as u64;
+                    self.read += n as u64;
+                    self.progress.fetch_add(n as u64, Ordering::Relaxed);
+                    return Ok(n);
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => return Err(e),
+                Err(e) => self.error = Some(e),
+            }
+        }
+
+        buf[..limit].fill(0);
+        self.remaining -= limit as u64;
+        Ok(limit)
+    }
+}
+
+/// Sync sink passing data written on a blocking thread to an async reader in chunks
+pub(crate) struct ChannelWriter {
+    sender: mpsc::Sender<io::Result<Bytes>>,
+    buffer: Vec<u8>,
+}
+
+impl ChannelWriter {
+    pub(crate) fn new(sender: mpsc::Sender<io::Result<Bytes>>) -> Self {
+        Self {
+            sender,
+            buffer: Vec::with_capacity(CHUNK_SIZE),
+        }
+    }
+
+    fn send(&mut self) -> io::Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        let chunk = Bytes::from(std::mem::replace(&mut self.buffer, Vec::with_capacity(CHUNK_SIZE)));
+        self.sender
+            .blocking_send(Ok(chunk))
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "download cancelled"))
+    }
+}
+
+impl Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        if self.buffer.len() >= CHUNK_SIZE {
+            self.send()?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.send()
+    }
+}
+
+trait ArchiveBuilder: Send {
+    fn add_directory(&mut self, name: &str, info: &FileInfo) -> io::Result<()>;
+    fn add_file(&mut self, name: &str, info: &FileInfo, contents: &mut dyn Read) -> io::Result<()>;
+    fn finish(self: Box<Self>) -> io::Result<()>;
+}
+
+fn builder<W: Write + Send + 'static>(
+    format: ArchiveFormat,
+    compression: ArchiveCompression,
+    out: W,
+) -> io::Result<Box<dyn ArchiveBuilder>> {
+    let (gzip_level, zstd_level) = match compression {
+        ArchiveCompression::None => (flate2::Compression::none(), 1),
+        ArchiveCompression::Fast => (flate2::Compression::fast(), 3),
+        ArchiveCompression::Best => (flate2::Compression::best(), 19),
+    };
+    Ok(match format {
+        ArchiveFormat::Zip => Box::new(ZipBuilder {
+            writer: ZipWriter::new_stream(out),
+            compression,
+        }),
+        ArchiveFormat::Tar => Box::new(TarBuilder {
+            builder: tar::Builder::new(out),
+            finish: |mut out| out.flush(),
+        }),
+        ArchiveFormat::TarGz => Box::new(TarBuilder {
+            builder: tar::Builder::new(GzEncoder::new(out, gzip_level)),
+            finish: |encoder| encoder.finish()?.flush(),
+        }),
+        ArchiveFormat::TarZst => Box::new(TarBuilder {
+            builder: tar::Builder::new(zstd::stream::write::Encoder::new(out, zstd_level)?),
+            finish: |encoder| encoder.finish()?.flush(),
+        }),
+    })
+}
+
+struct ZipBuilder<W: Write> {
+    writer: ZipWriter<StreamWriter<W>>,
+    compression: ArchiveCompression,
+}
+
+impl<W: Write> ZipBuilder<W> {
+    fn options(&self, info: &FileInfo, mode: u32) -> SimpleFileOptions {
+        let modified = info.modified.naive_utc();
+        let modified = zip::DateTime::from_date_and_time(
+            u16::try_from(modified.year()).unwrap_or(1980),
+            modified.month() as u8,
+            modified.day() as u8,
+            modified.hour() as u8,
+            modified.minute() as u8,
+            modified.second() as u8,
+        )
+        .unwrap_or_default();
+        let options = SimpleFileOptions::default()
+            .last_modified_time(modified)
+            .unix_permissions(mode)
+            .large_file(info.size >= u32::MAX as u64);
+        match self.compression {
+            ArchiveCompression::None => options.compression_method(CompressionMethod::Stored),
+            ArchiveCompression::Fast => options.compression_method(CompressionMethod::Deflated).compression_level(Some(1)),
+            ArchiveCompression::Best => options.compression_method(CompressionMethod::Deflated).compression_level(Some(9)),
+        }
+    }
+}
+
+impl<W: Write + Send> ArchiveBuilder for ZipBuilder<W> {
+    fn add_directory(&mut self, name: &str, info: &FileInfo) -> io::Result<()> {
+        let options = self.options(info, 0o755);
+        self.writer.add_directory(name, options).map_err(io::Error::other)
+    }
+
+    fn add_file(&mut self, name: &str, info: &FileInfo, contents: &mut dyn Read) -> io::Result<()> {
+        let options = self.options(info, 0o644);
+        self.writer.start_file(name, options).map_err(io::Error::other)?;
+        io::copy(contents, &mut self.writer)?;
+        Ok(())
+    }
+
+    fn finish(self: Box<Self>) -> io::Result<()> {
+        self.writer.finish().map_err(io::Error::other)?.flush()
+    }
+}
+
+fn tar_header(info: &FileInfo, directory: bool) -> tar::Header {
+    let mut header = tar::Header::new_gnu();
+    if directory {
+        header.set_entry_type(tar::EntryType::Directory);
+        header.set_mode(0o755);
+        header.set_size(0);
+    } else {
+        header.set_entry_type(tar::EntryType::Regular);
+        header.set_mode(0o644);
+        header.set_size(info.size);
+    }
+    header.set_mtime(info.modified.timestamp().max(0) as u64);
+    header
+}
+
+/// A tar, written straight out or through a compressor which `finish` ends
+struct TarBuilder<W: Write> {
+    builder: tar::Builder<W>,
+    finish: fn(W) -> io::Result<()>,
+}
+
+impl<W: Write + Send> ArchiveBuilder for TarBuilder<W> {
+    fn add_directory(&mut self, name: &str, info: &FileInfo) -> io::Result<()> {
+        self.builder.append_data(&mut tar_header(info, true), name, io::empty())
+    }
+
+    fn add_file(&mut self, name: &str, info: &FileInfo, contents: &mut dyn Read) -> io::Result<()> {
+        self.builder.append_data(&mut tar_header(info, false), name, contents)
+    }
+
+    fn finish(self: Box<Self>) -> io::Result<()> {
+        (self.finish)(self.builder.into_inner()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::Database;
+    use crate::services::{Job, JobContext, JobService, JobStatus};
+    use crate::storage::MemoryStorage;
+    use futures::StreamExt;
+    use tokio::io::AsyncWriteExt;
+
+    fn config() -> Arc<Config> {
+        Arc::new(Config::for_tests("."))
+    }
+
+    async fn write(storage: &dyn StorageBackend, path: &str, data: &[u8]) {
+        let mut writer = storage.open_write(path).await.unwrap();
+        writer.write_all(data).await.unwrap();
+        writer.commit().await.unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_zip_round_trip() {
+        let storage: Arc<dyn StorageBackend> = Arc::new(MemoryStorage::new());
+        storage.mkdir("docs/sub", true).await.unwrap();
+        write(storage.as_ref(), "docs/a.txt", b"alpha").await;
+        write(storage.as_ref(), "docs/sub/b.txt", b"beta").await;
+
+        write(storage.as_ref(), "gone.txt", b"gamma").await;
+
+        let service = ArchiveService::new(config(), storage.clone());
+        let roots = service.resolve(&["/docs".to_string(), "gone.txt".to_string()]).await.unwrap();
+        assert_eq!(ArchiveService::file_name(&roots, ArchiveFormat::Zip), "download.zip");
+        storage.delete("gone.txt").await.unwrap();
+
+        let mut data = Vec::new();
+        let mut stream = Box::pin(service.stream(roots, ArchiveFormat::Zip, ArchiveCompression::Best));
+        while let Some(chunk) = stream.next().await {
+            data.extend_from_slice(&chunk.unwrap());
+        }
+
+        let mut archive = zip::ZipArchive::new(io::Cursor::new(data)).unwrap();
+        let mut names: Vec<_> = archive.file_names().map(str::to_string).collect();
+        names.sort();
+        assert_eq!(names, ["docs/", "docs/a.txt", "docs/sub/", "docs/sub/b.txt", MANIFEST_NAME]);
+        let mut text = String::new();
+        archive.by_name("docs/sub/b.txt").unwrap().read_to_string(&mut text).unwrap();
+        assert_eq!(text, "beta");
+        let mut manifest = String::new();
+        archive.by_name(MANIFEST_NAME).unwrap().read_to_string(&mut manifest).unwrap();
+        assert!(manifest.contains("gone.txt: "));
+    }
+
+    /// Run an extraction as a job and wait for it to finish
+    async fn extract(service: Arc<ArchiveService>, storage: Arc<dyn StorageBackend>, archive: &str) -> Job {
+        let config = config();
+        let dir = tempfile::tempdir().unwrap();
+        let db = Database::new(&format!("sqlite:{}", dir.path().join("test.db").display())).await.unwrap();
+        let jobs = Arc::new(JobService::new(db, config.clone()));
+        let change_feed = Arc::new(ChangeFeed::new(config.clone()));
+        jobs.register("extract", move |archive: String, context: JobContext| {
+            let (service, change_feed) = (service.clone(), change_feed.clone());
+            let file_service = FileService::new(config.as_ref().clone(), storage.clone());
+            async move {
+                service.extract(file_service, change_feed, &archive, "out", false, context.reporter).await
+            }
+        });
+        let job = jobs.submit("extract", "test", &archive).await.unwrap();
+        jobs.wait(&job.id).await
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_extract_guards_against_slips_and_bombs() {
+        let storage: Arc<dyn StorageBackend> = Arc::new(MemoryStorage::new());
+        let service = Arc::new(ArchiveService::new(config(), storage.clone()));
+
+        let mut zip = ZipWriter::new(io::Cursor::new(Vec::new()));
+        let options = SimpleFileOptions::default();
+        for (name, data) in [("docs/a.txt", "alpha"), ("../evil.txt", "x"), ("/etc/evil", "x")] {
+            zip.start_file(name, options).unwrap();
+            zip.write_all(data.as_bytes()).unwrap();
+        }
+        zip.add_symlink("docs/link", "/etc/passwd", options).unwrap();
+        write(storage.as_ref(), "safe.zip", &zip.finish().unwrap().into_inner()).await;
+
+        let job = extract(service.clone(), storage.clone(), "safe.zip").await;
+        assert_eq!(job.status, JobStatus::Completed, "{:?}", job.error);
+        let result = job.result.unwrap();
+        assert_eq!(result["files"], 1);
+        assert_eq!(result["skipped_count"], 3);
+        assert_eq!(storage.stat("out/docs/a.txt").await.unwrap().size, 5);
+        assert!(storage.stat("out/docs/link").await.is_err());
+        assert_eq!(job.progress.total, Some(job.progress.done));
+
+        // A megabyte of zeros compresses far beyond the allowed ratio
+        let mut zip = ZipWriter::new(io::Cursor::new(Vec::new()));
+        zip.start_file("zeros.bin", options).unwrap();
+        zip.write_all(&vec![0; 1024 * 1024]).unwrap();
+        write(storage.as_ref(), "bomb.zip", &zip.finish().unwrap().into_inner()).await;
+
+        let job = extract(service, storage.clone(), "bomb.zip").await;
+        assert_eq!(job.status, JobStatus::Failed);
+        assert!(job.error.unwrap().contains("expands to more than"));
+    }
+}
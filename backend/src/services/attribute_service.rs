@@ -0,0 +1,318 @@
+use crate::{
+    config::Config,
+    errors::ApiError,
+    services::normalize,
+    utils::{owners, security::resolve_storage_path},
+};
+use chrono::{DateTime, Utc};
+use std::{
+    ffi::CString,
+    io,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use walkdir::WalkDir;
+
+/// The setuid and setgid permission bits
+const SETID_BITS: u32 = 0o6000;
+
+/// Who files are to belong to; either can be left as it is
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Ownership {
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+}
+
+impl Ownership {
+    /// Look up a user and a group given by name or numeric id
+    pub fn parse(user: Option<&str>, group: Option<&str>) -> Result<Self, ApiError> {
+        let uid = user
+            .map(|user| {
+                owners::user_id(user).ok_or_else(|| ApiError::BadRequest {
+                    message: format!("Unknown user: {}", user),
+                })
+            })
+            .transpose()?;
+        let gid = group
+            .map(|group| {
+                owners::group_id(group).ok_or_else(|| ApiError::BadRequest {
+                    message: format!("Unknown group: {}", group),
+                })
+            })
+            .transpose()?;
+        Ok(Self { uid, gid })
+    }
+}
+
+/// Changes the mode, owner and times of files and directories kept on the local disk. Symlinks
+/// are never followed, so that a link in the storage can't be used to change what is outside it.
+pub struct AttributeService {
+    config: Arc<Config>,
+}
+
+impl AttributeService {
+    pub fn new(config: Arc<Config>) -> Self {
+        Self { config }
+    }
+
+    /// Parse permission bits given in octal, like `644` or `2775`
+    pub fn parse_mode(mode: &str) -> Result<u32, ApiError> {
+        match mode.len() {
+            1..=4 if mode.chars().all(|c| ('0'..='7').contains(&c)) => Ok(u32::from_str_radix(mode, 8).unwrap_or_default()),
+            _ => Err(ApiError::BadRequest {
+                message: format!("Invalid mode: {}. Expected octal digits like 644", mode),
+            }),
+        }
+    }
+
+    /// Set the permission bits of the file or directory at `path`, and with `recursive` of
+    /// everything below it. Directories get `directory_mode` when it is given. Symlinks are
+    /// left alone. Files only get setuid or setgid when the configuration allows it. Returns
+    /// how many entries were changed.
+    pub async fn chmod(&self, path: &str, mode: u32, directory_mode: Option<u32>, recursive: bool) -> Result<u64, ApiError> {
+        let target = self.resolve(path)?;
+        let allow_setid_files = self.config.attributes.allow_setid_files;
+        run(move || {
+            use std::os::unix::fs::PermissionsExt;
+            let mut changes = Vec::new();
+            for entry in target.walk(recursive)? {
+                let (disk_path, metadata) = entry?;
+                if metadata.file_type().is_symlink() {
+                    continue;
+                }
+                let mode = match metadata.is_dir() {
+                    true => directory_mode.unwrap_or(mode),
+                    false => mode,
+                };
+                // Checked for every entry before any is changed, so a refusal changes nothing
+                if !metadata.is_dir() && mode & SETID_BITS != 0 && !allow_setid_files {
+                    return Err(ApiError::Forbidden {
+                        message: "Setting setuid or setgid on files is not allowed".to_string(),
+                    });
+                }
+                changes.push((disk_path, mode));
+            }
+            for (disk_path, mode) in &changes {
+                std::fs::set_permissions(disk_path, std::fs::Permissions::from_mode(*mode))
+                    .map_err(|e| target.error(disk_path, e))?;
+            }
+            Ok(changes.len() as u64)
+        })
+        .await
+    }
+
+    /// Change the owner of the file or directory at `path`, and with `recursive` of everything
+    /// below it. Symlinks themselves change owner. Only possible when the server runs with the
+    /// privilege to do so. Returns how many entries were changed.
+    pub async fn chown(&self, path: &str, owner: Ownership, recursive: bool) -> Result<u64, ApiError> {
+        let target = self.resolve(path)?;
+        run(move || {
+            let mut changed = 0;
+            for entry in target.walk(recursive)? {
+                let (disk_path, _) = entry?;
+                std::os::unix::fs::lchown(&disk_path, owner.uid, owner.gid).map_err(|e| target.error(&disk_path, e))?;
+                changed += 1;
+            }
+            Ok(changed)
+        })
+        .await
+    }
+
+    /// Set the modification and access times of the file or directory at `path`. The times
+    /// are set on the path itself rather than through an open file, so files the server
+    /// can't read can still be changed.
+    pub async fn set_times(
+        &self,
+        path: &str,
+        modified: Option<DateTime<Utc>>,
+        accessed: Option<DateTime<Utc>>,
+    ) -> Result<(), ApiError> {
+        let target = self.resolve(path)?;
+        run(move || {
+            use std::os::unix::ffi::OsStrExt;
+            for entry in target.walk(false)? {
+                let (disk_path, _) = entry?;
+                let c_path = CString::new(disk_path.as_os_str().as_bytes())
+                    .map_err(|e| target.error(&disk_path, io::Error::new(io::ErrorKind::InvalidInput, e)))?;
+                // Access time first, then modification time
+                let times = [timespec(accessed), timespec(modified)];
+                // Should the entry have been swapped for a symlink since `walk` looked, the
+                // link's own times are set rather than those of what it points to
+                let result = unsafe {
+                    libc::utimensat(libc::AT_FDCWD, c_path.as_ptr(), times.as_ptr(), libc::AT_SYMLINK_NOFOLLOW)
+                };
+                if result != 0 {
+                    return Err(target.error(&disk_path, io::Error::last_os_error()));
+                }
+            }
+            Ok(())
+        })
+        .await
+    }
+
+    fn resolve(&self, path: &str) -> Result<Target, ApiError> {
+        let key = normalize(path)?;
+        if let Some(mount) = self.config.storage.mount(&key).filter(|mount| mount.read_only) {
+            return Err(ApiError::Forbidden {
+                message: format!("The {} mount is read-only", mount.name),
+            });
+        }
+        let disk_path = resolve_storage_path(&self.config.storage, &key)?;
+        Ok(Target { key, disk_path })
+    }
+}
+
+/// A path in the storage and where it is on the local disk
+struct Target {
+    key: String,
+    disk_path: PathBuf,
+}
+
+impl Target {
+    /// The entry itself and, with `recursive`, everything below it, without following symlinks.
+    /// The entry can't be a symlink itself.
+    fn walk(&self, recursive: bool) -> Result<impl Iterator<Item = Result<(PathBuf, std::fs::Metadata), ApiError>> + '_, ApiError> {
+        let metadata = std::fs::symlink_metadata(&self.disk_path).map_err(|e| self.error(&self.disk_path, e))?;
+        if metadata.file_type().is_symlink() {
+            return Err(ApiError::BadRequest {
+                message: format!("{} is a symlink", self.key),
+            });
+        }
+
+        let walk = WalkDir::new(&self.disk_path)
+            .follow_links(false)
+            .follow_root_links(false)
+            .max_depth(if recursive { usize::MAX } else { 0 });
+        Ok(walk.into_iter().map(move |entry| {
+            let entry = entry.map_err(|e| {
+                let disk_path = e.path().unwrap_or(&self.disk_path).to_path_buf();
+                self.error(&disk_path, e.into())
+            })?;
+            let metadata = entry.metadata().map_err(|e| self.error(entry.path(), e.into()))?;
+            Ok((entry.into_path(), metadata))
+        }))
+    }
+
+    /// An error about the entry at `disk_path`, naming it by its path in the storage
+    fn error(&self, disk_path: &Path, error: io::Error) -> ApiError {
+        let path = match disk_path.strip_prefix(&self.disk_path) {
+            Ok(rest) if self.key.is_empty() => rest.to_string_lossy().into_owned(),
+            Ok(rest) if !rest.as_os_str().is_empty() => format!("{}/{}", self.key, rest.to_string_lossy()),
+            _ => self.key.clone(),
+        };
+        match (error.kind(), error.raw_os_error()) {
+            (io::ErrorKind::NotFound, _) => ApiError::FileNotFound { path },
+            (io::ErrorKind::PermissionDenied, _) => ApiError::Forbidden {
+                message: format!("The server is not permitted to change {}", path),
+            },
+            (_, Some(libc::ELOOP)) => ApiError::BadRequest {
+                message: format!("{} is a symlink", path),
+            },
+            _ => error.into(),
+        }
+    }
+}
+
+/// A time for `utimensat`, where `None` leaves it as it is
+fn timespec(time: Option<DateTime<Utc>>) -> libc::timespec {
+    match time {
+        Some(time) => libc::timespec {
+            tv_sec: time.timestamp() as libc::time_t,
+            tv_nsec: time.timestamp_subsec_nanos() as libc::c_long,
+        },
+        None => libc::timespec {
+            tv_sec: 0,
+            tv_nsec: libc::UTIME_OMIT,
+        },
+    }
+}
+
+/// Run a change to the disk on a blocking thread
+async fn run<T: Send + 'static>(change: impl FnOnce() -> Result<T, ApiError> + Send + 'static) -> Result<T, ApiError> {
+    tokio::task::spawn_blocking(change)
+        .await
+        .map_err(|e| ApiError::InternalServerError {
+            message: format!("Attribute task failed: {}", e),
+        })?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{fs, os::unix::fs::MetadataExt};
+
+    #[tokio::test]
+    async fn test_attributes_change_without_following_symlinks() {
+        let dir = tempfile::tempdir().unwrap();
+        let home = dir.path().join("files");
+        let outside = dir.path().join("outside.txt");
+        fs::create_dir_all(home.join("share/sub")).unwrap();
+        fs::write(home.join("share/a.txt"), "a").unwrap();
+        fs::write(home.join("share/sub/b.txt"), "b").unwrap();
+        fs::write(&outside, "x").unwrap();
+        std::os::unix::fs::symlink(&outside, home.join("share/link")).unwrap();
+        let mode = |path: &Path| fs::symlink_metadata(path).unwrap().mode() & 0o7777;
+        let outside_mode = mode(&outside);
+
+        let config = Config::for_tests(&home);
+        let service = AttributeService::new(Arc::new(config));
+
+        assert_eq!(AttributeService::parse_mode("2775").unwrap(), 0o2775);
+        assert!(AttributeService::parse_mode("+644").is_err() && AttributeService::parse_mode("888").is_err());
+
+        let changed = service.chmod("/share", 0o640, Some(0o750), true).await.unwrap();
+        assert_eq!(changed, 4);
+        assert_eq!(mode(&home.join("share/sub")), 0o750);
+        assert_eq!(mode(&home.join("share/sub/b.txt")), 0o640);
+        assert_eq!(mode(&outside), outside_mode);
+        assert_eq!(service.chmod("share/a.txt", 0o600, None, false).await.unwrap(), 1);
+        assert_eq!(mode(&home.join("share/a.txt")), 0o600);
+        assert!(matches!(service.chmod("share/link", 0o777, None, false).await, Err(ApiError::BadRequest { .. })));
+        assert!(service.chmod("../outside.txt", 0o777, None, false).await.is_err());
+
+        // Setgid is fine on directories, but files only get setuid or setgid when allowed
+        assert!(matches!(service.chmod("share", 0o4755, None, true).await, Err(ApiError::Forbidden { .. })));
+        assert_eq!(mode(&home.join("share")), 0o750);
+        assert_eq!(service.chmod("share", 0o644, Some(0o2775), true).await.unwrap(), 4);
+        assert_eq!(mode(&home.join("share/sub")), 0o2775);
+        let mut config = Config::for_tests(&home);
+        config.attributes.allow_setid_files = true;
+        let permissive = AttributeService::new(Arc::new(config));
+        assert_eq!(permissive.chmod("share/a.txt", 0o4755, None, false).await.unwrap(), 1);
+        assert_eq!(mode(&home.join("share/a.txt")), 0o4755);
+        service.chmod("share/a.txt", 0o600, None, false).await.unwrap();
+
+        // Nothing on a read-only mount changes
+        let mut config = Config::for_tests(".");
+        config.storage.mounts = vec![toml::from_str(&format!("name = \"archive\"\npath = {:?}\nread_only = true", home)).unwrap()];
+        let mounted = AttributeService::new(Arc::new(config));
+        assert!(matches!(mounted.chmod("archive/share/a.txt", 0o644, None, false).await, Err(ApiError::Forbidden { .. })));
+        assert!(matches!(mounted.set_times("archive/share", None, None).await, Err(ApiError::Forbidden { .. })));
+        assert_eq!(mode(&home.join("share/a.txt")), 0o600);
+
+        // Giving files to their current owner needs no privilege
+        let uid = fs::metadata(home.join("share/a.txt")).unwrap().uid();
+        let owner = Ownership::parse(Some(&uid.to_string()), None).unwrap();
+        assert_eq!(service.chown("share", owner, true).await.unwrap(), 5);
+
+        let modified = DateTime::parse_from_rfc3339("2020-01-02T03:04:05Z").unwrap().with_timezone(&Utc);
+        service.set_times("share/sub/b.txt", Some(modified), None).await.unwrap();
+        assert_eq!(fs::metadata(home.join("share/sub/b.txt")).unwrap().mtime(), modified.timestamp());
+        assert!(matches!(
+            service.set_times("share/link", Some(modified), None).await,
+            Err(ApiError::BadRequest { .. })
+        ));
+
+        // A file that can be written but not read still gets new times, and either time can be
+        // left as it is
+        fs::write(home.join("share/w.txt"), "w").unwrap();
+        fs::set_permissions(home.join("share/w.txt"), std::os::unix::fs::PermissionsExt::from_mode(0o200)).unwrap();
+        service.set_times("share/w.txt", Some(modified), Some(modified)).await.unwrap();
+        let accessed = DateTime::parse_from_rfc3339("2021-06-07T08:09:10.5Z").unwrap().with_timezone(&Utc);
+        service.set_times("share/w.txt", None, Some(accessed)).await.unwrap();
+        let metadata = fs::metadata(home.join("share/w.txt")).unwrap();
+        assert_eq!(metadata.mode() & 0o7777, 0o200);
+        assert_eq!(metadata.mtime(), modified.timestamp());
+        assert_eq!((metadata.atime(), metadata.atime_nsec()), (accessed.timestamp(), 500_000_000));
+    }
+}
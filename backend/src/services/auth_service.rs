@@ -9,7 +9,11 @@ use argon2::{
 use chrono::{DateTime, Duration, Utc};
 use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, Validation};
 use serde::{Deserialize, Serialize};
+use base64::Engine;
+use rand::RngCore;
+use sha2::{Digest, Sha256};
 use sqlx::Row;
+use std::{collections::HashMap, sync::Mutex};
 use uuid::Uuid;
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -22,10 +26,38 @@ pub struct Claims {
     pub jti: String, // JWT ID for token blacklisting
 }
 
+/// Prefix identifying FileDash API keys
+const API_KEY_PREFIX: &str = "fdk_";
+
+/// Prefix identifying FileDash S3 access key IDs
+const ACCESS_KEY_PREFIX: &str = "FD";
+
+/// How long a successful password check is reused for
+const CREDENTIAL_CACHE_TTL: std::time::Duration = std::time::Duration::from_secs(60);
+
+/// How long a ticket for the change feed can be used to open it
+pub const EVENT_TICKET_TTL: std::time::Duration = std::time::Duration::from_secs(30);
+
 pub struct AuthService {
     db: Database,
     jwt_secret: String,
     token_expiration_hours: i64,
+    credential_cache: Mutex<HashMap<String, CachedCredentials>>,
+    /// Event tickets by their hash
+    event_tickets: Mutex<HashMap<String, EventTicket>>,
+}
+
+/// A successful password check, with the password hash it was made against
+struct CachedCredentials {
+    user: UserInfo,
+    password_hash: String,
+    verified_at: std::time::Instant,
+}
+
+/// Stands in for the session token it was issued for, where a header can't be sent
+struct EventTicket {
+    token: String,
+    issued_at: std::time::Instant,
 }
 
 impl AuthService {
@@ -34,6 +66,8 @@ impl AuthService {
             db,
             jwt_secret,
             token_expiration_hours: token_expiration_hours.unwrap_or(24),
+            credential_cache: Mutex::new(HashMap::new()),
+            event_tickets: Mutex::new(HashMap::new()),
         }
     }
 
@@ -97,13 +131,13 @@ impl AuthService {
         })
     }
 
-    /// Authenticate user and return JWT token
-    pub async fn login(&self, request: LoginRequest) -> Result<LoginResponse, ApiError> {
+    /// Check an email and password, returning the active user they belong to
+    pub async fn verify_credentials(&self, email: &str, password: &str) -> Result<User, ApiError> {
         // Get user from database
         let user_row = sqlx::query(
             "SELECT id, email, password_hash, role, is_active, created_at, updated_at FROM users WHERE email = ?"
         )
-        .bind(&request.email)
+        .bind(email)
         .fetch_optional(self.db.pool())
         .await?;
 
@@ -163,12 +197,19 @@ impl AuthService {
         }
 
         // Verify password
-        if !self.verify_password(&request.password, &user.password_hash)? {
+        if !self.verify_password(password, &user.password_hash)? {
             return Err(ApiError::Unauthorized {
                 message: "Invalid credentials".to_string(),
             });
         }
 
+        Ok(user)
+    }
+
+    /// Authenticate user and return JWT token
+    pub async fn login(&self, request: LoginRequest) -> Result<LoginResponse, ApiError> {
+        let user = self.verify_credentials(&request.email, &request.password).await?;
+
         // Generate JWT token
         let token_id = Uuid::new_v4();
         let now = Utc::now();
@@ -240,6 +281,7 @@ impl AuthService {
             .execute(self.db.pool())
             .await?;
 
+        self.forget_credentials(user_id);
         Ok(())
     }
 
@@ -333,6 +375,330 @@ impl AuthService {
         })
     }
 
+    /// Create an API key for `user_id`. The key itself is only returned here; we keep its hash.
+    pub async fn create_api_key(&self, user_id: Uuid, name: &str) -> Result<CreatedApiKey, ApiError> {
+        let name = name.trim();
+        if name.is_empty() || name.len() > 100 {
+            return Err(ApiError::BadRequest {
+                message: "API key name must be between 1 and 100 characters".to_string(),
+            });
+        }
+
+        let mut secret = [0u8; 20];
+        OsRng.fill_bytes(&mut secret);
+        let key = format!("{}{}", API_KEY_PREFIX, hex::encode(secret));
+        let prefix = key[..API_KEY_PREFIX.len() + 8].to_string();
+
+        let id = Uuid::new_v4();
+        let now = Utc::now();
+        sqlx::query(
+            r#"
+            INSERT INTO api_keys (id, user_id, name, key_hash, prefix, created_at)
+            VALUES (?, ?, ?, ?, ?, ?)
+            "#,
+        )
+        .bind(id.to_string())
+        .bind(user_id.to_string())
+        .bind(name)
+        .bind(sha256_hex(&key))
+        .bind(&prefix)
+        .bind(now.to_rfc3339())
+        .execute(self.db.pool())
+        .await?;
+
+        Ok(CreatedApiKey {
+            id,
+            name: name.to_string(),
+            key,
+            prefix,
+            created_at: now,
+        })
+    }
+
+    /// List the API keys belonging to `user_id`
+    pub async fn list_api_keys(&self, user_id: Uuid) -> Result<Vec<ApiKeyInfo>, ApiError> {
+        let rows = sqlx::query(
+            "SELECT id, name, prefix, created_at, last_used_at FROM api_keys WHERE user_id = ? ORDER BY created_at",
+        )
+        .bind(user_id.to_string())
+        .fetch_all(self.db.pool())
+        .await?;
+
+        rows.into_iter()
+            .map(|row| {
+                Ok(ApiKeyInfo {
+                    id: Uuid::parse_str(&row.get::<String, _>("id")).map_err(|_| {
+                        ApiError::InternalServerError {
+                            message: "Invalid API key ID format".to_string(),
+                        }
+                    })?,
+                    name: row.get("name"),
+                    prefix: row.get("prefix"),
+                    created_at: parse_db_time(&row.get::<String, _>("created_at"))?,
+                    last_used_at: row
+                        .get::<Option<String>, _>("last_used_at")
+                        .map(|value| parse_db_time(&value))
+                        .transpose()?,
+                })
+            })
+            .collect()
+    }
+
+    /// Revoke one of `user_id`'s API keys
+    pub async fn revoke_api_key(&self, user_id: Uuid, key_id: Uuid) -> Result<(), ApiError> {
+        let result = sqlx::query("DELETE FROM api_keys WHERE id = ? AND user_id = ?")
+            .bind(key_id.to_string())
+            .bind(user_id.to_string())
+            .execute(self.db.pool())
+            .await?;
+
+        if result.rows_affected() == 0 {
+            return Err(ApiError::NotFound {
+                resource: "API key".to_string(),
+                id: key_id.to_string(),
+            });
+        }
+        self.forget_credentials(user_id);
+        Ok(())
+    }
+
+    /// Whether `secret` looks like an API key rather than a password or JWT
+    pub fn is_api_key(&self, secret: &str) -> bool {
+        secret.starts_with(API_KEY_PREFIX)
+    }
+
+    /// Return the active user an API key belongs to
+    pub async fn authenticate_api_key(&self, key: &str) -> Result<UserInfo, ApiError> {
+        let invalid = || ApiError::Unauthorized {
+            message: "Invalid API key".to_string(),
+        };
+        if !self.is_api_key(key) {
+            return Err(invalid());
+        }
+
+        let key_hash = sha256_hex(key);
+        let user_id: Option<String> = sqlx::query_scalar(
+            "UPDATE api_keys SET last_used_at = ? WHERE key_hash = ? RETURNING user_id",
+        )
+        .bind(Utc::now().to_rfc3339())
+        .bind(&key_hash)
+        .fetch_optional(self.db.pool())
+        .await?;
+
+        let user_id = user_id
+            .and_then(|id| Uuid::parse_str(&id).ok())
+            .ok_or_else(invalid)?;
+        let user = self.get_user_by_id(&user_id).await?;
+        if !user.is_active {
+            return Err(ApiError::Unauthorized {
+                message: "Account is disabled".to_string(),
+            });
+        }
+        Ok(user)
+    }
+
+    /// Create an S3 access key pair for `user_id`. The secret is only returned here, but it is
+    /// stored as-is since verifying SigV4 signatures requires it.
+    pub async fn create_access_key(&self, user_id: Uuid, name: &str) -> Result<CreatedAccessKey, ApiError> {
+        let name = name.trim();
+        if name.is_empty() || name.len() > 100 {
+            return Err(ApiError::BadRequest {
+                message: "Access key name must be between 1 and 100 characters".to_string(),
+            });
+        }
+
+        let mut id_bytes = [0u8; 9];
+        OsRng.fill_bytes(&mut id_bytes);
+        let access_key_id = format!("{}{}", ACCESS_KEY_PREFIX, hex::encode_upper(id_bytes));
+
+        let mut secret_bytes = [0u8; 30];
+        OsRng.fill_bytes(&mut secret_bytes);
+        let secret_access_key = base64::engine::general_purpose::STANDARD.encode(secret_bytes);
+
+        let now = Utc::now();
+        sqlx::query(
+            r#"
+            INSERT INTO s3_access_keys (access_key_id, user_id, name, secret_key, created_at)
+            VALUES (?, ?, ?, ?, ?)
+            "#,
+        )
+        .bind(&access_key_id)
+        .bind(user_id.to_string())
+        .bind(name)
+        .bind(&secret_access_key)
+        .bind(now.to_rfc3339())
+        .execute(self.db.pool())
+        .await?;
+
+        Ok(CreatedAccessKey {
+            access_key_id,
+            secret_access_key,
+            name: name.to_string(),
+            created_at: now,
+        })
+    }
+
+    /// List the S3 access keys belonging to `user_id`, without their secrets
+    pub async fn list_access_keys(&self, user_id: Uuid) -> Result<Vec<AccessKeyInfo>, ApiError> {
+        let rows = sqlx::query(
+            r#"
+            SELECT access_key_id, name, created_at, last_used_at FROM s3_access_keys
+            WHERE user_id = ? ORDER BY created_at
+            "#,
+        )
+        .bind(user_id.to_string())
+        .fetch_all(self.db.pool())
+        .await?;
+
+        rows.into_iter()
+            .map(|row| {
+                Ok(AccessKeyInfo {
+                    access_key_id: row.get("access_key_id"),
+                    name: row.get("name"),
+                    created_at: parse_db_time(&row.get::<String, _>("created_at"))?,
+                    last_used_at: row
+                        .get::<Option<String>, _>("last_used_at")
+                        .map(|value| parse_db_time(&value))
+                        .transpose()?,
+                })
+            })
+            .collect()
+    }
+
+    /// Revoke one of `user_id`'s S3 access keys
+    pub async fn revoke_access_key(&self, user_id: Uuid, access_key_id: &str) -> Result<(), ApiError> {
+        let result = sqlx::query("DELETE FROM s3_access_keys WHERE access_key_id = ? AND user_id = ?")
+            .bind(access_key_id)
+            .bind(user_id.to_string())
+            .execute(self.db.pool())
+            .await?;
+
+        if result.rows_affected() == 0 {
+            return Err(ApiError::NotFound {
+                resource: "Access key".to_string(),
+                id: access_key_id.to_string(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Look up the secret of an S3 access key and the active user it belongs to.
+    /// Returns `None` for unknown keys and keys of disabled users.
+    pub async fn access_key_secret(&self, access_key_id: &str) -> Result<Option<(String, UserInfo)>, ApiError> {
+        let row = sqlx::query("SELECT user_id, secret_key FROM s3_access_keys WHERE access_key_id = ?")
+            .bind(access_key_id)
+            .fetch_optional(self.db.pool())
+            .await?;
+
+        let Some(row) = row else {
+            return Ok(None);
+        };
+        let Ok(user_id) = Uuid::parse_str(&row.get::<String, _>("user_id")) else {
+            return Ok(None);
+        };
+        let user = self.get_user_by_id(&user_id).await?;
+        if !user.is_active {
+            return Ok(None);
+        }
+        Ok(Some((row.get("secret_key"), user)))
+    }
+
+    /// Record that a request signed with an S3 access key was verified
+    pub async fn touch_access_key(&self, access_key_id: &str) -> Result<(), ApiError> {
+        sqlx::query("UPDATE s3_access_keys SET last_used_at = ? WHERE access_key_id = ?")
+            .bind(Utc::now().to_rfc3339())
+            .bind(access_key_id)
+            .execute(self.db.pool())
+            .await?;
+        Ok(())
+    }
+
+    /// `verify_credentials` for clients that send the password with every request, such as
+    /// WebDAV over HTTP Basic. Successful checks are remembered briefly since Argon2 is
+    /// deliberately slow, for as long as the user stays active with the same password and role.
+    pub async fn verify_credentials_cached(&self, email: &str, password: &str) -> Result<UserInfo, ApiError> {
+        let cache_key = sha256_hex(&format!("{}\0{}", email, password));
+        let cached = {
+            let cache = self.credential_cache.lock().unwrap();
+            cache
+                .get(&cache_key)
+                .filter(|cached| cached.verified_at.elapsed() < CREDENTIAL_CACHE_TTL)
+                .map(|cached| (cached.user.clone(), cached.password_hash.clone()))
+        };
+        if let Some((user, password_hash)) = cached {
+            let current = sqlx::query("SELECT password_hash, role, is_active FROM users WHERE id = ?")
+                .bind(user.id.to_string())
+                .fetch_optional(self.db.pool())
+                .await?;
+            let unchanged = current.is_some_and(|row| {
+                row.get::<bool, _>("is_active")
+                    && row.get::<String, _>("password_hash") == password_hash
+                    && row.get::<String, _>("role") == user.role.to_string()
+            });
+            if unchanged {
+                return Ok(user);
+            }
+            self.credential_cache.lock().unwrap().remove(&cache_key);
+        }
+
+        let user = self.verify_credentials(email, password).await?;
+        let password_hash = user.password_hash.clone();
+        let user: UserInfo = user.into();
+
+        let mut cache = self.credential_cache.lock().unwrap();
+        cache.retain(|_, cached| cached.verified_at.elapsed() < CREDENTIAL_CACHE_TTL);
+        cache.insert(
+            cache_key,
+            CachedCredentials {
+                user: user.clone(),
+                password_hash,
+                verified_at: std::time::Instant::now(),
+            },
+        );
+        Ok(user)
+    }
+
+    /// Drop the remembered password checks of `user_id`, so its next request is checked in full
+    pub fn forget_credentials(&self, user_id: Uuid) {
+        self.credential_cache
+            .lock()
+            .unwrap()
+            .retain(|_, cached| cached.user.id != user_id);
+    }
+
+    /// Issue a ticket that opens the change feed for `EVENT_TICKET_TTL` in place of `token`,
+    /// for browsers' `EventSource`, which can only pass it in the URL. It stays usable until
+    /// then so that automatic reconnects work, and only as long as `token` does.
+    pub fn create_event_ticket(&self, token: &str) -> String {
+        let mut secret = [0u8; 20];
+        OsRng.fill_bytes(&mut secret);
+        let ticket = hex::encode(secret);
+
+        let mut tickets = self.event_tickets.lock().unwrap();
+        tickets.retain(|_, issued| issued.issued_at.elapsed() < EVENT_TICKET_TTL);
+        tickets.insert(
+            sha256_hex(&ticket),
+            EventTicket {
+                token: token.to_string(),
+                issued_at: std::time::Instant::now(),
+            },
+        );
+        ticket
+    }
+
+    /// The session token an unexpired event ticket was issued for
+    pub fn redeem_event_ticket(&self, ticket: &str) -> Result<String, ApiError> {
+        self.event_tickets
+            .lock()
+            .unwrap()
+            .get(&sha256_hex(ticket))
+            .filter(|issued| issued.issued_at.elapsed() < EVENT_TICKET_TTL)
+            .map(|issued| issued.token.clone())
+            .ok_or_else(|| ApiError::Unauthorized {
+                message: "Invalid or expired ticket".to_string(),
+            })
+    }
+
     /// Clean up expired sessions
     pub async fn cleanup_expired_sessions(&self) -> Result<u64, ApiError> {
         let result = sqlx::query("DELETE FROM sessions WHERE expires_at <= datetime('now')")
@@ -384,3 +750,67 @@ impl AuthService {
         password.len() >= 8
     }
 }
+
+fn sha256_hex(value: &str) -> String {
+    hex::encode(Sha256::digest(value.as_bytes()))
+}
+
+fn parse_db_time(value: &str) -> Result<DateTime<Utc>, ApiError> {
+    if let Ok(dt) = DateTime::parse_from_rfc3339(value) {
+        return Ok(dt.with_timezone(&Utc));
+    }
+    chrono::NaiveDateTime::parse_from_str(value, "%Y-%m-%d %H:%M:%S")
+        .map(|naive_dt| DateTime::<Utc>::from_naive_utc_and_offset(naive_dt, Utc))
+        .map_err(|_| ApiError::InternalServerError {
+            message: "Invalid date format".to_string(),
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_cached_credentials_follow_user_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Database::new(&format!("sqlite:{}", dir.path().join("test.db").display())).await.unwrap();
+        let auth = AuthService::new(db.clone(), "test".to_string(), None);
+        let user = auth
+            .create_user(CreateUserRequest {
+                email: "dav@example.com".to_string(),
+                password: "password123".to_string(),
+                role: None,
+            })
+            .await
+            .unwrap();
+        auth.verify_credentials_cached("dav@example.com", "password123").await.unwrap();
+
+        sqlx::query("UPDATE users SET is_active = 0 WHERE id = ?")
+            .bind(user.id.to_string())
+            .execute(db.pool())
+            .await
+            .unwrap();
+        assert!(auth.verify_credentials_cached("dav@example.com", "password123").await.is_err());
+
+        sqlx::query("UPDATE users SET is_active = 1, password_hash = ? WHERE id = ?")
+            .bind(auth.hash_password("changed123").unwrap())
+            .bind(user.id.to_string())
+            .execute(db.pool())
+            .await
+            .unwrap();
+        assert!(auth.verify_credentials_cached("dav@example.com", "password123").await.is_err());
+        auth.verify_credentials_cached("dav@example.com", "changed123").await.unwrap();
+
+        // A promotion or demotion is seen at once, not when the cached login expires
+        sqlx::query("UPDATE users SET role = 'admin' WHERE id = ?")
+            .bind(user.id.to_string())
+            .execute(db.pool())
+            .await
+            .unwrap();
+        let promoted = auth.verify_credentials_cached("dav@example.com", "changed123").await.unwrap();
+        assert!(matches!(promoted.role, UserRole::Admin));
+
+        auth.forget_credentials(user.id);
+        assert!(auth.credential_cache.lock().unwrap().is_empty());
+    }
+}
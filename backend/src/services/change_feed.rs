@@ -0,0 +1,527 @@
+use crate::config::{Config, LocalRoot};
+use notify::{
+    event::{ModifyKind, RenameMode},
+    Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher,
+};
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, HashSet, VecDeque},
+    fs,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::sync::{broadcast, mpsc, Mutex};
+
+/// Published batches buffered per subscriber before it starts lagging
+const FEED_CAPACITY: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChangeKind {
+    Created,
+    Modified,
+    Deleted,
+    Renamed,
+}
+
+impl ChangeKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ChangeKind::Created => "created",
+            ChangeKind::Modified => "modified",
+            ChangeKind::Deleted => "deleted",
+            ChangeKind::Renamed => "renamed",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FileChange {
+    /// Position in the feed, increasing across restarts
+    pub id: u64,
+    /// Relative, `/`-separated path below the storage root
+    pub path: String,
+    pub kind: ChangeKind,
+    /// Previous path of a renamed entry
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub from: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChangeEvent {
+    /// Debounced changes, sorted by path
+    Changed(Vec<FileChange>),
+    /// Events were lost; anything derived from the file tree must be rebuilt from disk
+    Rescan { id: u64 },
+}
+
+impl ChangeEvent {
+    /// Id of the last change in the event
+    pub fn last_id(&self) -> u64 {
+        match self {
+            ChangeEvent::Changed(changes) => changes.last().map(|change| change.id).unwrap_or(0),
+            ChangeEvent::Rescan { id } => *id,
+        }
+    }
+}
+
+/// What a subscriber resuming from an earlier id has to catch up on
+pub struct ChangeReplay {
+    /// Published events newer than the requested id
+    pub events: Vec<ChangeEvent>,
+    /// The requested id is older than the retained history, so some changes can't be replayed
+    pub missed: bool,
+}
+
+enum RawChange {
+    Path { path: String, created: bool },
+    Rename { from: String, to: String },
+    Rescan,
+}
+
+#[derive(Default)]
+struct PendingChange {
+    created: bool,
+    renamed_from: Option<String>,
+}
+
+struct History {
+    next_id: u64,
+    events: VecDeque<ChangeEvent>,
+}
+
+/// Debounced feed of changes to the storage directory, whether they were made through
+/// the API or by other tools writing to the volume directly.
+///
+/// Subscribers that fall behind should treat `RecvError::Lagged` like `ChangeEvent::Rescan`.
+pub struct ChangeFeed {
+    config: Arc<Config>,
+    raw_sender: mpsc::Sender<RawChange>,
+    raw_receiver: Mutex<Option<mpsc::Receiver<RawChange>>>,
+    overflowed: Arc<AtomicBool>,
+    events: broadcast::Sender<ChangeEvent>,
+    history: std::sync::Mutex<History>,
+    watcher: std::sync::Mutex<Option<RecommendedWatcher>>,
+}
+
+impl ChangeFeed {
+    pub fn new(config: Arc<Config>) -> Self {
+        let (raw_sender, raw_receiver) = mpsc::channel(config.watcher.queue_size.max(1));
+        let (events, _) = broadcast::channel(FEED_CAPACITY);
+        // Ids start from the clock so they keep increasing across restarts, and a client
+        // resuming with an id from before the restart is told it missed changes
+        let first_id = chrono::Utc::now().timestamp_micros().max(1) as u64;
+        Self {
+            config,
+            raw_sender,
+            raw_receiver: Mutex::new(Some(raw_receiver)),
+            overflowed: Arc::new(AtomicBool::new(false)),
+            events,
+            history: std::sync::Mutex::new(History {
+                next_id: first_id,
+                events: VecDeque::new(),
+            }),
+            watcher: std::sync::Mutex::new(None),
+        }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<ChangeEvent> {
+        self.events.subscribe()
+    }
+
+    /// Subscribe and collect the retained events published after `last_id`.
+    /// Nothing is lost or duplicated between the replay and the returned receiver.
+    pub fn subscribe_from(&self, last_id: u64) -> (ChangeReplay, broadcast::Receiver<ChangeEvent>) {
+        let history = self.history.lock().unwrap();
+        let receiver = self.events.subscribe();
+
+        let events: Vec<ChangeEvent> = history
+            .events
+            .iter()
+            .filter(|event| event.last_id() > last_id)
+            .map(|event| match event {
+                ChangeEvent::Changed(changes) => ChangeEvent::Changed(
+                    changes.iter().filter(|change| change.id > last_id).cloned().collect(),
+                ),
+                ChangeEvent::Rescan { id } => ChangeEvent::Rescan { id: *id },
+            })
+            .collect();
+
+        // The oldest retained id (or the next id when nothing is retained) must directly follow
+        let oldest = history
+            .events
+            .front()
+            .map(|event| match event {
+                ChangeEvent::Changed(changes) => changes.first().map(|change| change.id).unwrap_or(0),
+                ChangeEvent::Rescan { id } => *id,
+            })
+            .unwrap_or(history.next_id);
+        let missed = last_id.saturating_add(1) < oldest || last_id >= history.next_id;
+
+        (ChangeReplay { events, missed }, receiver)
+    }
+
+    /// Record a change FileDash made itself; `path` is a user path such as `/documents/a.txt`
+    pub fn publish(&self, path: &str, kind: ChangeKind) {
+        let Some(path) = normalize_or_warn(path) else {
+            return;
+        };
+        self.send_raw(RawChange::Path {
+            path,
+            created: kind == ChangeKind::Created,
+        });
+    }
+
+    /// Record a rename FileDash made itself
+    pub fn publish_rename(&self, from: &str, to: &str) {
+        let (Some(from), Some(to)) = (normalize_or_warn(from), normalize_or_warn(to)) else {
+            return;
+        };
+        self.send_raw(RawChange::Rename { from, to });
+    }
+
+    fn send_raw(&self, change: RawChange) {
+        if self.raw_sender.try_send(change).is_err() {
+            self.overflowed.store(true, Ordering::Release);
+        }
+    }
+
+    /// Start debouncing published changes and, if enabled, watching the storage directory
+    pub fn start(self: &Arc<Self>) {
+        let feed = self.clone();
+        tokio::spawn(async move {
+            let receiver = feed.raw_receiver.lock().await.take();
+            if let Some(receiver) = receiver {
+                feed.debounce(receiver).await;
+            }
+        });
+
+        if self.config.watcher.enabled {
+            match self.watch() {
+                Ok(watcher) => {
+                    *self.watcher.lock().unwrap() = Some(watcher);
+                    tracing::info!("Watching the storage directories for changes");
+                }
+                Err(e) => tracing::warn!(
+                    "Failed to watch the storage directory, relying on periodic reconciliation: {}",
+                    e
+                ),
+            }
+        }
+    }
+
+    fn watch(&self) -> notify::Result<RecommendedWatcher> {
+        // Events arrive with canonical paths, so the roots have to match them
+        let roots = self
+            .config
+            .storage
+            .local_roots()
+            .into_iter()
+            .map(|root| {
+                Ok(LocalRoot {
+                    directory: fs::canonicalize(&root.directory)?,
+                    ..root
+                })
+            })
+            .collect::<notify::Result<Vec<_>>>()?;
+        let sender = self.raw_sender.clone();
+        let overflowed = self.overflowed.clone();
+        let watch_roots = roots.clone();
+
+        // Runs on the watcher's own thread, so it must never block
+        let mut watcher = notify::recommended_watcher(move |result: notify::Result<Event>| {
+            let changes = match result {
+                Ok(event) => raw_changes(&watch_roots, &event),
+                Err(e) => {
+                    tracing::warn!("Filesystem watcher error, scheduling a rescan: {}", e);
+                    vec![RawChange::Rescan]
+                }
+            };
+            for change in changes {
+                if sender.try_send(change).is_err() {
+                    overflowed.store(true, Ordering::Release);
+                    return;
+                }
+            }
+        })?;
+        for root in &roots {
+            watcher.watch(&root.directory, RecursiveMode::Recursive)?;
+        }
+        Ok(watcher)
+    }
+
+    /// Collect changes for `debounce_ms` after the first one arrives, then publish them as one batch
+    async fn debounce(&self, mut receiver: mpsc::Receiver<RawChange>) {
+        let window = Duration::from_millis(self.config.watcher.debounce_ms);
+
+        while let Some(first) = receiver.recv().await {
+            let mut pending = BTreeMap::new();
+            let mut rescan = false;
+            collect(first, &mut pending, &mut rescan);
+
+            let deadline = tokio::time::sleep(window);
+            tokio::pin!(deadline);
+            loop {
+                tokio::select! {
+                    _ = &mut deadline => break,
+                    change = receiver.recv() => match change {
+                        Some(change) => collect(change, &mut pending, &mut rescan),
+                        None => break,
+                    },
+                }
+            }
+
+            // Changes dropped on a full queue can't be recovered, only rescanned
+            if self.overflowed.swap(false, Ordering::AcqRel) {
+                tracing::warn!("Change feed overflowed, scheduling a rescan");
+                rescan = true;
+            }
+
+            let changes = if rescan {
+                None
+            } else {
+                let roots = self.config.storage.local_roots();
+                match tokio::task::spawn_blocking(move || classify(&roots, pending)).await {
+                    Ok(changes) => Some(changes),
+                    Err(e) => {
+                        tracing::error!("Failed to classify changes: {}", e);
+                        None
+                    }
+                }
+            };
+
+            self.emit(changes);
+        }
+    }
+
+    /// Number, retain and broadcast one event; `None` publishes a rescan
+    fn emit(&self, changes: Option<Vec<FileChange>>) {
+        let mut history = self.history.lock().unwrap();
+
+        let event = match changes {
+            Some(mut changes) => {
+                if changes.is_empty() {
+                    return;
+                }
+                for change in &mut changes {
+                    change.id = history.next_id;
+                    history.next_id += 1;
+                }
+                ChangeEvent::Changed(changes)
+            }
+            None => {
+                let id = history.next_id;
+                history.next_id += 1;
+                ChangeEvent::Rescan { id }
+            }
+        };
+
+        history.events.push_back(event.clone());
+        while history.events.len() > self.config.watcher.history_size {
+            history.events.pop_front();
+        }
+
+        // Sending only fails when nobody is subscribed
+        let _ = self.events.send(event);
+    }
+}
+
+fn normalize_or_warn(path: &str) -> Option<String> {
+    match crate::services::normalize(path) {
+        Ok(path) => Some(path),
+        Err(e) => {
+            tracing::warn!("Ignoring change to invalid path {}: {}", path, e);
+            None
+        }
+    }
+}
+
+fn collect(change: RawChange, pending: &mut BTreeMap<String, PendingChange>, rescan: &mut bool) {
+    match change {
+        RawChange::Path { path, created } => {
+            pending.entry(path).or_default().created |= created;
+        }
+        RawChange::Rename { from, to } => {
+            pending.entry(from.clone()).or_default();
+            pending.entry(to).or_default().renamed_from = Some(from);
+        }
+        RawChange::Rescan => *rescan = true,
+    }
+}
+
+/// The final state on disk decides the kind; intermediate events within the window don't matter
+fn classify(roots: &[LocalRoot], pending: BTreeMap<String, PendingChange>) -> Vec<FileChange> {
+    let mut changes: Vec<FileChange> = pending
+        .into_iter()
+        .map(|(path, pending)| {
+            let exists = disk_path(roots, &path).is_some_and(|path| fs::symlink_metadata(path).is_ok());
+            let (kind, from) = match (exists, pending.renamed_from) {
+                (false, _) => (ChangeKind::Deleted, None),
+                (true, Some(from)) => (ChangeKind::Renamed, Some(from)),
+                (true, None) if pending.created => (ChangeKind::Created, None),
+                (true, None) => (ChangeKind::Modified, None),
+            };
+            FileChange {
+                id: 0,
+                path,
+                kind,
+                from,
+            }
+        })
+        .collect();
+
+    // A rename already says its source is gone
+    let renamed_from: HashSet<String> = changes
+        .iter()
+        .filter_map(|change| change.from.clone())
+        .collect();
+    changes.retain(|change| {
+        !(change.kind == ChangeKind::Deleted && renamed_from.contains(&change.path))
+    });
+    changes
+}
+
+fn raw_changes(roots: &[LocalRoot], event: &Event) -> Vec<RawChange> {
+    if event.need_rescan() {
+        return vec![RawChange::Rescan];
+    }
+
+    let created = match event.kind {
+        EventKind::Access(_) => return Vec::new(),
+        // Metadata-only changes such as atime updates don't alter anything we track
+        EventKind::Modify(ModifyKind::Metadata(notify::event::MetadataKind::AccessTime)) => {
+            return Vec::new()
+        }
+        EventKind::Modify(ModifyKind::Name(RenameMode::Both)) if event.paths.len() == 2 => {
+            return match (
+                relative_path(roots, &event.paths[0]),
+                relative_path(roots, &event.paths[1]),
+            ) {
+                (Some(from), Some(to)) => vec![RawChange::Rename { from, to }],
+                (from, to) => from
+                    .into_iter()
+                    .chain(to)
+                    .map(|path| RawChange::Path { path, created: false })
+                    .collect(),
+            };
+        }
+        // The destination half of a rename whose source we didn't see, e.g. moved in from outside
+        EventKind::Create(_) | EventKind::Modify(ModifyKind::Name(RenameMode::To)) => true,
+        _ => false,
+    };
+
+    event
+        .paths
+        .iter()
+        .filter_map(|path| relative_path(roots, path))
+        .map(|path| RawChange::Path { path, created })
+        .collect()
+}
+
+fn relative_path(roots: &[LocalRoot], path: &Path) -> Option<String> {
+    let root = roots
+        .iter()
+        .filter(|root| path.starts_with(&root.directory))
+        .max_by_key(|root| root.directory.as_os_str().len())?;
+    let relative = crate::services::relative_to_root(&root.directory, path)?;
+    // Events on a root itself carry no information about a particular entry
+    if relative.is_empty() {
+        None
+    } else if root.path.is_empty() {
+        Some(relative)
+    } else {
+        Some(format!("{}/{}", root.path, relative))
+    }
+}
+
+/// Where a storage path lives on disk, if it is kept under one of the local roots
+fn disk_path(roots: &[LocalRoot], path: &str) -> Option<PathBuf> {
+    roots
+        .iter()
+        .filter(|root| crate::storage::is_within(path, &root.path))
+        .max_by_key(|root| root.path.len())
+        .map(|root| root.directory.join(path[root.path.len()..].trim_start_matches('/')))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_classify_uses_final_state() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("new.txt"), b"new").unwrap();
+        fs::write(dir.path().join("old.txt"), b"old").unwrap();
+        fs::write(dir.path().join("moved.txt"), b"moved").unwrap();
+
+        let mut pending = BTreeMap::new();
+        let mut rescan = false;
+        for change in [
+            RawChange::Path { path: "new.txt".to_string(), created: true },
+            RawChange::Path { path: "old.txt".to_string(), created: false },
+            RawChange::Path { path: "gone.txt".to_string(), created: true },
+            RawChange::Rename { from: "source.txt".to_string(), to: "moved.txt".to_string() },
+        ] {
+            collect(change, &mut pending, &mut rescan);
+        }
+
+        let roots = [LocalRoot {
+            path: String::new(),
+            directory: dir.path().to_path_buf(),
+            encrypted: false,
+        }];
+        let changes = classify(&roots, pending);
+        let kinds: Vec<_> = changes
+            .iter()
+            .map(|c| (c.path.as_str(), c.kind, c.from.as_deref()))
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                ("gone.txt", ChangeKind::Deleted, None),
+                ("moved.txt", ChangeKind::Renamed, Some("source.txt")),
+                ("new.txt", ChangeKind::Created, None),
+                ("old.txt", ChangeKind::Modified, None),
+            ]
+        );
+        assert!(!rescan);
+    }
+
+    #[test]
+    fn test_subscribe_from_replays_and_detects_gaps() {
+        let mut config = Config::for_tests(".");
+        config.watcher.history_size = 2;
+        let feed = ChangeFeed::new(Arc::new(config));
+
+        let change = |path: &str| FileChange {
+            id: 0,
+            path: path.to_string(),
+            kind: ChangeKind::Modified,
+            from: None,
+        };
+        feed.emit(Some(vec![change("a"), change("b")]));
+        let first_id = feed.history.lock().unwrap().events[0].last_id() - 1;
+        feed.emit(Some(vec![change("c")]));
+
+        let (replay, _) = feed.subscribe_from(first_id);
+        assert!(!replay.missed);
+        assert_eq!(replay.events.len(), 2);
+        assert_eq!(replay.events[0].last_id(), first_id + 1);
+
+        // Pushes the first batch out of the retained history
+        feed.emit(None);
+        let (replay, _) = feed.subscribe_from(first_id);
+        assert!(replay.missed);
+        let (replay, _) = feed.subscribe_from(first_id + 2);
+        assert!(!replay.missed);
+        assert_eq!(replay.events, vec![ChangeEvent::Rescan { id: first_id + 3 }]);
+
+        // An id from the future (e.g. from before a clock change) can't be resumed either
+        let (replay, _) = feed.subscribe_from(u64::MAX - 1);
+        assert!(replay.missed);
+    }
+}
@@ -0,0 +1,333 @@
+use crate::{
+    db::Database,
+    errors::ApiError,
+    services::{normalize, subtree_bounds, ChangeEvent, ChangeKind, FileInfo},
+    storage::StorageBackend,
+};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use serde::{Deserialize, Serialize};
+use sha2::Digest;
+use std::{collections::BTreeMap, sync::Arc};
+use tokio::{io::AsyncReadExt, sync::broadcast};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChecksumAlgorithm {
+    Sha256,
+    Sha1,
+    Md5,
+    Blake3,
+}
+
+impl ChecksumAlgorithm {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Sha256 => "sha256",
+            Self::Sha1 => "sha1",
+            Self::Md5 => "md5",
+            Self::Blake3 => "blake3",
+        }
+    }
+
+    /// Parse a name as used here (`sha256`) or in HTTP digest headers (`SHA-256`)
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.trim().to_ascii_lowercase().replace('-', "").as_str() {
+            "sha256" => Some(Self::Sha256),
+            "sha1" | "sha" => Some(Self::Sha1),
+            "md5" => Some(Self::Md5),
+            "blake3" => Some(Self::Blake3),
+            _ => None,
+        }
+    }
+
+    /// Parse a comma-separated list of names, rejecting unknown ones
+    pub fn parse_list(names: &str) -> Result<Vec<Self>, ApiError> {
+        let mut algorithms = Vec::new();
+        for name in names.split(',').filter(|name| !name.trim().is_empty()) {
+            let algorithm = Self::from_name(name).ok_or_else(|| ApiError::BadRequest {
+                message: format!("Unknown checksum algorithm: {}", name.trim()),
+            })?;
+            if !algorithms.contains(&algorithm) {
+                algorithms.push(algorithm);
+            }
+        }
+        Ok(algorithms)
+    }
+
+    pub fn hasher(self) -> Hasher {
+        match self {
+            Self::Sha256 => Hasher::Sha256(sha2::Sha256::new()),
+            Self::Sha1 => Hasher::Sha1(sha1::Sha1::new()),
+            Self::Md5 => Hasher::Md5(md5::Md5::new()),
+            Self::Blake3 => Hasher::Blake3(Box::new(blake3::Hasher::new())),
+        }
+    }
+}
+
+/// Computes a checksum over data fed to it in pieces
+pub enum Hasher {
+    Sha256(sha2::Sha256),
+    Sha1(sha1::Sha1),
+    Md5(md5::Md5),
+    Blake3(Box<blake3::Hasher>),
+}
+
+impl Hasher {
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Self::Sha256(hasher) => hasher.update(data),
+            Self::Sha1(hasher) => hasher.update(data),
+            Self::Md5(hasher) => hasher.update(data),
+            Self::Blake3(hasher) => {
+                hasher.update(data);
+            }
+        }
+    }
+
+    pub fn finalize(self) -> Vec<u8> {
+        match self {
+            Self::Sha256(hasher) => hasher.finalize().to_vec(),
+            Self::Sha1(hasher) => hasher.finalize().to_vec(),
+            Self::Md5(hasher) => hasher.finalize().to_vec(),
+            Self::Blake3(hasher) => hasher.finalize().as_bytes().to_vec(),
+        }
+    }
+}
+
+/// A checksum that contents being written must have
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExpectedChecksum {
+    pub algorithm: ChecksumAlgorithm,
+    pub digest: Vec<u8>,
+}
+
+impl ExpectedChecksum {
+    /// Parse `<algorithm>:<hex>`, as in `sha256:9f86d0…`
+    pub fn parse(value: &str) -> Result<Self, ApiError> {
+        let invalid = || ApiError::BadRequest {
+            message: format!("Invalid checksum {:?}, expected <algorithm>:<hex digest>", value),
+        };
+        let (name, digest) = value.trim().split_once(':').ok_or_else(invalid)?;
+        let algorithm = ChecksumAlgorithm::from_name(name).ok_or_else(invalid)?;
+        let digest = hex::decode(digest.trim()).map_err(|_| invalid())?;
+        Self::new(algorithm, digest)
+    }
+
+    /// Parse a `Digest` header (RFC 3230): `SHA-256=<base64>, MD5=<base64>`. Algorithms
+    /// that aren't supported are ignored.
+    pub fn from_digest_header(value: &str) -> Result<Vec<Self>, ApiError> {
+        let mut expected = Vec::new();
+        for item in value.split(',') {
+            let Some((name, digest)) = item.split_once('=') else {
+                continue;
+            };
+            let Some(algorithm) = ChecksumAlgorithm::from_name(name) else {
+                continue;
+            };
+            let digest = BASE64.decode(digest.trim()).map_err(|_| ApiError::BadRequest {
+                message: format!("Invalid {} digest: {}", name.trim(), digest.trim()),
+            })?;
+            expected.push(Self::new(algorithm, digest)?);
+        }
+        Ok(expected)
+    }
+
+    /// Parse a `Content-MD5` header: the base64 of the MD5
+    pub fn from_content_md5(value: &str) -> Result<Self, ApiError> {
+        let digest = BASE64.decode(value.trim()).map_err(|_| ApiError::BadRequest {
+            message: format!("Invalid Content-MD5: {}", value.trim()),
+        })?;
+        Self::new(ChecksumAlgorithm::Md5, digest)
+    }
+
+    fn new(algorithm: ChecksumAlgorithm, digest: Vec<u8>) -> Result<Self, ApiError> {
+        if digest.len() != algorithm.hasher().finalize().len() {
+            return Err(ApiError::BadRequest {
+                message: format!("A {} checksum is {} bytes long", algorithm.as_str(), algorithm.hasher().finalize().len()),
+            });
+        }
+        Ok(Self { algorithm, digest })
+    }
+
+    /// Fail unless `actual` is the expected digest
+    pub fn verify(&self, actual: &[u8]) -> Result<(), ApiError> {
+        if actual != self.digest.as_slice() {
+            return Err(ApiError::ChecksumMismatch {
+                algorithm: self.algorithm.as_str().to_string(),
+                expected: hex::encode(&self.digest),
+                actual: hex::encode(actual),
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Checksums of stored files, computed on demand. They are cached in SQLite by path and
+/// kept for as long as the file's size and modification time stay the same.
+pub struct ChecksumService {
+    db: Database,
+    storage: Arc<dyn StorageBackend>,
+}
+
+impl ChecksumService {
+    pub fn new(db: Database, storage: Arc<dyn StorageBackend>) -> Self {
+        Self { db, storage }
+    }
+
+    /// Forget the checksums of deleted and moved files
+    pub fn start_background_tasks(self: &Arc<Self>, mut changes: broadcast::Receiver<ChangeEvent>) {
+        let service = self.clone();
+        tokio::spawn(async move {
+            loop {
+                match changes.recv().await {
+                    Ok(ChangeEvent::Changed(batch)) => {
+                        let gone = batch.iter().filter_map(|change| match change.kind {
+                            ChangeKind::Deleted => Some(change.path.as_str()),
+                            _ => change.from.as_deref(),
+                        });
+                        for path in gone {
+                            if let Err(e) = service.forget(path).await {
+                                tracing::warn!("Failed to drop the checksums of {}: {}", path, e);
+                            }
+                        }
+                    }
+                    Ok(ChangeEvent::Rescan { .. }) | Err(broadcast::error::RecvError::Lagged(_)) => {}
+                    Err(broadcast::error::RecvError::Closed) => return,
+                }
+            }
+        });
+    }
+
+    /// The file at `path` with its checksums by each of `algorithms`, computing those that
+    /// aren't cached in one read of the file
+    pub async fn checksums(
+        &self,
+        path: &str,
+        algorithms: &[ChecksumAlgorithm],
+    ) -> Result<(FileInfo, BTreeMap<ChecksumAlgorithm, String>), ApiError> {
+        let key = normalize(path)?;
+        let info = self.storage.stat(&key).await?;
+        if info.is_directory {
+            return Err(ApiError::BadRequest {
+                message: format!("{} is a directory", path),
+            });
+        }
+
+        let mut checksums = BTreeMap::new();
+        let rows: Vec<(String, String)> =
+            sqlx::query_as("SELECT algorithm, value FROM file_checksums WHERE path = ? AND size = ? AND modified = ?")
+                .bind(&key)
+                .bind(info.size as i64)
+                .bind(info.modified.timestamp_millis())
+                .fetch_all(self.db.pool())
+                .await?;
+        for (algorithm, value) in rows {
+            if let Some(algorithm) = ChecksumAlgorithm::from_name(&algorithm).filter(|a| algorithms.contains(a)) {
+                checksums.insert(algorithm, value);
+            }
+        }
+
+        let mut hashers: Vec<_> = algorithms
+            .iter()
+            .filter(|algorithm| !checksums.contains_key(algorithm))
+            .map(|&algorithm| (algorithm, algorithm.hasher()))
+            .collect();
+        if hashers.is_empty() {
+            return Ok((FileInfo { path: path.to_string(), ..info }, checksums));
+        }
+
+        let mut reader = self.storage.open_read(&key, None).await?;
+        let mut buffer = vec![0; 256 * 1024];
+        loop {
+            let read = reader.read(&mut buffer).await?;
+            if read == 0 {
+                break;
+            }
+            for (_, hasher) in &mut hashers {
+                hasher.update(&buffer[..read]);
+            }
+        }
+        for (algorithm, hasher) in hashers {
+            let value = hex::encode(hasher.finalize());
+            self.record(&key, &info, algorithm, &value).await?;
+            checksums.insert(algorithm, value);
+        }
+        Ok((FileInfo { path: path.to_string(), ..info }, checksums))
+    }
+
+    /// Remember the checksum of the file at normalized `key`, as it is described by `info`
+    pub async fn record(&self, key: &str, info: &FileInfo, algorithm: ChecksumAlgorithm, value: &str) -> Result<(), ApiError> {
+        sqlx::query(
+            r#"
+            INSERT INTO file_checksums (path, algorithm, size, modified, value) VALUES (?, ?, ?, ?, ?)
+            ON CONFLICT (path, algorithm) DO UPDATE SET
+                size = excluded.size, modified = excluded.modified, value = excluded.value
+            "#,
+        )
+        .bind(key)
+        .bind(algorithm.as_str())
+        .bind(info.size as i64)
+        .bind(info.modified.timestamp_millis())
+        .bind(value)
+        .execute(self.db.pool())
+        .await?;
+        Ok(())
+    }
+
+    /// Drop the checksums of `path` and everything below it
+    async fn forget(&self, path: &str) -> Result<(), ApiError> {
+        if path.is_empty() {
+            sqlx::query("DELETE FROM file_checksums").execute(self.db.pool()).await?;
+            return Ok(());
+        }
+        let (lower, upper) = subtree_bounds(path);
+        sqlx::query("DELETE FROM file_checksums WHERE path = ? OR (path > ? AND path < ?)")
+            .bind(path)
+            .bind(lower)
+            .bind(upper)
+            .execute(self.db.pool())
+            .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+    use tokio::io::AsyncWriteExt;
+
+    #[tokio::test]
+    async fn test_checksums_are_cached_until_the_file_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Database::new(&format!("sqlite:{}", dir.path().join("test.db").display())).await.unwrap();
+        let storage: Arc<dyn StorageBackend> = Arc::new(MemoryStorage::new());
+        let checksums = ChecksumService::new(db, storage.clone());
+
+        let mut writer = storage.open_write("hello.txt").await.unwrap();
+        writer.write_all(b"hello").await.unwrap();
+        let info = writer.commit().await.unwrap();
+
+        let all = [ChecksumAlgorithm::Sha256, ChecksumAlgorithm::Sha1, ChecksumAlgorithm::Md5, ChecksumAlgorithm::Blake3];
+        let (_, values) = checksums.checksums("/hello.txt", &all).await.unwrap();
+        assert_eq!(values[&ChecksumAlgorithm::Sha256], "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824");
+        assert_eq!(values[&ChecksumAlgorithm::Sha1], "aaf4c61ddcc5e8a2dabede0f3b482cd9aea9434d");
+        assert_eq!(values[&ChecksumAlgorithm::Md5], "5d41402abc4b2a76b9719d911017c592");
+        assert_eq!(values[&ChecksumAlgorithm::Blake3], "ea8f163db38682925e4491c5e58d4bb3506ef8c14eb78a86e908c5624a67200f");
+
+        // A cached value is used while the size and modification time match
+        checksums.record("hello.txt", &info, ChecksumAlgorithm::Md5, "cached").await.unwrap();
+        let (_, values) = checksums.checksums("hello.txt", &[ChecksumAlgorithm::Md5]).await.unwrap();
+        assert_eq!(values[&ChecksumAlgorithm::Md5], "cached");
+        let stale = FileInfo { size: 4, ..info };
+        checksums.record("hello.txt", &stale, ChecksumAlgorithm::Md5, "stale").await.unwrap();
+        let (_, values) = checksums.checksums("hello.txt", &[ChecksumAlgorithm::Md5]).await.unwrap();
+        assert_eq!(values[&ChecksumAlgorithm::Md5], "5d41402abc4b2a76b9719d911017c592");
+
+        let expected = ExpectedChecksum::from_digest_header("UNIXsum=30637, MD5=XUFAKrxLKna5cZ2REBfFkg==").unwrap();
+        assert_eq!(expected, [ExpectedChecksum::parse("md5:5d41402abc4b2a76b9719d911017c592").unwrap()]);
+        assert!(expected[0].verify(&hex::decode(&values[&ChecksumAlgorithm::Md5]).unwrap()).is_ok());
+        assert!(matches!(expected[0].verify(&[0; 16]), Err(ApiError::ChecksumMismatch { .. })));
+        assert!(ExpectedChecksum::parse("sha256:abcd").is_err());
+    }
+}
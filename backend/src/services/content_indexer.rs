@@ -0,0 +1,562 @@
+use crate::{
+    config::Config,
+    db::Database,
+    errors::ApiError,
+    services::{
+        index_service::{depth_of, subtree_bounds},
+        FileInfo, IndexSearch, IndexSearchOutcome, SearchHit,
+    },
+    storage::StorageBackend,
+};
+use chrono::{DateTime, Utc};
+use regex::Regex;
+use sqlx::{QueryBuilder, Row, Sqlite};
+use std::{
+    sync::{Arc, OnceLock},
+    time::Instant,
+};
+use tokio::{
+    io::AsyncReadExt,
+    sync::{mpsc, Mutex},
+};
+
+/// Extensions whose files are indexed as text, in addition to anything with a `text/*` MIME type
+const TEXT_EXTENSIONS: &[&str] = &[
+    "txt", "text", "log", "md", "markdown", "rst", "adoc", "tex", "csv", "tsv", "json", "jsonl",
+    "ndjson", "xml", "yaml", "yml", "toml", "ini", "cfg", "conf", "properties", "env", "html",
+    "htm", "xhtml", "css", "scss", "less", "js", "mjs", "cjs", "jsx", "ts", "tsx", "vue", "svelte",
+    "rs", "py", "rb", "go", "java", "kt", "kts", "scala", "c", "h", "cc", "cpp", "cxx", "hpp",
+    "hh", "cs", "swift", "m", "php", "pl", "pm", "lua", "r", "dart", "ex", "exs", "erl", "hs",
+    "clj", "sh", "bash", "zsh", "fish", "ps1", "bat", "sql", "graphql", "proto", "gradle", "srt",
+    "vtt",
+];
+
+/// Extension-less file names that are source code
+const TEXT_FILE_NAMES: &[&str] = &["dockerfile", "makefile", "readme", "license", "changelog"];
+
+/// How many leading bytes are checked for NUL when deciding whether a file is binary
+const BINARY_SNIFF_LEN: usize = 8192;
+
+/// Extracts text from text-like files on a bounded pool of background workers and keeps
+/// the `file_content` FTS table in sync with it
+pub struct ContentIndexer {
+    db: Database,
+    config: Arc<Config>,
+    storage: Arc<dyn StorageBackend>,
+    sender: mpsc::Sender<String>,
+    receiver: Mutex<Option<mpsc::Receiver<String>>>,
+}
+
+impl ContentIndexer {
+    pub fn new(db: Database, config: Arc<Config>, storage: Arc<dyn StorageBackend>) -> Self {
+        let (sender, receiver) = mpsc::channel(config.search.content.queue_size.max(1));
+        Self {
+            db,
+            config,
+            storage,
+            sender,
+            receiver: Mutex::new(Some(receiver)),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.config.search.index_enabled && self.config.search.content.enabled
+    }
+
+    /// Number of files waiting for extraction
+    pub fn queued(&self) -> usize {
+        self.sender.max_capacity() - self.sender.capacity()
+    }
+
+    /// Spawn the extraction workers. Each worker handles one file at a time, so at most
+    /// `workers` blocking threads are ever busy reading files.
+    pub async fn start_workers(self: &Arc<Self>) {
+        if !self.is_enabled() {
+            return;
+        }
+
+        let receiver = match self.receiver.lock().await.take() {
+            Some(receiver) => Arc::new(Mutex::new(receiver)),
+            None => return,
+        };
+
+        for _ in 0..self.config.search.content.workers.max(1) {
+            let indexer = self.clone();
+            let receiver = receiver.clone();
+            tokio::spawn(async move {
+                loop {
+                    let path = match receiver.lock().await.recv().await {
+                        Some(path) => path,
+                        None => break,
+                    };
+                    if let Err(e) = indexer.process(&path).await {
+                        tracing::warn!("Failed to index content of {}: {}", path, e);
+                    }
+                }
+            });
+        }
+    }
+
+    /// Queue a file for extraction without waiting; reconciliation catches anything dropped
+    pub fn try_enqueue(&self, path: &str, name: &str, mime_type: Option<&str>) {
+        if !self.is_enabled() || !self.should_index(path, name, mime_type) {
+            return;
+        }
+        if self.sender.try_send(path.to_string()).is_err() {
+            tracing::debug!("Content queue full, {} will be picked up by reconciliation", path);
+        }
+    }
+
+    /// Queue every file below `base` whose extracted content is missing or out of date
+    pub fn spawn_enqueue_stale(self: &Arc<Self>, base: &str) {
+        if !self.is_enabled() {
+            return;
+        }
+
+        let indexer = self.clone();
+        let base = base.to_string();
+        tokio::spawn(async move {
+            if let Err(e) = indexer.enqueue_stale(&base).await {
+                tracing::error!("Failed to queue stale content for indexing: {}", e);
+            }
+        });
+    }
+
+    async fn enqueue_stale(&self, base: &str) -> Result<(), ApiError> {
+        use futures::TryStreamExt;
+
+        let mut builder: QueryBuilder<Sqlite> = QueryBuilder::new(
+            r#"
+            SELECT f.path, f.name, f.mime_type
+            FROM file_index f
+            LEFT JOIN file_content c ON c.path = f.path
+            WHERE f.is_directory = 0
+              AND (c.path IS NULL OR c.modified != f.modified OR c.size != f.size)
+            "#,
+        );
+        if !base.is_empty() {
+            let (lower, upper) = subtree_bounds(base);
+            builder.push(" AND f.path > ").push_bind(lower);
+            builder.push(" AND f.path < ").push_bind(upper);
+        }
+
+        // Collect first so the read connection isn't held while waiting on a full queue
+        let mut stale = Vec::new();
+        let query = builder.build();
+        let mut rows = query.fetch(self.db.pool());
+        while let Some(row) = rows.try_next().await? {
+            let path: String = row.get("path");
+            let name: String = row.get("name");
+            let mime_type: Option<String> = row.get("mime_type");
+            if self.should_index(&path, &name, mime_type.as_deref()) {
+                stale.push(path);
+            }
+        }
+        drop(rows);
+
+        for path in stale {
+            if self.sender.send(path).await.is_err() {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether `path` is a text-like file inside the configured content paths. Files over
+    /// the size cap still qualify: they get an empty row so reconciliation doesn't re-queue them.
+    fn should_index(&self, path: &str, name: &str, mime_type: Option<&str>) -> bool {
+        is_text_like(name, mime_type) && self.path_allowed(path)
+    }
+
+    fn path_allowed(&self, path: &str) -> bool {
+        let content_config = &self.config.search.content;
+        let under = |prefix: &String| {
+            let prefix = prefix.trim_matches('/');
+            prefix.is_empty()
+                || path == prefix
+                || path.strip_prefix(prefix).is_some_and(|rest| rest.starts_with('/'))
+        };
+
+        if content_config.exclude_paths.iter().any(under) {
+            return false;
+        }
+        content_config.include_paths.is_empty() || content_config.include_paths.iter().any(under)
+    }
+
+    async fn process(&self, path: &str) -> Result<(), ApiError> {
+        let extracted = match self.read_for_index(path).await? {
+            Some(extracted) => extracted,
+            None => {
+                // The file is gone or no longer a regular file
+                sqlx::query("DELETE FROM file_content WHERE path = ?")
+                    .bind(path)
+                    .execute(self.db.pool())
+                    .await?;
+                return Ok(());
+            }
+        };
+
+        // Start with a write so the transaction takes the write lock up front instead of
+        // failing to upgrade a read lock while another writer is active
+        let mut tx = self.db.pool().begin().await?;
+
+        let rowid: i64 = sqlx::query_scalar(
+            r#"
+            INSERT INTO file_content (path, size, modified) VALUES (?, ?, ?)
+            ON CONFLICT(path) DO UPDATE SET
+                size = excluded.size,
+                modified = excluded.modified,
+                indexed_at = datetime('now')
+            RETURNING rowid
+            "#,
+        )
+        .bind(path)
+        .bind(extracted.size)
+        .bind(extracted.modified)
+        .fetch_one(&mut *tx)
+        .await?;
+
+        sqlx::query("DELETE FROM file_content_fts WHERE rowid = ?")
+            .bind(rowid)
+            .execute(&mut *tx)
+            .await?;
+
+        sqlx::query("INSERT INTO file_content_fts (rowid, body) VALUES (?, ?)")
+            .bind(rowid)
+            .bind(extracted.text.unwrap_or_default())
+            .execute(&mut *tx)
+            .await?;
+
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Read a file's text, or only its size and modification time when it is over the size
+    /// cap. `None` when it is gone or no longer a file.
+    async fn read_for_index(&self, path: &str) -> Result<Option<ExtractedContent>, ApiError> {
+        let max_file_size = self.config.search.content.max_file_size;
+        let info = match self.storage.stat(path).await {
+            Ok(info) if !info.is_directory => info,
+            Ok(_) | Err(ApiError::FileNotFound { .. }) => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let mut extracted = ExtractedContent {
+            size: info.size as i64,
+            modified: info.modified.timestamp_millis(),
+            text: None,
+        };
+        if info.size > max_file_size {
+            return Ok(Some(extracted));
+        }
+
+        let mut bytes = Vec::with_capacity(info.size as usize);
+        match self.storage.open_read(path, None).await {
+            Ok(reader) => reader.take(max_file_size).read_to_end(&mut bytes).await?,
+            Err(ApiError::FileNotFound { .. }) => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        extracted.text = extract_text(&info.name, &bytes);
+        Ok(Some(extracted))
+    }
+
+    pub async fn indexed_count(&self) -> Result<i64, ApiError> {
+        let count = sqlx::query_scalar("SELECT COUNT(*) FROM file_content")
+            .fetch_one(self.db.pool())
+            .await?;
+        Ok(count)
+    }
+
+    /// Full-text search over extracted content, best matches first
+    pub(crate) async fn search(
+        &self,
+        search: &IndexSearch<'_>,
+        text: &str,
+    ) -> Result<IndexSearchOutcome, ApiError> {
+        use futures::TryStreamExt;
+
+        let match_query = fts_match_query(text).ok_or_else(|| ApiError::BadRequest {
+            message: "Content search needs at least one search term".to_string(),
+        })?;
+
+        // \x01 and \x02 mark highlights so the snippet can be HTML-escaped safely afterwards
+        let mut builder: QueryBuilder<Sqlite> = QueryBuilder::new(
+            r#"
+            SELECT f.path, f.name, f.size, f.modified, f.is_directory, f.mime_type,
+                   bm25(file_content_fts) AS rank,
+                   snippet(file_content_fts, 0, char(1), char(2), '…', 16) AS snippet
+            FROM file_content_fts
+            JOIN file_content c ON c.rowid = file_content_fts.rowid
+            JOIN file_index f ON f.path = c.path
+            WHERE file_content_fts MATCH
+            "#,
+        );
+        builder.push_bind(match_query);
+
+        if !search.base.is_empty() {
+            let (lower, upper) = subtree_bounds(search.base);
+            builder.push(" AND f.path > ").push_bind(lower);
+            builder.push(" AND f.path < ").push_bind(upper);
+        }
+        if let Some(min_size) = search.filter.min_size() {
+            builder.push(" AND f.size >= ").push_bind(min_size as i64);
+        }
+        if let Some(max_size) = search.filter.max_size() {
+            builder.push(" AND f.size <= ").push_bind(max_size as i64);
+        }
+        if let Some(after) = search.filter.modified_after() {
+            builder.push(" AND f.modified >= ").push_bind(after.timestamp_millis());
+        }
+        if let Some(before) = search.filter.modified_before() {
+            builder.push(" AND f.modified <= ").push_bind(before.timestamp_millis());
+        }
+        builder.push(" ORDER BY rank");
+
+        let base_depth = depth_of(search.base);
+        let mut results = Vec::new();
+        let mut skipped = 0;
+        let mut has_more = false;
+        let mut timed_out = false;
+
+        let query = builder.build();
+        let mut rows = query.fetch(self.db.pool());
+        while let Some(row) = rows.try_next().await? {
+            if Instant::now() >= search.deadline {
+                timed_out = true;
+                break;
+            }
+
+            let path: String = row.get("path");
+            if depth_of(&path) - base_depth > search.max_depth {
+                continue;
+            }
+
+            let modified: i64 = row.get("modified");
+            let size: i64 = row.get("size");
+            let file = FileInfo {
+                name: row.get("name"),
+                path,
+                size: size.max(0) as u64,
+                modified: DateTime::<Utc>::from_timestamp_millis(modified).unwrap_or_default(),
+                is_directory: row.get("is_directory"),
+                mime_type: row.get("mime_type"),
+            };
+            if !search.filter.matches_info(&file) {
+                continue;
+            }
+
+            if skipped < search.offset {
+                skipped += 1;
+                continue;
+            }
+
+            if results.len() == search.limit {
+                has_more = true;
+                break;
+            }
+
+            let rank: f64 = row.get("rank");
+            let snippet: String = row.get("snippet");
+            results.push(SearchHit {
+                file,
+                // bm25() is lower for better matches; flip it so higher scores rank first
+                score: Some(-rank),
+                snippet: Some(highlight_snippet(&snippet)),
+            });
+        }
+
+        Ok(IndexSearchOutcome {
+            results,
+            has_more,
+            timed_out,
+        })
+    }
+}
+
+struct ExtractedContent {
+    size: i64,
+    modified: i64,
+    /// `None` for files that are too large or turned out to be binary
+    text: Option<String>,
+}
+
+/// Whether a file should have its text extracted, judged by name and MIME type
+pub(crate) fn is_text_like(name: &str, mime_type: Option<&str>) -> bool {
+    let lower = name.to_lowercase();
+    let extension = lower.rsplit_once('.').map(|(_, extension)| extension);
+
+    match extension {
+        Some(extension) if TEXT_EXTENSIONS.contains(&extension) => true,
+        None if TEXT_FILE_NAMES.contains(&lower.as_str()) => true,
+        _ => mime_type.is_some_and(|mime| mime.starts_with("text/")),
+    }
+}
+
+/// Decode a text-like file, returning `None` for content that looks binary
+pub(crate) fn extract_text(name: &str, bytes: &[u8]) -> Option<String> {
+    let sniff_len = bytes.len().min(BINARY_SNIFF_LEN);
+    if bytes[..sniff_len].contains(&0) {
+        return None;
+    }
+
+    let text = String::from_utf8_lossy(bytes);
+    let lower = name.to_lowercase();
+    if lower.ends_with(".html") || lower.ends_with(".htm") || lower.ends_with(".xhtml") {
+        Some(strip_html(&text))
+    } else {
+        Some(text.into_owned())
+    }
+}
+
+fn strip_html(html: &str) -> String {
+    static HIDDEN: OnceLock<Regex> = OnceLock::new();
+    static TAGS: OnceLock<Regex> = OnceLock::new();
+
+    let hidden = HIDDEN.get_or_init(|| {
+        Regex::new(r"(?is)<(script|style|noscript)\b.*?</(script|style|noscript)\s*>|<!--.*?-->")
+            .expect("Invalid HTML regex")
+    });
+    let tags = TAGS.get_or_init(|| Regex::new(r"(?s)<[^>]*>").expect("Invalid HTML regex"));
+
+    let without_hidden = hidden.replace_all(html, " ");
+    let without_tags = tags.replace_all(&without_hidden, " ");
+
+    without_tags
+        .replace("&nbsp;", " ")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&amp;", "&")
+}
+
+/// Turn user input into an FTS5 MATCH expression: every word must appear, `"quoted text"`
+/// is a phrase and a trailing `*` makes a prefix match. FTS operators are never passed through.
+pub(crate) fn fts_match_query(text: &str) -> Option<String> {
+    let mut terms = Vec::new();
+    let mut chars = text.chars().peekable();
+
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+            continue;
+        }
+
+        let (raw, quoted) = if c == '"' {
+            chars.next();
+            let phrase: String = chars.by_ref().take_while(|&c| c != '"').collect();
+            (phrase, true)
+        } else {
+            let mut word = String::new();
+            while let Some(&c) = chars.peek() {
+                if c.is_whitespace() {
+                    break;
+                }
+                word.push(c);
+                chars.next();
+            }
+            (word, false)
+        };
+
+        let prefix = !quoted && raw.ends_with('*');
+        let cleaned: String = raw
+            .trim_end_matches('*')
+            .chars()
+            .filter(|&c| c != '"')
+            .collect();
+        if cleaned.trim().is_empty() {
+            continue;
+        }
+
+        terms.push(if prefix {
+            format!("\"{}\"*", cleaned)
+        } else {
+            format!("\"{}\"", cleaned)
+        });
+    }
+
+    if terms.is_empty() {
+        None
+    } else {
+        Some(terms.join(" "))
+    }
+}
+
+/// HTML-escape a snippet and turn the \x01/\x02 markers into `<mark>` tags
+fn highlight_snippet(snippet: &str) -> String {
+    let mut highlighted = String::with_capacity(snippet.len() + 16);
+    for c in snippet.chars() {
+        match c {
+            '\u{1}' => highlighted.push_str("<mark>"),
+            '\u{2}' => highlighted.push_str("</mark>"),
+            '<' => highlighted.push_str("&lt;"),
+            '>' => highlighted.push_str("&gt;"),
+            '&' => highlighted.push_str("&amp;"),
+            '"' => highlighted.push_str("&quot;"),
+            _ => highlighted.push(c),
+        }
+    }
+    highlighted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{DedupStorage, MemoryStorage};
+    use tokio::io::AsyncWriteExt;
+
+    #[test]
+    fn test_fts_match_query() {
+        assert_eq!(fts_match_query("annual report").as_deref(), Some("\"annual\" \"report\""));
+        assert_eq!(fts_match_query("\"annual report\" budg*").as_deref(), Some("\"annual report\" \"budg\"*"));
+        assert_eq!(fts_match_query("a\"b OR c").as_deref(), Some("\"ab\" \"OR\" \"c\""));
+        assert_eq!(fts_match_query("   "), None);
+    }
+
+    #[test]
+    fn test_extract_text_skips_binary_and_strips_html() {
+        assert_eq!(extract_text("data.bin", b"abc\0def"), None);
+        let html = b"<html><head><style>p{}</style><script>var x = 1;</script></head>\
+                     <body><p>Annual&nbsp;report &amp; budget</p></body></html>";
+        let text = extract_text("page.html", html).unwrap();
+        assert!(text.contains("Annual report & budget"));
+        assert!(!text.contains("var x"));
+    }
+
+    #[test]
+    fn test_is_text_like() {
+        assert!(is_text_like("main.rs", None));
+        assert!(is_text_like("Dockerfile", None));
+        assert!(is_text_like("notes.weird", Some("text/plain")));
+        assert!(!is_text_like("photo.jpg", Some("image/jpeg")));
+    }
+
+    #[test]
+    fn test_highlight_snippet_escapes_html() {
+        assert_eq!(
+            highlight_snippet("<b>\u{1}annual\u{2}</b>"),
+            "&lt;b&gt;<mark>annual</mark>&lt;/b&gt;"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_extracts_deduplicated_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Database::new(&format!("sqlite:{}", dir.path().join("test.db").display())).await.unwrap();
+        let mut config = Config::for_tests(dir.path().join("files"));
+        config.storage.dedup.enabled = true;
+        let storage: Arc<dyn StorageBackend> = Arc::new(DedupStorage::new(db.clone(), "", Arc::new(MemoryStorage::new())));
+        let mut writer = storage.open_write("notes.txt").await.unwrap();
+        writer.write_all(b"annual report").await.unwrap();
+        writer.commit().await.unwrap();
+
+        let indexer = ContentIndexer::new(db, Arc::new(config), storage.clone());
+        let extracted = indexer.read_for_index("notes.txt").await.unwrap().unwrap();
+        assert_eq!(extracted.text.as_deref(), Some("annual report"));
+        indexer.process("notes.txt").await.unwrap();
+        assert_eq!(indexer.indexed_count().await.unwrap(), 1);
+
+        storage.delete("notes.txt").await.unwrap();
+        indexer.process("notes.txt").await.unwrap();
+        assert_eq!(indexer.indexed_count().await.unwrap(), 0);
+    }
+}
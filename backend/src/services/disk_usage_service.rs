@@ -0,0 +1,404 @@
+use crate::{
+    config::Config,
+    errors::ApiError,
+    services::{normalize, parent_of, subtree_bounds, ChangeEvent, FileInfo},
+    storage::StorageBackend,
+};
+use chrono::{DateTime, Duration, Utc};
+use futures::future::BoxFuture;
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, HashMap},
+    ops::Bound,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+use tokio::sync::broadcast;
+
+/// Largest files remembered per directory, and so the most a report can list
+pub const MAX_REPORT_ENTRIES: usize = 100;
+
+/// Age buckets by time since last modification; anything older is `older`
+const AGES: [(&str, i64); 4] = [("last_day", 1), ("last_week", 7), ("last_month", 30), ("last_year", 365)];
+
+/// Total size of everything in a directory tree
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct DirectorySize {
+    pub bytes: u64,
+    pub files: u64,
+    pub directories: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DirectoryUsage {
+    pub path: String,
+    #[serde(flatten)]
+    pub size: DirectorySize,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FileUsage {
+    pub path: String,
+    pub bytes: u64,
+    pub modified: DateTime<Utc>,
+}
+
+/// Files sharing an extension; `None` for files without one
+#[derive(Debug, Clone, Serialize)]
+pub struct TypeUsage {
+    pub extension: Option<String>,
+    pub bytes: u64,
+    pub files: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AgeUsage {
+    pub age: &'static str,
+    pub bytes: u64,
+    pub files: u64,
+}
+
+/// What takes up the space in a directory tree
+#[derive(Debug, Serialize)]
+pub struct DiskUsageReport {
+    pub path: String,
+    #[serde(flatten)]
+    pub size: DirectorySize,
+    /// Immediate subdirectories, largest first
+    pub subdirectories: Vec<DirectoryUsage>,
+    /// Files anywhere in the tree, largest first
+    pub largest_files: Vec<FileUsage>,
+    /// Largest first
+    pub types: Vec<TypeUsage>,
+    /// Newest first
+    pub ages: Vec<AgeUsage>,
+    /// When the oldest of the sizes was computed; ages are relative to it
+    pub computed_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Tally {
+    bytes: u64,
+    files: u64,
+}
+
+impl Tally {
+    fn add(&mut self, bytes: u64, files: u64) {
+        self.bytes += bytes;
+        self.files += files;
+    }
+}
+
+/// A directory's totals, with the breakdowns of only the files directly in it. Reports add
+/// up the breakdowns of the subdirectories, which are cached alongside.
+struct Summary {
+    size: DirectorySize,
+    subdirectories: Vec<String>,
+    types: HashMap<Option<String>, Tally>,
+    ages: [Tally; AGES.len() + 1],
+    /// Largest first, at most `MAX_REPORT_ENTRIES`
+    largest: Vec<FileUsage>,
+    computed_at: DateTime<Utc>,
+}
+
+impl Summary {
+    fn new(now: DateTime<Utc>) -> Self {
+        Self {
+            size: DirectorySize::default(),
+            subdirectories: Vec::new(),
+            types: HashMap::new(),
+            ages: [Tally::default(); AGES.len() + 1],
+            largest: Vec::new(),
+            computed_at: now,
+        }
+    }
+
+    fn add_file(&mut self, file: FileInfo, now: DateTime<Utc>) {
+        self.size.bytes += file.size;
+        self.size.files += 1;
+
+        let extension = file
+            .name
+            .rsplit_once('.')
+            .filter(|(stem, _)| !stem.is_empty())
+            .map(|(_, extension)| extension.to_lowercase());
+        self.types.entry(extension).or_default().add(file.size, 1);
+
+        let age = now - file.modified;
+        let bucket = AGES
+            .iter()
+            .position(|(_, days)| age < Duration::days(*days))
+            .unwrap_or(AGES.len());
+        self.ages[bucket].add(file.size, 1);
+
+        self.largest.push(FileUsage {
+            path: file.path,
+            bytes: file.size,
+            modified: file.modified,
+        });
+    }
+
+    fn add_directory(&mut self, path: String, child: &Summary) {
+        self.size.bytes += child.size.bytes;
+        self.size.files += child.size.files;
+        self.size.directories += child.size.directories + 1;
+        self.computed_at = self.computed_at.min(child.computed_at);
+        self.subdirectories.push(path);
+    }
+}
+
+fn largest_first(files: &mut Vec<FileUsage>, limit: usize) {
+    files.sort_by(|a, b| b.bytes.cmp(&a.bytes).then_with(|| a.path.cmp(&b.path)));
+    files.truncate(limit);
+}
+
+/// Recursive sizes of directories. Each directory's totals are computed from its listing and
+/// the cached totals of its subdirectories, and dropped along with those of its ancestors
+/// when the change feed reports a change below it, so only the changed branch is listed again.
+pub struct DiskUsageService {
+    config: Arc<Config>,
+    storage: Arc<dyn StorageBackend>,
+    cache: Mutex<BTreeMap<String, Arc<Summary>>>,
+    /// Bumped on every invalidation, so that totals computed across one aren't cached
+    generation: AtomicU64,
+}
+
+impl DiskUsageService {
+    pub fn new(config: Arc<Config>, storage: Arc<dyn StorageBackend>) -> Self {
+        Self {
+            config,
+            storage,
+            cache: Mutex::new(BTreeMap::new()),
+            generation: AtomicU64::new(0),
+        }
+    }
+
+    /// Drop the totals of changed directories as changes arrive
+    pub fn start_background_tasks(self: &Arc<Self>, mut changes: broadcast::Receiver<ChangeEvent>) {
+        let service = self.clone();
+        tokio::spawn(async move {
+            loop {
+                match changes.recv().await {
+                    Ok(event) => service.apply(&event),
+                    Err(broadcast::error::RecvError::Lagged(_)) => service.clear(),
+                    Err(broadcast::error::RecvError::Closed) => return,
+                }
+            }
+        });
+    }
+
+    fn apply(&self, event: &ChangeEvent) {
+        match event {
+            ChangeEvent::Changed(changes) => {
+                let paths = changes
+                    .iter()
+                    .flat_map(|change| std::iter::once(&change.path).chain(change.from.as_ref()));
+                self.invalidate(paths);
+            }
+            ChangeEvent::Rescan { .. } => self.clear(),
+        }
+    }
+
+    fn invalidate<'a>(&self, paths: impl Iterator<Item = &'a String>) {
+        let mut cache = self.cache.lock().unwrap();
+        for path in paths {
+            if path.is_empty() {
+                cache.clear();
+                break;
+            }
+            // A changed directory may have been replaced by a file or moved away entirely
+            let (lower, upper) = subtree_bounds(path);
+            let below: Vec<String> = cache
+                .range::<String, _>((Bound::Excluded(lower), Bound::Excluded(upper)))
+                .map(|(key, _)| key.clone())
+                .collect();
+            for key in below {
+                cache.remove(&key);
+            }
+            cache.remove(path);
+
+            let mut ancestor = path.clone();
+            while !ancestor.is_empty() {
+                ancestor = parent_of(&ancestor);
+                cache.remove(&ancestor);
+            }
+        }
+        self.generation.fetch_add(1, Ordering::AcqRel);
+    }
+
+    fn clear(&self) {
+        let mut cache = self.cache.lock().unwrap();
+        cache.clear();
+        self.generation.fetch_add(1, Ordering::AcqRel);
+    }
+
+    fn cached(&self, key: &str) -> Option<Arc<Summary>> {
+        let ttl = self.config.disk_usage.cache_ttl_seconds;
+        let cache = self.cache.lock().unwrap();
+        cache
+            .get(key)
+            .filter(|summary| ttl == 0 || Utc::now() - summary.computed_at < Duration::seconds(ttl as i64))
+            .cloned()
+    }
+
+    fn summarize<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<Arc<Summary>, ApiError>> {
+        Box::pin(async move {
+            if let Some(summary) = self.cached(key) {
+                return Ok(summary);
+            }
+
+            let generation = self.generation.load(Ordering::Acquire);
+            let now = Utc::now();
+            let mut summary = Summary::new(now);
+            for entry in self.storage.list(key).await? {
+                match entry.is_directory {
+                    true => {
+                        let child = self.summarize(&entry.path).await?;
+                        summary.add_directory(entry.path, &child);
+                    }
+                    false => summary.add_file(entry, now),
+                }
+            }
+            largest_first(&mut summary.largest, MAX_REPORT_ENTRIES);
+
+            let summary = Arc::new(summary);
+            let mut cache = self.cache.lock().unwrap();
+            if self.generation.load(Ordering::Acquire) == generation {
+                cache.insert(key.to_string(), summary.clone());
+            }
+            Ok(summary)
+        })
+    }
+
+    /// Size of everything below the directory at `path`
+    pub async fn size(&self, path: &str) -> Result<DirectorySize, ApiError> {
+        Ok(self.summarize(&normalize(path)?).await?.size)
+    }
+
+    /// The largest subdirectories and files below the directory at `path`, and how its size
+    /// divides up by file type and age, listing at most `limit` of each
+    pub async fn report(&self, path: &str, limit: usize) -> Result<DiskUsageReport, ApiError> {
+        let key = normalize(path)?;
+        let info = self.storage.stat(&key).await?;
+        if !info.is_directory {
+            return Err(ApiError::BadRequest {
+                message: format!("Not a directory: {}", path),
+            });
+        }
+        let limit = limit.min(MAX_REPORT_ENTRIES);
+
+        let root = self.summarize(&key).await?;
+        let mut directories = Vec::new();
+        for subdirectory in &root.subdirectories {
+            let child = self.summarize(subdirectory).await?;
+            directories.push(DirectoryUsage {
+                path: subdirectory.clone(),
+                size: child.size,
+            });
+        }
+        directories.sort_by(|a, b| b.size.bytes.cmp(&a.size.bytes).then_with(|| a.path.cmp(&b.path)));
+        directories.truncate(limit);
+
+        let mut files = Vec::new();
+        let mut types: HashMap<Option<String>, Tally> = HashMap::new();
+        let mut ages = [Tally::default(); AGES.len() + 1];
+        let mut pending = vec![root.clone()];
+        while let Some(summary) = pending.pop() {
+            files.extend(summary.largest.iter().take(limit).cloned());
+            for (extension, tally) in &summary.types {
+                types.entry(extension.clone()).or_default().add(tally.bytes, tally.files);
+            }
+            for (total, tally) in ages.iter_mut().zip(&summary.ages) {
+                total.add(tally.bytes, tally.files);
+            }
+            for subdirectory in &summary.subdirectories {
+                pending.push(self.summarize(subdirectory).await?);
+            }
+        }
+        largest_first(&mut files, limit);
+
+        let mut types: Vec<TypeUsage> = types
+            .into_iter()
+            .map(|(extension, tally)| TypeUsage {
+                extension,
+                bytes: tally.bytes,
+                files: tally.files,
+            })
+            .collect();
+        types.sort_by(|a, b| b.bytes.cmp(&a.bytes).then_with(|| a.extension.cmp(&b.extension)));
+        types.truncate(limit);
+
+        let ages = AGES
+            .iter()
+            .map(|(age, _)| *age)
+            .chain(std::iter::once("older"))
+            .zip(ages)
+            .map(|(age, tally)| AgeUsage {
+                age,
+                bytes: tally.bytes,
+                files: tally.files,
+            })
+            .collect();
+
+        Ok(DiskUsageReport {
+            path: key,
+            size: root.size,
+            subdirectories: directories,
+            largest_files: files,
+            types,
+            ages,
+            computed_at: root.computed_at,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{services::{ChangeKind, FileChange}, storage::MemoryStorage};
+    use tokio::io::AsyncWriteExt;
+
+    async fn write(storage: &dyn StorageBackend, path: &str, size: usize) {
+        let mut writer = storage.open_write(path).await.unwrap();
+        writer.write_all(&vec![0; size]).await.unwrap();
+        writer.commit().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_sizes_are_cached_until_changed() {
+        let config = Config::for_tests(".");
+        let storage: Arc<dyn StorageBackend> = Arc::new(MemoryStorage::new());
+        storage.mkdir("docs/old", true).await.unwrap();
+        storage.mkdir("media", true).await.unwrap();
+        write(storage.as_ref(), "docs/a.txt", 10).await;
+        write(storage.as_ref(), "docs/old/b.TXT", 20).await;
+        write(storage.as_ref(), "media/c.mp4", 300).await;
+        write(storage.as_ref(), "README", 5).await;
+        let usage = DiskUsageService::new(Arc::new(config), storage.clone());
+
+        let report = usage.report("/", 10).await.unwrap();
+        assert_eq!(report.size, DirectorySize { bytes: 335, files: 4, directories: 3 });
+        assert_eq!(report.subdirectories[0].path, "media");
+        assert_eq!(report.subdirectories[1].size, DirectorySize { bytes: 30, files: 2, directories: 1 });
+        assert_eq!(report.largest_files[0].path, "media/c.mp4");
+        assert_eq!(report.types[1].extension.as_deref(), Some("txt"));
+        assert_eq!(report.types[1].files, 2);
+        assert_eq!(report.ages[0].bytes, 335);
+        assert!(usage.report("README", 10).await.is_err());
+
+        // Unchanged until the change is reported, and then only the changed branch is listed
+        write(storage.as_ref(), "docs/old/d.txt", 100).await;
+        assert_eq!(usage.size("docs").await.unwrap().bytes, 30);
+        usage.apply(&ChangeEvent::Changed(vec![FileChange {
+            id: 1,
+            path: "docs/old/d.txt".to_string(),
+            kind: ChangeKind::Created,
+            from: None,
+        }]));
+        assert!(usage.cached("media").is_some() && usage.cached("docs").is_none());
+        assert_eq!(usage.size("/").await.unwrap().bytes, 435);
+        assert_eq!(usage.size("docs/old").await.unwrap().files, 2);
+    }
+}
@@ -0,0 +1,267 @@
+use crate::{
+    config::Config,
+    errors::ApiError,
+    services::{normalize, ChecksumAlgorithm, ChecksumService, FileInfo, FileService, JobReporter, TRASH_DIRECTORY},
+    storage::StorageBackend,
+    utils::security::resolve_storage_path,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, HashSet},
+    sync::Arc,
+};
+
+/// Files with the same contents
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DuplicateSet {
+    pub size: u64,
+    pub sha256: String,
+    pub paths: Vec<String>,
+    /// Space that keeping only one of them would free. Paths that are hard links to the
+    /// same file on the disk only count once.
+    pub wasted: u64,
+}
+
+/// What a search for duplicates found, the sets wasting the most space first
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DuplicateReport {
+    pub path: String,
+    /// Number of files compared
+    pub files: u64,
+    pub sets: Vec<DuplicateSet>,
+    pub wasted: u64,
+}
+
+/// What to do with a duplicate of the file that is kept
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DuplicateAction {
+    /// Move it to the trash
+    Trash,
+    /// Replace it with a hard link to the file that is kept
+    Hardlink,
+}
+
+/// Finds files with the same contents below a directory. Files are grouped by size and only
+/// those sharing a size are hashed, through the checksum cache.
+pub struct DuplicateService {
+    config: Arc<Config>,
+    storage: Arc<dyn StorageBackend>,
+    checksums: Arc<ChecksumService>,
+}
+
+impl DuplicateService {
+    pub fn new(config: Arc<Config>, storage: Arc<dyn StorageBackend>, checksums: Arc<ChecksumService>) -> Self {
+        Self {
+            config,
+            storage,
+            checksums,
+        }
+    }
+
+    /// The sets of files of at least `min_size` bytes below the directory at `path` that have
+    /// the same contents. Trash directories are skipped, and progress is counted in bytes hashed.
+    pub async fn find(&self, path: &str, min_size: u64, reporter: &JobReporter) -> Result<DuplicateReport, ApiError> {
+        let root = normalize(path)?;
+        if !self.storage.stat(&root).await?.is_directory {
+            return Err(ApiError::BadRequest {
+                message: format!("{} is not a directory", path),
+            });
+        }
+
+        let mut by_size: BTreeMap<u64, Vec<String>> = BTreeMap::new();
+        let mut files = 0;
+        let mut pending = vec![root];
+        while let Some(directory) = pending.pop() {
+            reporter.check_cancelled()?;
+            let entries = match self.storage.list(&directory).await {
+                Err(ApiError::FileNotFound { .. }) => continue,
+                entries => entries?,
+            };
+            for entry in entries {
+                if entry.is_directory {
+                    if entry.name != TRASH_DIRECTORY {
+                        pending.push(entry.path);
+                    }
+                } else if entry.size >= min_size.max(1) {
+                    files += 1;
+                    by_size.entry(entry.size).or_default().push(entry.path);
+                }
+            }
+        }
+        by_size.retain(|_, paths| paths.len() > 1);
+        reporter.set_total(Some(by_size.iter().map(|(size, paths)| size * paths.len() as u64).sum()));
+
+        let mut done = 0;
+        let mut sets = Vec::new();
+        for (size, paths) in by_size {
+            let mut by_hash: BTreeMap<String, Vec<String>> = BTreeMap::new();
+            for path in paths {
+                reporter.check_cancelled()?;
+                match self.sha256(&path).await {
+                    Ok(sha256) => by_hash.entry(sha256).or_default().push(path),
+                    // Gone, or replaced by a directory, since it was listed
+                    Err(ApiError::FileNotFound { .. } | ApiError::BadRequest { .. }) => {}
+                    Err(e) => return Err(e),
+                }
+                done += size;
+                reporter.progress(done);
+            }
+
+            for (sha256, mut paths) in by_hash {
+                let copies = self.copies(&paths);
+                if copies > 1 {
+                    paths.sort();
+                    sets.push(DuplicateSet {
+                        size,
+                        sha256,
+                        paths,
+                        wasted: size * (copies - 1),
+                    });
+                }
+            }
+        }
+        sets.sort_by_key(|set| Reverse(set.wasted));
+
+        Ok(DuplicateReport {
+            path: path.to_string(),
+            files,
+            wasted: sets.iter().map(|set| set.wasted).sum(),
+            sets,
+        })
+    }
+
+    /// Move the file at `path` to the trash, or replace it with a hard link to `keep`, once
+    /// it has been checked to still have the same contents as `keep`
+    pub async fn resolve(
+        &self,
+        files: &FileService,
+        keep: &str,
+        path: &str,
+        action: DuplicateAction,
+    ) -> Result<FileInfo, ApiError> {
+        let key = normalize(path)?;
+        if key == normalize(keep)? {
+            return Err(ApiError::BadRequest {
+                message: format!("{} is the file being kept", path),
+            });
+        }
+        let sha256 = self.sha256(keep).await?;
+        if self.sha256(path).await? != sha256 {
+            return Err(ApiError::Conflict {
+                message: format!("{} doesn't have the same contents as {}", path, keep),
+            });
+        }
+
+        match action {
+            DuplicateAction::Trash => files.trash(path).await,
+            DuplicateAction::Hardlink => {
+                let info = files.hard_link(keep, path).await?;
+                // It now has the modification time of the file kept
+                self.checksums.record(&key, &info, ChecksumAlgorithm::Sha256, &sha256).await?;
+                Ok(info)
+            }
+        }
+    }
+
+    async fn sha256(&self, path: &str) -> Result<String, ApiError> {
+        let (_, mut checksums) = self.checksums.checksums(path, &[ChecksumAlgorithm::Sha256]).await?;
+        Ok(checksums.remove(&ChecksumAlgorithm::Sha256).unwrap_or_default())
+    }
+
+    /// How many separate copies of the same contents `paths` are, counting the names of one
+    /// file on the disk once
+    fn copies(&self, paths: &[String]) -> u64 {
+        let mut seen = HashSet::new();
+        paths
+            .iter()
+            .filter(|path| match self.file_id(path) {
+                Some(id) => seen.insert(id),
+                None => true,
+            })
+            .count() as u64
+    }
+
+    /// The device and inode of a file kept on the local disk
+    #[cfg(unix)]
+    fn file_id(&self, path: &str) -> Option<(u64, u64)> {
+        use std::os::unix::fs::MetadataExt;
+        let metadata = std::fs::metadata(resolve_storage_path(&self.config.storage, path).ok()?).ok()?;
+        Some((metadata.dev(), metadata.ino()))
+    }
+
+    #[cfg(not(unix))]
+    fn file_id(&self, _path: &str) -> Option<(u64, u64)> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::Database;
+    use crate::services::{JobContext, JobService, JobStatus};
+    use crate::storage::LocalStorage;
+    use std::fs;
+
+    #[tokio::test]
+    async fn test_duplicates_are_found_trashed_and_linked() {
+        let dir = tempfile::tempdir().unwrap();
+        let home = dir.path().join("files");
+        let config = Config::for_tests(&home);
+        let config = Arc::new(config);
+        fs::create_dir_all(home.join("docs/sub")).unwrap();
+        fs::create_dir_all(home.join(TRASH_DIRECTORY)).unwrap();
+        for (path, data) in [
+            ("docs/a.txt", "same"),
+            ("docs/sub/b.txt", "same"),
+            ("x.txt", "same"),
+            ("near.txt", "sane"),
+            ("other.txt", "different"),
+            ("empty.txt", ""),
+            ("empty2.txt", ""),
+            (".trash/old.txt", "same"),
+        ] {
+            fs::write(home.join(path), data).unwrap();
+        }
+
+        let db = Database::new(&format!("sqlite:{}", dir.path().join("test.db").display())).await.unwrap();
+        let storage: Arc<dyn StorageBackend> = Arc::new(LocalStorage::new(home.clone()));
+        let checksums = Arc::new(ChecksumService::new(db.clone(), storage.clone()));
+        let service = Arc::new(DuplicateService::new(config.clone(), storage.clone(), checksums));
+        let jobs = Arc::new(JobService::new(db, config.clone()));
+        let finder = service.clone();
+        jobs.register("duplicates", move |path: String, context: JobContext| {
+            let finder = finder.clone();
+            async move { finder.find(&path, 0, &context.reporter).await }
+        });
+        let find = || async {
+            let job = jobs.submit("duplicates", "test", &"/").await.unwrap();
+            let job = jobs.wait(&job.id).await;
+            assert_eq!(job.status, JobStatus::Completed, "{:?}", job.error);
+            serde_json::from_value::<DuplicateReport>(job.result.unwrap()).unwrap()
+        };
+
+        let report = find().await;
+        assert_eq!(report.files, 5);
+        assert_eq!(report.sets.len(), 1);
+        assert_eq!(report.sets[0].paths, ["docs/a.txt", "docs/sub/b.txt", "x.txt"]);
+        assert_eq!(report.wasted, 8);
+
+        let files = FileService::new(config.as_ref().clone(), storage.clone());
+        let linked = service.resolve(&files, "docs/a.txt", "docs/sub/b.txt", DuplicateAction::Hardlink).await.unwrap();
+        assert_eq!(linked.size, 4);
+        let trashed = service.resolve(&files, "docs/a.txt", "/x.txt", DuplicateAction::Trash).await.unwrap();
+        assert!(trashed.path.starts_with(".trash/x_") && trashed.path.ends_with(".txt"));
+        assert!(!home.join("x.txt").exists());
+        assert!(matches!(
+            service.resolve(&files, "docs/a.txt", "near.txt", DuplicateAction::Trash).await,
+            Err(ApiError::Conflict { .. })
+        ));
+
+        // The linked copies share one file, and the trash isn't searched
+        assert!(find().await.sets.is_empty());
+        assert_eq!(fs::read_to_string(home.join("docs/sub/b.txt")).unwrap(), "same");
+    }
+}
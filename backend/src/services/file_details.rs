@@ -0,0 +1,118 @@
+use crate::utils::owners::OwnerNames;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::{fs, path::Path};
+
+/// What the local disk records about a file or directory beyond its `FileInfo`. Only
+/// `is_hidden` is known of files that aren't kept on the local disk.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct FileDetails {
+    /// Permission bits in octal, like `0644`
+    pub mode: Option<String>,
+    /// The same bits as `ls -l` shows them, like `rw-r--r--`
+    pub permissions: Option<String>,
+    pub uid: Option<u32>,
+    pub user: Option<String>,
+    pub gid: Option<u32>,
+    pub group: Option<String>,
+    pub created: Option<DateTime<Utc>>,
+    pub accessed: Option<DateTime<Utc>>,
+    pub inode: Option<u64>,
+    pub links: Option<u64>,
+    pub is_symlink: bool,
+    /// Where a symlink points, as it was written
+    pub symlink_target: Option<String>,
+    pub is_hidden: bool,
+}
+
+impl FileDetails {
+    /// What can be told from the name of an entry alone
+    pub fn of_name(name: &str) -> Self {
+        Self {
+            is_hidden: name.starts_with('.'),
+            ..Default::default()
+        }
+    }
+
+    /// Details of the entry called `name` at `path` on the local disk. A symlink is described
+    /// itself, not what it points to.
+    pub fn read(name: &str, path: &Path, owners: &mut OwnerNames) -> std::io::Result<Self> {
+        let metadata = fs::symlink_metadata(path)?;
+        let mut details = Self::of_name(name);
+        details.created = metadata.created().ok().map(DateTime::from);
+        details.accessed = metadata.accessed().ok().map(DateTime::from);
+        details.is_symlink = metadata.file_type().is_symlink();
+        if details.is_symlink {
+            details.symlink_target = Some(fs::read_link(path)?.to_string_lossy().into_owned());
+        }
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            let mode = metadata.mode() & 0o7777;
+            details.mode = Some(format!("{:04o}", mode));
+            details.permissions = Some(permissions(mode));
+            details.uid = Some(metadata.uid());
+            details.user = owners.user(metadata.uid());
+            details.gid = Some(metadata.gid());
+            details.group = owners.group(metadata.gid());
+            details.inode = Some(metadata.ino());
+            details.links = Some(metadata.nlink());
+        }
+        #[cfg(not(unix))]
+        let _ = owners;
+        Ok(details)
+    }
+}
+
+/// Permission bits as `ls -l` shows them, with the setuid, setgid and sticky bits in the
+/// execute columns
+pub fn permissions(mode: u32) -> String {
+    let mut columns: Vec<char> = "rwxrwxrwx"
+        .chars()
+        .enumerate()
+        .map(|(i, c)| if mode & (0o400 >> i) != 0 { c } else { '-' })
+        .collect();
+    for (bit, column, set) in [(0o4000, 2, 's'), (0o2000, 5, 's'), (0o1000, 8, 't')] {
+        if mode & bit != 0 {
+            columns[column] = match columns[column] {
+                'x' => set,
+                _ => set.to_ascii_uppercase(),
+            };
+        }
+    }
+    columns.into_iter().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_details_describe_the_entry_itself() {
+        assert_eq!(permissions(0o644), "rw-r--r--");
+        assert_eq!(permissions(0o4755), "rwsr-xr-x");
+        assert_eq!(permissions(0o1776), "rwxrwxrwT");
+
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join(".env"), "x").unwrap();
+        let mut owners = OwnerNames::default();
+        let details = FileDetails::read(".env", &dir.path().join(".env"), &mut owners).unwrap();
+        assert!(details.is_hidden && !details.is_symlink);
+        assert_eq!(details.links, Some(1));
+        assert!(details.inode.is_some() && details.uid.is_some());
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(dir.path().join(".env"), fs::Permissions::from_mode(0o640)).unwrap();
+            std::os::unix::fs::symlink(".env", dir.path().join("link")).unwrap();
+            let details = FileDetails::read("link", &dir.path().join("link"), &mut owners).unwrap();
+            assert!(details.is_symlink && !details.is_hidden);
+            assert_eq!(details.symlink_target.as_deref(), Some(".env"));
+            let details = FileDetails::read(".env", &dir.path().join(".env"), &mut owners).unwrap();
+            assert_eq!(details.mode.as_deref(), Some("0640"));
+            assert_eq!(details.permissions.as_deref(), Some("rw-r-----"));
+        }
+    }
+}
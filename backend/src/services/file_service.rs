@@ -1,16 +1,33 @@
 use crate::{
     config::Config,
     errors::ApiError,
-    utils::security::{resolve_path, validate_file_extension, validate_file_size},
+    services::{
+        normalize, parent_of, split_archive_path, Allowance, ArchiveBrowser, ChecksumService, ExpectedChecksum,
+        FileDetails, QuotaService, VersionService,
+    },
+    storage::{is_within, StorageBackend, StorageReader},
+    utils::{
+        owners::OwnerNames,
+        security::{validate_file_extension, validate_file_size},
+    },
 };
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use std::{
     fs,
+    ops::Range,
     path::Path,
+    sync::Arc,
     time::SystemTime,
 };
-use tokio::fs as async_fs;
+use tokio::{
+    fs as async_fs,
+    io::{AsyncReadExt, AsyncWriteExt},
+};
+use uuid::Uuid;
+
+/// Directory at the top of the storage, or of each mount, that trashed files are moved to
+pub const TRASH_DIRECTORY: &str = ".trash";
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct FileInfo {
@@ -22,6 +39,39 @@ pub struct FileInfo {
     pub mime_type: Option<String>,
 }
 
+impl FileInfo {
+    /// Build a `FileInfo` from metadata that has already been read from disk
+    pub(crate) fn from_metadata(name: String, relative_path: &str, metadata: &fs::Metadata) -> Self {
+        let modified_time = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+        let modified = DateTime::<Utc>::from(modified_time);
+        let is_directory = metadata.is_dir();
+
+        let mime_type = if !is_directory {
+            mime_guess::from_path(&name).first().map(|mime| mime.to_string())
+        } else {
+            None
+        };
+
+        FileInfo {
+            name,
+            path: relative_path.to_string(),
+            size: metadata.len(),
+            modified,
+            is_directory,
+            mime_type,
+        }
+    }
+
+    /// A quoted entity tag derived from the modification time and size
+    pub fn etag(&self) -> String {
+        format!(
+            "\"{:x}-{:x}\"",
+            self.modified.timestamp_nanos_opt().unwrap_or_default(),
+            self.size
+        )
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct UploadResult {
     pub uploaded: Vec<FileInfo>,
@@ -36,52 +86,140 @@ pub struct UploadError {
 
 pub struct FileService {
     config: Config,
+    storage: Arc<dyn StorageBackend>,
+    /// Email of the user the changes are made for
+    user: String,
+    /// Where overwritten contents are kept
+    versions: Option<Arc<VersionService>>,
+    quotas: Option<Arc<QuotaService>>,
+    /// Reads paths inside archives, like `bundle.zip!/inner/path`
+    archives: Option<Arc<ArchiveBrowser>>,
+    /// Where checksums verified while writing are remembered
+    checksums: Option<Arc<ChecksumService>>,
 }
 
 impl FileService {
-    pub fn new(config: Config) -> Self {
-        Self { config }
+    pub fn new(config: Config, storage: Arc<dyn StorageBackend>) -> Self {
+        Self {
+            config,
+            storage,
+            user: String::new(),
+            versions: None,
+            quotas: None,
+            archives: None,
+            checksums: None,
+        }
+    }
+
+    /// Make changes for `user`, who owns the files written and replaces the versions kept
+    pub fn as_user(mut self, user: &str) -> Self {
+        self.user = user.to_string();
+        self
+    }
+
+    /// Keep the previous contents of files this service overwrites as versions
+    pub fn with_versions(mut self, versions: Arc<VersionService>) -> Self {
+        self.versions = Some(versions);
+        self
+    }
+
+    /// Hold writes to the quotas of the user and of the directories written to, and keep
+    /// track of the usage they add
+    pub fn with_quotas(mut self, quotas: Arc<QuotaService>) -> Self {
+        self.quotas = Some(quotas);
+        self
+    }
+
+    /// Treat archives as directories that can be listed and read from
+    pub fn with_archives(mut self, archives: Arc<ArchiveBrowser>) -> Self {
+        self.archives = Some(archives);
+        self
+    }
+
+    /// Remember the checksums of files verified as they are written
+    pub fn with_checksums(mut self, checksums: Arc<ChecksumService>) -> Self {
+        self.checksums = Some(checksums);
+        self
+    }
+
+    /// The archive browser, if `path` is inside an archive and archives can be browsed
+    fn archive_browser(&self, path: &str) -> Option<&ArchiveBrowser> {
+        self.archives.as_deref().filter(|_| split_archive_path(path).is_some())
+    }
+
+    /// Keep the current contents of the file at `path`, if there is one, as a version
+    pub async fn preserve_version(&self, path: &str) -> Result<(), ApiError> {
+        self.preserve(&normalize(path)?).await.map(|_| ())
+    }
+
+    async fn preserve(&self, key: &str) -> Result<Option<String>, ApiError> {
+        match &self.versions {
+            Some(versions) => Ok(versions.preserve(key, &self.user).await?.map(|version| version.id)),
+            None => Ok(None),
+        }
+    }
+
+    /// Check that a file of `size` bytes, if known, can be written at `key` within the quotas
+    async fn allowance(&self, key: &str, size: Option<u64>) -> Result<Option<Allowance>, ApiError> {
+        match &self.quotas {
+            Some(quotas) => quotas.check_write(&self.user, key, size).await,
+            None => Ok(None),
+        }
+    }
+
+    /// Count a file that has been written towards the quotas. The write has happened, so a
+    /// failure is left for the next reconciliation.
+    async fn record_write(&self, key: &str, size: u64) {
+        if let Some(quotas) = &self.quotas {
+            if let Err(e) = quotas.record_write(key, &self.user, size).await {
+                tracing::warn!("Failed to record quota usage of {}: {}", key, e);
+            }
+        }
+    }
+
+    /// Information about `path`, or `None` if nothing is there
+    async fn find(&self, key: &str) -> Result<Option<FileInfo>, ApiError> {
+        match self.storage.stat(key).await {
+            Ok(info) => Ok(Some(info)),
+            Err(ApiError::FileNotFound { .. }) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Look up `path`, reporting it as the user wrote it
+    async fn stat(&self, path: &str, key: &str) -> Result<FileInfo, ApiError> {
+        match self.find(key).await? {
+            Some(info) => Ok(FileInfo {
+                path: path.to_string(),
+                ..info
+            }),
+            None => Err(ApiError::FileNotFound {
+                path: path.to_string(),
+            }),
+        }
     }
 
     /// List files and directories in the given path
     pub async fn list_files(&self, path: &str) -> Result<Vec<FileInfo>, ApiError> {
-        let resolved_path = resolve_path(&self.config.storage.home_directory, path)?;
-        
-        if !resolved_path.exists() {
-            return Err(ApiError::FileNotFound {
-                path: path.to_string(),
-            });
+        if let Some(archives) = self.archive_browser(path) {
+            return archives.list(path).await;
         }
+        let key = normalize(path)?;
+        let info = self.stat(path, &key).await?;
 
-        if !resolved_path.is_dir() {
+        if !info.is_directory {
             // If it's a file, return just that file's info
-            let file_info = self.get_file_info(&resolved_path, path)?;
-            return Ok(vec![file_info]);
+            return Ok(vec![info]);
         }
 
-        let mut files = Vec::new();
-        let entries = async_fs::read_dir(&resolved_path).await?;
-        let mut entries = tokio_stream::wrappers::ReadDirStream::new(entries);
-
-        use tokio_stream::StreamExt;
-        while let Some(entry) = entries.next().await {
-            let entry = entry?;
-            let entry_path = entry.path();
-            
+        let mut files = self.storage.list(&key).await?;
+        for file in &mut files {
             // Calculate relative path for the response
-            let relative_path = if path.is_empty() || path == "/" {
-                entry.file_name().to_string_lossy().to_string()
+            file.path = if path.is_empty() || path == "/" {
+                file.name.clone()
             } else {
-                format!("{}/{}", path.trim_end_matches('/'), entry.file_name().to_string_lossy())
+                format!("{}/{}", path.trim_end_matches('/'), file.name)
             };
-
-            match self.get_file_info(&entry_path, &relative_path) {
-                Ok(file_info) => files.push(file_info),
-                Err(e) => {
-                    tracing::warn!("Failed to get info for file {:?}: {}", entry_path, e);
-                    // Continue with other files
-                }
-            }
         }
 
         // Sort files: directories first, then by name
@@ -103,123 +241,117 @@ impl FileService {
         filename: &str,
         data: Vec<u8>,
     ) -> Result<FileInfo, ApiError> {
+        // Calculate relative path for response
+        let relative_path = if path.is_empty() || path == "/" {
+            filename.to_string()
+        } else {
+            format!("{}/{}", path.trim_end_matches('/'), filename)
+        };
+        let key = normalize(&relative_path)?;
+
         // Validate file extension
-        validate_file_extension(filename, &self.config.storage.allowed_extensions)?;
+        validate_file_extension(filename, self.config.storage.allowed_extensions_for(&key))?;
         
         // Validate file size
-        validate_file_size(data.len() as u64, self.config.storage.max_upload_size)?;
-
-        let target_dir = resolve_path(&self.config.storage.home_directory, path)?;
-        let file_path = target_dir.join(filename);
+        validate_file_size(data.len() as u64, self.config.storage.max_upload_size_for(&key))?;
 
         // Create directory if it doesn't exist
-        if let Some(parent) = file_path.parent() {
-            async_fs::create_dir_all(parent).await?;
-        }
+        self.storage.mkdir(&parent_of(&key), true).await?;
 
         // Check if file already exists
-        if file_path.exists() {
+        if self.find(&key).await?.is_some() {
             return Err(ApiError::FileExists {
-                path: file_path.to_string_lossy().to_string(),
+                path: relative_path,
             });
         }
+        self.allowance(&key, Some(data.len() as u64)).await?;
 
         // Write file
-        async_fs::write(&file_path, &data).await?;
+        let mut writer = self.storage.open_write(&key).await?;
+        writer.write_all(&data).await?;
+        let info = writer.commit().await?;
+        self.record_write(&key, info.size).await;
 
-        // Calculate relative path for response
-        let relative_path = if path.is_empty() || path == "/" {
-            filename.to_string()
-        } else {
-            format!("{}/{}", path.trim_end_matches('/'), filename)
-        };
-
-        self.get_file_info(&file_path, &relative_path)
+        Ok(FileInfo {
+            path: relative_path,
+            ..info
+        })
     }
 
     /// Download a file from the given path
     pub async fn download_file(&self, path: &str) -> Result<(Vec<u8>, String), ApiError> {
-        let resolved_path = resolve_path(&self.config.storage.home_directory, path)?;
-        
-        if !resolved_path.exists() {
-            return Err(ApiError::FileNotFound {
-                path: path.to_string(),
-            });
-        }
+        let (mut reader, info) = self.open_file(path, None).await?;
 
-        if resolved_path.is_dir() {
-            return Err(ApiError::BadRequest {
-                message: "Cannot download a directory".to_string(),
-            });
-        }
-
-        let data = async_fs::read(&resolved_path).await?;
-        let filename = resolved_path
-            .file_name()
-            .and_then(|name| name.to_str())
-            .unwrap_or("download")
-            .to_string();
+        let mut data = Vec::with_capacity(info.size as usize);
+        reader.read_to_end(&mut data).await?;
+        let filename = if info.name.is_empty() {
+            "download".to_string()
+        } else {
+            info.name
+        };
 
         Ok((data, filename))
     }
 
     /// Delete a file or directory
     pub async fn delete_file(&self, path: &str) -> Result<(), ApiError> {
-        let resolved_path = resolve_path(&self.config.storage.home_directory, path)?;
-        
-        if !resolved_path.exists() {
-            return Err(ApiError::FileNotFound {
-                path: path.to_string(),
-            });
-        }
+        let key = normalize(path)?;
+        self.stat(path, &key).await?;
 
-        if resolved_path.is_dir() {
-            async_fs::remove_dir_all(&resolved_path).await?;
-        } else {
-            async_fs::remove_file(&resolved_path).await?;
+        self.storage.delete(&key).await?;
+        if let Some(quotas) = &self.quotas {
+            if let Err(e) = quotas.record_delete(&key).await {
+                tracing::warn!("Failed to record quota usage of {}: {}", path, e);
+            }
         }
-
         Ok(())
     }
 
     /// Rename/move a file or directory
     pub async fn rename_file(&self, from_path: &str, to_path: &str) -> Result<FileInfo, ApiError> {
-        let resolved_from_path = resolve_path(&self.config.storage.home_directory, from_path)?;
-        let resolved_to_path = resolve_path(&self.config.storage.home_directory, to_path)?;
+        let (from_key, to_key) = (normalize(from_path)?, normalize(to_path)?);
         
         // Check if source file exists
-        if !resolved_from_path.exists() {
-            return Err(ApiError::FileNotFound {
-                path: from_path.to_string(),
-            });
-        }
+        self.stat(from_path, &from_key).await?;
 
         // Check if destination already exists
-        if resolved_to_path.exists() {
+        if self.find(&to_key).await?.is_some() {
             return Err(ApiError::FileExists {
                 path: to_path.to_string(),
             });
         }
 
-        // Create parent directory if it doesn't exist
-        if let Some(parent) = resolved_to_path.parent() {
-            async_fs::create_dir_all(parent).await?;
+        if let Some(quotas) = &self.quotas {
+            quotas.check_move(&from_key, &to_key).await?;
         }
 
+        // Create parent directory if it doesn't exist
+        self.storage.mkdir(&parent_of(&to_key), true).await?;
+
         // Rename/move the file
-        async_fs::rename(&resolved_from_path, &resolved_to_path).await?;
+        self.storage.rename(&from_key, &to_key).await?;
+        if let Some(versions) = &self.versions {
+            if let Err(e) = versions.rename(&from_key, &to_key).await {
+                tracing::warn!("Failed to move the versions of {}: {}", from_path, e);
+            }
+        }
+        if let Some(quotas) = &self.quotas {
+            if let Err(e) = quotas.record_move(&from_key, &to_key).await {
+                tracing::warn!("Failed to record quota usage of {}: {}", to_path, e);
+            }
+        }
 
         // Return file info for the renamed file
-        self.get_file_info(&resolved_to_path, to_path)
+        self.stat(to_path, &to_key).await
     }
 
     /// Create a directory
     pub async fn create_directory(&self, path: &str, recursive: bool) -> Result<FileInfo, ApiError> {
-        let resolved_path = resolve_path(&self.config.storage.home_directory, path)?;
+        let key = normalize(path)?;
         
         // Check if directory already exists
-        if resolved_path.exists() {
-            if resolved_path.is_dir() {
+        if let Some(info) = self.find(&key).await? {
+            if info.is_directory {
                 return Err(ApiError::BadRequest {
                     message: "Directory already exists".to_string(),
                 });
@@ -230,43 +362,416 @@ impl FileService {
             }
         }
 
-        // Create directory
-        if recursive {
-            async_fs::create_dir_all(&resolved_path).await?;
-        } else {
-            async_fs::create_dir(&resolved_path).await?;
-        }
+        self.storage.mkdir(&key, recursive).await?;
 
         // Return file info for the created directory
-        self.get_file_info(&resolved_path, path)
+        self.stat(path, &key).await
     }
 
-    /// Get file information
-    fn get_file_info(&self, file_path: &Path, relative_path: &str) -> Result<FileInfo, ApiError> {
-        let metadata = fs::metadata(file_path)?;
-        let modified_time = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
-        let modified = DateTime::<Utc>::from(modified_time);
-        let is_directory = metadata.is_dir();
-        
-        let name = file_path
-            .file_name()
-            .and_then(|name| name.to_str())
-            .unwrap_or("")
-            .to_string();
+    /// Create a directory and any missing parents; an existing directory is fine
+    pub async fn ensure_directory(&self, path: &str) -> Result<(), ApiError> {
+        self.storage.mkdir(&normalize(path)?, true).await
+    }
 
-        let mime_type = if !is_directory {
-            mime_guess::from_path(file_path).first().map(|mime| mime.to_string())
-        } else {
-            None
-        };
+    /// Get information about a single file or directory
+    pub async fn get_info(&self, path: &str) -> Result<FileInfo, ApiError> {
+        if let Some(archives) = self.archive_browser(path) {
+            return archives.stat(path).await;
+        }
+        self.stat(path, &normalize(path)?).await
+    }
 
-        Ok(FileInfo {
-            name,
-            path: relative_path.to_string(),
-            size: metadata.len(),
-            modified,
-            is_directory,
-            mime_type,
+    /// Information about a single file or directory with what the local disk records about it
+    pub async fn get_details(&self, path: &str) -> Result<(FileInfo, FileDetails), ApiError> {
+        let info = self.get_info(path).await?;
+        let details = self.details(std::slice::from_ref(&info)).await?.pop().unwrap_or_default();
+        Ok((info, details))
+    }
+
+    /// The details of each of a number of files and directories, read from the local disk
+    /// for those the storage backend keeps on it
+    pub async fn details(&self, infos: &[FileInfo]) -> Result<Vec<FileDetails>, ApiError> {
+        let entries: Vec<_> = infos
+            .iter()
+            .map(|info| {
+                let on_disk = match split_archive_path(&info.path) {
+                    Some(_) => None,
+                    None => normalize(&info.path).ok().and_then(|key| self.storage.local_path(&key)),
+                };
+                (info.name.clone(), on_disk)
+            })
+            .collect();
+
+        tokio::task::spawn_blocking(move || {
+            let mut owners = OwnerNames::default();
+            entries
+                .into_iter()
+                .map(|(name, on_disk)| {
+                    on_disk
+                        .and_then(|path| FileDetails::read(&name, &path, &mut owners).ok())
+                        .unwrap_or_else(|| FileDetails::of_name(&name))
+                })
+                .collect()
+        })
+        .await
+        .map_err(|e| ApiError::InternalServerError {
+            message: format!("Stat task failed: {}", e),
         })
     }
+
+    /// Open a file for streaming reads, optionally of only the bytes in `range`
+    pub async fn open_file(&self, path: &str, range: Option<Range<u64>>) -> Result<(StorageReader, FileInfo), ApiError> {
+        if let Some(archives) = self.archive_browser(path) {
+            return archives.open(path, range).await;
+        }
+        let key = normalize(path)?;
+        let info = self.stat(path, &key).await?;
+        if info.is_directory {
+            return Err(ApiError::BadRequest {
+                message: "Cannot download a directory".to_string(),
+            });
+        }
+
+        let reader = self.storage.open_read(&key, range).await?;
+        Ok((reader, info))
+    }
+
+    /// Write a file from a stream of chunks, replacing any existing file once it has been
+    /// written in full. The parent directory must already exist.
+    /// Returns the file info and whether it was created.
+    pub async fn write_file<S, E>(&self, path: &str, body: S) -> Result<(FileInfo, bool), ApiError>
+    where
+        S: futures::Stream<Item = Result<bytes::Bytes, E>> + Unpin,
+        E: std::fmt::Display,
+    {
+        self.write_file_verified(path, body, &[]).await
+    }
+
+    /// Write a file as `write_file` does, but only replace what is at `path` if the contents
+    /// have each of the `expected` checksums; `ChecksumMismatch` otherwise
+    pub async fn write_file_verified<S, E>(
+        &self,
+        path: &str,
+        mut body: S,
+        expected: &[ExpectedChecksum],
+    ) -> Result<(FileInfo, bool), ApiError>
+    where
+        S: futures::Stream<Item = Result<bytes::Bytes, E>> + Unpin,
+        E: std::fmt::Display,
+    {
+        use futures::StreamExt;
+
+        let key = normalize(path)?;
+        self.check_writable(path, &key).await?;
+        let parent_exists = self
+            .find(&parent_of(&key))
+            .await?
+            .is_some_and(|parent| parent.is_directory);
+        if !parent_exists {
+            return Err(ApiError::Conflict {
+                message: "Parent directory does not exist".to_string(),
+            });
+        }
+
+        let created = self.find(&key).await?.is_none();
+        let allowance = self.allowance(&key, None).await?;
+        let mut writer = self.storage.open_write(&key).await?;
+        let mut written: u64 = 0;
+        let mut hashers: Vec<_> = expected.iter().map(|expected| expected.algorithm.hasher()).collect();
+
+        // Dropping the writer on error leaves any existing file untouched
+        while let Some(chunk) = body.next().await {
+            let chunk = chunk.map_err(|e| ApiError::BadRequest {
+                message: format!("Failed to read request body: {}", e),
+            })?;
+            written += chunk.len() as u64;
+            validate_file_size(written, self.config.storage.max_upload_size_for(&key))?;
+            if let Some(allowance) = &allowance {
+                allowance.check(written)?;
+            }
+            for hasher in &mut hashers {
+                hasher.update(&chunk);
+            }
+            writer.write_all(&chunk).await?;
+        }
+        for (expected, hasher) in expected.iter().zip(hashers) {
+            expected.verify(&hasher.finalize())?;
+        }
+        if !created {
+            self.preserve(&key).await?;
+        }
+        let info = writer.commit().await?;
+        self.record_write(&key, info.size).await;
+        if let Some(checksums) = &self.checksums {
+            for expected in expected {
+                let value = hex::encode(&expected.digest);
+                if let Err(e) = checksums.record(&key, &info, expected.algorithm, &value).await {
+                    tracing::warn!("Failed to record the checksum of {}: {}", path, e);
+                }
+            }
+        }
+
+        Ok((FileInfo { path: path.to_string(), ..info }, created))
+    }
+
+    /// Move a fully written file from outside the storage directory into place at `path`,
+    /// replacing any existing file and creating missing parent directories.
+    /// Returns the file info and whether it was created.
+    pub async fn install_file(&self, path: &str, source: &Path) -> Result<(FileInfo, bool), ApiError> {
+        let key = normalize(path)?;
+        self.check_writable(path, &key).await?;
+        let size = async_fs::metadata(source).await?.len();
+        validate_file_size(size, self.config.storage.max_upload_size_for(&key))?;
+        self.allowance(&key, Some(size)).await?;
+
+        self.storage.mkdir(&parent_of(&key), true).await?;
+        let created = self.find(&key).await?.is_none();
+        if !created {
+            self.preserve(&key).await?;
+        }
+        let info = self.storage.import(&key, source).await?;
+        self.record_write(&key, info.size).await;
+
+        Ok((FileInfo { path: path.to_string(), ..info }, created))
+    }
+
+    /// Create or replace a file from contents the storage already has, identified by their
+    /// SHA-256, so that they don't have to be uploaded again. The parent directory must exist.
+    /// Returns the file info and whether it was created.
+    pub async fn link_file(&self, path: &str, sha256: &str) -> Result<(FileInfo, bool), ApiError> {
+        let key = normalize(path)?;
+        self.check_writable(path, &key).await?;
+
+        let created = self.find(&key).await?.is_none();
+        // The size of the contents isn't known until they are found
+        self.allowance(&key, None).await?;
+        let version = match created {
+            true => None,
+            false => self.preserve(&key).await?,
+        };
+        let Some(info) = self.storage.link(&key, sha256).await? else {
+            // Nothing was replaced after all
+            if let (Some(versions), Some(id)) = (&self.versions, version) {
+                versions.delete(&id).await?;
+            }
+            return Err(ApiError::NotFound {
+                resource: "Contents".to_string(),
+                id: sha256.to_string(),
+            });
+        };
+
+        self.record_write(&key, info.size).await;
+
+        Ok((FileInfo { path: path.to_string(), ..info }, created))
+    }
+
+    /// Copy a file or directory tree. The destination must not exist and its parent must.
+    pub async fn copy_path(&self, from_path: &str, to_path: &str) -> Result<FileInfo, ApiError> {
+        let (from_key, to_key) = (normalize(from_path)?, normalize(to_path)?);
+
+        self.stat(from_path, &from_key).await?;
+        if self.find(&to_key).await?.is_some() {
+            return Err(ApiError::FileExists {
+                path: to_path.to_string(),
+            });
+        }
+
+        if let Some(quotas) = &self.quotas {
+            quotas.check_copy(&self.user, &from_key, &to_key).await?;
+        }
+
+        self.storage.copy(&from_key, &to_key).await?;
+        if let Some(quotas) = &self.quotas {
+            if let Err(e) = quotas.record_copy(&from_key, &to_key, &self.user).await {
+                tracing::warn!("Failed to record quota usage of {}: {}", to_path, e);
+            }
+        }
+        self.stat(to_path, &to_key).await
+    }
+
+    /// Move a file or directory into the trash at the top of its mount, named with the time
+    /// it was trashed, and return its info there
+    pub async fn trash(&self, path: &str) -> Result<FileInfo, ApiError> {
+        let key = normalize(path)?;
+        let info = self.stat(path, &key).await?;
+        let (top, trash) = match self.config.storage.mount(&key) {
+            Some(mount) => (mount.name.as_str(), format!("{}/{}", mount.name, TRASH_DIRECTORY)),
+            None => ("", TRASH_DIRECTORY.to_string()),
+        };
+        if key == top || is_within(&key, &trash) {
+            return Err(ApiError::BadRequest {
+                message: format!("{} can't be moved to the trash", path),
+            });
+        }
+
+        let (stem, extension) = match info.name.rsplit_once('.') {
+            Some((stem, extension)) if !stem.is_empty() && !info.is_directory => (stem, format!(".{}", extension)),
+            _ => (info.name.as_str(), String::new()),
+        };
+        let stamp = Utc::now().format("%Y%m%d_%H%M%S");
+        let mut attempt = 0;
+        loop {
+            let name = match attempt {
+                0 => format!("{}_{}{}", stem, stamp, extension),
+                n => format!("{}_{}_{}{}", stem, stamp, n, extension),
+            };
+            let to = format!("{}/{}", trash, name);
+            if self.find(&to).await?.is_none() {
+                return self.rename_file(path, &to).await;
+            }
+            attempt += 1;
+        }
+    }
+
+    /// Replace the file at `path` with a hard link to the file at `target`, so that both
+    /// names share one copy on the disk. Both have to be on the local disk, in the same
+    /// writable mount.
+    pub async fn hard_link(&self, target: &str, path: &str) -> Result<FileInfo, ApiError> {
+        let (target_key, key) = (normalize(target)?, normalize(path)?);
+        self.storage.check_writable(&key)?;
+        for (path, key) in [(target, &target_key), (path, &key)] {
+            if self.stat(path, key).await?.is_directory {
+                return Err(ApiError::BadRequest {
+                    message: format!("{} is a directory", path),
+                });
+            }
+        }
+        let storage = &self.config.storage;
+        if storage.mount(&target_key).map(|mount| &mount.name) != storage.mount(&key).map(|mount| &mount.name) {
+            return Err(ApiError::BadRequest {
+                message: format!("{} and {} are on different mounts", target, path),
+            });
+        }
+
+        let (Some(from), Some(to)) = (self.storage.local_path(&target_key), self.storage.local_path(&key)) else {
+            return Err(ApiError::BadRequest {
+                message: format!("{} and {} must both be kept as files on the local disk to be linked", target, path),
+            });
+        };
+        tokio::task::spawn_blocking(move || link_over(&from, &to))
+            .await
+            .map_err(|e| ApiError::InternalServerError {
+                message: format!("Link task failed: {}", e),
+            })??;
+        self.stat(path, &key).await
+    }
+
+    /// Check that a file with an allowed name can be written at `path`
+    async fn check_writable(&self, path: &str, key: &str) -> Result<(), ApiError> {
+        let filename = key.rsplit('/').next().filter(|name| !name.is_empty()).ok_or_else(|| {
+            ApiError::InvalidPath {
+                path: path.to_string(),
+            }
+        })?;
+        validate_file_extension(filename, self.config.storage.allowed_extensions_for(key))?;
+
+        if self.find(key).await?.is_some_and(|info| info.is_directory) {
+            return Err(ApiError::Conflict {
+                message: "A directory with this name already exists".to_string(),
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Make `path` a hard link to `target`, replacing the file there in one step
+fn link_over(target: &Path, path: &Path) -> Result<(), ApiError> {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let temp = path.with_file_name(format!(".{}.{}.link", name, Uuid::new_v4().simple()));
+    fs::hard_link(target, &temp).map_err(|e| match e.kind() {
+        std::io::ErrorKind::CrossesDevices => ApiError::BadRequest {
+            message: "The files are on different filesystems".to_string(),
+        },
+        _ => e.into(),
+    })?;
+    if let Err(e) = fs::rename(&temp, path) {
+        let _ = fs::remove_file(&temp);
+        return Err(e.into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{LocalStorage, MemoryStorage};
+    use bytes::Bytes;
+
+    fn file_service() -> FileService {
+        let mut config = Config::for_tests(".");
+        config.storage.allowed_extensions = vec!["txt".to_string()];
+        config.storage.max_upload_size = 16;
+        FileService::new(config, Arc::new(MemoryStorage::new()))
+    }
+
+    fn body(chunks: &[&'static str]) -> impl futures::Stream<Item = Result<Bytes, std::io::Error>> + Unpin {
+        futures::stream::iter(chunks.iter().map(|chunk| Ok(Bytes::from(*chunk))).collect::<Vec<_>>())
+    }
+
+    #[tokio::test]
+    async fn test_failed_writes_leave_existing_file_in_place() {
+        let files = file_service();
+        files.create_directory("/docs", true).await.unwrap();
+        let (info, created) = files.write_file("docs/a.txt", body(&["hello"])).await.unwrap();
+        assert!(created);
+        assert_eq!(info.path, "docs/a.txt");
+
+        // Too large, so it fails part way through
+        let result = files.write_file("docs/a.txt", body(&["0123456789", "0123456789"])).await;
+        assert!(matches!(result, Err(ApiError::FileTooLarge { .. })));
+        assert_eq!(files.download_file("docs/a.txt").await.unwrap().0, b"hello");
+
+        // Arrives intact in size, but not in content
+        let expected = [ExpectedChecksum::parse("md5:5d41402abc4b2a76b9719d911017c592").unwrap()];
+        let result = files.write_file_verified("docs/a.txt", body(&["jello"]), &expected).await;
+        assert!(matches!(result, Err(ApiError::ChecksumMismatch { .. })));
+        assert_eq!(files.download_file("docs/a.txt").await.unwrap().0, b"hello");
+
+        assert!(matches!(
+            files.write_file("docs/a.exe", body(&["x"])).await,
+            Err(ApiError::InvalidFileType { .. })
+        ));
+        assert!(matches!(
+            files.write_file("missing/a.txt", body(&["x"])).await,
+            Err(ApiError::Conflict { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_list_files_puts_directories_first() {
+        let files = file_service();
+        files.upload_file("/", "a.txt", b"a".to_vec()).await.unwrap();
+        files.create_directory("b", true).await.unwrap();
+        files.rename_file("a.txt", "b/c.txt").await.unwrap();
+        files.upload_file("/", "d.txt", b"d".to_vec()).await.unwrap();
+        files.create_directory("z", true).await.unwrap();
+
+        let root: Vec<String> = files.list_files("/").await.unwrap().into_iter().map(|info| info.path).collect();
+        assert_eq!(root, vec!["b", "z", "d.txt"]);
+        let nested: Vec<String> = files.list_files("/b").await.unwrap().into_iter().map(|info| info.path).collect();
+        assert_eq!(nested, vec!["/b/c.txt"]);
+    }
+
+    #[tokio::test]
+    async fn test_links_and_details_need_files_on_the_local_disk() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("a.txt"), "on disk").unwrap();
+        let config = Config::for_tests(dir.path());
+
+        // The configured directory has an a.txt too, but it isn't the one in storage
+        let files = FileService::new(config.clone(), Arc::new(MemoryStorage::new()));
+        files.upload_file("/", "a.txt", b"a".to_vec()).await.unwrap();
+        files.upload_file("/", "b.txt", b"a".to_vec()).await.unwrap();
+        let (_, details) = files.get_details("a.txt").await.unwrap();
+        assert_eq!((details.mode, details.inode), (None, None));
+        assert!(matches!(files.hard_link("a.txt", "b.txt").await, Err(ApiError::BadRequest { .. })));
+        assert_eq!(files.download_file("b.txt").await.unwrap().0, b"a");
+
+        let files = FileService::new(config, Arc::new(LocalStorage::new(dir.path())));
+        files.upload_file("/", "b.txt", b"b".to_vec()).await.unwrap();
+        files.hard_link("a.txt", "b.txt").await.unwrap();
+        let (_, details) = files.get_details("b.txt").await.unwrap();
+        assert_eq!(details.links, Some(2));
+        assert_eq!(files.download_file("b.txt").await.unwrap().0, b"on disk");
+    }
 }
@@ -0,0 +1,819 @@
+use crate::{
+    config::Config,
+    db::Database,
+    errors::ApiError,
+    services::{ChangeEvent, ContentIndexer, FileChange, FileInfo, SearchFilter, SearchHit},
+    storage::StorageBackend,
+    utils::security::validate_path,
+};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sqlx::{QueryBuilder, Row, Sqlite};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+use tokio::sync::{broadcast, Mutex};
+
+const LAST_FULL_SCAN_KEY: &str = "last_full_scan";
+const LAST_UPDATED_KEY: &str = "last_updated";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IndexState {
+    Disabled,
+    Empty,
+    Building,
+    Ready,
+}
+
+#[derive(Debug, Serialize)]
+pub struct IndexStatus {
+    pub status: IndexState,
+    pub total_entries: i64,
+    pub indexed_files: i64,
+    pub indexed_directories: i64,
+    pub last_full_scan: Option<DateTime<Utc>>,
+    pub last_updated: Option<DateTime<Utc>>,
+    /// Size of the SQLite database holding the index, in bytes
+    pub index_size: i64,
+    pub pending_updates: usize,
+    /// Files whose text has been through content extraction
+    pub content_indexed_files: i64,
+    /// Files waiting for content extraction
+    pub content_queue: usize,
+    pub statistics: IndexStatistics,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct IndexStatistics {
+    pub documents: i64,
+    pub images: i64,
+    pub videos: i64,
+    pub audio: i64,
+    pub other: i64,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct ScanSummary {
+    pub path: String,
+    pub directories_scanned: u64,
+    pub entries_written: u64,
+    pub entries_removed: u64,
+    /// Time spent scanning in milliseconds
+    pub took: u64,
+}
+
+/// Parameters for answering a search from the index
+pub(crate) struct IndexSearch<'a> {
+    pub base: &'a str,
+    /// Literal text every matching name must contain, used to narrow candidates through FTS
+    pub literal: Option<&'a str>,
+    pub filter: &'a SearchFilter,
+    pub max_depth: usize,
+    pub offset: usize,
+    pub limit: usize,
+    pub deadline: Instant,
+}
+
+pub(crate) struct IndexSearchOutcome {
+    pub results: Vec<SearchHit>,
+    pub has_more: bool,
+    pub timed_out: bool,
+}
+
+struct IndexEntry {
+    name: String,
+    size: i64,
+    modified: i64,
+    is_directory: bool,
+    mime_type: Option<String>,
+}
+
+/// The contents of one directory, as read by the scanner
+struct DirectoryBatch {
+    parent: String,
+    entries: Vec<IndexEntry>,
+}
+
+/// Persistent metadata index of the storage, kept in SQLite
+pub struct IndexService {
+    db: Database,
+    config: Arc<Config>,
+    storage: Arc<dyn StorageBackend>,
+    content: Arc<ContentIndexer>,
+    scan_lock: Mutex<()>,
+    ready: AtomicBool,
+    pending_updates: AtomicUsize,
+}
+
+impl IndexService {
+    pub fn new(db: Database, config: Arc<Config>, storage: Arc<dyn StorageBackend>) -> Self {
+        Self {
+            content: Arc::new(ContentIndexer::new(db.clone(), config.clone(), storage.clone())),
+            db,
+            config,
+            storage,
+            scan_lock: Mutex::new(()),
+            ready: AtomicBool::new(false),
+            pending_updates: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.config.search.index_enabled
+    }
+
+    /// True once a full scan has completed and searches can be answered from the index
+    pub fn is_ready(&self) -> bool {
+        self.is_enabled() && self.ready.load(Ordering::Acquire)
+    }
+
+    pub fn is_scanning(&self) -> bool {
+        self.scan_lock.try_lock().is_err()
+    }
+
+    /// Run the initial scan if the index has never been built, apply changes from the
+    /// change feed as they arrive, and reconcile periodically
+    pub fn start_background_tasks(self: &Arc<Self>, changes: broadcast::Receiver<ChangeEvent>) {
+        if !self.is_enabled() {
+            return;
+        }
+
+        let index = self.clone();
+        tokio::spawn(async move { index.follow_changes(changes).await });
+
+        let index = self.clone();
+        tokio::spawn(async move {
+            index.content.start_workers().await;
+
+            match index.get_meta(LAST_FULL_SCAN_KEY).await {
+                Ok(Some(_)) => {
+                    index.ready.store(true, Ordering::Release);
+                    index.content.spawn_enqueue_stale("");
+                }
+                Ok(None) => {
+                    tracing::info!("Search index is empty, building it in the background");
+                    if let Err(e) = index.rebuild(&[]).await {
+                        tracing::error!("Initial index build failed: {}", e);
+                    }
+                }
+                Err(e) => tracing::error!("Failed to read index metadata: {}", e),
+            }
+
+            let interval_seconds = index.config.search.reconcile_interval_seconds;
+            if interval_seconds == 0 {
+                return;
+            }
+
+            let mut interval = tokio::time::interval(Duration::from_secs(interval_seconds));
+            // The first tick completes immediately and we've just scanned
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                match index.update(&[]).await {
+                    Ok(summaries) => {
+                        for summary in summaries {
+                            tracing::info!(
+                                "Index reconciliation: {} written, {} removed in {}ms",
+                                summary.entries_written,
+                                summary.entries_removed,
+                                summary.took
+                            );
+                        }
+                    }
+                    Err(ApiError::Conflict { .. }) => {
+                        tracing::debug!("Skipping index reconciliation, a scan is already running");
+                    }
+                    Err(e) => tracing::error!("Index reconciliation failed: {}", e),
+                }
+            }
+        });
+    }
+
+    /// Drop and re-scan the given subtrees (the whole tree when `paths` is empty)
+    pub async fn rebuild(&self, paths: &[String]) -> Result<Vec<ScanSummary>, ApiError> {
+        self.ensure_enabled()?;
+        let _guard = self.scan_lock.try_lock().map_err(|_| ApiError::Conflict {
+            message: "An index scan is already running".to_string(),
+        })?;
+
+        let paths = normalize_all(paths)?;
+        let mut summaries = Vec::new();
+        for path in paths {
+            let full_scan = path.is_empty();
+            if full_scan {
+                self.ready.store(false, Ordering::Release);
+            }
+
+            let removed = self.remove_subtree(&path).await?;
+            let mut summary = self.refresh_path(&path).await?;
+            summary.entries_removed += removed;
+
+            if full_scan {
+                self.set_meta(LAST_FULL_SCAN_KEY, &Utc::now().to_rfc3339()).await?;
+                self.ready.store(true, Ordering::Release);
+            }
+            summaries.push(summary);
+        }
+
+        Ok(summaries)
+    }
+
+    /// Incrementally reconcile the given subtrees with the disk (the whole tree when empty)
+    pub async fn update(&self, paths: &[String]) -> Result<Vec<ScanSummary>, ApiError> {
+        self.ensure_enabled()?;
+        let _guard = self.scan_lock.try_lock().map_err(|_| ApiError::Conflict {
+            message: "An index scan is already running".to_string(),
+        })?;
+
+        let paths = normalize_all(paths)?;
+        let mut summaries = Vec::new();
+        for path in paths {
+            summaries.push(self.reconcile(&path).await?);
+        }
+
+        Ok(summaries)
+    }
+
+    async fn reconcile(&self, path: &str) -> Result<ScanSummary, ApiError> {
+        let summary = self.refresh_path(path).await?;
+        if path.is_empty() {
+            self.set_meta(LAST_FULL_SCAN_KEY, &Utc::now().to_rfc3339()).await?;
+            self.ready.store(true, Ordering::Release);
+        }
+        Ok(summary)
+    }
+
+    /// Keep the index in line with the change feed until it closes
+    async fn follow_changes(&self, mut changes: broadcast::Receiver<ChangeEvent>) {
+        loop {
+            match changes.recv().await {
+                Ok(ChangeEvent::Changed(batch)) => {
+                    self.pending_updates.fetch_add(1, Ordering::AcqRel);
+                    // A rebuild drops and re-adds subtrees, which must not interleave with this
+                    let _guard = self.scan_lock.lock().await;
+                    for path in refresh_roots(&batch) {
+                        if let Err(e) = self.refresh_path(path).await {
+                            tracing::warn!("Failed to update search index for {}: {}", path, e);
+                        }
+                    }
+                    self.pending_updates.fetch_sub(1, Ordering::AcqRel);
+                }
+                Ok(ChangeEvent::Rescan { .. }) | Err(broadcast::error::RecvError::Lagged(_)) => {
+                    // Wait for any running scan rather than skipping: it may have started
+                    // before the changes we missed
+                    let _guard = self.scan_lock.lock().await;
+                    match self.reconcile("").await {
+                        Ok(summary) => tracing::info!(
+                            "Index rescan after missed changes: {} written, {} removed in {}ms",
+                            summary.entries_written,
+                            summary.entries_removed,
+                            summary.took
+                        ),
+                        Err(e) => tracing::error!("Index rescan failed: {}", e),
+                    }
+                }
+                Err(broadcast::error::RecvError::Closed) => return,
+            }
+        }
+    }
+
+    pub async fn status(&self) -> Result<IndexStatus, ApiError> {
+        let pool = self.db.pool();
+
+        let counts = sqlx::query(
+            r#"
+            SELECT
+                COUNT(*) AS total,
+                COALESCE(SUM(CASE WHEN is_directory THEN 0 ELSE 1 END), 0) AS files,
+                COALESCE(SUM(CASE WHEN is_directory THEN 1 ELSE 0 END), 0) AS directories,
+                COALESCE(SUM(CASE WHEN mime_type LIKE 'image/%' THEN 1 ELSE 0 END), 0) AS images,
+                COALESCE(SUM(CASE WHEN mime_type LIKE 'video/%' THEN 1 ELSE 0 END), 0) AS videos,
+                COALESCE(SUM(CASE WHEN mime_type LIKE 'audio/%' THEN 1 ELSE 0 END), 0) AS audio,
+                COALESCE(SUM(CASE WHEN mime_type LIKE 'text/%'
+                    OR mime_type IN ('application/pdf', 'application/msword', 'application/rtf', 'application/json')
+                    OR mime_type LIKE 'application/vnd.openxmlformats-officedocument.%'
+                    OR mime_type LIKE 'application/vnd.oasis.opendocument.%'
+                    THEN 1 ELSE 0 END), 0) AS documents
+            FROM file_index
+            "#,
+        )
+        .fetch_one(pool)
+        .await?;
+
+        let index_size: i64 = sqlx::query_scalar(
+            "SELECT page_count * page_size FROM pragma_page_count(), pragma_page_size()",
+        )
+        .fetch_one(pool)
+        .await?;
+
+        let files: i64 = counts.get("files");
+        let documents: i64 = counts.get("documents");
+        let images: i64 = counts.get("images");
+        let videos: i64 = counts.get("videos");
+        let audio: i64 = counts.get("audio");
+
+        let status = if !self.is_enabled() {
+            IndexState::Disabled
+        } else if self.is_scanning() {
+            IndexState::Building
+        } else if self.is_ready() {
+            IndexState::Ready
+        } else {
+            IndexState::Empty
+        };
+
+        Ok(IndexStatus {
+            status,
+            total_entries: counts.get("total"),
+            indexed_files: files,
+            indexed_directories: counts.get("directories"),
+            last_full_scan: self.get_meta_time(LAST_FULL_SCAN_KEY).await?,
+            last_updated: self.get_meta_time(LAST_UPDATED_KEY).await?,
+            index_size,
+            pending_updates: self.pending_updates.load(Ordering::Acquire),
+            content_indexed_files: self.content.indexed_count().await?,
+            content_queue: self.content.queued(),
+            statistics: IndexStatistics {
+                documents,
+                images,
+                videos,
+                audio,
+                other: (files - documents - images - videos - audio).max(0),
+            },
+        })
+    }
+
+    /// Answer a search from the index instead of walking the disk
+    pub(crate) async fn search(&self, search: IndexSearch<'_>) -> Result<IndexSearchOutcome, ApiError> {
+        use futures::TryStreamExt;
+
+        let mut builder: QueryBuilder<Sqlite> = QueryBuilder::new(
+            "SELECT path, name, size, modified, is_directory, mime_type FROM file_index WHERE 1 = 1",
+        );
+
+        // Both go through an index: one level down is the base's children, anything deeper
+        // the range of paths below it
+        if search.max_depth == 1 {
+            builder.push(" AND parent = ").push_bind(search.base);
+        } else if !search.base.is_empty() {
+            let (lower, upper) = subtree_bounds(search.base);
+            builder.push(" AND path > ").push_bind(lower);
+            builder.push(" AND path < ").push_bind(upper);
+        }
+
+        // Trigram FTS only helps with at least three characters of literal text
+        if let Some(literal) = search.literal.filter(|literal| literal.chars().count() >= 3) {
+            builder
+                .push(" AND rowid IN (SELECT rowid FROM file_index_fts WHERE name LIKE ")
+                .push_bind(format!("%{}%", literal))
+                .push(")");
+        }
+
+        if let Some(entry_type) = search.filter.entry_type() {
+            builder
+                .push(" AND is_directory = ")
+                .push_bind(entry_type == crate::services::EntryType::Directory);
+        }
+        if let Some(min_size) = search.filter.min_size() {
+            builder.push(" AND size >= ").push_bind(min_size as i64);
+        }
+        if let Some(max_size) = search.filter.max_size() {
+            builder.push(" AND size <= ").push_bind(max_size as i64);
+        }
+        if let Some(after) = search.filter.modified_after() {
+            builder.push(" AND modified >= ").push_bind(after.timestamp_millis());
+        }
+        if let Some(before) = search.filter.modified_before() {
+            builder.push(" AND modified <= ").push_bind(before.timestamp_millis());
+        }
+
+        builder.push(" ORDER BY path");
+
+        let base_depth = depth_of(search.base);
+        let mut results = Vec::new();
+        let mut skipped = 0;
+        let mut has_more = false;
+        let mut timed_out = false;
+
+        let query = builder.build();
+        let mut rows = query.fetch(self.db.pool());
+        while let Some(row) = rows.try_next().await? {
+            if Instant::now() >= search.deadline {
+                timed_out = true;
+                break;
+            }
+
+            let path: String = row.get("path");
+            if depth_of(&path) - base_depth > search.max_depth {
+                continue;
+            }
+
+            let name: String = row.get("name");
+            if !search.filter.matches_name(&name) {
+                continue;
+            }
+
+            let modified: i64 = row.get("modified");
+            let size: i64 = row.get("size");
+            let file_info = FileInfo {
+                name,
+                path,
+                size: size.max(0) as u64,
+                modified: DateTime::<Utc>::from_timestamp_millis(modified).unwrap_or_default(),
+                is_directory: row.get("is_directory"),
+                mime_type: row.get("mime_type"),
+            };
+            if !search.filter.matches_info(&file_info) {
+                continue;
+            }
+
+            if skipped < search.offset {
+                skipped += 1;
+                continue;
+            }
+
+            if results.len() == search.limit {
+                has_more = true;
+                break;
+            }
+
+            results.push(file_info.into());
+        }
+
+        Ok(IndexSearchOutcome {
+            results,
+            has_more,
+            timed_out,
+        })
+    }
+
+    /// Answer a full-text query over extracted file contents
+    pub(crate) async fn search_content(
+        &self,
+        search: &IndexSearch<'_>,
+        text: &str,
+    ) -> Result<IndexSearchOutcome, ApiError> {
+        if !self.content.is_enabled() {
+            return Err(ApiError::BadRequest {
+                message: "Content indexing is disabled".to_string(),
+            });
+        }
+        self.content.search(search, text).await
+    }
+
+    /// Bring the index entry for `path` (and everything below it) in line with the storage
+    async fn refresh_path(&self, path: &str) -> Result<ScanSummary, ApiError> {
+        let started = Instant::now();
+        let mut summary = ScanSummary {
+            path: path.to_string(),
+            ..Default::default()
+        };
+
+        if !path.is_empty() {
+            match self.storage.stat(path).await {
+                Ok(info) => {
+                    summary.entries_written += self.upsert_ancestors(path).await?;
+                    let entry = index_entry(info);
+                    self.upsert(&parent_of(path), &entry).await?;
+                    summary.entries_written += 1;
+
+                    if !entry.is_directory {
+                        self.content
+                            .try_enqueue(path, &entry.name, entry.mime_type.as_deref());
+                        self.touch_last_updated().await?;
+                        summary.took = started.elapsed().as_millis() as u64;
+                        return Ok(summary);
+                    }
+                }
+                Err(ApiError::FileNotFound { .. }) => {
+                    summary.entries_removed += self.remove_subtree(path).await?;
+                    self.touch_last_updated().await?;
+                    summary.took = started.elapsed().as_millis() as u64;
+                    return Ok(summary);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        // Depth-first, one batch per directory
+        let mut pending = vec![path.to_string()];
+        while let Some(directory) = pending.pop() {
+            let entries = match self.storage.list(&directory).await {
+                Ok(entries) => entries,
+                Err(e) => {
+                    // Leave existing rows alone rather than dropping a subtree we can't read
+                    tracing::warn!("Skipping unreadable directory {:?} during indexing: {}", directory, e);
+                    continue;
+                }
+            };
+            pending.extend(entries.iter().filter(|info| info.is_directory).map(|info| info.path.clone()));
+
+            let batch = DirectoryBatch {
+                parent: directory,
+                entries: entries.into_iter().map(index_entry).collect(),
+            };
+            let (written, removed) = self.apply_batch(&batch).await?;
+            summary.directories_scanned += 1;
+            summary.entries_written += written;
+            summary.entries_removed += removed;
+        }
+
+        // Queue extraction for every file in the subtree that changed since it was last read
+        self.content.spawn_enqueue_stale(path);
+
+        self.touch_last_updated().await?;
+        summary.took = started.elapsed().as_millis() as u64;
+        Ok(summary)
+    }
+
+    /// Write one directory's entries, skipping unchanged rows and dropping vanished ones
+    async fn apply_batch(&self, batch: &DirectoryBatch) -> Result<(u64, u64), ApiError> {
+        // Read outside the write transaction: a deferred transaction that reads first can't
+        // upgrade to a write lock while another writer is active. A stale read only means a
+        // redundant upsert.
+        let existing_rows = sqlx::query(
+            "SELECT name, size, modified, is_directory FROM file_index WHERE parent = ?",
+        )
+        .bind(&batch.parent)
+        .fetch_all(self.db.pool())
+        .await?;
+
+        let mut existing: HashMap<String, (i64, i64, bool)> = existing_rows
+            .into_iter()
+            .map(|row| {
+                (
+                    row.get::<String, _>("name"),
+                    (row.get("size"), row.get("modified"), row.get("is_directory")),
+                )
+            })
+            .collect();
+
+        let mut tx = self.db.pool().begin().await?;
+        let mut written = 0;
+        for entry in &batch.entries {
+            let unchanged = existing
+                .remove(&entry.name)
+                .is_some_and(|(size, modified, is_directory)| {
+                    size == entry.size && modified == entry.modified && is_directory == entry.is_directory
+                });
+            if unchanged {
+                continue;
+            }
+
+            upsert_entry(&mut tx, &batch.parent, entry).await?;
+            written += 1;
+        }
+
+        // Whatever is left in `existing` no longer exists on disk
+        let mut removed = 0;
+        for name in existing.keys() {
+            let path = join_relative(&batch.parent, name);
+            let (lower, upper) = subtree_bounds(&path);
+            let result = sqlx::query("DELETE FROM file_index WHERE path = ? OR (path > ? AND path < ?)")
+                .bind(&path)
+                .bind(lower)
+                .bind(upper)
+                .execute(&mut *tx)
+                .await?;
+            removed += result.rows_affected();
+        }
+
+        tx.commit().await?;
+        Ok((written, removed))
+    }
+
+    async fn upsert(&self, parent: &str, entry: &IndexEntry) -> Result<(), ApiError> {
+        let mut conn = self.db.pool().acquire().await?;
+        upsert_entry(&mut conn, parent, entry).await
+    }
+
+    /// Make sure every directory above `path` is indexed
+    async fn upsert_ancestors(&self, path: &str) -> Result<u64, ApiError> {
+        let mut written = 0;
+        let mut ancestor = parent_of(path);
+        while !ancestor.is_empty() {
+            if let Ok(info) = self.storage.stat(&ancestor).await {
+                self.upsert(&parent_of(&ancestor), &index_entry(info)).await?;
+                written += 1;
+            }
+            ancestor = parent_of(&ancestor);
+        }
+        Ok(written)
+    }
+
+    async fn remove_subtree(&self, path: &str) -> Result<u64, ApiError> {
+        let result = if path.is_empty() {
+            sqlx::query("DELETE FROM file_index")
+                .execute(self.db.pool())
+                .await?
+        } else {
+            let (lower, upper) = subtree_bounds(path);
+            sqlx::query("DELETE FROM file_index WHERE path = ? OR (path > ? AND path < ?)")
+                .bind(path)
+                .bind(lower)
+                .bind(upper)
+                .execute(self.db.pool())
+                .await?
+        };
+        Ok(result.rows_affected())
+    }
+
+    fn ensure_enabled(&self) -> Result<(), ApiError> {
+        if self.is_enabled() {
+            Ok(())
+        } else {
+            Err(ApiError::BadRequest {
+                message: "The search index is disabled".to_string(),
+            })
+        }
+    }
+
+    async fn touch_last_updated(&self) -> Result<(), ApiError> {
+        self.set_meta(LAST_UPDATED_KEY, &Utc::now().to_rfc3339()).await
+    }
+
+    async fn get_meta(&self, key: &str) -> Result<Option<String>, ApiError> {
+        let value = sqlx::query_scalar("SELECT value FROM index_meta WHERE key = ?")
+            .bind(key)
+            .fetch_optional(self.db.pool())
+            .await?;
+        Ok(value)
+    }
+
+    async fn get_meta_time(&self, key: &str) -> Result<Option<DateTime<Utc>>, ApiError> {
+        Ok(self.get_meta(key).await?.and_then(|value| {
+            DateTime::parse_from_rfc3339(&value)
+                .ok()
+                .map(|dt| dt.with_timezone(&Utc))
+        }))
+    }
+
+    async fn set_meta(&self, key: &str, value: &str) -> Result<(), ApiError> {
+        sqlx::query(
+            "INSERT INTO index_meta (key, value) VALUES (?, ?) ON CONFLICT(key) DO UPDATE SET value = excluded.value",
+        )
+        .bind(key)
+        .bind(value)
+        .execute(self.db.pool())
+        .await?;
+        Ok(())
+    }
+}
+
+async fn upsert_entry(
+    conn: &mut sqlx::SqliteConnection,
+    parent: &str,
+    entry: &IndexEntry,
+) -> Result<(), ApiError> {
+    sqlx::query(
+        r#"
+        INSERT INTO file_index (path, parent, name, size, modified, is_directory, mime_type, indexed_at)
+        VALUES (?, ?, ?, ?, ?, ?, ?, datetime('now'))
+        ON CONFLICT(path) DO UPDATE SET
+            size = excluded.size,
+            modified = excluded.modified,
+            is_directory = excluded.is_directory,
+            mime_type = excluded.mime_type,
+            indexed_at = excluded.indexed_at
+        "#,
+    )
+    .bind(join_relative(parent, &entry.name))
+    .bind(parent)
+    .bind(&entry.name)
+    .bind(entry.size)
+    .bind(entry.modified)
+    .bind(entry.is_directory)
+    .bind(&entry.mime_type)
+    .execute(conn)
+    .await?;
+    Ok(())
+}
+
+fn index_entry(info: FileInfo) -> IndexEntry {
+    IndexEntry {
+        name: info.name,
+        size: info.size as i64,
+        modified: info.modified.timestamp_millis(),
+        is_directory: info.is_directory,
+        mime_type: info.mime_type,
+    }
+}
+
+/// The paths touched by a batch that aren't inside another touched path;
+/// refreshing a directory already covers everything below it
+fn refresh_roots(batch: &[FileChange]) -> Vec<&str> {
+    let mut paths: Vec<&str> = batch
+        .iter()
+        .flat_map(|change| std::iter::once(change.path.as_str()).chain(change.from.as_deref()))
+        .collect();
+    // Sorting by components keeps every path directly after its ancestors
+    paths.sort_by(|a, b| a.split('/').cmp(b.split('/')));
+    paths.dedup();
+
+    let mut roots: Vec<&str> = Vec::new();
+    for path in paths {
+        let covered = roots.last().is_some_and(|root| {
+            path.starts_with(root) && path.as_bytes().get(root.len()) == Some(&b'/')
+        });
+        if !covered {
+            roots.push(path);
+        }
+    }
+    roots
+}
+
+/// Normalize a user path to the index form: relative, `/`-separated, "" for the root
+pub(crate) fn normalize(path: &str) -> Result<String, ApiError> {
+    let validated = validate_path(path)?;
+    let parts: Vec<String> = validated
+        .components()
+        .filter_map(|component| match component {
+            std::path::Component::Normal(part) => Some(part.to_string_lossy().to_string()),
+            _ => None,
+        })
+        .collect();
+    Ok(parts.join("/"))
+}
+
+fn normalize_all(paths: &[String]) -> Result<Vec<String>, ApiError> {
+    if paths.is_empty() {
+        return Ok(vec![String::new()]);
+    }
+    paths.iter().map(|path| normalize(path)).collect()
+}
+
+fn join_relative(parent: &str, name: &str) -> String {
+    if parent.is_empty() {
+        name.to_string()
+    } else {
+        format!("{}/{}", parent, name)
+    }
+}
+
+pub(crate) fn parent_of(path: &str) -> String {
+    path.rsplit_once('/')
+        .map(|(parent, _)| parent.to_string())
+        .unwrap_or_default()
+}
+
+pub(crate) fn depth_of(path: &str) -> usize {
+    if path.is_empty() {
+        0
+    } else {
+        path.matches('/').count() + 1
+    }
+}
+
+/// Exclusive bounds selecting every path strictly below `path`.
+/// `'0'` is the character after `'/'`, so `[path/, path0)` covers the whole subtree.
+pub(crate) fn subtree_bounds(path: &str) -> (String, String) {
+    (format!("{}/", path), format!("{}0", path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize_paths() {
+        assert_eq!(normalize("/").unwrap(), "");
+        assert_eq!(normalize("/documents/").unwrap(), "documents");
+        assert_eq!(normalize("documents/./reports").unwrap(), "documents/reports");
+        assert!(normalize("../etc").is_err());
+    }
+
+    #[test]
+    fn test_subtree_bounds_exclude_siblings() {
+        let (lower, upper) = subtree_bounds("docs");
+        let inside = |path: &str| path > lower.as_str() && path < upper.as_str();
+        assert!(inside("docs/a.txt"));
+        assert!(inside("docs/nested/b.txt"));
+        assert!(!inside("docs"));
+        assert!(!inside("docs-old/a.txt"));
+        assert!(!inside("docs2/a.txt"));
+    }
+
+    #[test]
+    fn test_refresh_roots_skip_covered_paths() {
+        let change = |path: &str, from: Option<&str>| FileChange {
+            id: 0,
+            path: path.to_string(),
+            kind: crate::services::ChangeKind::Modified,
+            from: from.map(str::to_string),
+        };
+        let batch = vec![
+            change("docs", None),
+            change("docs-old/c.txt", None),
+            change("docs/a.txt", None),
+            change("docs/nested/b.txt", None),
+            change("notes.txt", Some("docs2/notes.txt")),
+        ];
+        assert_eq!(refresh_roots(&batch), vec!["docs", "docs-old/c.txt", "docs2/notes.txt", "notes.txt"]);
+    }
+}
@@ -0,0 +1,527 @@
+use crate::{config::Config, db::Database, errors::ApiError};
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use futures::{future::BoxFuture, FutureExt};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sqlx::{sqlite::SqliteRow, QueryBuilder, Row, Sqlite};
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{Arc, Mutex, RwLock},
+};
+use tokio::sync::{watch, Semaphore};
+use tokio_util::sync::CancellationToken;
+use uuid::Uuid;
+
+/// How often the progress of a running job is saved
+const PROGRESS_SAVE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(2);
+/// How often finished jobs past `jobs.retention_days` are deleted
+const CLEANUP_INTERVAL: std::time::Duration = std::time::Duration::from_secs(3600);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JobStatus {
+    /// Waiting for a worker
+    Queued,
+    Running,
+    Completed,
+    Failed,
+    Cancelled,
+}
+
+impl JobStatus {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Queued => "queued",
+            Self::Running => "running",
+            Self::Completed => "completed",
+            Self::Failed => "failed",
+            Self::Cancelled => "cancelled",
+        }
+    }
+
+    fn parse(status: &str) -> Self {
+        match status {
+            "queued" => Self::Queued,
+            "running" => Self::Running,
+            "completed" => Self::Completed,
+            "cancelled" => Self::Cancelled,
+            _ => Self::Failed,
+        }
+    }
+
+    pub fn is_finished(self) -> bool {
+        !matches!(self, Self::Queued | Self::Running)
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct JobProgress {
+    pub done: u64,
+    /// Unknown until the job has looked at what it has to do
+    pub total: Option<u64>,
+}
+
+/// A long operation run in the background
+#[derive(Debug, Clone, Serialize)]
+pub struct Job {
+    pub id: String,
+    pub kind: String,
+    /// Email of the user who started it
+    pub owner: String,
+    /// What the job was started with
+    pub params: serde_json::Value,
+    pub status: JobStatus,
+    pub progress: JobProgress,
+    /// What the job did, once completed
+    pub result: Option<serde_json::Value>,
+    pub error: Option<String>,
+    /// The job this one is a retry of
+    pub retry_of: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub started_at: Option<DateTime<Utc>>,
+    pub finished_at: Option<DateTime<Utc>>,
+}
+
+impl Job {
+    fn from_row(row: &SqliteRow) -> Self {
+        let json = |column: &str| {
+            row.get::<Option<String>, _>(column)
+                .and_then(|value| serde_json::from_str(&value).ok())
+        };
+        Self {
+            id: row.get("id"),
+            kind: row.get("kind"),
+            owner: row.get("owner"),
+            params: json("params").unwrap_or_default(),
+            status: JobStatus::parse(row.get("status")),
+            progress: JobProgress {
+                done: row.get::<i64, _>("done") as u64,
+                total: row.get::<Option<i64>, _>("total").map(|total| total as u64),
+            },
+            result: json("result"),
+            error: row.get("error"),
+            retry_of: row.get("retry_of"),
+            created_at: from_millis(row.get("created_at")),
+            started_at: row.get::<Option<i64>, _>("started_at").map(from_millis),
+            finished_at: row.get::<Option<i64>, _>("finished_at").map(from_millis),
+        }
+    }
+}
+
+fn from_millis(millis: i64) -> DateTime<Utc> {
+    Utc.timestamp_millis_opt(millis).single().unwrap_or_default()
+}
+
+/// Given to a job handler along with the job's parameters
+pub struct JobContext {
+    /// Email of the user who started the job; it runs on their behalf
+    pub owner: String,
+    pub reporter: JobReporter,
+}
+
+/// Lets a running job report how far it has got, and find out whether it was cancelled
+#[derive(Clone)]
+pub struct JobReporter {
+    updates: watch::Sender<Job>,
+    cancel: CancellationToken,
+}
+
+impl JobReporter {
+    /// How much there is to do, in whatever unit the job counts in
+    pub fn set_total(&self, total: Option<u64>) {
+        self.updates.send_modify(|job| job.progress.total = total);
+    }
+
+    /// How much has been done so far
+    pub fn progress(&self, done: u64) {
+        self.updates.send_modify(|job| job.progress.done = done);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancel.is_cancelled()
+    }
+
+    /// Fails once the job has been cancelled. A cancelled job stops at its next `.await`;
+    /// work on a blocking thread has to check for itself.
+    pub fn check_cancelled(&self) -> Result<(), ApiError> {
+        match self.cancel.is_cancelled() {
+            true => Err(ApiError::Conflict {
+                message: "The job was cancelled".to_string(),
+            }),
+            false => Ok(()),
+        }
+    }
+}
+
+type Handler =
+    Arc<dyn Fn(serde_json::Value, JobContext) -> BoxFuture<'static, Result<serde_json::Value, ApiError>> + Send + Sync>;
+
+/// A job that hasn't finished yet
+struct ActiveJob {
+    updates: watch::Sender<Job>,
+    cancel: CancellationToken,
+}
+
+/// Runs long operations outside of the request that started them, on a bounded pool of
+/// workers. Jobs are recorded in SQLite with their parameters, progress and outcome, so they
+/// can be looked up and retried later; each kind of job is run by the handler registered
+/// for it.
+pub struct JobService {
+    db: Database,
+    config: Arc<Config>,
+    handlers: RwLock<HashMap<String, Handler>>,
+    active: Mutex<HashMap<String, ActiveJob>>,
+    workers: Arc<Semaphore>,
+}
+
+impl JobService {
+    pub fn new(db: Database, config: Arc<Config>) -> Self {
+        Self {
+            db,
+            workers: Arc::new(Semaphore::new(config.jobs.workers.max(1))),
+            config,
+            handlers: RwLock::new(HashMap::new()),
+            active: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Run jobs of `kind` with `handler`, which is given the parameters they were submitted
+    /// with and returns their result
+    pub fn register<P, F, Fut, R>(&self, kind: &str, handler: F)
+    where
+        P: DeserializeOwned + Send + 'static,
+        F: Fn(P, JobContext) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<R, ApiError>> + Send + 'static,
+        R: Serialize,
+    {
+        let handler = Arc::new(handler);
+        let handler: Handler = Arc::new(move |params, context| {
+            let handler = handler.clone();
+            async move {
+                let params = serde_json::from_value(params).map_err(|e| ApiError::BadRequest {
+                    message: format!("Invalid job parameters: {}", e),
+                })?;
+                let result = handler(params, context).await?;
+                serde_json::to_value(result).map_err(|e| ApiError::Internal(e.into()))
+            }
+            .boxed()
+        });
+        self.handlers.write().unwrap().insert(kind.to_string(), handler);
+    }
+
+    /// Mark jobs left queued or running by a previous run of the server as failed. Call
+    /// before submitting any.
+    pub async fn recover(&self) -> Result<u64, ApiError> {
+        let result = sqlx::query(
+            "UPDATE jobs SET status = 'failed', error = ?, finished_at = ? WHERE status IN ('queued', 'running')",
+        )
+        .bind("Interrupted by a server restart")
+        .bind(Utc::now().timestamp_millis())
+        .execute(self.db.pool())
+        .await?;
+        Ok(result.rows_affected())
+    }
+
+    /// Delete finished jobs past the retention period every hour
+    pub fn start_background_tasks(self: &Arc<Self>) {
+        let service = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(CLEANUP_INTERVAL);
+            loop {
+                interval.tick().await;
+                match service.delete_expired().await {
+                    Ok(0) => {}
+                    Ok(count) => tracing::info!("Deleted {} finished jobs", count),
+                    Err(e) => tracing::error!("Failed to delete finished jobs: {}", e),
+                }
+            }
+        });
+    }
+
+    async fn delete_expired(&self) -> Result<u64, ApiError> {
+        let cutoff = Utc::now() - Duration::days(self.config.jobs.retention_days.into());
+        let result = sqlx::query("DELETE FROM jobs WHERE finished_at IS NOT NULL AND finished_at < ?")
+            .bind(cutoff.timestamp_millis())
+            .execute(self.db.pool())
+            .await?;
+        Ok(result.rows_affected())
+    }
+
+    /// Queue a job of `kind` for `owner`. It starts once a worker is free.
+    pub async fn submit<P: Serialize>(self: &Arc<Self>, kind: &str, owner: &str, params: &P) -> Result<Job, ApiError> {
+        let params = serde_json::to_value(params).map_err(|e| ApiError::Internal(e.into()))?;
+        self.enqueue(kind, owner, params, None).await
+    }
+
+    /// Queue a failed or cancelled job again, as a new job with the same parameters
+    pub async fn retry(self: &Arc<Self>, id: &str) -> Result<Job, ApiError> {
+        let job = self.get(id).await?.ok_or_else(|| not_found(id))?;
+        if !matches!(job.status, JobStatus::Failed | JobStatus::Cancelled) {
+            return Err(ApiError::Conflict {
+                message: "Only failed or cancelled jobs can be retried".to_string(),
+            });
+        }
+        self.enqueue(&job.kind, &job.owner, job.params, Some(job.id)).await
+    }
+
+    async fn enqueue(
+        self: &Arc<Self>,
+        kind: &str,
+        owner: &str,
+        params: serde_json::Value,
+        retry_of: Option<String>,
+    ) -> Result<Job, ApiError> {
+        let handler = self.handlers.read().unwrap().get(kind).cloned().ok_or_else(|| {
+            ApiError::InternalServerError {
+                message: format!("No handler for {} jobs", kind),
+            }
+        })?;
+
+        let job = Job {
+            id: Uuid::new_v4().simple().to_string(),
+            kind: kind.to_string(),
+            owner: owner.to_string(),
+            params,
+            status: JobStatus::Queued,
+            progress: JobProgress::default(),
+            result: None,
+            error: None,
+            retry_of,
+            created_at: Utc::now(),
+            started_at: None,
+            finished_at: None,
+        };
+        sqlx::query(
+            "INSERT INTO jobs (id, kind, owner, params, status, retry_of, created_at) VALUES (?, ?, ?, ?, ?, ?, ?)",
+        )
+        .bind(&job.id)
+        .bind(&job.kind)
+        .bind(&job.owner)
+        .bind(job.params.to_string())
+        .bind(job.status.as_str())
+        .bind(&job.retry_of)
+        .bind(job.created_at.timestamp_millis())
+        .execute(self.db.pool())
+        .await?;
+
+        let (updates, _) = watch::channel(job.clone());
+        let cancel = CancellationToken::new();
+        self.active.lock().unwrap().insert(
+            job.id.clone(),
+            ActiveJob {
+                updates: updates.clone(),
+                cancel: cancel.clone(),
+            },
+        );
+        tokio::spawn(self.clone().run(handler, updates, cancel));
+        Ok(job)
+    }
+
+    async fn run(self: Arc<Self>, handler: Handler, updates: watch::Sender<Job>, cancel: CancellationToken) {
+        let permit = tokio::select! {
+            permit = self.workers.clone().acquire_owned() => permit.ok(),
+            _ = cancel.cancelled() => None,
+        };
+
+        // `None` when cancelled before finishing
+        let outcome = match permit {
+            Some(_permit) => {
+                updates.send_modify(|job| {
+                    job.status = JobStatus::Running;
+                    job.started_at = Some(Utc::now());
+                });
+                let job = updates.borrow().clone();
+                self.save(&job).await;
+
+                let context = JobContext {
+                    owner: job.owner.clone(),
+                    reporter: JobReporter {
+                        updates: updates.clone(),
+                        cancel: cancel.clone(),
+                    },
+                };
+                let mut task = handler(job.params, context);
+                let mut interval = tokio::time::interval(PROGRESS_SAVE_INTERVAL);
+                interval.tick().await;
+                loop {
+                    tokio::select! {
+                        outcome = &mut task => break Some(outcome),
+                        _ = cancel.cancelled() => break None,
+                        _ = interval.tick() => {
+                            let job = updates.borrow().clone();
+                            self.save(&job).await;
+                        }
+                    }
+                }
+            }
+            None => None,
+        };
+
+        updates.send_modify(|job| {
+            match outcome {
+                Some(Ok(result)) => {
+                    job.status = JobStatus::Completed;
+                    job.result = Some(result);
+                }
+                Some(Err(e)) if !cancel.is_cancelled() => {
+                    tracing::warn!("Job {} failed: {}", job.id, e);
+                    job.status = JobStatus::Failed;
+                    job.error = Some(e.to_string());
+                }
+                _ => job.status = JobStatus::Cancelled,
+            }
+            job.finished_at = Some(Utc::now());
+        });
+        let job = updates.borrow().clone();
+        self.save(&job).await;
+        self.active.lock().unwrap().remove(&job.id);
+    }
+
+    /// Record the state of `job`. Failures are logged: the job carries on regardless.
+    async fn save(&self, job: &Job) {
+        let result = sqlx::query(
+            "UPDATE jobs SET status = ?, done = ?, total = ?, result = ?, error = ?, started_at = ?, finished_at = ? WHERE id = ?",
+        )
+        .bind(job.status.as_str())
+        .bind(job.progress.done as i64)
+        .bind(job.progress.total.map(|total| total as i64))
+        .bind(job.result.as_ref().map(|result| result.to_string()))
+        .bind(&job.error)
+        .bind(job.started_at.map(|at| at.timestamp_millis()))
+        .bind(job.finished_at.map(|at| at.timestamp_millis()))
+        .bind(&job.id)
+        .execute(self.db.pool())
+        .await;
+        if let Err(e) = result {
+            tracing::error!("Failed to save the state of job {}: {}", job.id, e);
+        }
+    }
+
+    /// The job as it is now, including the progress of a running one
+    pub async fn get(&self, id: &str) -> Result<Option<Job>, ApiError> {
+        if let Some(active) = self.active.lock().unwrap().get(id) {
+            return Ok(Some(active.updates.borrow().clone()));
+        }
+        let row = sqlx::query("SELECT * FROM jobs WHERE id = ?")
+            .bind(id)
+            .fetch_optional(self.db.pool())
+            .await?;
+        Ok(row.as_ref().map(Job::from_row))
+    }
+
+    /// Up to `limit` jobs started by `owner`, or by anyone when `None`, newest first
+    pub async fn list(&self, owner: Option<&str>, status: Option<JobStatus>, limit: u32) -> Result<Vec<Job>, ApiError> {
+        let mut query = QueryBuilder::<Sqlite>::new("SELECT * FROM jobs WHERE 1 = 1");
+        if let Some(owner) = owner {
+            query.push(" AND owner = ").push_bind(owner);
+        }
+        if let Some(status) = status {
+            query.push(" AND status = ").push_bind(status.as_str());
+        }
+        query.push(" ORDER BY created_at DESC LIMIT ").push_bind(limit as i64);
+        let rows = query.build().fetch_all(self.db.pool()).await?;
+
+        let active = self.active.lock().unwrap();
+        Ok(rows
+            .iter()
+            .map(|row| {
+                let job = Job::from_row(row);
+                match active.get(&job.id) {
+                    Some(active) => active.updates.borrow().clone(),
+                    None => job,
+                }
+            })
+            .collect())
+    }
+
+    /// Follow the changes to a job that hasn't finished; `None` once it has
+    pub fn watch(&self, id: &str) -> Option<watch::Receiver<Job>> {
+        self.active.lock().unwrap().get(id).map(|active| active.updates.subscribe())
+    }
+
+    /// Stop a queued or running job
+    pub async fn cancel(&self, id: &str) -> Result<Job, ApiError> {
+        if let Some(active) = self.active.lock().unwrap().get(id) {
+            active.cancel.cancel();
+            return Ok(active.updates.borrow().clone());
+        }
+        match self.get(id).await? {
+            Some(_) => Err(ApiError::Conflict {
+                message: "The job has already finished".to_string(),
+            }),
+            None => Err(not_found(id)),
+        }
+    }
+}
+
+#[cfg(test)]
+impl JobService {
+    /// Wait for a job to finish
+    pub(crate) async fn wait(&self, id: &str) -> Job {
+        if let Some(mut updates) = self.watch(id) {
+            if let Ok(job) = updates.wait_for(|job| job.status.is_finished()).await {
+                return job.clone();
+            }
+        }
+        // Finished before it could be watched, and saved by then
+        self.get(id).await.unwrap().expect("no such job")
+    }
+}
+
+fn not_found(id: &str) -> ApiError {
+    ApiError::NotFound {
+        resource: "Job".to_string(),
+        id: id.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_jobs_queue_cancel_retry_and_recover() {
+        let dir = tempfile::tempdir().unwrap();
+        let url = format!("sqlite:{}", dir.path().join("test.db").display());
+        let db = Database::new(&url).await.unwrap();
+        let mut config = Config::for_tests(".");
+        config.jobs.workers = 1;
+        let config = Arc::new(config);
+        let jobs = Arc::new(JobService::new(db.clone(), config.clone()));
+        // Sleeps for the given number of milliseconds, or forever
+        jobs.register("sleep", |millis: Option<u64>, context: JobContext| async move {
+            context.reporter.set_total(millis);
+            match millis {
+                Some(millis) => tokio::time::sleep(std::time::Duration::from_millis(millis)).await,
+                None => futures::future::pending().await,
+            }
+            Ok(context.owner)
+        });
+
+        let stuck = jobs.submit("sleep", "alice", &None::<u64>).await.unwrap();
+        let waiting = jobs.submit("sleep", "bob", &Some(5)).await.unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        assert_eq!(jobs.get(&stuck.id).await.unwrap().unwrap().status, JobStatus::Running);
+        assert_eq!(jobs.get(&waiting.id).await.unwrap().unwrap().status, JobStatus::Queued);
+
+        jobs.cancel(&stuck.id).await.unwrap();
+        assert_eq!(jobs.wait(&stuck.id).await.status, JobStatus::Cancelled);
+        let done = jobs.wait(&waiting.id).await;
+        assert_eq!(done.status, JobStatus::Completed);
+        assert_eq!(done.result, Some(serde_json::json!("bob")));
+        assert_eq!(done.progress.total, Some(5));
+        assert!(matches!(jobs.cancel(&waiting.id).await, Err(ApiError::Conflict { .. })));
+        assert!(matches!(jobs.retry(&waiting.id).await, Err(ApiError::Conflict { .. })));
+        assert_eq!(jobs.list(Some("bob"), None, 10).await.unwrap().len(), 1);
+
+        // Left running when the server stops, then failed when it starts again
+        let retried = jobs.retry(&stuck.id).await.unwrap();
+        assert_eq!(retried.retry_of.as_deref(), Some(stuck.id.as_str()));
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        let restarted = JobService::new(db, config);
+        assert_eq!(restarted.recover().await.unwrap(), 1);
+        let job = restarted.get(&retried.id).await.unwrap().unwrap();
+        assert_eq!(job.status, JobStatus::Failed);
+        assert_eq!(job.params, serde_json::Value::Null);
+    }
+}
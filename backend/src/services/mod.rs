@@ -1,5 +1,39 @@
 pub mod file_service;
 pub mod auth_service;
+pub mod search_service;
+pub mod index_service;
+pub mod content_indexer;
+pub mod change_feed;
+pub mod webdav_service;
+pub mod s3_service;
+pub mod version_service;
+pub mod quota_service;
+pub mod disk_usage_service;
+pub mod thumbnail_service;
+pub mod archive_service;
+pub mod archive_browser;
+pub mod job_service;
+pub mod checksum_service;
+pub mod duplicate_service;
+pub mod file_details;
+pub mod attribute_service;
 
 pub use file_service::*;
 pub use auth_service::*;
+pub use search_service::*;
+pub use index_service::*;
+pub use content_indexer::*;
+pub use change_feed::*;
+pub use webdav_service::*;
+pub use s3_service::*;
+pub use version_service::*;
+pub use quota_service::*;
+pub use disk_usage_service::*;
+pub use thumbnail_service::*;
+pub use archive_service::*;
+pub use archive_browser::*;
+pub use job_service::*;
+pub use checksum_service::*;
+pub use duplicate_service::*;
+pub use file_details::*;
+pub use attribute_service::*;
@@ -0,0 +1,712 @@
+use crate::{
+    config::Config,
+    db::Database,
+    errors::ApiError,
+    services::normalize,
+    storage::StorageBackend,
+};
+use chrono::Utc;
+use serde::Serialize;
+use sqlx::SqliteConnection;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
+
+const USER: &str = "user";
+const GROUP: &str = "group";
+const PATH: &str = "path";
+
+/// SQL matching `column` when it is the path bound to `param` or below it
+fn in_subtree(column: &str, param: u8) -> String {
+    format!(
+        "(?{param} = '' OR {column} = ?{param} OR ({column} > ?{param} || '/' AND {column} < ?{param} || '0'))"
+    )
+}
+
+/// Whether normalized `path` is `root` or below it
+fn within(path: &str, root: &str) -> bool {
+    root.is_empty() || path == root || path.strip_prefix(root).is_some_and(|rest| rest.starts_with('/'))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Target {
+    /// A user's email, or `"*"` for every user without a quota of their own
+    User(String),
+    Group(String),
+    Path(String),
+}
+
+#[derive(Debug, Clone)]
+struct Quota {
+    target: Target,
+    max_bytes: Option<u64>,
+    max_files: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct Usage {
+    pub bytes: u64,
+    pub files: u64,
+}
+
+/// A quota and how much of it is used
+#[derive(Debug, Serialize)]
+pub struct QuotaStatus {
+    /// `user`, `group` or `path`
+    pub scope: &'static str,
+    pub name: String,
+    pub max_bytes: Option<u64>,
+    pub max_files: Option<u64>,
+    pub used_bytes: u64,
+    pub used_files: u64,
+    pub remaining_bytes: Option<u64>,
+    pub remaining_files: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct UserUsage {
+    pub user: String,
+    pub bytes: u64,
+    pub files: u64,
+}
+
+/// Every quota and every user's usage, for administrators
+#[derive(Debug, Serialize)]
+pub struct QuotaOverview {
+    pub quotas: Vec<QuotaStatus>,
+    pub users: Vec<UserUsage>,
+}
+
+/// How much a write that passed the quota checks may still grow to
+#[derive(Debug, Clone)]
+pub struct Allowance {
+    quota: String,
+    remaining_bytes: u64,
+    remaining_files: Option<u64>,
+}
+
+impl Allowance {
+    /// Fail once a write has grown to `size` bytes and no longer fits
+    pub fn check(&self, size: u64) -> Result<(), ApiError> {
+        match size > self.remaining_bytes {
+            true => Err(ApiError::QuotaExceeded {
+                quota: self.quota.clone(),
+                remaining_bytes: Some(self.remaining_bytes),
+                remaining_files: self.remaining_files,
+            }),
+            false => Ok(()),
+        }
+    }
+}
+
+/// Storage quotas per user, group and directory tree. Each file's owner and size are kept in
+/// SQLite along with running totals per user and per directory with a quota, updated as files
+/// are written, moved and deleted; a periodic reconciliation with what storage actually holds
+/// catches changes made outside FileDash. Nothing is tracked while no quotas are configured.
+pub struct QuotaService {
+    db: Database,
+    storage: Arc<dyn StorageBackend>,
+    quotas: Vec<Quota>,
+    groups: HashMap<String, Vec<String>>,
+    /// Directories with a quota, which need totals of their own
+    paths: Vec<String>,
+    reconcile_interval_seconds: u64,
+}
+
+impl QuotaService {
+    pub fn new(db: Database, config: &Config, storage: Arc<dyn StorageBackend>) -> Result<Self, ApiError> {
+        let invalid = |message: String| ApiError::InternalServerError { message };
+
+        let mut groups = HashMap::new();
+        for group in &config.quotas.groups {
+            if groups.insert(group.name.clone(), group.members.clone()).is_some() {
+                return Err(invalid(format!("Duplicate quota group: {}", group.name)));
+            }
+        }
+
+        let mut quotas = Vec::new();
+        for rule in &config.quotas.rules {
+            let target = match (&rule.user, &rule.group, &rule.path) {
+                (Some(user), None, None) => Target::User(user.clone()),
+                (None, Some(group), None) if groups.contains_key(group) => Target::Group(group.clone()),
+                (None, Some(group), None) => return Err(invalid(format!("Unknown quota group: {}", group))),
+                (None, None, Some(path)) => Target::Path(normalize(path)?),
+                _ => return Err(invalid("Each quota needs exactly one of user, group and path".to_string())),
+            };
+            quotas.push(Quota {
+                target,
+                max_bytes: rule.max_bytes,
+                max_files: rule.max_files,
+            });
+        }
+
+        let mut paths: Vec<String> = quotas
+            .iter()
+            .filter_map(|quota| match &quota.target {
+                Target::Path(path) => Some(path.clone()),
+                _ => None,
+            })
+            .collect();
+        paths.sort();
+        paths.dedup();
+
+        Ok(Self {
+            db,
+            storage,
+            quotas,
+            groups,
+            paths,
+            reconcile_interval_seconds: config.quotas.reconcile_interval_seconds,
+        })
+    }
+
+    /// Whether any quotas are configured, and usage is tracked
+    pub fn enabled(&self) -> bool {
+        !self.quotas.is_empty()
+    }
+
+    /// The quotas a write by `user` at normalized `path` counts against, with the names
+    /// they are reported under
+    fn applicable(&self, user: &str, path: &str) -> Vec<(&Quota, String)> {
+        let has_own = self.quotas.iter().any(|quota| quota.target == Target::User(user.to_string()));
+        self.quotas
+            .iter()
+            .filter(|quota| match &quota.target {
+                Target::User(name) if name == "*" => !has_own,
+                Target::User(name) => name == user,
+                Target::Group(group) => self.groups[group].iter().any(|member| member == user),
+                Target::Path(root) => within(path, root),
+            })
+            .map(|quota| match &quota.target {
+                Target::User(_) => (quota, user.to_string()),
+                Target::Group(name) | Target::Path(name) => (quota, name.clone()),
+            })
+            .collect()
+    }
+
+    /// Whether a file owned by `owner` at `path` counts against a quota
+    fn counts_against(&self, quota: &Quota, name: &str, owner: &str, path: &str) -> bool {
+        match &quota.target {
+            Target::User(_) => owner == name,
+            Target::Group(group) => self.groups[group].iter().any(|member| member == owner),
+            Target::Path(root) => within(path, root),
+        }
+    }
+
+    async fn total(&self, scope: &str, name: &str) -> Result<Usage, ApiError> {
+        let totals: Option<(i64, i64)> = sqlx::query_as("SELECT bytes, files FROM quota_usage WHERE scope = ? AND name = ?")
+            .bind(scope)
+            .bind(name)
+            .fetch_optional(self.db.pool())
+            .await?;
+        let (bytes, files) = totals.unwrap_or_default();
+        Ok(Usage {
+            bytes: bytes.max(0) as u64,
+            files: files.max(0) as u64,
+        })
+    }
+
+    async fn usage(&self, quota: &Quota, name: &str) -> Result<Usage, ApiError> {
+        match &quota.target {
+            Target::User(_) => self.total(USER, name).await,
+            Target::Path(path) => self.total(PATH, path).await,
+            Target::Group(group) => {
+                let mut usage = Usage::default();
+                for member in &self.groups[group] {
+                    let member_usage = self.total(USER, member).await?;
+                    usage.bytes += member_usage.bytes;
+                    usage.files += member_usage.files;
+                }
+                Ok(usage)
+            }
+        }
+    }
+
+    /// The files at or below normalized `path`
+    async fn subtree_usage(&self, path: &str) -> Result<Usage, ApiError> {
+        let (bytes, files): (i64, i64) = sqlx::query_as(&format!(
+            "SELECT COALESCE(SUM(size), 0), COUNT(*) FROM quota_files WHERE {}",
+            in_subtree("path", 1)
+        ))
+        .bind(path)
+        .fetch_one(self.db.pool())
+        .await?;
+        Ok(Usage {
+            bytes: bytes as u64,
+            files: files as u64,
+        })
+    }
+
+    /// Check that `adding` fits in each of `quotas`, less a file being replaced
+    async fn allowance(
+        &self,
+        quotas: Vec<(&Quota, String)>,
+        adding: Usage,
+        replaced: Option<(String, String, u64)>,
+    ) -> Result<Option<Allowance>, ApiError> {
+        let mut tightest: Option<Allowance> = None;
+        for (quota, name) in quotas {
+            let usage = self.usage(quota, &name).await?;
+            let (freed_bytes, freed_files) = match &replaced {
+                Some((path, owner, size)) if self.counts_against(quota, &name, owner, path) => (*size, 1),
+                _ => (0, 0),
+            };
+            let label = format!("{} {}", scope_name(&quota.target), name);
+
+            let remaining_files = quota
+                .max_files
+                .map(|max| max.saturating_sub(usage.files.saturating_sub(freed_files)));
+            let remaining_bytes = quota
+                .max_bytes
+                .map(|max| max.saturating_sub(usage.bytes.saturating_sub(freed_bytes)));
+
+            let exceeded = remaining_files.is_some_and(|remaining| adding.files > remaining)
+                || remaining_bytes.is_some_and(|remaining| adding.bytes > remaining);
+            if exceeded {
+                return Err(ApiError::QuotaExceeded {
+                    quota: label,
+                    remaining_bytes,
+                    remaining_files,
+                });
+            }
+            if let Some(remaining_bytes) = remaining_bytes {
+                if tightest.as_ref().is_none_or(|allowance| remaining_bytes < allowance.remaining_bytes) {
+                    tightest = Some(Allowance {
+                        quota: label,
+                        remaining_bytes,
+                        remaining_files,
+                    });
+                }
+            }
+        }
+        Ok(tightest)
+    }
+
+    /// Check that `user` may write a file at normalized `path`, of `size` bytes if known.
+    /// Returns what the write may grow to when a quota limits it.
+    pub async fn check_write(&self, user: &str, path: &str, size: Option<u64>) -> Result<Option<Allowance>, ApiError> {
+        if !self.enabled() {
+            return Ok(None);
+        }
+        let replaced: Option<(String, i64)> = sqlx::query_as("SELECT owner, size FROM quota_files WHERE path = ?")
+            .bind(path)
+            .fetch_optional(self.db.pool())
+            .await?;
+        let adding = Usage {
+            bytes: size.unwrap_or(0),
+            files: 1,
+        };
+        let replaced = replaced.map(|(owner, size)| (path.to_string(), owner, size as u64));
+        self.allowance(self.applicable(user, path), adding, replaced).await
+    }
+
+    /// Check that `user` may copy the tree at `from` to `to`
+    pub async fn check_copy(&self, user: &str, from: &str, to: &str) -> Result<(), ApiError> {
+        if !self.enabled() {
+            return Ok(());
+        }
+        let adding = self.subtree_usage(from).await?;
+        self.allowance(self.applicable(user, to), adding, None).await?;
+        Ok(())
+    }
+
+    /// Check that the tree at `from` may be moved to `to`, into directories with quotas it
+    /// isn't already in
+    pub async fn check_move(&self, from: &str, to: &str) -> Result<(), ApiError> {
+        if !self.enabled() {
+            return Ok(());
+        }
+        let quotas = self
+            .quotas
+            .iter()
+            .filter_map(|quota| match &quota.target {
+                Target::Path(root) if within(to, root) && !within(from, root) => Some((quota, root.clone())),
+                _ => None,
+            })
+            .collect();
+        let adding = self.subtree_usage(from).await?;
+        self.allowance(quotas, adding, None).await?;
+        Ok(())
+    }
+
+    /// Add (`sign` 1) or take away (-1) the files at or below `root` from the totals
+    async fn shift(&self, conn: &mut SqliteConnection, root: &str, sign: i64) -> Result<(), ApiError> {
+        let owners: Vec<(String, i64, i64)> = sqlx::query_as(&format!(
+            "SELECT owner, SUM(size), COUNT(*) FROM quota_files WHERE {} AND owner != '' GROUP BY owner",
+            in_subtree("path", 1)
+        ))
+        .bind(root)
+        .fetch_all(&mut *conn)
+        .await?;
+        for (owner, bytes, files) in owners {
+            adjust(conn, USER, &owner, sign * bytes, sign * files).await?;
+        }
+
+        for path in self.paths.iter().filter(|path| within(path, root) || within(root, path)) {
+            let (bytes, files): (i64, i64) = sqlx::query_as(&format!(
+                "SELECT COALESCE(SUM(size), 0), COUNT(*) FROM quota_files WHERE {} AND {}",
+                in_subtree("path", 1),
+                in_subtree("path", 2)
+            ))
+            .bind(root)
+            .bind(path)
+            .fetch_one(&mut *conn)
+            .await?;
+            adjust(conn, PATH, path, sign * bytes, sign * files).await?;
+        }
+        Ok(())
+    }
+
+    /// Forget the files at or below `root`
+    async fn remove(&self, conn: &mut SqliteConnection, root: &str) -> Result<(), ApiError> {
+        self.shift(conn, root, -1).await?;
+        sqlx::query(&format!("DELETE FROM quota_files WHERE {}", in_subtree("path", 1)))
+            .bind(root)
+            .execute(&mut *conn)
+            .await?;
+        Ok(())
+    }
+
+    /// Record that `owner` wrote a file of `size` bytes at normalized `path`
+    pub async fn record_write(&self, path: &str, owner: &str, size: u64) -> Result<(), ApiError> {
+        if !self.enabled() {
+            return Ok(());
+        }
+        let mut tx = self.db.pool().begin().await?;
+        self.remove(&mut tx, path).await?;
+        sqlx::query("INSERT INTO quota_files (path, owner, size, updated) VALUES (?, ?, ?, ?)")
+            .bind(path)
+            .bind(owner)
+            .bind(size as i64)
+            .bind(Utc::now().timestamp_millis())
+            .execute(&mut *tx)
+            .await?;
+        self.shift(&mut tx, path, 1).await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Record that the file or tree at normalized `path` was deleted
+    pub async fn record_delete(&self, path: &str) -> Result<(), ApiError> {
+        if !self.enabled() {
+            return Ok(());
+        }
+        let mut tx = self.db.pool().begin().await?;
+        self.remove(&mut tx, path).await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Record that the file or tree at `from` was moved to `to`; it keeps its owners
+    pub async fn record_move(&self, from: &str, to: &str) -> Result<(), ApiError> {
+        if !self.enabled() {
+            return Ok(());
+        }
+        let mut tx = self.db.pool().begin().await?;
+        self.remove(&mut tx, to).await?;
+        self.shift(&mut tx, from, -1).await?;
+        sqlx::query(&format!(
+            "UPDATE quota_files SET path = ?2 || substr(path, length(?1) + 1), updated = ?3 WHERE {}",
+            in_subtree("path", 1)
+        ))
+        .bind(from)
+        .bind(to)
+        .bind(Utc::now().timestamp_millis())
+        .execute(&mut *tx)
+        .await?;
+        self.shift(&mut tx, to, 1).await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Record that `owner` copied the file or tree at `from` to `to`
+    pub async fn record_copy(&self, from: &str, to: &str, owner: &str) -> Result<(), ApiError> {
+        if !self.enabled() {
+            return Ok(());
+        }
+        let mut tx = self.db.pool().begin().await?;
+        self.remove(&mut tx, to).await?;
+        sqlx::query(&format!(
+            r#"
+            INSERT INTO quota_files (path, owner, size, updated)
+            SELECT ?2 || substr(path, length(?1) + 1), ?3, size, ?4 FROM quota_files WHERE {}
+            "#,
+            in_subtree("path", 1)
+        ))
+        .bind(from)
+        .bind(to)
+        .bind(owner)
+        .bind(Utc::now().timestamp_millis())
+        .execute(&mut *tx)
+        .await?;
+        self.shift(&mut tx, to, 1).await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Bring the recorded files in line with what storage holds, and recount the totals.
+    /// Files that appeared without going through FileDash have no owner.
+    pub async fn reconcile(&self) -> Result<(), ApiError> {
+        if !self.enabled() {
+            return Ok(());
+        }
+        let started = Utc::now().timestamp_millis();
+        let mut files = HashMap::new();
+        let mut pending = vec![String::new()];
+        while let Some(directory) = pending.pop() {
+            for entry in self.storage.list(&directory).await? {
+                match entry.is_directory {
+                    true => pending.push(entry.path),
+                    false => {
+                        files.insert(entry.path, entry.size as i64);
+                    }
+                }
+            }
+        }
+
+        let mut tx = self.db.pool().begin().await?;
+        // Records changed since the walk started are newer than what it saw
+        let recorded: Vec<(String, i64, i64)> = sqlx::query_as("SELECT path, size, updated FROM quota_files")
+            .fetch_all(&mut *tx)
+            .await?;
+        let mut seen = HashSet::new();
+        for (path, size, updated) in recorded {
+            match files.get(&path) {
+                Some(actual) if *actual != size && updated < started => {
+                    sqlx::query("UPDATE quota_files SET size = ? WHERE path = ?")
+                        .bind(actual)
+                        .bind(&path)
+                        .execute(&mut *tx)
+                        .await?;
+                }
+                None if updated < started => {
+                    sqlx::query("DELETE FROM quota_files WHERE path = ?")
+                        .bind(&path)
+                        .execute(&mut *tx)
+                        .await?;
+                }
+                _ => {}
+            }
+            seen.insert(path);
+        }
+        for (path, size) in files.iter().filter(|(path, _)| !seen.contains(*path)) {
+            sqlx::query("INSERT OR IGNORE INTO quota_files (path, owner, size, updated) VALUES (?, '', ?, ?)")
+                .bind(path)
+                .bind(size)
+                .bind(started)
+                .execute(&mut *tx)
+                .await?;
+        }
+
+        sqlx::query("DELETE FROM quota_usage").execute(&mut *tx).await?;
+        sqlx::query(
+            r#"
+            INSERT INTO quota_usage (scope, name, bytes, files)
+            SELECT ?, owner, SUM(size), COUNT(*) FROM quota_files WHERE owner != '' GROUP BY owner
+            "#,
+        )
+        .bind(USER)
+        .execute(&mut *tx)
+        .await?;
+        for path in &self.paths {
+            sqlx::query(&format!(
+                r#"
+                INSERT INTO quota_usage (scope, name, bytes, files)
+                SELECT ?2, ?1, COALESCE(SUM(size), 0), COUNT(*) FROM quota_files WHERE {}
+                "#,
+                in_subtree("path", 1)
+            ))
+            .bind(path)
+            .bind(PATH)
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn status(&self, quota: &Quota, name: &str) -> Result<QuotaStatus, ApiError> {
+        let usage = self.usage(quota, name).await?;
+        Ok(QuotaStatus {
+            scope: scope_name(&quota.target),
+            name: name.to_string(),
+            max_bytes: quota.max_bytes,
+            max_files: quota.max_files,
+            used_bytes: usage.bytes,
+            used_files: usage.files,
+            remaining_bytes: quota.max_bytes.map(|max| max.saturating_sub(usage.bytes)),
+            remaining_files: quota.max_files.map(|max| max.saturating_sub(usage.files)),
+        })
+    }
+
+    /// How much `user` stores, and the quotas that apply to them: their own, their groups'
+    /// and those of directories
+    pub async fn user_quotas(&self, user: &str) -> Result<(Usage, Vec<QuotaStatus>), ApiError> {
+        let mut statuses = Vec::new();
+        for (quota, name) in self.applicable(user, "") {
+            statuses.push(self.status(quota, &name).await?);
+        }
+        for quota in self.quotas.iter().filter(|quota| matches!(quota.target, Target::Path(_))) {
+            if let Target::Path(path) = &quota.target {
+                if !path.is_empty() {
+                    statuses.push(self.status(quota, path).await?);
+                }
+            }
+        }
+        Ok((self.total(USER, user).await?, statuses))
+    }
+
+    /// Every quota, with the catch-all user quota listed for each user it applies to, and
+    /// every user's usage
+    pub async fn overview(&self) -> Result<QuotaOverview, ApiError> {
+        let users: Vec<(String, i64, i64)> =
+            sqlx::query_as("SELECT name, bytes, files FROM quota_usage WHERE scope = ? ORDER BY bytes DESC, name")
+                .bind(USER)
+                .fetch_all(self.db.pool())
+                .await?;
+
+        let mut quotas = Vec::new();
+        for quota in &self.quotas {
+            match &quota.target {
+                Target::User(name) if name == "*" => {
+                    for (user, _, _) in &users {
+                        let has_own = self.quotas.iter().any(|other| other.target == Target::User(user.clone()));
+                        if !has_own {
+                            quotas.push(self.status(quota, user).await?);
+                        }
+                    }
+                }
+                Target::User(name) | Target::Group(name) | Target::Path(name) => {
+                    quotas.push(self.status(quota, name).await?);
+                }
+            }
+        }
+
+        Ok(QuotaOverview {
+            quotas,
+            users: users
+                .into_iter()
+                .map(|(user, bytes, files)| UserUsage {
+                    user,
+                    bytes: bytes.max(0) as u64,
+                    files: files.max(0) as u64,
+                })
+                .collect(),
+        })
+    }
+
+    /// Reconcile on startup and then every `reconcile_interval_seconds`
+    pub fn start_background_tasks(self: &Arc<Self>) {
+        if !self.enabled() {
+            return;
+        }
+        let service = self.clone();
+        tokio::spawn(async move {
+            loop {
+                if let Err(e) = service.reconcile().await {
+                    tracing::error!("Failed to reconcile quota usage: {}", e);
+                }
+                if service.reconcile_interval_seconds == 0 {
+                    break;
+                }
+                tokio::time::sleep(std::time::Duration::from_secs(service.reconcile_interval_seconds)).await;
+            }
+        });
+    }
+}
+
+fn scope_name(target: &Target) -> &'static str {
+    match target {
+        Target::User(_) => USER,
+        Target::Group(_) => GROUP,
+        Target::Path(_) => PATH,
+    }
+}
+
+async fn adjust(conn: &mut SqliteConnection, scope: &str, name: &str, bytes: i64, files: i64) -> Result<(), ApiError> {
+    if bytes == 0 && files == 0 {
+        return Ok(());
+    }
+    sqlx::query(
+        r#"
+        INSERT INTO quota_usage (scope, name, bytes, files) VALUES (?, ?, ?, ?)
+        ON CONFLICT (scope, name) DO UPDATE SET bytes = bytes + excluded.bytes, files = files + excluded.files
+        "#,
+    )
+    .bind(scope)
+    .bind(name)
+    .bind(bytes)
+    .bind(files)
+    .execute(&mut *conn)
+    .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+    use tokio::io::AsyncWriteExt;
+
+    #[tokio::test]
+    async fn test_quotas_follow_writes_moves_and_deletes() {
+        let dir = tempfile::tempdir().unwrap();
+        let url = format!("sqlite:{}", dir.path().join("test.db").display());
+        let db = Database::new(&url).await.unwrap();
+        let mut config = Config::for_tests(".");
+        config.quotas = toml::from_str(
+            r#"
+            [[rules]]
+            user = "*"
+            max_bytes = 100
+            [[rules]]
+            group = "team"
+            max_files = 3
+            [[rules]]
+            path = "shared"
+            max_bytes = 50
+            [[groups]]
+            name = "team"
+            members = ["alice", "bob"]
+            "#,
+        )
+        .unwrap();
+        let storage: Arc<dyn StorageBackend> = Arc::new(MemoryStorage::new());
+        let quotas = QuotaService::new(db, &config, storage.clone()).unwrap();
+
+        let allowance = quotas.check_write("alice", "a.bin", None).await.unwrap().unwrap();
+        assert!(allowance.check(100).is_ok() && allowance.check(101).is_err());
+        quotas.record_write("a.bin", "alice", 60).await.unwrap();
+
+        // Replacing a file only counts the difference
+        assert!(quotas.check_write("alice", "a.bin", Some(100)).await.is_ok());
+        let exceeded = quotas.check_write("alice", "b.bin", Some(41)).await.unwrap_err();
+        assert!(matches!(exceeded, ApiError::QuotaExceeded { remaining_bytes: Some(40), .. }));
+
+        // The directory quota applies to whoever writes there
+        assert!(quotas.check_write("carol", "shared/x.bin", Some(51)).await.is_err());
+        quotas.record_write("shared/x.bin", "carol", 30).await.unwrap();
+        quotas.check_move("a.bin", "shared/a.bin").await.unwrap_err();
+
+        quotas.record_copy("shared", "copy", "bob").await.unwrap();
+        quotas.record_write("b.bin", "bob", 1).await.unwrap();
+        let exceeded = quotas.check_write("alice", "c.bin", Some(1)).await.unwrap_err();
+        assert!(matches!(exceeded, ApiError::QuotaExceeded { remaining_files: Some(0), .. }));
+
+        quotas.record_move("copy", "shared/copy").await.unwrap();
+        quotas.record_delete("shared/x.bin").await.unwrap();
+        assert_eq!(quotas.total(PATH, "shared").await.unwrap().bytes, 30);
+        assert_eq!(quotas.total(USER, "bob").await.unwrap().files, 2);
+
+        // Reconciling drops what storage doesn't hold and adopts what it does without an owner
+        let mut writer = storage.open_write("outside.bin").await.unwrap();
+        writer.write_all(&[0; 7]).await.unwrap();
+        writer.commit().await.unwrap();
+        sqlx::query("UPDATE quota_files SET updated = 0").execute(quotas.db.pool()).await.unwrap();
+        quotas.reconcile().await.unwrap();
+        let overview = quotas.overview().await.unwrap();
+        assert!(overview.users.is_empty());
+        assert_eq!(quotas.subtree_usage("").await.unwrap().bytes, 7);
+    }
+}
@@ -0,0 +1,331 @@
+use crate::{
+    config::Config,
+    db::Database,
+    errors::ApiError,
+    utils::validate_file_size,
+};
+use bytes::Bytes;
+use chrono::{DateTime, Duration, Utc};
+use futures::{Stream, StreamExt, TryStreamExt};
+use sha2::{Digest, Sha256};
+use sqlx::Row;
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tokio::{fs as async_fs, io::AsyncWriteExt};
+use uuid::Uuid;
+
+/// How often abandoned multipart uploads are looked for
+const CLEANUP_INTERVAL: std::time::Duration = std::time::Duration::from_secs(3600);
+
+/// A file assembled in the staging directory. It is removed when dropped unless it has
+/// been moved into the storage directory with `FileService::install_file`.
+pub struct StagedFile {
+    path: PathBuf,
+    pub size: u64,
+    /// Hex SHA-256 of the contents
+    pub sha256: String,
+}
+
+impl StagedFile {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for StagedFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MultipartUpload {
+    pub upload_id: String,
+    pub bucket: String,
+    pub key: String,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone)]
+pub struct UploadedPart {
+    pub part_number: u32,
+    /// Quoted entity tag, as returned to the client
+    pub etag: String,
+    pub size: u64,
+    pub uploaded_at: DateTime<Utc>,
+}
+
+/// Upload staging and multipart upload state behind the S3-compatible API
+pub struct S3Service {
+    db: Database,
+    config: Arc<Config>,
+}
+
+impl S3Service {
+    pub fn new(db: Database, config: Arc<Config>) -> Self {
+        Self { db, config }
+    }
+
+    /// Periodically discard multipart uploads older than `s3.multipart_expiry_hours`
+    pub fn start_background_tasks(self: &Arc<Self>) {
+        let service = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(CLEANUP_INTERVAL);
+            loop {
+                interval.tick().await;
+                match service.remove_expired_uploads().await {
+                    Ok(0) => {}
+                    Ok(count) => tracing::info!("Discarded {} abandoned multipart uploads", count),
+                    Err(e) => tracing::warn!("Failed to discard abandoned multipart uploads: {}", e),
+                }
+            }
+        });
+    }
+
+    fn tmp_dir(&self) -> PathBuf {
+        self.config.s3.staging_dir.join("tmp")
+    }
+
+    fn upload_dir(&self, upload_id: &str) -> PathBuf {
+        self.config.s3.staging_dir.join("uploads").join(upload_id)
+    }
+
+    /// Write a request body to a new staged file, enforcing the largest upload size of any
+    /// mount; the destination's own limit is checked when the file is installed
+    pub async fn stage<S, E>(&self, mut body: S) -> Result<StagedFile, ApiError>
+    where
+        S: Stream<Item = Result<Bytes, E>> + Unpin,
+        E: std::fmt::Display,
+    {
+        async_fs::create_dir_all(self.tmp_dir()).await?;
+        let mut staged = StagedFile {
+            path: self.tmp_dir().join(Uuid::new_v4().to_string()),
+            size: 0,
+            sha256: String::new(),
+        };
+
+        let mut file = async_fs::File::create(&staged.path).await?;
+        let mut hasher = Sha256::new();
+        while let Some(chunk) = body.next().await {
+            let chunk = chunk.map_err(|e| ApiError::BadRequest {
+                message: format!("Failed to read request body: {}", e),
+            })?;
+            staged.size += chunk.len() as u64;
+            validate_file_size(staged.size, self.config.storage.largest_upload_size())?;
+            hasher.update(&chunk);
+            file.write_all(&chunk).await?;
+        }
+        file.flush().await?;
+
+        staged.sha256 = hex::encode(hasher.finalize());
+        Ok(staged)
+    }
+
+    pub async fn create_multipart_upload(&self, bucket: &str, key: &str) -> Result<MultipartUpload, ApiError> {
+        let upload = MultipartUpload {
+            upload_id: Uuid::new_v4().simple().to_string(),
+            bucket: bucket.to_string(),
+            key: key.to_string(),
+            created_at: Utc::now(),
+        };
+        async_fs::create_dir_all(self.upload_dir(&upload.upload_id)).await?;
+
+        sqlx::query("INSERT INTO s3_multipart_uploads (upload_id, bucket, key, created_at) VALUES (?, ?, ?, ?)")
+            .bind(&upload.upload_id)
+            .bind(&upload.bucket)
+            .bind(&upload.key)
+            .bind(upload.created_at.to_rfc3339())
+            .execute(self.db.pool())
+            .await?;
+        Ok(upload)
+    }
+
+    pub async fn multipart_upload(&self, upload_id: &str) -> Result<Option<MultipartUpload>, ApiError> {
+        let row = sqlx::query("SELECT bucket, key, created_at FROM s3_multipart_uploads WHERE upload_id = ?")
+            .bind(upload_id)
+            .fetch_optional(self.db.pool())
+            .await?;
+
+        Ok(row.map(|row| MultipartUpload {
+            upload_id: upload_id.to_string(),
+            bucket: row.get("bucket"),
+            key: row.get("key"),
+            created_at: parse_time(&row.get::<String, _>("created_at")),
+        }))
+    }
+
+    /// Keep a staged part, replacing an earlier upload of the same part number
+    pub async fn put_part(&self, upload_id: &str, part_number: u32, staged: StagedFile) -> Result<UploadedPart, ApiError> {
+        let part = UploadedPart {
+            part_number,
+            etag: format!("\"{}\"", staged.sha256),
+            size: staged.size,
+            uploaded_at: Utc::now(),
+        };
+        async_fs::rename(staged.path(), self.upload_dir(upload_id).join(part_number.to_string())).await?;
+
+        sqlx::query(
+            r#"
+            INSERT INTO s3_multipart_parts (upload_id, part_number, etag, size, uploaded_at) VALUES (?, ?, ?, ?, ?)
+            ON CONFLICT(upload_id, part_number) DO UPDATE
+            SET etag = excluded.etag, size = excluded.size, uploaded_at = excluded.uploaded_at
+            "#,
+        )
+        .bind(upload_id)
+        .bind(part_number)
+        .bind(&part.etag)
+        .bind(part.size as i64)
+        .bind(part.uploaded_at.to_rfc3339())
+        .execute(self.db.pool())
+        .await?;
+        Ok(part)
+    }
+
+    pub async fn list_parts(&self, upload_id: &str) -> Result<Vec<UploadedPart>, ApiError> {
+        let rows = sqlx::query(
+            "SELECT part_number, etag, size, uploaded_at FROM s3_multipart_parts WHERE upload_id = ? ORDER BY part_number",
+        )
+        .bind(upload_id)
+        .fetch_all(self.db.pool())
+        .await?;
+
+        Ok(rows
+            .into_iter()
+            .map(|row| UploadedPart {
+                part_number: row.get::<i64, _>("part_number") as u32,
+                etag: row.get("etag"),
+                size: row.get::<i64, _>("size") as u64,
+                uploaded_at: parse_time(&row.get::<String, _>("uploaded_at")),
+            })
+            .collect())
+    }
+
+    /// Concatenate the listed parts, in ascending part order with matching entity tags, into
+    /// a staged file. The upload itself is kept until `remove_upload`.
+    pub async fn assemble_parts(&self, upload_id: &str, parts: &[(u32, String)]) -> Result<StagedFile, ApiError> {
+        if parts.is_empty() {
+            return Err(ApiError::BadRequest {
+                message: "At least one part must be specified".to_string(),
+            });
+        }
+        if parts.windows(2).any(|pair| pair[0].0 >= pair[1].0) {
+            return Err(ApiError::BadRequest {
+                message: "Parts must be listed in ascending order".to_string(),
+            });
+        }
+
+        let uploaded = self.list_parts(upload_id).await?;
+        for (part_number, etag) in parts {
+            let matches = uploaded
+                .iter()
+                .any(|part| part.part_number == *part_number && part.etag.trim_matches('"') == etag.trim_matches('"'));
+            if !matches {
+                return Err(ApiError::NotFound {
+                    resource: "Part".to_string(),
+                    id: part_number.to_string(),
+                });
+            }
+        }
+
+        // Parts are opened one at a time as the previous one runs out
+        let upload_dir = self.upload_dir(upload_id);
+        let part_paths: Vec<PathBuf> = parts
+            .iter()
+            .map(|(part_number, _)| upload_dir.join(part_number.to_string()))
+            .collect();
+        let files = futures::stream::unfold(part_paths.into_iter(), |mut paths| async move {
+            let path = paths.next()?;
+            let file = async_fs::File::open(path).await.map(tokio_util::io::ReaderStream::new);
+            Some((file, paths))
+        })
+        .try_flatten();
+        self.stage(Box::pin(files)).await
+    }
+
+    /// Forget a multipart upload and its parts; returns whether it existed
+    pub async fn remove_upload(&self, upload_id: &str) -> Result<bool, ApiError> {
+        sqlx::query("DELETE FROM s3_multipart_parts WHERE upload_id = ?")
+            .bind(upload_id)
+            .execute(self.db.pool())
+            .await?;
+        let result = sqlx::query("DELETE FROM s3_multipart_uploads WHERE upload_id = ?")
+            .bind(upload_id)
+            .execute(self.db.pool())
+            .await?;
+
+        match async_fs::remove_dir_all(self.upload_dir(upload_id)).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn remove_expired_uploads(&self) -> Result<usize, ApiError> {
+        let expiry = Duration::hours(self.config.s3.multipart_expiry_hours as i64);
+        let cutoff = Utc::now() - expiry;
+
+        let upload_ids: Vec<String> = sqlx::query_scalar("SELECT upload_id FROM s3_multipart_uploads WHERE created_at < ?")
+            .bind(cutoff.to_rfc3339())
+            .fetch_all(self.db.pool())
+            .await?;
+        for upload_id in &upload_ids {
+            self.remove_upload(upload_id).await?;
+        }
+
+        // Staged files are normally dropped with their request; these were left by a crash
+        if let Ok(mut entries) = async_fs::read_dir(self.tmp_dir()).await {
+            while let Ok(Some(entry)) = entries.next_entry().await {
+                let stale = entry
+                    .metadata()
+                    .await
+                    .and_then(|metadata| metadata.modified())
+                    .is_ok_and(|modified| DateTime::<Utc>::from(modified) < cutoff);
+                if stale {
+                    let _ = async_fs::remove_file(entry.path()).await;
+                }
+            }
+        }
+        Ok(upload_ids.len())
+    }
+}
+
+fn parse_time(value: &str) -> DateTime<Utc> {
+    DateTime::parse_from_rfc3339(value)
+        .map(|time| time.with_timezone(&Utc))
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    #[tokio::test]
+    async fn test_multipart_parts_are_assembled_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let url = format!("sqlite:{}", dir.path().join("test.db").display());
+        let db = Database::new(&url).await.unwrap();
+        let mut config = Config::for_tests(".");
+        config.s3.staging_dir = dir.path().join("staging");
+        let s3 = S3Service::new(db, Arc::new(config));
+
+        let body = |text: &'static str| futures::stream::iter([Ok::<_, std::io::Error>(Bytes::from(text))]);
+        let upload = s3.create_multipart_upload("bucket", "big.bin").await.unwrap();
+        let second = s3.put_part(&upload.upload_id, 2, s3.stage(body("world")).await.unwrap()).await.unwrap();
+        let first = s3.put_part(&upload.upload_id, 1, s3.stage(body("hello ")).await.unwrap()).await.unwrap();
+
+        // Parts must be named with the tags they were given
+        let wrong = [(1, second.etag.clone()), (2, second.etag.clone())];
+        assert!(s3.assemble_parts(&upload.upload_id, &wrong).await.is_err());
+
+        let parts = [(1, first.etag), (2, second.etag)];
+        let staged = s3.assemble_parts(&upload.upload_id, &parts).await.unwrap();
+        assert_eq!(std::fs::read_to_string(staged.path()).unwrap(), "hello world");
+
+        assert!(s3.remove_upload(&upload.upload_id).await.unwrap());
+        assert!(s3.multipart_upload(&upload.upload_id).await.unwrap().is_none());
+        assert!(!s3.upload_dir(&upload.upload_id).exists());
+    }
+}
@@ -0,0 +1,711 @@
+use crate::{
+    config::Config,
+    errors::ApiError,
+    services::{index_service, FileInfo, IndexSearch, IndexService},
+    storage::StorageBackend,
+};
+use chrono::{DateTime, Utc};
+use regex::{Regex, RegexBuilder};
+use serde::{Deserialize, Serialize};
+use std::{
+    path::Path,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+/// How the `q` parameter is matched against file names
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MatchMode {
+    #[default]
+    Substring,
+    Glob,
+    Regex,
+}
+
+/// Where search results come from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SearchSource {
+    Index,
+    Walk,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EntryType {
+    File,
+    Directory,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct SearchQuery {
+    /// Name pattern; when omitted every entry passing the filters matches
+    pub q: Option<String>,
+    /// Directory to search from (default: "/")
+    pub path: Option<String>,
+    #[serde(default)]
+    pub mode: MatchMode,
+    #[serde(default)]
+    pub case_sensitive: bool,
+    #[serde(rename = "type")]
+    pub entry_type: Option<EntryType>,
+    pub min_size: Option<u64>,
+    pub max_size: Option<u64>,
+    pub modified_after: Option<DateTime<Utc>>,
+    pub modified_before: Option<DateTime<Utc>>,
+    /// Exact MIME type or a `type/*` wildcard such as `image/*`
+    pub mime_type: Option<String>,
+    pub max_depth: Option<usize>,
+    pub limit: Option<usize>,
+    pub offset: Option<usize>,
+    /// Force a source; by default the index is used once it is ready
+    pub source: Option<SearchSource>,
+    /// Match `q` against extracted file contents instead of names
+    #[serde(default)]
+    pub content: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SearchResponse {
+    pub query: Option<String>,
+    pub path: String,
+    pub source: SearchSource,
+    pub results: Vec<SearchHit>,
+    /// Time spent searching in milliseconds
+    pub took: u64,
+    /// True when the walk hit the configured timeout and results may be incomplete
+    pub timed_out: bool,
+    pub pagination: Pagination,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SearchHit {
+    #[serde(flatten)]
+    pub file: FileInfo,
+    /// Relevance of a content match; higher is better
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub score: Option<f64>,
+    /// Excerpt around a content match, HTML-escaped with matches wrapped in `<mark>`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub snippet: Option<String>,
+}
+
+impl From<FileInfo> for SearchHit {
+    fn from(file: FileInfo) -> Self {
+        Self {
+            file,
+            score: None,
+            snippet: None,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Pagination {
+    pub limit: usize,
+    pub offset: usize,
+    pub has_more: bool,
+}
+
+pub struct SearchService {
+    config: Config,
+    index: Arc<IndexService>,
+    storage: Arc<dyn StorageBackend>,
+}
+
+impl SearchService {
+    pub fn new(config: Config, index: Arc<IndexService>, storage: Arc<dyn StorageBackend>) -> Self {
+        Self { config, index, storage }
+    }
+
+    /// Find the entries below `query.path` matching the query, from the index when it is
+    /// ready and by walking the storage otherwise
+    pub async fn search(&self, query: SearchQuery) -> Result<SearchResponse, ApiError> {
+        let started = Instant::now();
+        let search_config = &self.config.search;
+
+        let base_path = query.path.clone().unwrap_or_else(|| "/".to_string());
+        let base = index_service::normalize(&base_path)?;
+        match self.storage.stat(&base).await {
+            Ok(info) if info.is_directory => {}
+            Ok(_) => {
+                return Err(ApiError::BadRequest {
+                    message: "Search path must be a directory".to_string(),
+                });
+            }
+            Err(ApiError::FileNotFound { .. }) => return Err(ApiError::FileNotFound { path: base_path }),
+            Err(e) => return Err(e),
+        }
+
+        // In content mode `q` is a full-text query, so it must not also filter names
+        let filter = if query.content {
+            SearchFilter::new(&SearchQuery {
+                q: None,
+                ..query.clone()
+            })?
+        } else {
+            SearchFilter::new(&query)?
+        };
+        let limit = query
+            .limit
+            .unwrap_or(search_config.default_limit)
+            .clamp(1, search_config.max_limit);
+        let offset = query.offset.unwrap_or(0);
+        let max_depth = query
+            .max_depth
+            .map(|depth| depth.min(search_config.max_depth))
+            .unwrap_or(search_config.max_depth);
+        let deadline = started + Duration::from_secs(search_config.timeout_seconds);
+
+        let source = match query.source {
+            Some(SearchSource::Walk) if query.content => {
+                return Err(ApiError::BadRequest {
+                    message: "Content search is only available from the index".to_string(),
+                });
+            }
+            Some(SearchSource::Index) if !self.index.is_ready() => {
+                return Err(ApiError::Conflict {
+                    message: "The search index is not ready yet".to_string(),
+                });
+            }
+            Some(source) => source,
+            None if self.index.is_ready() => SearchSource::Index,
+            None if query.content => {
+                return Err(ApiError::Conflict {
+                    message: "The search index is not ready yet".to_string(),
+                });
+            }
+            None => SearchSource::Walk,
+        };
+
+        if source == SearchSource::Index {
+            let literal = match query.content {
+                true => None,
+                false => query.q.as_deref().and_then(|q| literal_hint(q, query.mode)),
+            };
+            let search = IndexSearch {
+                base: &base,
+                literal: literal.as_deref(),
+                filter: &filter,
+                max_depth,
+                offset,
+                limit,
+                deadline,
+            };
+            let outcome = if query.content {
+                let text = query.q.as_deref().unwrap_or_default();
+                self.index.search_content(&search, text).await?
+            } else {
+                self.index.search(search).await?
+            };
+
+            return Ok(SearchResponse {
+                query: query.q,
+                path: base_path,
+                source,
+                results: outcome.results,
+                took: started.elapsed().as_millis() as u64,
+                timed_out: outcome.timed_out,
+                pagination: Pagination {
+                    limit,
+                    offset,
+                    has_more: outcome.has_more,
+                },
+            });
+        }
+
+        let walk = WalkOptions {
+            base,
+            max_depth,
+            offset,
+            limit,
+            deadline,
+        };
+        let outcome = walk_and_filter(self.storage.as_ref(), &walk, &filter).await;
+
+        Ok(SearchResponse {
+            query: query.q,
+            path: base_path,
+            source,
+            results: outcome.results,
+            took: started.elapsed().as_millis() as u64,
+            timed_out: outcome.timed_out,
+            pagination: Pagination {
+                limit,
+                offset,
+                has_more: outcome.has_more,
+            },
+        })
+    }
+}
+
+struct WalkOptions {
+    base: String,
+    max_depth: usize,
+    offset: usize,
+    limit: usize,
+    deadline: Instant,
+}
+
+struct WalkOutcome {
+    results: Vec<SearchHit>,
+    has_more: bool,
+    timed_out: bool,
+}
+
+/// Walk the storage below `options.base` depth-first, each directory in name order so the
+/// order is stable between pages
+async fn walk_and_filter(storage: &dyn StorageBackend, options: &WalkOptions, filter: &SearchFilter) -> WalkOutcome {
+    let mut results = Vec::new();
+    let mut skipped = 0;
+    let mut has_more = false;
+    let mut timed_out = false;
+
+    // The entries still to visit in each directory being walked, deepest last
+    let mut pending = Vec::new();
+    if options.max_depth > 0 {
+        pending.push(list_sorted(storage, &options.base).await.into_iter());
+    }
+    while let Some(entries) = pending.last_mut() {
+        let Some(file_info) = entries.next() else {
+            pending.pop();
+            continue;
+        };
+        if Instant::now() >= options.deadline {
+            timed_out = true;
+            break;
+        }
+
+        if file_info.is_directory && pending.len() < options.max_depth {
+            let entries = list_sorted(storage, &file_info.path).await;
+            pending.push(entries.into_iter());
+        }
+        if !filter.matches_name(&file_info.name) || !filter.matches_info(&file_info) {
+            continue;
+        }
+
+        if skipped < options.offset {
+            skipped += 1;
+            continue;
+        }
+
+        if results.len() == options.limit {
+            has_more = true;
+            break;
+        }
+
+        results.push(file_info.into());
+    }
+
+    WalkOutcome {
+        results,
+        has_more,
+        timed_out,
+    }
+}
+
+/// The entries of a directory in name order, or none when it can't be read
+async fn list_sorted(storage: &dyn StorageBackend, path: &str) -> Vec<FileInfo> {
+    match storage.list(path).await {
+        Ok(mut entries) => {
+            entries.sort_by(|a, b| a.name.cmp(&b.name));
+            entries
+        }
+        Err(e) => {
+            tracing::warn!("Skipping unreadable directory {:?} during search: {}", path, e);
+            Vec::new()
+        }
+    }
+}
+
+/// Path of `path` relative to the storage root, using `/` separators
+pub(crate) fn relative_to_root(storage_root: &Path, path: &Path) -> Option<String> {
+    let relative = path.strip_prefix(storage_root).ok()?;
+    let parts: Vec<String> = relative
+        .components()
+        .map(|component| component.as_os_str().to_string_lossy().to_string())
+        .collect();
+    Some(parts.join("/"))
+}
+
+enum NameMatcher {
+    Any,
+    Substring { needle: String, case_sensitive: bool },
+    Pattern(Regex),
+}
+
+impl NameMatcher {
+    fn new(pattern: Option<&str>, mode: MatchMode, case_sensitive: bool) -> Result<Self, ApiError> {
+        let pattern = match pattern {
+            Some(pattern) if !pattern.is_empty() => pattern,
+            _ => return Ok(NameMatcher::Any),
+        };
+
+        let source = match mode {
+            MatchMode::Substring => {
+                let needle = if case_sensitive {
+                    pattern.to_string()
+                } else {
+                    pattern.to_lowercase()
+                };
+                return Ok(NameMatcher::Substring { needle, case_sensitive });
+            }
+            MatchMode::Glob => glob_to_regex(pattern),
+            MatchMode::Regex => pattern.to_string(),
+        };
+
+        let regex = RegexBuilder::new(&source)
+            .case_insensitive(!case_sensitive)
+            .size_limit(1 << 20)
+            .build()
+            .map_err(|e| ApiError::BadRequest {
+                message: format!("Invalid search pattern: {}", e),
+            })?;
+
+        Ok(NameMatcher::Pattern(regex))
+    }
+
+    fn is_match(&self, name: &str) -> bool {
+        match self {
+            NameMatcher::Any => true,
+            NameMatcher::Substring { needle, case_sensitive: true } => name.contains(needle.as_str()),
+            NameMatcher::Substring { needle, case_sensitive: false } => {
+                name.to_lowercase().contains(needle.as_str())
+            }
+            NameMatcher::Pattern(regex) => regex.is_match(name),
+        }
+    }
+}
+
+/// Compiled form of the filters in a `SearchQuery`
+pub(crate) struct SearchFilter {
+    name: NameMatcher,
+    entry_type: Option<EntryType>,
+    min_size: Option<u64>,
+    max_size: Option<u64>,
+    modified_after: Option<DateTime<Utc>>,
+    modified_before: Option<DateTime<Utc>>,
+    mime_type: Option<String>,
+}
+
+impl SearchFilter {
+    pub(crate) fn new(query: &SearchQuery) -> Result<Self, ApiError> {
+        if let (Some(min), Some(max)) = (query.min_size, query.max_size) {
+            if min > max {
+                return Err(ApiError::BadRequest {
+                    message: "min_size must not be greater than max_size".to_string(),
+                });
+            }
+        }
+
+        Ok(Self {
+            name: NameMatcher::new(query.q.as_deref(), query.mode, query.case_sensitive)?,
+            entry_type: query.entry_type,
+            min_size: query.min_size,
+            max_size: query.max_size,
+            modified_after: query.modified_after,
+            modified_before: query.modified_before,
+            mime_type: query.mime_type.as_ref().map(|mime| mime.to_lowercase()),
+        })
+    }
+
+    pub(crate) fn entry_type(&self) -> Option<EntryType> {
+        self.entry_type
+    }
+
+    pub(crate) fn min_size(&self) -> Option<u64> {
+        self.min_size
+    }
+
+    pub(crate) fn max_size(&self) -> Option<u64> {
+        self.max_size
+    }
+
+    pub(crate) fn modified_after(&self) -> Option<DateTime<Utc>> {
+        self.modified_after
+    }
+
+    pub(crate) fn modified_before(&self) -> Option<DateTime<Utc>> {
+        self.modified_before
+    }
+
+    pub(crate) fn matches_name(&self, name: &str) -> bool {
+        self.name.is_match(name)
+    }
+
+    pub(crate) fn matches_info(&self, info: &FileInfo) -> bool {
+        match self.entry_type {
+            Some(EntryType::File) if info.is_directory => return false,
+            Some(EntryType::Directory) if !info.is_directory => return false,
+            _ => {}
+        }
+
+        // Directory sizes are not meaningful, so size filters only ever match files
+        if self.min_size.is_some() || self.max_size.is_some() {
+            if info.is_directory {
+                return false;
+            }
+            if self.min_size.is_some_and(|min| info.size < min) {
+                return false;
+            }
+            if self.max_size.is_some_and(|max| info.size > max) {
+                return false;
+            }
+        }
+
+        if self.modified_after.is_some_and(|after| info.modified < after) {
+            return false;
+        }
+        if self.modified_before.is_some_and(|before| info.modified > before) {
+            return false;
+        }
+
+        if let Some(wanted) = &self.mime_type {
+            let actual = match &info.mime_type {
+                Some(mime) => mime.to_lowercase(),
+                None => return false,
+            };
+            let matches = match wanted.strip_suffix("/*") {
+                Some(prefix) => actual.split('/').next() == Some(prefix),
+                None => actual == *wanted,
+            };
+            if !matches {
+                return false;
+            }
+        }
+
+        true
+    }
+}
+
+/// Longest run of literal text that every name matching `pattern` must contain.
+/// `%` and `_` are dropped because the hint ends up inside a SQL `LIKE` pattern.
+fn literal_hint(pattern: &str, mode: MatchMode) -> Option<String> {
+    let literal_runs: Vec<String> = match mode {
+        MatchMode::Substring => vec![pattern.to_string()],
+        MatchMode::Glob => {
+            let mut runs = Vec::new();
+            let mut current = String::new();
+            let mut in_class = false;
+            for c in pattern.chars() {
+                match c {
+                    '[' if !in_class => {
+                        in_class = true;
+                        runs.push(std::mem::take(&mut current));
+                    }
+                    ']' if in_class => in_class = false,
+                    _ if in_class => {}
+                    '*' | '?' => runs.push(std::mem::take(&mut current)),
+                    _ => current.push(c),
+                }
+            }
+            runs.push(current);
+            runs
+        }
+        MatchMode::Regex => return None,
+    };
+
+    literal_runs
+        .iter()
+        .flat_map(|run| run.split(['%', '_']))
+        .max_by_key(|run| run.chars().count())
+        .filter(|run| !run.is_empty())
+        .map(|run| run.to_string())
+}
+
+/// Translate a shell-style glob (`*`, `?`, `[...]`) into an anchored regex
+pub(crate) fn glob_to_regex(glob: &str) -> String {
+    let mut regex = String::with_capacity(glob.len() * 2 + 2);
+    regex.push('^');
+
+    let mut chars = glob.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' => regex.push_str(".*"),
+            '?' => regex.push('.'),
+            '[' => {
+                // Copy a character class through, translating `[!...]` negation
+                let mut class = String::from("[");
+                if chars.peek() == Some(&'!') {
+                    chars.next();
+                    class.push('^');
+                }
+                let mut closed = false;
+                for c in chars.by_ref() {
+                    if c == ']' {
+                        closed = true;
+                        break;
+                    }
+                    if c == '\\' || c == '[' {
+                        class.push('\\');
+                    }
+                    class.push(c);
+                }
+                if closed {
+                    class.push(']');
+                    regex.push_str(&class);
+                } else {
+                    // Unterminated class: treat the bracket literally
+                    regex.push_str(&regex::escape("["));
+                    regex.push_str(&regex::escape(&class[1..]));
+                }
+            }
+            _ => regex.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+
+    regex.push('$');
+    regex
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        db::Database,
+        storage::{DedupStorage, MemoryStorage},
+    };
+    use tokio::io::AsyncWriteExt;
+
+    fn glob_matches(glob: &str, name: &str) -> bool {
+        Regex::new(&glob_to_regex(glob)).unwrap().is_match(name)
+    }
+
+    #[test]
+    fn test_glob_to_regex_wildcards() {
+        assert!(glob_matches("*.txt", "notes.txt"));
+        assert!(!glob_matches("*.txt", "notes.txt.bak"));
+        assert!(glob_matches("report-20??.pdf", "report-2025.pdf"));
+        assert!(!glob_matches("report-20??.pdf", "report-202.pdf"));
+    }
+
+    #[test]
+    fn test_glob_to_regex_classes_and_literals() {
+        assert!(glob_matches("img[0-9].png", "img7.png"));
+        assert!(!glob_matches("img[!0-9].png", "img7.png"));
+        assert!(glob_matches("a+b(1).txt", "a+b(1).txt"));
+        assert!(glob_matches("[abc", "[abc"));
+    }
+
+    #[test]
+    fn test_literal_hint() {
+        assert_eq!(literal_hint("report", MatchMode::Substring).as_deref(), Some("report"));
+        assert_eq!(literal_hint("annual_report", MatchMode::Substring).as_deref(), Some("report"));
+        assert_eq!(literal_hint("*.tar.gz", MatchMode::Glob).as_deref(), Some(".tar.gz"));
+        assert_eq!(literal_hint("img[abc]2025-*.png", MatchMode::Glob).as_deref(), Some("2025-"));
+        assert_eq!(literal_hint("*", MatchMode::Glob), None);
+        assert_eq!(literal_hint("^a.*b$", MatchMode::Regex), None);
+    }
+
+    #[test]
+    fn test_filter_mime_wildcard() {
+        let query = SearchQuery {
+            mime_type: Some("image/*".to_string()),
+            ..Default::default()
+        };
+        let filter = SearchFilter::new(&query).unwrap();
+        let mut info = FileInfo {
+            name: "photo.jpg".to_string(),
+            path: "photo.jpg".to_string(),
+            size: 10,
+            modified: Utc::now(),
+            is_directory: false,
+            mime_type: Some("image/jpeg".to_string()),
+        };
+        assert!(filter.matches_info(&info));
+        info.mime_type = Some("text/plain".to_string());
+        assert!(!filter.matches_info(&info));
+    }
+
+    #[test]
+    fn test_filter_rejects_inverted_size_range() {
+        let query = SearchQuery {
+            min_size: Some(10),
+            max_size: Some(5),
+            ..Default::default()
+        };
+        assert!(SearchFilter::new(&query).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_walk_paginates_and_filters_by_type() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let root = temp_dir.path().to_path_buf();
+        std::fs::create_dir_all(root.join("docs/nested")).unwrap();
+        for name in ["a.txt", "b.txt", "docs/c.txt", "docs/nested/d.txt", "docs/e.md"] {
+            std::fs::write(root.join(name), b"content").unwrap();
+        }
+        let storage = crate::storage::LocalStorage::new(root);
+
+        let query = SearchQuery {
+            q: Some("*.txt".to_string()),
+            mode: MatchMode::Glob,
+            entry_type: Some(EntryType::File),
+            ..Default::default()
+        };
+        let filter = SearchFilter::new(&query).unwrap();
+        let mut options = WalkOptions {
+            base: String::new(),
+            max_depth: 32,
+            offset: 0,
+            limit: 3,
+            deadline: Instant::now() + Duration::from_secs(30),
+        };
+
+        let first_page = walk_and_filter(&storage, &options, &filter).await;
+        assert_eq!(first_page.results.len(), 3);
+        assert!(first_page.has_more);
+
+        options.offset = 3;
+        let second_page = walk_and_filter(&storage, &options, &filter).await;
+        assert_eq!(second_page.results.len(), 1);
+        assert!(!second_page.has_more);
+        let paths: Vec<_> = first_page.results.iter().chain(&second_page.results).map(|hit| hit.file.path.as_str()).collect();
+        assert_eq!(paths, ["a.txt", "b.txt", "docs/c.txt", "docs/nested/d.txt"]);
+
+        options.offset = 0;
+        options.max_depth = 1;
+        let shallow = walk_and_filter(&storage, &options, &filter).await;
+        let paths: Vec<_> = shallow.results.iter().map(|hit| hit.file.path.as_str()).collect();
+        assert_eq!(paths, ["a.txt", "b.txt"]);
+
+        options.base = "docs".to_string();
+        let below = walk_and_filter(&storage, &options, &filter).await;
+        let paths: Vec<_> = below.results.iter().map(|hit| hit.file.path.as_str()).collect();
+        assert_eq!(paths, ["docs/c.txt"]);
+    }
+
+    #[tokio::test]
+    async fn test_search_deduplicated_storage_without_mounts() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Database::new(&format!("sqlite:{}", dir.path().join("test.db").display())).await.unwrap();
+        let mut config = Config::for_tests(dir.path().join("files"));
+        config.storage.dedup.enabled = true;
+        let storage: Arc<dyn StorageBackend> = Arc::new(DedupStorage::new(db.clone(), "", Arc::new(MemoryStorage::new())));
+        storage.mkdir("docs", true).await.unwrap();
+        for path in ["docs/a.txt", "b.txt"] {
+            let mut writer = storage.open_write(path).await.unwrap();
+            writer.write_all(b"same").await.unwrap();
+            writer.commit().await.unwrap();
+        }
+
+        // Nothing is on the local disk by path, so both sources must go through the backend
+        let index = Arc::new(IndexService::new(db, Arc::new(config.clone()), storage.clone()));
+        index.rebuild(&[]).await.unwrap();
+        let search = SearchService::new(config, index, storage);
+        for source in [SearchSource::Walk, SearchSource::Index] {
+            let response = search
+                .search(SearchQuery {
+                    q: Some("*.txt".to_string()),
+                    mode: MatchMode::Glob,
+                    source: Some(source),
+                    ..Default::default()
+                })
+                .await
+                .unwrap();
+            let paths: Vec<_> = response.results.iter().map(|hit| hit.file.path.as_str()).collect();
+            assert_eq!(paths, ["b.txt", "docs/a.txt"], "{:?}", source);
+        }
+    }
+}
@@ -0,0 +1,321 @@
+use crate::{
+    config::Config,
+    errors::ApiError,
+    services::{normalize, parent_of, FileInfo},
+    storage::{EncryptedStorage, Keyring, LocalStorage, StorageBackend},
+};
+use image::{
+    codecs::{jpeg::JpegEncoder, webp::WebPEncoder},
+    DynamicImage, ImageDecoder, ImageFormat, ImageReader,
+};
+use sha2::{Digest, Sha256};
+use std::{
+    io::Cursor,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    sync::Semaphore,
+};
+
+/// Formats thumbnails can be made of
+const SOURCE_FORMATS: [ImageFormat; 6] = [
+    ImageFormat::Jpeg,
+    ImageFormat::Png,
+    ImageFormat::Gif,
+    ImageFormat::WebP,
+    ImageFormat::Bmp,
+    ImageFormat::Tiff,
+];
+
+/// Extension and content type of each format thumbnails are cached in
+const OUTPUTS: [(&str, &str); 2] = [("jpg", "image/jpeg"), ("webp", "image/webp")];
+
+/// A resized image, ready to be served
+pub struct Thumbnail {
+    pub data: Vec<u8>,
+    pub content_type: &'static str,
+    /// Changes whenever the image or the requested size does
+    pub etag: String,
+}
+
+/// Counts a request waiting for a worker for as long as it lives
+struct Waiting<'a>(&'a AtomicUsize);
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// Scaled-down previews of images. They are decoded and resized on a bounded number of
+/// blocking threads, and cached in a store of their own under a hash of the image's path,
+/// modification time and size and of the thumbnail size, so a changed image gets new ones.
+pub struct ThumbnailService {
+    config: Arc<Config>,
+    storage: Arc<dyn StorageBackend>,
+    /// Generated thumbnails, under their cache key
+    store: Arc<dyn StorageBackend>,
+    /// Held by a generation until its blocking thread finishes, even if the request is gone
+    workers: Arc<Semaphore>,
+    waiting: AtomicUsize,
+}
+
+impl ThumbnailService {
+    pub fn new(config: Arc<Config>, storage: Arc<dyn StorageBackend>) -> Result<Self, ApiError> {
+        let mut store: Arc<dyn StorageBackend> = Arc::new(LocalStorage::new(config.thumbnails.directory.clone()));
+        if config.storage.encryption_in_use() {
+            // A thumbnail shows as much as many documents would
+            let keys = Keyring::from_config(&config.storage.encryption)?;
+            store = Arc::new(EncryptedStorage::new(store, Arc::new(keys)));
+        }
+
+        Ok(Self {
+            workers: Arc::new(Semaphore::new(config.thumbnails.workers.max(1))),
+            waiting: AtomicUsize::new(0),
+            config,
+            storage,
+            store,
+        })
+    }
+
+    /// The backend holding the cached thumbnails
+    pub fn store(&self) -> Arc<dyn StorageBackend> {
+        self.store.clone()
+    }
+
+    /// Trim the cache to its maximum size periodically
+    pub fn start_background_tasks(self: &Arc<Self>) {
+        let config = &self.config.thumbnails;
+        if !config.enabled || config.max_cache_size == 0 || config.cleanup_interval_seconds == 0 {
+            return;
+        }
+        let service = self.clone();
+        tokio::spawn(async move {
+            let mut interval =
+                tokio::time::interval(Duration::from_secs(service.config.thumbnails.cleanup_interval_seconds));
+            loop {
+                interval.tick().await;
+                match service.trim_cache().await {
+                    Ok(0) => {}
+                    Ok(deleted) => tracing::info!("Deleted {} cached thumbnails", deleted),
+                    Err(e) => tracing::error!("Thumbnail cache cleanup failed: {}", e),
+                }
+            }
+        });
+    }
+
+    /// The size generated for a request: the smallest configured size at least as large,
+    /// or the largest there is
+    fn edge(&self, requested: Option<u32>) -> u32 {
+        let mut sizes = self.config.thumbnails.sizes.clone();
+        sizes.sort_unstable();
+        let largest = sizes.last().copied().unwrap_or(256);
+        match requested {
+            Some(requested) => sizes.into_iter().find(|size| *size >= requested).unwrap_or(largest),
+            None => sizes.get(sizes.len() / 2).copied().unwrap_or(largest),
+        }
+    }
+
+    fn cache_key(info: &FileInfo, key: &str, edge: u32) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(key.as_bytes());
+        hasher.update([0]);
+        hasher.update(info.modified.timestamp_nanos_opt().unwrap_or_default().to_le_bytes());
+        hasher.update(info.size.to_le_bytes());
+        hasher.update(edge.to_le_bytes());
+        hex::encode(hasher.finalize())
+    }
+
+    /// A cached thumbnail, in whichever format it was made in
+    async fn cached(&self, cache_key: &str) -> Result<Option<Thumbnail>, ApiError> {
+        for (extension, content_type) in OUTPUTS {
+            let blob = format!("{}/{}.{}", &cache_key[..2], cache_key, extension);
+            let mut reader = match self.store.open_read(&blob, None).await {
+                Ok(reader) => reader,
+                Err(ApiError::FileNotFound { .. }) => continue,
+                Err(e) => return Err(e),
+            };
+            let mut data = Vec::new();
+            reader.read_to_end(&mut data).await?;
+            return Ok(Some(Thumbnail {
+                data,
+                content_type,
+                etag: format!("\"{}\"", cache_key),
+            }));
+        }
+        Ok(None)
+    }
+
+    /// A thumbnail of the image at `path` no larger than about `size` pixels on its longest
+    /// edge, generated if it isn't cached
+    pub async fn thumbnail(&self, path: &str, size: Option<u32>) -> Result<Thumbnail, ApiError> {
+        if !self.config.thumbnails.enabled {
+            return Err(ApiError::BadRequest {
+                message: "Thumbnails are disabled".to_string(),
+            });
+        }
+        let key = normalize(path)?;
+        let info = match self.storage.stat(&key).await {
+            Ok(info) => info,
+            Err(ApiError::FileNotFound { .. }) => {
+                return Err(ApiError::FileNotFound {
+                    path: path.to_string(),
+                })
+            }
+            Err(e) => return Err(e),
+        };
+        let format = ImageFormat::from_path(&info.name)
+            .ok()
+            .filter(|format| !info.is_directory && SOURCE_FORMATS.contains(format))
+            .ok_or_else(|| ApiError::InvalidFileType {
+                file_type: info.mime_type.clone().unwrap_or_else(|| info.name.clone()),
+            })?;
+        if info.size > self.config.thumbnails.max_source_size {
+            return Err(ApiError::FileTooLarge { size: info.size });
+        }
+
+        let edge = self.edge(size);
+        let cache_key = Self::cache_key(&info, &key, edge);
+        if let Some(thumbnail) = self.cached(&cache_key).await? {
+            return Ok(thumbnail);
+        }
+
+        let waiting = self.waiting.fetch_add(1, Ordering::AcqRel);
+        let guard = Waiting(&self.waiting);
+        if waiting >= self.config.thumbnails.queue_size {
+            return Err(ApiError::ServiceUnavailable {
+                message: "Too many thumbnails are being generated, try again shortly".to_string(),
+            });
+        }
+        let permit = self.workers.clone().acquire_owned().await.map_err(|e| ApiError::InternalServerError {
+            message: e.to_string(),
+        })?;
+        drop(guard);
+        // Another request for the same thumbnail may have made it while this one waited
+        if let Some(thumbnail) = self.cached(&cache_key).await? {
+            return Ok(thumbnail);
+        }
+
+        let mut data = Vec::with_capacity(info.size as usize);
+        self.storage.open_read(&key, None).await?.read_to_end(&mut data).await?;
+        let quality = self.config.thumbnails.jpeg_quality;
+        let (data, output) = tokio::task::spawn_blocking(move || {
+            let _permit = permit;
+            render(&data, format, edge, quality)
+        })
+            .await
+            .map_err(|e| ApiError::InternalServerError {
+                message: format!("Thumbnail generation failed: {}", e),
+            })?
+            .map_err(|e| ApiError::BadRequest {
+                message: format!("Cannot make a thumbnail of {}: {}", path, e),
+            })?;
+
+        let (extension, content_type) = OUTPUTS[output];
+        let blob = format!("{}/{}.{}", &cache_key[..2], cache_key, extension);
+        if let Err(e) = self.save(&blob, &data).await {
+            tracing::warn!("Failed to cache thumbnail of {}: {}", key, e);
+        }
+        Ok(Thumbnail {
+            data,
+            content_type,
+            etag: format!("\"{}\"", cache_key),
+        })
+    }
+
+    async fn save(&self, blob: &str, data: &[u8]) -> Result<(), ApiError> {
+        self.store.mkdir(&parent_of(blob), true).await?;
+        let mut writer = self.store.open_write(blob).await?;
+        writer.write_all(data).await?;
+        writer.commit().await?;
+        Ok(())
+    }
+
+    /// Delete the oldest thumbnails until the cache is within its maximum size, returning
+    /// how many were deleted
+    pub async fn trim_cache(&self) -> Result<usize, ApiError> {
+        let mut thumbnails = Vec::new();
+        for directory in self.store.list("").await? {
+            if directory.is_directory {
+                thumbnails.extend(self.store.list(&directory.path).await?);
+            }
+        }
+        let mut total: u64 = thumbnails.iter().map(|thumbnail| thumbnail.size).sum();
+        thumbnails.sort_by_key(|thumbnail| thumbnail.modified);
+
+        let mut deleted = 0;
+        for thumbnail in thumbnails {
+            if total <= self.config.thumbnails.max_cache_size {
+                break;
+            }
+            match self.store.delete(&thumbnail.path).await {
+                Ok(()) | Err(ApiError::FileNotFound { .. }) => {}
+                Err(e) => return Err(e),
+            }
+            total -= thumbnail.size;
+            deleted += 1;
+        }
+        Ok(deleted)
+    }
+}
+
+/// Decode an image, turn it upright, shrink it to fit `edge` and encode it as JPEG, or as
+/// WebP if it has transparency. Returns the encoded image and its index in `OUTPUTS`.
+fn render(data: &[u8], format: ImageFormat, edge: u32, quality: u8) -> image::ImageResult<(Vec<u8>, usize)> {
+    let mut decoder = ImageReader::with_format(Cursor::new(data), format).into_decoder()?;
+    let orientation = decoder.orientation()?;
+    let mut image = DynamicImage::from_decoder(decoder)?;
+    image.apply_orientation(orientation);
+    if image.width() > edge || image.height() > edge {
+        image = image.thumbnail(edge, edge);
+    }
+
+    let mut output = Vec::new();
+    if image.color().has_alpha() {
+        let rgba = image.to_rgba8();
+        WebPEncoder::new_lossless(&mut output).encode(
+            &rgba,
+            rgba.width(),
+            rgba.height(),
+            image::ExtendedColorType::Rgba8,
+        )?;
+        Ok((output, 1))
+    } else {
+        JpegEncoder::new_with_quality(&mut output, quality).encode_image(&image.to_rgb8())?;
+        Ok((output, 0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgb, RgbImage};
+
+    #[test]
+    fn test_render_fits_edge_and_applies_orientation() {
+        let mut jpeg = Vec::new();
+        let image = RgbImage::from_pixel(400, 100, Rgb([200, 10, 10]));
+        JpegEncoder::new(&mut jpeg).encode_image(&image).unwrap();
+        let (thumbnail, output) = render(&jpeg, ImageFormat::Jpeg, 128, 80).unwrap();
+        assert_eq!(OUTPUTS[output].0, "jpg");
+        let thumbnail = image::load_from_memory(&thumbnail).unwrap();
+        assert_eq!((thumbnail.width(), thumbnail.height()), (128, 32));
+
+        // An Exif segment saying the camera was turned: orientation 6, rotate 90° clockwise
+        let exif: [u8; 36] = [
+            0xFF, 0xE1, 0x00, 0x22, b'E', b'x', b'i', b'f', 0, 0, b'I', b'I', 0x2A, 0, 8, 0, 0, 0, 1, 0,
+            0x12, 0x01, 3, 0, 1, 0, 0, 0, 6, 0, 0, 0, 0, 0, 0, 0,
+        ];
+        let rotated: Vec<u8> = jpeg[..2].iter().chain(&exif).chain(&jpeg[2..]).copied().collect();
+        let (thumbnail, _) = render(&rotated, ImageFormat::Jpeg, 128, 80).unwrap();
+        let thumbnail = image::load_from_memory(&thumbnail).unwrap();
+        assert_eq!((thumbnail.width(), thumbnail.height()), (32, 128));
+
+        assert!(render(b"not an image", ImageFormat::Jpeg, 128, 80).is_err());
+    }
+}
@@ -0,0 +1,453 @@
+use crate::{
+    config::Config,
+    db::Database,
+    errors::ApiError,
+    services::{parent_of, subtree_bounds, FileInfo},
+    storage::{EncryptedStorage, Keyring, LocalStorage, StorageBackend, StorageReader},
+    utils::diff,
+};
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use sqlx::{sqlite::SqliteRow, Row};
+use std::{collections::HashSet, sync::Arc};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use uuid::Uuid;
+
+/// Largest file compared line by line
+const MAX_DIFF_SIZE: u64 = 1024 * 1024;
+
+/// Contents a file had before it was overwritten
+#[derive(Debug, Clone, Serialize)]
+pub struct FileVersion {
+    pub id: String,
+    pub path: String,
+    pub size: u64,
+    pub sha256: String,
+    /// When these contents were written
+    pub modified: DateTime<Utc>,
+    /// When they were overwritten, and by whom
+    pub replaced_at: DateTime<Utc>,
+    pub replaced_by: String,
+}
+
+impl FileVersion {
+    fn from_row(row: &SqliteRow) -> Self {
+        Self {
+            id: row.get("id"),
+            path: row.get("path"),
+            size: row.get::<i64, _>("size") as u64,
+            sha256: row.get("sha256"),
+            modified: from_millis(row.get("modified")),
+            replaced_at: from_millis(row.get("replaced_at")),
+            replaced_by: row.get("replaced_by"),
+        }
+    }
+}
+
+/// Two sets of contents of a file compared line by line
+#[derive(Debug, Serialize)]
+pub struct VersionDiff {
+    pub from: FileVersion,
+    /// `None` when compared with the file as it is now
+    pub to: Option<FileVersion>,
+    pub identical: bool,
+    /// Unified diff from `from` to `to`
+    pub diff: String,
+}
+
+fn from_millis(millis: i64) -> DateTime<Utc> {
+    Utc.timestamp_millis_opt(millis).single().unwrap_or_default()
+}
+
+/// Where a version's contents are kept in the versions store
+fn blob_path(id: &str) -> String {
+    format!("{}/{}", &id[..2], id)
+}
+
+/// Earlier contents of overwritten files. They are copied to a store of their own before
+/// being replaced and recorded in SQLite by path, so that they can be listed, compared and
+/// restored; they stay when the file is deleted and follow it when it is renamed.
+pub struct VersionService {
+    db: Database,
+    config: Arc<Config>,
+    storage: Arc<dyn StorageBackend>,
+    /// Each version's contents, under its id
+    store: Arc<dyn StorageBackend>,
+}
+
+impl VersionService {
+    pub fn new(db: Database, config: Arc<Config>, storage: Arc<dyn StorageBackend>) -> Result<Self, ApiError> {
+        let mut store: Arc<dyn StorageBackend> = Arc::new(LocalStorage::new(config.versioning.directory.clone()));
+        if config.storage.encryption_in_use() {
+            // Earlier contents are no less sensitive than the files themselves
+            let keys = Keyring::from_config(&config.storage.encryption)?;
+            store = Arc::new(EncryptedStorage::new(store, Arc::new(keys)));
+        }
+
+        Ok(Self {
+            db,
+            config,
+            storage,
+            store,
+        })
+    }
+
+    /// The backend holding the versions' contents
+    pub fn store(&self) -> Arc<dyn StorageBackend> {
+        self.store.clone()
+    }
+
+    /// Keep the current contents of the file at normalized `path` as a version, before
+    /// `user` overwrites it. `None` when versioning is off or there is no file there.
+    pub async fn preserve(&self, path: &str, user: &str) -> Result<Option<FileVersion>, ApiError> {
+        if !self.config.versioning.enabled {
+            return Ok(None);
+        }
+        let info = match self.storage.stat(path).await {
+            Ok(info) if !info.is_directory => info,
+            Ok(_) | Err(ApiError::FileNotFound { .. }) => return Ok(None),
+            Err(e) => return Err(e),
+        };
+
+        let id = Uuid::new_v4().simple().to_string();
+        let blob = blob_path(&id);
+        self.store.mkdir(&parent_of(&blob), true).await?;
+
+        let mut reader = self.storage.open_read(path, None).await?;
+        let mut writer = self.store.open_write(&blob).await?;
+        let mut hasher = Sha256::new();
+        let mut buffer = vec![0; 64 * 1024];
+        let mut size: u64 = 0;
+        loop {
+            let read = reader.read(&mut buffer).await?;
+            if read == 0 {
+                break;
+            }
+            hasher.update(&buffer[..read]);
+            writer.write_all(&buffer[..read]).await?;
+            size += read as u64;
+        }
+        writer.commit().await?;
+
+        let version = FileVersion {
+            id,
+            path: path.to_string(),
+            size,
+            sha256: hex::encode(hasher.finalize()),
+            modified: info.modified,
+            replaced_at: Utc::now(),
+            replaced_by: user.to_string(),
+        };
+        sqlx::query(
+            r#"
+            INSERT INTO file_versions (id, path, size, sha256, modified, replaced_at, replaced_by)
+            VALUES (?, ?, ?, ?, ?, ?, ?)
+            "#,
+        )
+        .bind(&version.id)
+        .bind(&version.path)
+        .bind(version.size as i64)
+        .bind(&version.sha256)
+        .bind(version.modified.timestamp_millis())
+        .bind(version.replaced_at.timestamp_millis())
+        .bind(&version.replaced_by)
+        .execute(self.db.pool())
+        .await?;
+
+        // A failed cleanup only leaves more versions than asked for
+        if let Err(e) = self.apply_retention(Some(path)).await {
+            tracing::warn!("Failed to delete old versions: {}", e);
+        }
+        Ok(Some(version))
+    }
+
+    /// The versions of the file at normalized `path`, newest first
+    pub async fn list(&self, path: &str) -> Result<Vec<FileVersion>, ApiError> {
+        let rows = sqlx::query("SELECT * FROM file_versions WHERE path = ? ORDER BY replaced_at DESC, id")
+            .bind(path)
+            .fetch_all(self.db.pool())
+            .await?;
+        Ok(rows.iter().map(FileVersion::from_row).collect())
+    }
+
+    pub async fn get(&self, id: &str) -> Result<FileVersion, ApiError> {
+        sqlx::query("SELECT * FROM file_versions WHERE id = ?")
+            .bind(id)
+            .fetch_optional(self.db.pool())
+            .await?
+            .map(|row| FileVersion::from_row(&row))
+            .ok_or_else(|| ApiError::NotFound {
+                resource: "Version".to_string(),
+                id: id.to_string(),
+            })
+    }
+
+    /// Read the contents of a version
+    pub async fn open(&self, id: &str) -> Result<(StorageReader, FileVersion), ApiError> {
+        let version = self.get(id).await?;
+        let reader = self.store.open_read(&blob_path(&version.id), None).await?;
+        Ok((reader, version))
+    }
+
+    /// Put a version's contents back in place as `user`, keeping the contents it replaces as
+    /// a version in turn. Returns the file info and whether the file was created.
+    pub async fn restore(&self, id: &str, user: &str) -> Result<(FileInfo, bool), ApiError> {
+        let version = self.get(id).await?;
+        let created = match self.storage.stat(&version.path).await {
+            Ok(info) if info.is_directory => {
+                return Err(ApiError::Conflict {
+                    message: "A directory with this name already exists".to_string(),
+                })
+            }
+            Ok(_) => false,
+            Err(ApiError::FileNotFound { .. }) => true,
+            Err(e) => return Err(e),
+        };
+
+        self.storage.mkdir(&parent_of(&version.path), true).await?;
+        let mut reader = self.store.open_read(&blob_path(&version.id), None).await?;
+        let mut writer = self.storage.open_write(&version.path).await?;
+        tokio::io::copy(&mut reader, &mut writer).await?;
+        if !created {
+            self.preserve(&version.path, user).await?;
+        }
+        let info = writer.commit().await?;
+
+        Ok((info, created))
+    }
+
+    /// Compare a version line by line with another version, or with the file as it is now
+    pub async fn diff(&self, id: &str, against: Option<&str>) -> Result<VersionDiff, ApiError> {
+        let from = self.get(id).await?;
+        let old = self.read_text(&*self.store, &blob_path(&from.id), from.size).await?;
+
+        let (to, new, new_label) = match against {
+            Some(other) => {
+                let to = self.get(other).await?;
+                let new = self.read_text(&*self.store, &blob_path(&to.id), to.size).await?;
+                let label = format!("{} ({})", to.path, to.replaced_at.to_rfc3339());
+                (Some(to), new, label)
+            }
+            None => {
+                let size = match self.storage.stat(&from.path).await {
+                    Ok(info) if !info.is_directory => info.size,
+                    Ok(_) | Err(ApiError::FileNotFound { .. }) => {
+                        return Err(ApiError::FileNotFound {
+                            path: from.path.clone(),
+                        })
+                    }
+                    Err(e) => return Err(e),
+                };
+                let new = self.read_text(&*self.storage, &from.path, size).await?;
+                (None, new, format!("{} (current)", from.path))
+            }
+        };
+
+        let old_label = format!("{} ({})", from.path, from.replaced_at.to_rfc3339());
+        let diff = diff::unified(&old, &new, &old_label, &new_label);
+        Ok(VersionDiff {
+            from,
+            to,
+            identical: old == new,
+            diff,
+        })
+    }
+
+    async fn read_text(&self, backend: &dyn StorageBackend, path: &str, size: u64) -> Result<String, ApiError> {
+        if size > MAX_DIFF_SIZE {
+            return Err(ApiError::BadRequest {
+                message: format!("Only files up to {} bytes can be compared", MAX_DIFF_SIZE),
+            });
+        }
+        let mut data = Vec::with_capacity(size as usize);
+        backend.open_read(path, None).await?.read_to_end(&mut data).await?;
+        String::from_utf8(data).map_err(|_| ApiError::BadRequest {
+            message: "Only text files can be compared".to_string(),
+        })
+    }
+
+    /// Delete a version and its contents
+    pub async fn delete(&self, id: &str) -> Result<(), ApiError> {
+        let version = self.get(id).await?;
+        self.remove(&version.id).await
+    }
+
+    /// Delete all but the newest `keep` versions of the file at normalized `path`,
+    /// returning how many were deleted
+    pub async fn prune(&self, path: &str, keep: usize) -> Result<usize, ApiError> {
+        let ids: Vec<String> = sqlx::query_scalar(
+            "SELECT id FROM file_versions WHERE path = ? ORDER BY replaced_at DESC, id LIMIT -1 OFFSET ?",
+        )
+        .bind(path)
+        .bind(keep as i64)
+        .fetch_all(self.db.pool())
+        .await?;
+
+        for id in &ids {
+            self.remove(id).await?;
+        }
+        Ok(ids.len())
+    }
+
+    /// Let the versions of a file or directory tree follow it to its new path
+    pub async fn rename(&self, from: &str, to: &str) -> Result<(), ApiError> {
+        let (lower, upper) = subtree_bounds(from);
+        sqlx::query(
+            r#"
+            UPDATE file_versions SET path = ? || substr(path, length(?) + 1)
+            WHERE path = ? OR (path > ? AND path < ?)
+            "#,
+        )
+        .bind(to)
+        .bind(from)
+        .bind(from)
+        .bind(lower)
+        .bind(upper)
+        .execute(self.db.pool())
+        .await?;
+        Ok(())
+    }
+
+    /// Delete the versions the retention policy no longer keeps, only counting those of
+    /// `path` against `max_versions` when given. Returns how many were deleted.
+    pub async fn apply_retention(&self, path: Option<&str>) -> Result<usize, ApiError> {
+        let policy = &self.config.versioning;
+        let mut expired: Vec<String> = Vec::new();
+
+        if policy.max_versions > 0 {
+            expired.extend(
+                sqlx::query_scalar::<_, String>(
+                    r#"
+                    SELECT id FROM (
+                        SELECT id, ROW_NUMBER() OVER (PARTITION BY path ORDER BY replaced_at DESC, id) AS n
+                        FROM file_versions WHERE ?1 IS NULL OR path = ?1
+                    ) WHERE n > ?2
+                    "#,
+                )
+                .bind(path)
+                .bind(policy.max_versions as i64)
+                .fetch_all(self.db.pool())
+                .await?,
+            );
+        }
+
+        if policy.max_age_days > 0 {
+            let cutoff = Utc::now() - Duration::days(policy.max_age_days as i64);
+            expired.extend(
+                sqlx::query_scalar::<_, String>("SELECT id FROM file_versions WHERE replaced_at < ?")
+                    .bind(cutoff.timestamp_millis())
+                    .fetch_all(self.db.pool())
+                    .await?,
+            );
+        }
+
+        if policy.max_total_size > 0 {
+            // The newest versions are kept for as long as they fit
+            let versions: Vec<(String, i64)> =
+                sqlx::query_as("SELECT id, size FROM file_versions ORDER BY replaced_at DESC, id")
+                    .fetch_all(self.db.pool())
+                    .await?;
+            let mut total: u64 = 0;
+            for (id, size) in versions {
+                total += size as u64;
+                if total > policy.max_total_size {
+                    expired.push(id);
+                }
+            }
+        }
+
+        let mut seen = HashSet::new();
+        expired.retain(|id| seen.insert(id.clone()));
+        for id in &expired {
+            self.remove(id).await?;
+        }
+        Ok(expired.len())
+    }
+
+    async fn remove(&self, id: &str) -> Result<(), ApiError> {
+        match self.store.delete(&blob_path(id)).await {
+            Ok(()) | Err(ApiError::FileNotFound { .. }) => {}
+            Err(e) => return Err(e),
+        }
+        sqlx::query("DELETE FROM file_versions WHERE id = ?")
+            .bind(id)
+            .execute(self.db.pool())
+            .await?;
+        Ok(())
+    }
+
+    /// Apply the retention policy every `cleanup_interval_seconds`, so that versions expire
+    /// by age even when nothing is written
+    pub fn start_background_tasks(self: &Arc<Self>) {
+        let interval_seconds = self.config.versioning.cleanup_interval_seconds;
+        if !self.config.versioning.enabled || interval_seconds == 0 {
+            return;
+        }
+        let service = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(std::time::Duration::from_secs(interval_seconds));
+            loop {
+                interval.tick().await;
+                match service.apply_retention(None).await {
+                    Ok(0) => {}
+                    Ok(count) => tracing::info!("Deleted {} old file versions", count),
+                    Err(e) => tracing::error!("Failed to delete old file versions: {}", e),
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+
+    async fn write(storage: &dyn StorageBackend, path: &str, contents: &str) {
+        let mut writer = storage.open_write(path).await.unwrap();
+        writer.write_all(contents.as_bytes()).await.unwrap();
+        writer.commit().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_overwritten_contents_can_be_restored() {
+        let dir = tempfile::tempdir().unwrap();
+        let url = format!("sqlite:{}", dir.path().join("test.db").display());
+        let db = Database::new(&url).await.unwrap();
+        let mut config = Config::for_tests(".");
+        config.versioning.max_versions = 2;
+        config.versioning.directory = dir.path().join("versions");
+        let storage: Arc<dyn StorageBackend> = Arc::new(MemoryStorage::new());
+        let versions = VersionService::new(db, Arc::new(config), storage.clone()).unwrap();
+
+        assert!(versions.preserve("sheet.csv", "alice").await.unwrap().is_none());
+        for contents in ["a,1\n", "a,2\n", "a,3\n", "a,4\n"] {
+            versions.preserve("sheet.csv", "alice").await.unwrap();
+            write(storage.as_ref(), "sheet.csv", contents).await;
+        }
+
+        // Only the newest two of the three replaced contents are kept
+        let list = versions.list("sheet.csv").await.unwrap();
+        assert_eq!(list.len(), 2);
+        assert_eq!(list[1].sha256, hex::encode(Sha256::digest(b"a,2\n")));
+
+        let diff = versions.diff(&list[1].id, None).await.unwrap();
+        assert!(diff.diff.ends_with("@@ -1,1 +1,1 @@\n-a,2\n+a,4\n"));
+
+        let (_, created) = versions.restore(&list[1].id, "bob").await.unwrap();
+        assert!(!created);
+        let mut restored = String::new();
+        storage.open_read("sheet.csv", None).await.unwrap().read_to_string(&mut restored).await.unwrap();
+        assert_eq!(restored, "a,2\n");
+
+        // What the restore replaced is a version in turn
+        let list = versions.list("sheet.csv").await.unwrap();
+        assert_eq!((list[0].replaced_by.as_str(), list[0].size), ("bob", 4));
+        assert_eq!(list[0].sha256, hex::encode(Sha256::digest(b"a,4\n")));
+
+        versions.rename("sheet.csv", "archive.csv").await.unwrap();
+        assert_eq!(versions.prune("archive.csv", 0).await.unwrap(), 2);
+        assert!(versions.list("archive.csv").await.unwrap().is_empty());
+    }
+}
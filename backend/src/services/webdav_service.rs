@@ -0,0 +1,347 @@
+use crate::{
+    db::Database,
+    errors::ApiError,
+    services::{subtree_bounds, ChangeEvent, ChangeKind},
+};
+use sqlx::Row;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::sync::broadcast;
+use uuid::Uuid;
+
+/// Longest lock timeout we grant, whatever the client asks for
+pub const MAX_LOCK_TIMEOUT: Duration = Duration::from_secs(3600);
+
+/// A WebDAV write lock
+#[derive(Debug, Clone)]
+pub struct DavLock {
+    /// `opaquelocktoken:` URI identifying the lock
+    pub token: String,
+    /// Normalized path of the locked resource
+    pub path: String,
+    pub exclusive: bool,
+    /// Whether the lock also covers everything below `path`
+    pub deep: bool,
+    /// The client-supplied owner element, as XML
+    pub owner: Option<String>,
+    pub timeout: Duration,
+    expires_at: Instant,
+}
+
+impl DavLock {
+    fn covers(&self, path: &str) -> bool {
+        self.path == path || (self.deep && is_below(path, &self.path))
+    }
+
+    pub fn remaining(&self) -> Duration {
+        self.expires_at.saturating_duration_since(Instant::now())
+    }
+}
+
+/// A property set through PROPPATCH
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeadProperty {
+    pub namespace: String,
+    pub name: String,
+    /// Property value as XML
+    pub value: String,
+}
+
+pub enum PropertyUpdate {
+    Set(DeadProperty),
+    Remove { namespace: String, name: String },
+}
+
+/// State behind the WebDAV endpoint: in-memory locks and SQLite-backed dead properties
+pub struct WebDavService {
+    db: Database,
+    locks: Mutex<HashMap<String, DavLock>>,
+}
+
+impl WebDavService {
+    pub fn new(db: Database) -> Self {
+        Self {
+            db,
+            locks: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Drop properties of entries removed outside WebDAV
+    pub fn start_background_tasks(self: &Arc<Self>, mut changes: broadcast::Receiver<ChangeEvent>) {
+        let service = self.clone();
+        tokio::spawn(async move {
+            loop {
+                match changes.recv().await {
+                    Ok(ChangeEvent::Changed(batch)) => {
+                        // Only deletions are safe to apply here: the kind reflects the final
+                        // state on disk, while a rename's source may have been reused since
+                        for change in batch.iter().filter(|change| change.kind == ChangeKind::Deleted) {
+                            if let Err(e) = service.delete_properties(&change.path).await {
+                                tracing::warn!("Failed to drop WebDAV properties of {}: {}", change.path, e);
+                            }
+                        }
+                    }
+                    Ok(ChangeEvent::Rescan { .. }) | Err(broadcast::error::RecvError::Lagged(_)) => {}
+                    Err(broadcast::error::RecvError::Closed) => return,
+                }
+            }
+        });
+    }
+
+    /// Take a new lock, failing if it conflicts with an existing one
+    pub fn lock(
+        &self,
+        path: &str,
+        exclusive: bool,
+        deep: bool,
+        owner: Option<String>,
+        timeout: Duration,
+    ) -> Option<DavLock> {
+        let mut locks = self.locks.lock().unwrap();
+        locks.retain(|_, lock| lock.expires_at > Instant::now());
+
+        let conflict = locks.values().any(|existing| {
+            let overlaps = existing.covers(path) || (deep && is_below(&existing.path, path));
+            overlaps && (existing.exclusive || exclusive)
+        });
+        if conflict {
+            return None;
+        }
+
+        let timeout = timeout.min(MAX_LOCK_TIMEOUT);
+        let lock = DavLock {
+            token: format!("opaquelocktoken:{}", Uuid::new_v4()),
+            path: path.to_string(),
+            exclusive,
+            deep,
+            owner,
+            timeout,
+            expires_at: Instant::now() + timeout,
+        };
+        locks.insert(lock.token.clone(), lock.clone());
+        Some(lock)
+    }
+
+    /// Extend a lock covering `path` whose token the client presented
+    pub fn refresh(&self, path: &str, tokens: &[String], timeout: Duration) -> Option<DavLock> {
+        let mut locks = self.locks.lock().unwrap();
+        locks.retain(|_, lock| lock.expires_at > Instant::now());
+
+        let timeout = timeout.min(MAX_LOCK_TIMEOUT);
+        tokens.iter().find_map(|token| {
+            let lock = locks.get_mut(token).filter(|lock| lock.covers(path))?;
+            lock.timeout = timeout;
+            lock.expires_at = Instant::now() + timeout;
+            Some(lock.clone())
+        })
+    }
+
+    /// Release the lock `token` if it covers `path`
+    pub fn unlock(&self, path: &str, token: &str) -> bool {
+        let mut locks = self.locks.lock().unwrap();
+        if locks.get(token).is_some_and(|lock| lock.covers(path)) {
+            locks.remove(token);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Active locks covering `path`
+    pub fn locks_on(&self, path: &str) -> Vec<DavLock> {
+        let locks = self.locks.lock().unwrap();
+        locks
+            .values()
+            .filter(|lock| lock.expires_at > Instant::now() && lock.covers(path))
+            .cloned()
+            .collect()
+    }
+
+    /// Check that the client may modify `path` (and, with `subtree`, everything below it):
+    /// every lock involved must be one whose token was submitted
+    pub fn can_modify(&self, path: &str, subtree: bool, tokens: &[String]) -> bool {
+        let locks = self.locks.lock().unwrap();
+        locks
+            .values()
+            .filter(|lock| lock.expires_at > Instant::now())
+            .filter(|lock| lock.covers(path) || (subtree && is_below(&lock.path, path)))
+            .all(|lock| tokens.contains(&lock.token))
+    }
+
+    /// Forget locks on `path` and below, after it was deleted or moved away
+    pub fn release_subtree(&self, path: &str) {
+        let mut locks = self.locks.lock().unwrap();
+        locks.retain(|_, lock| lock.path != path && !is_below(&lock.path, path));
+    }
+
+    pub async fn properties(&self, path: &str) -> Result<Vec<DeadProperty>, ApiError> {
+        let rows = sqlx::query(
+            "SELECT namespace, name, value FROM dav_properties WHERE path = ? ORDER BY namespace, name",
+        )
+        .bind(path)
+        .fetch_all(self.db.pool())
+        .await?;
+
+        Ok(rows
+            .into_iter()
+            .map(|row| DeadProperty {
+                namespace: row.get("namespace"),
+                name: row.get("name"),
+                value: row.get("value"),
+            })
+            .collect())
+    }
+
+    /// Apply every update or none of them
+    pub async fn update_properties(&self, path: &str, updates: &[PropertyUpdate]) -> Result<(), ApiError> {
+        let mut tx = self.db.pool().begin().await?;
+        for update in updates {
+            match update {
+                PropertyUpdate::Set(property) => {
+                    sqlx::query(
+                        r#"
+                        INSERT INTO dav_properties (path, namespace, name, value) VALUES (?, ?, ?, ?)
+                        ON CONFLICT(path, namespace, name) DO UPDATE SET value = excluded.value
+                        "#,
+                    )
+                    .bind(path)
+                    .bind(&property.namespace)
+                    .bind(&property.name)
+                    .bind(&property.value)
+                    .execute(&mut *tx)
+                    .await?;
+                }
+                PropertyUpdate::Remove { namespace, name } => {
+                    sqlx::query("DELETE FROM dav_properties WHERE path = ? AND namespace = ? AND name = ?")
+                        .bind(path)
+                        .bind(namespace)
+                        .bind(name)
+                        .execute(&mut *tx)
+                        .await?;
+                }
+            }
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Copy the properties of `from` and everything below it to `to`
+    pub async fn copy_properties(&self, from: &str, to: &str) -> Result<(), ApiError> {
+        let (lower, upper) = subtree_bounds(from);
+        sqlx::query(
+            r#"
+            INSERT OR REPLACE INTO dav_properties (path, namespace, name, value)
+            SELECT ? || substr(path, length(?) + 1), namespace, name, value FROM dav_properties
+            WHERE path = ? OR (path > ? AND path < ?)
+            "#,
+        )
+        .bind(to)
+        .bind(from)
+        .bind(from)
+        .bind(lower)
+        .bind(upper)
+        .execute(self.db.pool())
+        .await?;
+        Ok(())
+    }
+
+    /// Move the properties of `from` and everything below it to `to`
+    pub async fn move_properties(&self, from: &str, to: &str) -> Result<(), ApiError> {
+        self.delete_properties(to).await?;
+        let (lower, upper) = subtree_bounds(from);
+        sqlx::query(
+            r#"
+            UPDATE dav_properties SET path = ? || substr(path, length(?) + 1)
+            WHERE path = ? OR (path > ? AND path < ?)
+            "#,
+        )
+        .bind(to)
+        .bind(from)
+        .bind(from)
+        .bind(lower)
+        .bind(upper)
+        .execute(self.db.pool())
+        .await?;
+        Ok(())
+    }
+
+    /// Drop the properties of `path` and everything below it
+    pub async fn delete_properties(&self, path: &str) -> Result<(), ApiError> {
+        let (lower, upper) = subtree_bounds(path);
+        sqlx::query("DELETE FROM dav_properties WHERE path = ? OR (path > ? AND path < ?)")
+            .bind(path)
+            .bind(lower)
+            .bind(upper)
+            .execute(self.db.pool())
+            .await?;
+        Ok(())
+    }
+}
+
+/// Whether `path` is strictly inside `ancestor`; "" is the root
+fn is_below(path: &str, ancestor: &str) -> bool {
+    if ancestor.is_empty() {
+        return !path.is_empty();
+    }
+    path.strip_prefix(ancestor)
+        .is_some_and(|rest| rest.starts_with('/'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn service() -> (tempfile::TempDir, WebDavService) {
+        let dir = tempfile::tempdir().unwrap();
+        let url = format!("sqlite:{}", dir.path().join("test.db").display());
+        let db = Database::new(&url).await.unwrap();
+        (dir, WebDavService::new(db))
+    }
+
+    #[tokio::test]
+    async fn test_locks_conflict_and_require_tokens() {
+        let (_dir, dav) = service().await;
+        let timeout = Duration::from_secs(60);
+
+        let deep = dav.lock("docs", true, true, None, timeout).unwrap();
+        assert!(dav.lock("docs/a.txt", false, false, None, timeout).is_none());
+        assert!(dav.lock("", true, true, None, timeout).is_none());
+        assert!(dav.lock("docs-old", true, false, None, timeout).is_some());
+
+        assert!(!dav.can_modify("docs/a.txt", false, &[]));
+        assert!(dav.can_modify("docs/a.txt", false, std::slice::from_ref(&deep.token)));
+        // Deleting the root would remove the locked subtree
+        assert!(!dav.can_modify("", true, &[]));
+
+        assert!(!dav.unlock("elsewhere", &deep.token));
+        assert!(dav.unlock("docs/a.txt", &deep.token));
+        assert!(dav.can_modify("docs/a.txt", false, &[]));
+    }
+
+    #[tokio::test]
+    async fn test_properties_follow_copies_and_moves() {
+        let (_dir, dav) = service().await;
+        let property = |value: &str| DeadProperty {
+            namespace: "urn:example".to_string(),
+            name: "color".to_string(),
+            value: value.to_string(),
+        };
+        dav.update_properties("docs", &[PropertyUpdate::Set(property("red"))]).await.unwrap();
+        dav.update_properties("docs/a.txt", &[PropertyUpdate::Set(property("blue"))]).await.unwrap();
+        dav.update_properties("docs-old", &[PropertyUpdate::Set(property("grey"))]).await.unwrap();
+
+        dav.copy_properties("docs", "copy").await.unwrap();
+        assert_eq!(dav.properties("copy/a.txt").await.unwrap(), vec![property("blue")]);
+
+        dav.move_properties("docs", "moved").await.unwrap();
+        assert!(dav.properties("docs").await.unwrap().is_empty());
+        assert_eq!(dav.properties("moved").await.unwrap(), vec![property("red")]);
+        assert_eq!(dav.properties("docs-old").await.unwrap(), vec![property("grey")]);
+
+        dav.delete_properties("moved").await.unwrap();
+        assert!(dav.properties("moved/a.txt").await.unwrap().is_empty());
+    }
+}
@@ -0,0 +1,777 @@
+use super::{file_name, is_within, StorageBackend, StorageReader, StorageWriter};
+use crate::{
+    db::Database,
+    errors::ApiError,
+    services::{parent_of, FileInfo},
+};
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, Utc};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use sqlx::{Row, SqliteConnection};
+use std::{
+    ops::Range,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+use tokio::{
+    fs as async_fs,
+    io::{AsyncReadExt, AsyncWrite},
+};
+use uuid::Uuid;
+
+/// Stored contents without a record are only collected once they are this old, so an upload
+/// between storing its contents and recording them isn't collected from under it
+const ORPHAN_GRACE_MINUTES: i64 = 60;
+
+/// Files stored once per distinct content. The tree lives in SQLite, and each file refers to
+/// its contents by SHA-256; the contents are kept in another backend under their hash, with a
+/// count of the paths referring to them. Contents nothing refers to any more are left for
+/// `collect_garbage`, so that they can still be reused until then.
+#[derive(Clone)]
+pub struct DedupStorage {
+    db: Database,
+    /// Tells apart the trees of several deduplicated mounts
+    root: String,
+    blobs: Arc<dyn StorageBackend>,
+}
+
+/// Contents deleted by a garbage collection
+#[derive(Debug, Default, Clone, Copy, Serialize)]
+pub struct CollectedGarbage {
+    pub blobs: u64,
+    pub bytes: u64,
+}
+
+impl std::ops::AddAssign for CollectedGarbage {
+    fn add_assign(&mut self, other: Self) {
+        self.blobs += other.blobs;
+        self.bytes += other.bytes;
+    }
+}
+
+struct Entry {
+    is_directory: bool,
+    size: u64,
+    modified: DateTime<Utc>,
+    hash: Option<String>,
+}
+
+impl Entry {
+    fn info(&self, path: &str) -> FileInfo {
+        let name = file_name(path).to_string();
+        FileInfo {
+            mime_type: match self.is_directory {
+                true => None,
+                false => mime_guess::from_path(&name).first().map(|mime| mime.to_string()),
+            },
+            name,
+            path: path.to_string(),
+            size: self.size,
+            modified: self.modified,
+            is_directory: self.is_directory,
+        }
+    }
+}
+
+/// Where contents are kept in the blob backend, spread over two levels of directories
+fn blob_path(hash: &str) -> String {
+    format!("{}/{}/{}", &hash[..2], &hash[2..4], hash)
+}
+
+fn is_hash(value: &str) -> bool {
+    value.len() == 64 && value.bytes().all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+}
+
+/// SQL matching `column` when it is the path bound to `param` or below it
+fn subtree(column: &str, param: u8) -> String {
+    format!(
+        "(?{param} = '' OR {column} = ?{param} OR substr({column}, 1, length(?{param}) + 1) = ?{param} || '/')"
+    )
+}
+
+fn millis(time: DateTime<Utc>) -> i64 {
+    time.timestamp_millis()
+}
+
+impl DedupStorage {
+    pub fn new(db: Database, root: &str, blobs: Arc<dyn StorageBackend>) -> Self {
+        Self {
+            db,
+            root: root.to_string(),
+            blobs,
+        }
+    }
+
+    async fn find(&self, conn: &mut SqliteConnection, path: &str) -> Result<Option<Entry>, ApiError> {
+        // The root always exists
+        if path.is_empty() {
+            return Ok(Some(Entry {
+                is_directory: true,
+                size: 0,
+                modified: Utc::now(),
+                hash: None,
+            }));
+        }
+
+        let row = sqlx::query("SELECT is_directory, size, modified, hash FROM dedup_entries WHERE root = ? AND path = ?")
+            .bind(&self.root)
+            .bind(path)
+            .fetch_optional(&mut *conn)
+            .await?;
+        Ok(row.map(|row| Entry {
+            is_directory: row.get("is_directory"),
+            size: row.get::<i64, _>("size") as u64,
+            modified: DateTime::from_timestamp_millis(row.get("modified")).unwrap_or_default(),
+            hash: row.get("hash"),
+        }))
+    }
+
+    async fn get(&self, conn: &mut SqliteConnection, path: &str) -> Result<Entry, ApiError> {
+        self.find(conn, path).await?.ok_or_else(|| ApiError::FileNotFound {
+            path: path.to_string(),
+        })
+    }
+
+    async fn check_parent(&self, conn: &mut SqliteConnection, path: &str) -> Result<(), ApiError> {
+        if path.is_empty() || !self.find(conn, &parent_of(path)).await?.is_some_and(|parent| parent.is_directory) {
+            return Err(ApiError::Conflict {
+                message: "Parent directory does not exist".to_string(),
+            });
+        }
+        Ok(())
+    }
+
+    /// `from` must exist and `to` must be a new path outside it
+    async fn check_move(&self, conn: &mut SqliteConnection, from: &str, to: &str) -> Result<(), ApiError> {
+        self.get(conn, from).await?;
+        if self.find(conn, to).await?.is_some() {
+            return Err(ApiError::FileExists {
+                path: to.to_string(),
+            });
+        }
+        self.check_parent(conn, to).await?;
+        if is_within(to, from) {
+            return Err(ApiError::BadRequest {
+                message: "Cannot copy a directory into itself".to_string(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Change the reference counts of the contents of the files at and below `path`
+    async fn count_references(&self, conn: &mut SqliteConnection, path: &str, sign: i64) -> Result<(), ApiError> {
+        let query = format!(
+            r#"
+            UPDATE dedup_blobs SET refcount = refcount + ?3 * (
+                SELECT COUNT(*) FROM dedup_entries e
+                WHERE e.root = dedup_blobs.root AND e.hash = dedup_blobs.hash AND {}
+            )
+            WHERE root = ?1 AND hash IN (
+                SELECT hash FROM dedup_entries WHERE root = ?1 AND hash IS NOT NULL AND {}
+            )
+            "#,
+            subtree("e.path", 2),
+            subtree("path", 2),
+        );
+        sqlx::query(&query)
+            .bind(&self.root)
+            .bind(path)
+            .bind(sign)
+            .execute(&mut *conn)
+            .await?;
+        Ok(())
+    }
+
+    /// Point `path` at contents stored under `hash`, storing them from `source` if they aren't
+    /// yet. Without a source, `None` when the contents aren't stored.
+    async fn store(&self, path: &str, hash: &str, source: Option<&Path>) -> Result<Option<FileInfo>, ApiError> {
+        let mut tx = self.db.pool().begin().await?;
+        let stored: Option<i64> = sqlx::query_scalar(
+            "UPDATE dedup_blobs SET refcount = refcount + 1 WHERE root = ? AND hash = ? RETURNING size",
+        )
+        .bind(&self.root)
+        .bind(hash)
+        .fetch_optional(&mut *tx)
+        .await?;
+
+        let size = match (stored, source) {
+            (Some(size), source) => {
+                // Already stored, so nothing needs to be transferred
+                if let Some(source) = source {
+                    let _ = async_fs::remove_file(source).await;
+                }
+                size
+            }
+            (None, None) => return Ok(None),
+            (None, Some(source)) => {
+                tx.rollback().await?;
+
+                // Stored before it is recorded; contents left behind by a failure in between
+                // are collected as garbage
+                let size = async_fs::metadata(source).await?.len() as i64;
+                let blob = blob_path(hash);
+                self.blobs.mkdir(&parent_of(&blob), true).await?;
+                self.blobs.import(&blob, source).await?;
+
+                tx = self.db.pool().begin().await?;
+                sqlx::query(
+                    r#"
+                    INSERT INTO dedup_blobs (root, hash, size, refcount, created) VALUES (?, ?, ?, 1, ?)
+                    ON CONFLICT(root, hash) DO UPDATE SET refcount = refcount + 1
+                    "#,
+                )
+                .bind(&self.root)
+                .bind(hash)
+                .bind(size)
+                .bind(millis(Utc::now()))
+                .execute(&mut *tx)
+                .await?;
+                size
+            }
+        };
+
+        self.check_parent(&mut tx, path).await?;
+        match self.find(&mut tx, path).await? {
+            Some(existing) if existing.is_directory => {
+                return Err(ApiError::Conflict {
+                    message: "A directory with this name already exists".to_string(),
+                })
+            }
+            Some(_) => self.count_references(&mut tx, path, -1).await?,
+            None => {}
+        }
+
+        let entry = Entry {
+            is_directory: false,
+            size: size as u64,
+            modified: Utc::now(),
+            hash: Some(hash.to_string()),
+        };
+        sqlx::query(
+            r#"
+            INSERT INTO dedup_entries (root, path, parent, is_directory, size, modified, hash)
+            VALUES (?, ?, ?, FALSE, ?, ?, ?)
+            ON CONFLICT(root, path) DO UPDATE SET
+                size = excluded.size,
+                modified = excluded.modified,
+                hash = excluded.hash
+            "#,
+        )
+        .bind(&self.root)
+        .bind(path)
+        .bind(parent_of(path))
+        .bind(size)
+        .bind(millis(entry.modified))
+        .bind(hash)
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+        Ok(Some(entry.info(path)))
+    }
+
+    /// Delete stored contents nothing refers to any more
+    pub async fn collect_garbage(&self) -> Result<CollectedGarbage, ApiError> {
+        let mut collected = CollectedGarbage::default();
+
+        let unreferenced: Vec<(String, i64)> =
+            sqlx::query_as("SELECT hash, size FROM dedup_blobs WHERE root = ? AND refcount <= 0")
+                .bind(&self.root)
+                .fetch_all(self.db.pool())
+                .await?;
+        for (hash, size) in unreferenced {
+            let mut tx = self.db.pool().begin().await?;
+            let deleted = sqlx::query("DELETE FROM dedup_blobs WHERE root = ? AND hash = ? AND refcount <= 0")
+                .bind(&self.root)
+                .bind(&hash)
+                .execute(&mut *tx)
+                .await?
+                .rows_affected();
+            if deleted == 0 {
+                // Referred to again in the meantime
+                continue;
+            }
+
+            // Deleted while the transaction still holds the write lock, so nothing can start
+            // referring to the contents again before they are gone
+            match self.blobs.delete(&blob_path(&hash)).await {
+                Ok(()) | Err(ApiError::FileNotFound { .. }) => {}
+                Err(e) => {
+                    tracing::warn!("Failed to delete unreferenced contents {}: {}", hash, e);
+                    continue;
+                }
+            }
+            tx.commit().await?;
+            collected += CollectedGarbage {
+                blobs: 1,
+                bytes: size as u64,
+            };
+        }
+
+        collected += self.collect_orphans().await?;
+        Ok(collected)
+    }
+
+    /// Delete stored contents that were never recorded, e.g. after a failed upload
+    async fn collect_orphans(&self) -> Result<CollectedGarbage, ApiError> {
+        let mut collected = CollectedGarbage::default();
+        let cutoff = Utc::now() - Duration::minutes(ORPHAN_GRACE_MINUTES);
+
+        let mut pending = vec![String::new()];
+        while let Some(directory) = pending.pop() {
+            let entries = match self.blobs.list(&directory).await {
+                Ok(entries) => entries,
+                // Nothing has been stored yet
+                Err(ApiError::FileNotFound { .. }) if directory.is_empty() => continue,
+                Err(e) => return Err(e),
+            };
+            for entry in entries {
+                if entry.is_directory {
+                    pending.push(entry.path);
+                    continue;
+                }
+                if !is_hash(&entry.name) || entry.modified > cutoff {
+                    continue;
+                }
+
+                let recorded: Option<i64> = sqlx::query_scalar("SELECT 1 FROM dedup_blobs WHERE root = ? AND hash = ?")
+                    .bind(&self.root)
+                    .bind(&entry.name)
+                    .fetch_optional(self.db.pool())
+                    .await?;
+                if recorded.is_none() {
+                    self.blobs.delete(&entry.path).await?;
+                    collected += CollectedGarbage {
+                        blobs: 1,
+                        bytes: entry.size,
+                    };
+                }
+            }
+        }
+        Ok(collected)
+    }
+}
+
+#[async_trait]
+impl StorageBackend for DedupStorage {
+    async fn stat(&self, path: &str) -> Result<FileInfo, ApiError> {
+        let mut conn = self.db.pool().acquire().await?;
+        Ok(self.get(&mut conn, path).await?.info(path))
+    }
+
+    async fn list(&self, path: &str) -> Result<Vec<FileInfo>, ApiError> {
+        let mut conn = self.db.pool().acquire().await?;
+        if !self.get(&mut conn, path).await?.is_directory {
+            return Err(ApiError::BadRequest {
+                message: "Not a directory".to_string(),
+            });
+        }
+
+        let rows = sqlx::query(
+            "SELECT path, is_directory, size, modified FROM dedup_entries WHERE root = ? AND parent = ? AND path != ''",
+        )
+        .bind(&self.root)
+        .bind(path)
+        .fetch_all(&mut *conn)
+        .await?;
+        Ok(rows
+            .into_iter()
+            .map(|row| {
+                let entry = Entry {
+                    is_directory: row.get("is_directory"),
+                    size: row.get::<i64, _>("size") as u64,
+                    modified: DateTime::from_timestamp_millis(row.get("modified")).unwrap_or_default(),
+                    hash: None,
+                };
+                entry.info(row.get("path"))
+            })
+            .collect())
+    }
+
+    async fn open_read(&self, path: &str, range: Option<Range<u64>>) -> Result<StorageReader, ApiError> {
+        let mut conn = self.db.pool().acquire().await?;
+        let Some(hash) = self.get(&mut conn, path).await?.hash else {
+            return Err(ApiError::BadRequest {
+                message: "Cannot read a directory".to_string(),
+            });
+        };
+        self.blobs.open_read(&blob_path(&hash), range).await
+    }
+
+    async fn open_write(&self, path: &str) -> Result<Box<dyn StorageWriter>, ApiError> {
+        let mut conn = self.db.pool().acquire().await?;
+        if self.find(&mut conn, path).await?.is_some_and(|entry| entry.is_directory) {
+            return Err(ApiError::Conflict {
+                message: "A directory with this name already exists".to_string(),
+            });
+        }
+        self.check_parent(&mut conn, path).await?;
+
+        // Spooled to disk, since where the contents go depends on their hash
+        let temp = std::env::temp_dir().join(format!("filedash-{}.partial", Uuid::new_v4().simple()));
+        let file = async_fs::File::create(&temp).await?;
+
+        Ok(Box::new(DedupWriter {
+            file,
+            temp: Some(temp),
+            hasher: Sha256::new(),
+            storage: self.clone(),
+            path: path.to_string(),
+        }))
+    }
+
+    async fn rename(&self, from: &str, to: &str) -> Result<(), ApiError> {
+        let mut tx = self.db.pool().begin().await?;
+        self.check_move(&mut tx, from, to).await?;
+
+        let query = format!(
+            r#"
+            UPDATE dedup_entries SET
+                path = ?3 || substr(path, length(?2) + 1),
+                parent = CASE WHEN path = ?2 THEN ?4 ELSE ?3 || substr(parent, length(?2) + 1) END
+            WHERE root = ?1 AND {}
+            "#,
+            subtree("path", 2),
+        );
+        sqlx::query(&query)
+            .bind(&self.root)
+            .bind(from)
+            .bind(to)
+            .bind(parent_of(to))
+            .execute(&mut *tx)
+            .await?;
+
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn copy(&self, from: &str, to: &str) -> Result<(), ApiError> {
+        let mut tx = self.db.pool().begin().await?;
+        self.check_move(&mut tx, from, to).await?;
+
+        // Copies refer to the same contents, so nothing is copied but the records
+        let query = format!(
+            r#"
+            INSERT INTO dedup_entries (root, path, parent, is_directory, size, modified, hash)
+            SELECT
+                root,
+                ?3 || substr(path, length(?2) + 1),
+                CASE WHEN path = ?2 THEN ?4 ELSE ?3 || substr(parent, length(?2) + 1) END,
+                is_directory, size, modified, hash
+            FROM dedup_entries WHERE root = ?1 AND {}
+            "#,
+            subtree("path", 2),
+        );
+        sqlx::query(&query)
+            .bind(&self.root)
+            .bind(from)
+            .bind(to)
+            .bind(parent_of(to))
+            .execute(&mut *tx)
+            .await?;
+        self.count_references(&mut tx, to, 1).await?;
+
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn delete(&self, path: &str) -> Result<(), ApiError> {
+        let mut tx = self.db.pool().begin().await?;
+        self.get(&mut tx, path).await?;
+        self.count_references(&mut tx, path, -1).await?;
+
+        let query = format!("DELETE FROM dedup_entries WHERE root = ?1 AND {}", subtree("path", 2));
+        sqlx::query(&query)
+            .bind(&self.root)
+            .bind(path)
+            .execute(&mut *tx)
+            .await?;
+
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn mkdir(&self, path: &str, recursive: bool) -> Result<(), ApiError> {
+        let mut tx = self.db.pool().begin().await?;
+        let mut missing = Vec::new();
+        let mut current = path.to_string();
+        loop {
+            match self.find(&mut tx, &current).await? {
+                Some(entry) if entry.is_directory => break,
+                Some(_) => {
+                    return Err(ApiError::Conflict {
+                        message: "A file exists where a directory is needed".to_string(),
+                    })
+                }
+                None => missing.push(current.clone()),
+            }
+            current = parent_of(&current);
+        }
+
+        if missing.is_empty() && !recursive {
+            return Err(ApiError::FileExists {
+                path: path.to_string(),
+            });
+        }
+        if missing.len() > 1 && !recursive {
+            return Err(ApiError::Conflict {
+                message: "Parent directory does not exist".to_string(),
+            });
+        }
+        for directory in missing {
+            sqlx::query(
+                "INSERT INTO dedup_entries (root, path, parent, is_directory, modified) VALUES (?, ?, ?, TRUE, ?)",
+            )
+            .bind(&self.root)
+            .bind(&directory)
+            .bind(parent_of(&directory))
+            .bind(millis(Utc::now()))
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn import(&self, path: &str, source: &Path) -> Result<FileInfo, ApiError> {
+        let mut file = async_fs::File::open(source).await?;
+        let mut hasher = Sha256::new();
+        let mut buffer = vec![0; 64 * 1024];
+        loop {
+            let read = file.read(&mut buffer).await?;
+            if read == 0 {
+                break;
+            }
+            hasher.update(&buffer[..read]);
+        }
+        drop(file);
+
+        let hash = hex::encode(hasher.finalize());
+        let stored = self.store(path, &hash, Some(source)).await?;
+        Ok(stored.expect("contents are stored when a source is given"))
+    }
+
+    async fn link(&self, path: &str, sha256: &str) -> Result<Option<FileInfo>, ApiError> {
+        let hash = sha256.to_ascii_lowercase();
+        if !is_hash(&hash) {
+            return Err(ApiError::BadRequest {
+                message: "Expected a hex-encoded SHA-256 hash".to_string(),
+            });
+        }
+        self.store(path, &hash, None).await
+    }
+
+    async fn reencrypt(&self, path: &str) -> Result<bool, ApiError> {
+        let mut conn = self.db.pool().acquire().await?;
+        match self.get(&mut conn, path).await?.hash {
+            Some(hash) => self.blobs.reencrypt(&blob_path(&hash)).await,
+            None => Ok(false),
+        }
+    }
+
+    async fn collect_garbage(&self) -> Result<CollectedGarbage, ApiError> {
+        DedupStorage::collect_garbage(self).await
+    }
+}
+
+/// Hashes the contents as they are spooled
+struct DedupWriter {
+    file: async_fs::File,
+    temp: Option<PathBuf>,
+    hasher: Sha256,
+    storage: DedupStorage,
+    path: String,
+}
+
+impl AsyncWrite for DedupWriter {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        let result = Pin::new(&mut self.file).poll_write(cx, buf);
+        if let Poll::Ready(Ok(written)) = result {
+            self.hasher.update(&buf[..written]);
+        }
+        result
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.file).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.file).poll_shutdown(cx)
+    }
+}
+
+#[async_trait]
+impl StorageWriter for DedupWriter {
+    async fn commit(mut self: Box<Self>) -> Result<FileInfo, ApiError> {
+        use tokio::io::AsyncWriteExt;
+
+        self.file.flush().await?;
+        let hash = hex::encode(std::mem::take(&mut self.hasher).finalize());
+        let temp = self.temp.take().expect("a writer is only committed once");
+        let result = self.storage.store(&self.path, &hash, Some(&temp)).await;
+        let _ = async_fs::remove_file(&temp).await;
+        Ok(result?.expect("contents are stored when a source is given"))
+    }
+}
+
+impl Drop for DedupWriter {
+    fn drop(&mut self) {
+        if let Some(temp) = &self.temp {
+            let _ = std::fs::remove_file(temp);
+        }
+    }
+}
+
+/// How much deduplication saves, over every deduplicated root
+#[derive(Debug, Serialize)]
+pub struct DedupReport {
+    /// Files referring to stored contents
+    pub files: u64,
+    /// What the files would take up if each were stored separately
+    pub logical_bytes: u64,
+    /// Distinct contents referred to by at least one file
+    pub blobs: u64,
+    pub stored_bytes: u64,
+    pub saved_bytes: u64,
+    /// `logical_bytes / stored_bytes`
+    pub ratio: f64,
+    /// Contents no longer referred to, deleted by the next garbage collection
+    pub unreferenced_blobs: u64,
+    pub unreferenced_bytes: u64,
+}
+
+pub async fn report(db: &Database) -> Result<DedupReport, ApiError> {
+    let (files, logical_bytes): (i64, i64) = sqlx::query_as(
+        "SELECT COUNT(*), COALESCE(SUM(size), 0) FROM dedup_entries WHERE NOT is_directory",
+    )
+    .fetch_one(db.pool())
+    .await?;
+    let (blobs, stored_bytes, unreferenced_blobs, unreferenced_bytes): (i64, i64, i64, i64) = sqlx::query_as(
+        r#"
+        SELECT
+            COALESCE(SUM(refcount > 0), 0),
+            COALESCE(SUM(CASE WHEN refcount > 0 THEN size ELSE 0 END), 0),
+            COALESCE(SUM(refcount <= 0), 0),
+            COALESCE(SUM(CASE WHEN refcount <= 0 THEN size ELSE 0 END), 0)
+        FROM dedup_blobs
+        "#,
+    )
+    .fetch_one(db.pool())
+    .await?;
+
+    Ok(DedupReport {
+        files: files as u64,
+        logical_bytes: logical_bytes as u64,
+        blobs: blobs as u64,
+        stored_bytes: stored_bytes as u64,
+        saved_bytes: logical_bytes.saturating_sub(stored_bytes).max(0) as u64,
+        ratio: match stored_bytes {
+            0 => 1.0,
+            stored => logical_bytes as f64 / stored as f64,
+        },
+        unreferenced_blobs: unreferenced_blobs as u64,
+        unreferenced_bytes: unreferenced_bytes as u64,
+    })
+}
+
+/// Delete unreferenced contents every `interval_seconds`
+pub fn start_garbage_collection(storage: Arc<dyn StorageBackend>, interval_seconds: u64) {
+    if interval_seconds == 0 {
+        return;
+    }
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(interval_seconds));
+        loop {
+            interval.tick().await;
+            match storage.collect_garbage().await {
+                Ok(collected) if collected.blobs > 0 => tracing::info!(
+                    "Deleted {} unreferenced contents, freeing {} bytes",
+                    collected.blobs,
+                    collected.bytes
+                ),
+                Ok(_) => {}
+                Err(e) => tracing::error!("Failed to collect unreferenced contents: {}", e),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+    use tokio::io::AsyncWriteExt;
+
+    async fn storage() -> (tempfile::TempDir, DedupStorage, Arc<dyn StorageBackend>) {
+        let dir = tempfile::tempdir().unwrap();
+        let url = format!("sqlite:{}", dir.path().join("test.db").display());
+        let db = Database::new(&url).await.unwrap();
+        let blobs: Arc<dyn StorageBackend> = Arc::new(MemoryStorage::new());
+        (dir, DedupStorage::new(db, "", blobs.clone()), blobs)
+    }
+
+    async fn write(storage: &DedupStorage, path: &str, data: &[u8]) -> FileInfo {
+        let mut writer = storage.open_write(path).await.unwrap();
+        writer.write_all(data).await.unwrap();
+        writer.commit().await.unwrap()
+    }
+
+    async fn blob_count(blobs: &dyn StorageBackend) -> usize {
+        let mut count = 0;
+        let mut pending = vec![String::new()];
+        while let Some(directory) = pending.pop() {
+            for entry in blobs.list(&directory).await.unwrap() {
+                match entry.is_directory {
+                    true => pending.push(entry.path),
+                    false => count += 1,
+                }
+            }
+        }
+        count
+    }
+
+    #[tokio::test]
+    async fn test_identical_contents_are_stored_once() {
+        let (_dir, storage, blobs) = storage().await;
+        storage.mkdir("a/b", true).await.unwrap();
+        assert_eq!(write(&storage, "a/one.bin", b"same contents").await.size, 13);
+        write(&storage, "a/b/two.bin", b"same contents").await;
+        write(&storage, "other.bin", b"different").await;
+        assert_eq!(blob_count(blobs.as_ref()).await, 2);
+
+        let hash = hex::encode(Sha256::digest(b"same contents"));
+        let linked = storage.link("a/b/three.bin", &hash).await.unwrap().unwrap();
+        assert_eq!(linked.size, 13);
+        assert!(storage.link("x.bin", &hex::encode(Sha256::digest(b"unknown"))).await.unwrap().is_none());
+
+        storage.copy("a", "copy").await.unwrap();
+        storage.rename("copy/b", "moved").await.unwrap();
+        let mut text = String::new();
+        storage.open_read("moved/three.bin", Some(5..13)).await.unwrap().read_to_string(&mut text).await.unwrap();
+        assert_eq!(text, "contents");
+        let names: Vec<_> = storage.list("moved").await.unwrap().into_iter().map(|info| info.path).collect();
+        assert_eq!(names.len(), 2);
+
+        let report = report(&storage.db).await.unwrap();
+        assert_eq!((report.files, report.blobs), (7, 2));
+        assert_eq!(report.saved_bytes, 5 * 13);
+
+        // Contents stay until they are collected, and only once nothing refers to them
+        write(&storage, "other.bin", b"same contents").await;
+        storage.delete("a").await.unwrap();
+        storage.delete("copy").await.unwrap();
+        let collected = storage.collect_garbage().await.unwrap();
+        assert_eq!((collected.blobs, collected.bytes), (1, 9));
+        assert_eq!(blob_count(blobs.as_ref()).await, 1);
+        storage.delete("moved").await.unwrap();
+        storage.delete("other.bin").await.unwrap();
+        assert_eq!(storage.collect_garbage().await.unwrap().blobs, 1);
+        assert_eq!(blob_count(blobs.as_ref()).await, 0);
+        assert!(storage.list("").await.unwrap().is_empty());
+    }
+}
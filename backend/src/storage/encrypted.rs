@@ -0,0 +1,529 @@
+use super::{StorageBackend, StorageReader, StorageWriter};
+use crate::{config::EncryptionConfig, errors::ApiError, services::FileInfo};
+use async_trait::async_trait;
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use bytes::Bytes;
+use chacha20poly1305::{aead::Aead, ChaCha20Poly1305, KeyInit, Nonce};
+use futures::future::join_all;
+use hkdf::Hkdf;
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use std::{
+    ops::Range,
+    path::PathBuf,
+    pin::Pin,
+    sync::Arc,
+    task::{ready, Context, Poll},
+};
+use tokio::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio_util::io::StreamReader;
+
+/// Marks a stored file as encrypted, followed by the key id and the file's salt
+const MAGIC: &[u8; 6] = b"FDENC\x01";
+const KEY_ID_LEN: usize = 8;
+const SALT_LEN: usize = 32;
+const HEADER_LEN: usize = MAGIC.len() + KEY_ID_LEN + SALT_LEN;
+
+/// Plaintext bytes per chunk. Each chunk is sealed on its own so ranged reads only
+/// decrypt the chunks they touch.
+const CHUNK_LEN: usize = 64 * 1024;
+const TAG_LEN: usize = 16;
+const SEALED_CHUNK_LEN: usize = CHUNK_LEN + TAG_LEN;
+
+/// File headers read at once when listing a directory
+const LIST_CONCURRENCY: usize = 32;
+
+/// The master keys files are encrypted under
+pub struct Keyring {
+    current: MasterKey,
+    previous: Vec<MasterKey>,
+}
+
+struct MasterKey {
+    id: [u8; KEY_ID_LEN],
+    key: [u8; 32],
+}
+
+impl MasterKey {
+    fn parse(encoded: &str) -> Result<Self, ApiError> {
+        let key: [u8; 32] = STANDARD
+            .decode(encoded.trim())
+            .ok()
+            .and_then(|key| key.try_into().ok())
+            .ok_or_else(|| ApiError::InternalServerError {
+                message: "Encryption keys must be 32 bytes, base64 encoded".to_string(),
+            })?;
+
+        // Identifies the key in file headers without revealing anything about it
+        let digest = Sha256::new().chain_update(b"filedash key id").chain_update(key).finalize();
+        let mut id = [0; KEY_ID_LEN];
+        id.copy_from_slice(&digest[..KEY_ID_LEN]);
+        Ok(Self { id, key })
+    }
+
+    /// The key for one file, so that no two files share a key and nonces can simply count chunks
+    fn file_cipher(&self, salt: &[u8]) -> ChaCha20Poly1305 {
+        let mut key = [0; 32];
+        Hkdf::<Sha256>::new(Some(salt), &self.key)
+            .expand(b"filedash file encryption", &mut key)
+            .expect("32 bytes is a valid HKDF-SHA256 output length");
+        ChaCha20Poly1305::new(&key.into())
+    }
+}
+
+impl Keyring {
+    pub fn from_config(config: &EncryptionConfig) -> Result<Self, ApiError> {
+        let master_key = config.master_key.as_deref().ok_or_else(|| ApiError::InternalServerError {
+            message: "Encryption is enabled but storage.encryption.master_key is not set".to_string(),
+        })?;
+        Ok(Self {
+            current: MasterKey::parse(master_key)?,
+            previous: config
+                .previous_keys
+                .iter()
+                .map(|key| MasterKey::parse(key))
+                .collect::<Result<_, _>>()?,
+        })
+    }
+
+    fn find(&self, id: &[u8]) -> Option<&MasterKey> {
+        std::iter::once(&self.current)
+            .chain(&self.previous)
+            .find(|key| key.id == id)
+    }
+
+    fn cipher(&self, header: &Header) -> Result<ChaCha20Poly1305, ApiError> {
+        let key = self.find(&header.key_id).ok_or_else(|| ApiError::InternalServerError {
+            message: "File is encrypted with a key that is no longer configured".to_string(),
+        })?;
+        Ok(key.file_cipher(&header.salt))
+    }
+}
+
+struct Header {
+    key_id: [u8; KEY_ID_LEN],
+    salt: [u8; SALT_LEN],
+}
+
+impl Header {
+    /// `None` for a file stored as plaintext, e.g. from before encryption was enabled
+    fn parse(bytes: &[u8]) -> Option<Self> {
+        let rest = bytes.strip_prefix(MAGIC)?;
+        Some(Self {
+            key_id: rest.get(..KEY_ID_LEN)?.try_into().ok()?,
+            salt: rest.get(KEY_ID_LEN..KEY_ID_LEN + SALT_LEN)?.try_into().ok()?,
+        })
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        [&MAGIC[..], &self.key_id, &self.salt].concat()
+    }
+}
+
+/// Chunks count up from zero and the last one is marked, so chunks can't be reordered,
+/// dropped or truncated without failing authentication
+fn nonce(index: u64, last: bool) -> Nonce {
+    let mut nonce = [0; 12];
+    nonce[..8].copy_from_slice(&index.to_be_bytes());
+    nonce[11] = last as u8;
+    nonce.into()
+}
+
+/// The plaintext size of an encrypted file. The last chunk is always shorter than a full one,
+/// possibly empty, so the size follows from the stored size alone.
+pub(crate) fn plaintext_len(stored_len: u64) -> u64 {
+    let body = stored_len.saturating_sub(HEADER_LEN as u64);
+    let full_chunks = body / SEALED_CHUNK_LEN as u64;
+    let last_chunk = body % SEALED_CHUNK_LEN as u64;
+    full_chunks * CHUNK_LEN as u64 + last_chunk.saturating_sub(TAG_LEN as u64)
+}
+
+fn invalid_data(message: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message.to_string())
+}
+
+/// Encrypts file contents before they reach another backend. Files are sealed in chunks with
+/// ChaCha20-Poly1305 under a key derived from the master key and a random per-file salt.
+/// Unencrypted files are still read as they are until they are re-encrypted.
+pub struct EncryptedStorage {
+    inner: Arc<dyn StorageBackend>,
+    keys: Arc<Keyring>,
+}
+
+impl EncryptedStorage {
+    pub fn new(inner: Arc<dyn StorageBackend>, keys: Arc<Keyring>) -> Self {
+        Self { inner, keys }
+    }
+
+    async fn header(&self, path: &str, stored_len: u64) -> Result<Option<Header>, ApiError> {
+        if stored_len < HEADER_LEN as u64 {
+            return Ok(None);
+        }
+        let mut bytes = Vec::with_capacity(HEADER_LEN);
+        self.inner
+            .open_read(path, Some(0..HEADER_LEN as u64))
+            .await?
+            .read_to_end(&mut bytes)
+            .await?;
+        Ok(Header::parse(&bytes))
+    }
+
+    /// Report a stored file with the size of its contents
+    async fn decrypted_info(&self, mut info: FileInfo) -> Result<FileInfo, ApiError> {
+        if !info.is_directory && self.header(&info.path, info.size).await?.is_some() {
+            info.size = plaintext_len(info.size);
+        }
+        Ok(info)
+    }
+}
+
+#[async_trait]
+impl StorageBackend for EncryptedStorage {
+    async fn stat(&self, path: &str) -> Result<FileInfo, ApiError> {
+        let info = self.inner.stat(path).await?;
+        self.decrypted_info(info).await
+    }
+
+    async fn list(&self, path: &str) -> Result<Vec<FileInfo>, ApiError> {
+        let mut files = Vec::new();
+        let mut stored = self.inner.list(path).await?.into_iter().peekable();
+        while stored.peek().is_some() {
+            let batch = stored.by_ref().take(LIST_CONCURRENCY).map(|info| self.decrypted_info(info));
+            for info in join_all(batch).await {
+                files.push(info?);
+            }
+        }
+        Ok(files)
+    }
+
+    async fn open_read(&self, path: &str, range: Option<Range<u64>>) -> Result<StorageReader, ApiError> {
+        let stored_len = self.inner.stat(path).await?.size;
+        let header = match self.header(path, stored_len).await? {
+            Some(header) => header,
+            None => return self.inner.open_read(path, range).await,
+        };
+        let cipher = self.keys.cipher(&header)?;
+
+        let len = plaintext_len(stored_len);
+        let range = range.unwrap_or(0..len);
+        let (start, end) = (range.start.min(len), range.end.min(len));
+        if start >= end {
+            return Ok(Box::new(tokio::io::empty()));
+        }
+
+        // Only the chunks overlapping the range are fetched and opened
+        let first = start / CHUNK_LEN as u64;
+        let last = (end - 1) / CHUNK_LEN as u64;
+        let stored_range = HEADER_LEN as u64 + first * SEALED_CHUNK_LEN as u64
+            ..(HEADER_LEN as u64 + (last + 1) * SEALED_CHUNK_LEN as u64).min(stored_len);
+        let reader = self.inner.open_read(path, Some(stored_range)).await?;
+
+        let state = Decryption {
+            reader,
+            cipher,
+            index: first,
+            last_index: plaintext_len(stored_len) / CHUNK_LEN as u64,
+            skip: (start - first * CHUNK_LEN as u64) as usize,
+            remaining: end - start,
+        };
+        let chunks = futures::stream::unfold(state, |mut state| async move {
+            if state.remaining == 0 {
+                return None;
+            }
+            let chunk = state.next_chunk().await;
+            if chunk.is_err() {
+                state.remaining = 0;
+            }
+            Some((chunk, state))
+        });
+        Ok(Box::new(StreamReader::new(Box::pin(chunks))))
+    }
+
+    async fn open_write(&self, path: &str) -> Result<Box<dyn StorageWriter>, ApiError> {
+        let inner = self.inner.open_write(path).await?;
+
+        let mut salt = [0; SALT_LEN];
+        rand::thread_rng().fill_bytes(&mut salt);
+        let header = Header {
+            key_id: self.keys.current.id,
+            salt,
+        };
+
+        Ok(Box::new(EncryptedWriter {
+            inner,
+            cipher: self.keys.current.file_cipher(&header.salt),
+            index: 0,
+            plaintext: Vec::with_capacity(CHUNK_LEN),
+            sealed: header.to_bytes(),
+            written: 0,
+        }))
+    }
+
+    async fn rename(&self, from: &str, to: &str) -> Result<(), ApiError> {
+        self.inner.rename(from, to).await
+    }
+
+    // File keys don't depend on the path, so stored files can be copied as they are
+    async fn copy(&self, from: &str, to: &str) -> Result<(), ApiError> {
+        self.inner.copy(from, to).await
+    }
+
+    async fn delete(&self, path: &str) -> Result<(), ApiError> {
+        self.inner.delete(path).await
+    }
+
+    async fn mkdir(&self, path: &str, recursive: bool) -> Result<(), ApiError> {
+        self.inner.mkdir(path, recursive).await
+    }
+
+    // As with copies, a stored file can be linked as it is, and its attributes are its own
+    fn local_path(&self, path: &str) -> Option<PathBuf> {
+        self.inner.local_path(path)
+    }
+
+    async fn reencrypt(&self, path: &str) -> Result<bool, ApiError> {
+        let stored = self.inner.stat(path).await?;
+        if stored.is_directory {
+            return Ok(false);
+        }
+        if let Some(header) = self.header(path, stored.size).await? {
+            if header.key_id == self.keys.current.id {
+                return Ok(false);
+            }
+        }
+
+        let mut reader = self.open_read(path, None).await?;
+        let mut writer = self.open_write(path).await?;
+        tokio::io::copy(&mut reader, &mut writer).await?;
+        writer.flush().await?;
+
+        // A file changed while it was being copied is left for the next pass
+        let current = self.inner.stat(path).await?;
+        if current.size != stored.size || current.modified != stored.modified {
+            return Ok(false);
+        }
+        writer.commit().await?;
+        Ok(true)
+    }
+}
+
+struct Decryption {
+    reader: StorageReader,
+    cipher: ChaCha20Poly1305,
+    index: u64,
+    last_index: u64,
+    /// Bytes before the range in the first chunk
+    skip: usize,
+    remaining: u64,
+}
+
+impl Decryption {
+    async fn next_chunk(&mut self) -> std::io::Result<Bytes> {
+        let mut sealed = Vec::with_capacity(SEALED_CHUNK_LEN);
+        (&mut self.reader)
+            .take(SEALED_CHUNK_LEN as u64)
+            .read_to_end(&mut sealed)
+            .await?;
+
+        let last = self.index == self.last_index;
+        let mut chunk = self
+            .cipher
+            .decrypt(&nonce(self.index, last), sealed.as_slice())
+            .map_err(|_| invalid_data("Encrypted file failed authentication"))?;
+        if !last && chunk.len() != CHUNK_LEN {
+            return Err(invalid_data("Encrypted file is truncated"));
+        }
+        self.index += 1;
+
+        let skip = std::mem::take(&mut self.skip).min(chunk.len());
+        chunk.drain(..skip);
+        chunk.truncate(self.remaining.min(chunk.len() as u64) as usize);
+        self.remaining -= chunk.len() as u64;
+        if chunk.is_empty() && self.remaining > 0 {
+            return Err(invalid_data("Encrypted file is truncated"));
+        }
+        Ok(chunk.into())
+    }
+}
+
+/// Seals each full chunk as it is written. The last, partial chunk is sealed on commit.
+struct EncryptedWriter {
+    inner: Box<dyn StorageWriter>,
+    cipher: ChaCha20Poly1305,
+    index: u64,
+    plaintext: Vec<u8>,
+    /// Sealed bytes not yet passed on, starting with the header
+    sealed: Vec<u8>,
+    written: usize,
+}
+
+impl EncryptedWriter {
+    fn seal(&mut self, last: bool) -> std::io::Result<()> {
+        let sealed = self
+            .cipher
+            .encrypt(&nonce(self.index, last), self.plaintext.as_slice())
+            .map_err(|_| std::io::Error::other("Failed to encrypt file contents"))?;
+        self.index += 1;
+        self.plaintext.clear();
+        self.sealed.extend_from_slice(&sealed);
+        Ok(())
+    }
+
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        while self.written < self.sealed.len() {
+            let written = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.sealed[self.written..]))?;
+            if written == 0 {
+                return Poll::Ready(Err(std::io::ErrorKind::WriteZero.into()));
+            }
+            self.written += written;
+        }
+        self.sealed.clear();
+        self.written = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for EncryptedWriter {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        ready!(self.poll_drain(cx))?;
+        let accepted = buf.len().min(CHUNK_LEN - self.plaintext.len());
+        self.plaintext.extend_from_slice(&buf[..accepted]);
+        if self.plaintext.len() == CHUNK_LEN {
+            self.seal(false)?;
+        }
+        Poll::Ready(Ok(accepted))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        ready!(self.poll_drain(cx))?;
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        ready!(self.poll_drain(cx))?;
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[async_trait]
+impl StorageWriter for EncryptedWriter {
+    async fn commit(mut self: Box<Self>) -> Result<FileInfo, ApiError> {
+        self.seal(true)?;
+        let written = self.written;
+        self.inner.write_all(&self.sealed[written..]).await?;
+        self.inner.flush().await?;
+
+        let mut info = self.inner.commit().await?;
+        info.size = plaintext_len(info.size);
+        Ok(info)
+    }
+}
+
+/// Rewrite every file stored unencrypted or under an earlier key, in the background
+pub fn start_reencryption(storage: Arc<dyn StorageBackend>) {
+    tokio::spawn(async move {
+        match reencrypt_all(storage.as_ref()).await {
+            Ok(0) => {}
+            Ok(count) => tracing::info!("Re-encrypted {} files under the current key", count),
+            Err(e) => tracing::error!("Failed to re-encrypt stored files: {}", e),
+        }
+    });
+}
+
+async fn reencrypt_all(storage: &dyn StorageBackend) -> Result<usize, ApiError> {
+    let mut count = 0;
+    let mut pending = vec![String::new()];
+    while let Some(directory) = pending.pop() {
+        for entry in storage.list(&directory).await? {
+            if entry.is_directory {
+                pending.push(entry.path);
+                continue;
+            }
+            match storage.reencrypt(&entry.path).await {
+                Ok(true) => count += 1,
+                Ok(false) => {}
+                Err(e) => tracing::warn!("Failed to re-encrypt {}: {}", entry.path, e),
+            }
+        }
+    }
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+
+    fn keyring(master_key: &[u8; 32], previous_keys: &[&[u8; 32]]) -> Arc<Keyring> {
+        let config = EncryptionConfig {
+            enabled: true,
+            master_key: Some(STANDARD.encode(master_key)),
+            previous_keys: previous_keys.iter().map(|key| STANDARD.encode(key)).collect(),
+        };
+        Arc::new(Keyring::from_config(&config).unwrap())
+    }
+
+    async fn write(storage: &dyn StorageBackend, path: &str, data: &[u8]) -> FileInfo {
+        let mut writer = storage.open_write(path).await.unwrap();
+        writer.write_all(data).await.unwrap();
+        writer.commit().await.unwrap()
+    }
+
+    async fn read(storage: &dyn StorageBackend, path: &str, range: Option<Range<u64>>) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        storage.open_read(path, range).await.unwrap().read_to_end(&mut bytes).await.unwrap();
+        bytes
+    }
+
+    #[tokio::test]
+    async fn test_ranged_reads_of_chunked_files() {
+        let inner: Arc<dyn StorageBackend> = Arc::new(MemoryStorage::new());
+        let storage = EncryptedStorage::new(inner.clone(), keyring(&[1; 32], &[]));
+        let data: Vec<u8> = (0..CHUNK_LEN * 2 + 100).map(|i| (i % 251) as u8).collect();
+
+        for len in [0, 5, CHUNK_LEN, data.len()] {
+            assert_eq!(write(&storage, "a.bin", &data[..len]).await.size, len as u64);
+            assert_eq!(storage.stat("a.bin").await.unwrap().size, len as u64);
+            assert_eq!(read(&storage, "a.bin", None).await, &data[..len]);
+        }
+        assert_eq!(storage.list("").await.unwrap()[0].size, data.len() as u64);
+
+        let stored = read(inner.as_ref(), "a.bin", None).await;
+        assert!(stored.starts_with(MAGIC));
+        assert!(!stored.windows(64).any(|window| window == &data[1000..1064]));
+
+        for range in [0..10, CHUNK_LEN as u64 - 3..CHUNK_LEN as u64 + 3, 10..data.len() as u64 + 50] {
+            let end = (range.end as usize).min(data.len());
+            assert_eq!(read(&storage, "a.bin", Some(range.clone())).await, &data[range.start as usize..end]);
+        }
+
+        // Tampering with any chunk fails the read rather than returning altered contents
+        let mut tampered = stored.clone();
+        tampered[HEADER_LEN + SEALED_CHUNK_LEN + 7] ^= 1;
+        write(inner.as_ref(), "a.bin", &tampered).await;
+        let mut reader = storage.open_read("a.bin", Some(CHUNK_LEN as u64..CHUNK_LEN as u64 + 1)).await.unwrap();
+        assert!(reader.read_to_end(&mut Vec::new()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_reencrypts_plaintext_and_old_keys() {
+        let inner: Arc<dyn StorageBackend> = Arc::new(MemoryStorage::new());
+        inner.mkdir("docs", false).await.unwrap();
+        write(inner.as_ref(), "docs/plain.txt", b"from before encryption").await;
+        let old = EncryptedStorage::new(inner.clone(), keyring(&[1; 32], &[]));
+        write(&old, "docs/old.txt", b"under the old key").await;
+
+        let storage = EncryptedStorage::new(inner.clone(), keyring(&[2; 32], &[&[1; 32]]));
+        assert_eq!(read(&storage, "docs/plain.txt", None).await, b"from before encryption");
+        assert_eq!(read(&storage, "docs/old.txt", None).await, b"under the old key");
+
+        assert_eq!(reencrypt_all(&storage).await.unwrap(), 2);
+        assert_eq!(reencrypt_all(&storage).await.unwrap(), 0);
+        assert!(read(inner.as_ref(), "docs/plain.txt", None).await.starts_with(MAGIC));
+
+        // Only the new key is needed from now on
+        let rotated = EncryptedStorage::new(inner, keyring(&[2; 32], &[]));
+        assert_eq!(read(&rotated, "docs/plain.txt", None).await, b"from before encryption");
+        assert_eq!(read(&rotated, "docs/old.txt", None).await, b"under the old key");
+    }
+}
@@ -0,0 +1,328 @@
+use super::{import_by_copy, is_within, StorageBackend, StorageReader, StorageWriter};
+use crate::{errors::ApiError, services::FileInfo, utils::security::resolve_path};
+use async_trait::async_trait;
+use std::{
+    fs,
+    io::{ErrorKind, SeekFrom},
+    ops::Range,
+    path::{Path, PathBuf},
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tokio::{
+    fs as async_fs,
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWrite},
+};
+use uuid::Uuid;
+
+/// Files kept in a directory on the local disk
+pub struct LocalStorage {
+    root: PathBuf,
+}
+
+impl LocalStorage {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    fn resolve(&self, path: &str) -> Result<PathBuf, ApiError> {
+        resolve_path(&self.root, path)
+    }
+
+    async fn file_info(full_path: &Path, path: &str) -> Result<FileInfo, ApiError> {
+        let metadata = async_fs::metadata(full_path)
+            .await
+            .map_err(|e| not_found(e, path))?;
+        let name = full_path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or("")
+            .to_string();
+        Ok(FileInfo::from_metadata(name, path, &metadata))
+    }
+
+    /// Check that `full_path` can be written as a file: its parent exists and it isn't a directory
+    fn check_writable(full_path: &Path) -> Result<(), ApiError> {
+        if full_path.is_dir() {
+            return Err(ApiError::Conflict {
+                message: "A directory with this name already exists".to_string(),
+            });
+        }
+        if !full_path.parent().is_some_and(|parent| parent.is_dir()) {
+            return Err(ApiError::Conflict {
+                message: "Parent directory does not exist".to_string(),
+            });
+        }
+        Ok(())
+    }
+
+    fn check_destination(full_path: &Path, path: &str) -> Result<(), ApiError> {
+        if fs::symlink_metadata(full_path).is_ok() {
+            return Err(ApiError::FileExists {
+                path: path.to_string(),
+            });
+        }
+        if !full_path.parent().is_some_and(|parent| parent.is_dir()) {
+            return Err(ApiError::Conflict {
+                message: "Parent directory does not exist".to_string(),
+            });
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl StorageBackend for LocalStorage {
+    async fn stat(&self, path: &str) -> Result<FileInfo, ApiError> {
+        Self::file_info(&self.resolve(path)?, path).await
+    }
+
+    async fn list(&self, path: &str) -> Result<Vec<FileInfo>, ApiError> {
+        let full_path = self.resolve(path)?;
+        let mut entries = async_fs::read_dir(&full_path)
+            .await
+            .map_err(|e| not_found(e, path))?;
+
+        let mut files = Vec::new();
+        while let Some(entry) = entries.next_entry().await? {
+            let name = entry.file_name().to_string_lossy().to_string();
+            let child = if path.is_empty() {
+                name
+            } else {
+                format!("{}/{}", path, name)
+            };
+            match Self::file_info(&entry.path(), &child).await {
+                Ok(info) => files.push(info),
+                // Continue with other files
+                Err(e) => tracing::warn!("Failed to get info for file {:?}: {}", entry.path(), e),
+            }
+        }
+        Ok(files)
+    }
+
+    async fn open_read(&self, path: &str, range: Option<Range<u64>>) -> Result<StorageReader, ApiError> {
+        let full_path = self.resolve(path)?;
+        let mut file = async_fs::File::open(&full_path)
+            .await
+            .map_err(|e| not_found(e, path))?;
+        if file.metadata().await?.is_dir() {
+            return Err(ApiError::BadRequest {
+                message: "Cannot read a directory".to_string(),
+            });
+        }
+
+        match range {
+            Some(range) => {
+                file.seek(SeekFrom::Start(range.start)).await?;
+                Ok(Box::new(file.take(range.end.saturating_sub(range.start))))
+            }
+            None => Ok(Box::new(file)),
+        }
+    }
+
+    async fn open_write(&self, path: &str) -> Result<Box<dyn StorageWriter>, ApiError> {
+        let target = self.resolve(path)?;
+        Self::check_writable(&target)?;
+
+        // Written next to the target so that committing is a rename on the same filesystem
+        let name = target
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or_else(|| ApiError::InvalidPath {
+                path: path.to_string(),
+            })?;
+        let temp = target.with_file_name(format!(".{}.{}.partial", name, Uuid::new_v4().simple()));
+        let file = async_fs::File::create(&temp).await?;
+
+        Ok(Box::new(LocalWriter {
+            file,
+            temp: Some(temp),
+            target,
+            path: path.to_string(),
+        }))
+    }
+
+    async fn rename(&self, from: &str, to: &str) -> Result<(), ApiError> {
+        let (from_path, to_path) = (self.resolve(from)?, self.resolve(to)?);
+        Self::check_destination(&to_path, to)?;
+        async_fs::rename(&from_path, &to_path)
+            .await
+            .map_err(|e| not_found(e, from))
+    }
+
+    async fn copy(&self, from: &str, to: &str) -> Result<(), ApiError> {
+        let (from_path, to_path) = (self.resolve(from)?, self.resolve(to)?);
+        if fs::symlink_metadata(&from_path).is_err() {
+            return Err(ApiError::FileNotFound {
+                path: from.to_string(),
+            });
+        }
+        Self::check_destination(&to_path, to)?;
+        if is_within(to, from) {
+            return Err(ApiError::BadRequest {
+                message: "Cannot copy a directory into itself".to_string(),
+            });
+        }
+
+        tokio::task::spawn_blocking(move || copy_recursive(&from_path, &to_path))
+            .await
+            .map_err(|e| ApiError::InternalServerError {
+                message: format!("Copy task failed: {}", e),
+            })??;
+        Ok(())
+    }
+
+    async fn delete(&self, path: &str) -> Result<(), ApiError> {
+        let full_path = self.resolve(path)?;
+        let metadata = async_fs::symlink_metadata(&full_path)
+            .await
+            .map_err(|e| not_found(e, path))?;
+
+        if metadata.is_dir() {
+            async_fs::remove_dir_all(&full_path).await?;
+        } else {
+            async_fs::remove_file(&full_path).await?;
+        }
+        Ok(())
+    }
+
+    async fn mkdir(&self, path: &str, recursive: bool) -> Result<(), ApiError> {
+        let full_path = self.resolve(path)?;
+        let result = if recursive {
+            async_fs::create_dir_all(&full_path).await
+        } else {
+            async_fs::create_dir(&full_path).await
+        };
+
+        result.map_err(|e| match e.kind() {
+            ErrorKind::AlreadyExists if !recursive && full_path.is_dir() => ApiError::FileExists {
+                path: path.to_string(),
+            },
+            ErrorKind::AlreadyExists | ErrorKind::NotADirectory => ApiError::Conflict {
+                message: "A file exists where a directory is needed".to_string(),
+            },
+            ErrorKind::NotFound => ApiError::Conflict {
+                message: "Parent directory does not exist".to_string(),
+            },
+            _ => e.into(),
+        })
+    }
+
+    async fn import(&self, path: &str, source: &Path) -> Result<FileInfo, ApiError> {
+        let target = self.resolve(path)?;
+        Self::check_writable(&target)?;
+
+        // A rename is enough when the source is on the same filesystem
+        if async_fs::rename(source, &target).await.is_ok() {
+            return Self::file_info(&target, path).await;
+        }
+        import_by_copy(self, path, source).await
+    }
+
+    fn local_path(&self, path: &str) -> Option<PathBuf> {
+        self.resolve(path).ok()
+    }
+}
+
+struct LocalWriter {
+    file: async_fs::File,
+    /// The file being written, until it is committed
+    temp: Option<PathBuf>,
+    target: PathBuf,
+    path: String,
+}
+
+impl AsyncWrite for LocalWriter {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.file).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.file).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.file).poll_shutdown(cx)
+    }
+}
+
+#[async_trait]
+impl StorageWriter for LocalWriter {
+    async fn commit(mut self: Box<Self>) -> Result<FileInfo, ApiError> {
+        use tokio::io::AsyncWriteExt;
+
+        self.file.flush().await?;
+        if let Some(temp) = &self.temp {
+            async_fs::rename(temp, &self.target).await?;
+            self.temp = None;
+        }
+        LocalStorage::file_info(&self.target, &self.path).await
+    }
+}
+
+impl Drop for LocalWriter {
+    fn drop(&mut self) {
+        if let Some(temp) = &self.temp {
+            let _ = fs::remove_file(temp);
+        }
+    }
+}
+
+fn not_found(error: std::io::Error, path: &str) -> ApiError {
+    if error.kind() == ErrorKind::NotFound {
+        ApiError::FileNotFound {
+            path: path.to_string(),
+        }
+    } else {
+        error.into()
+    }
+}
+
+/// Copy `from` to `to`, recursing into directories. Symlinks are recreated, not followed.
+fn copy_recursive(from: &Path, to: &Path) -> std::io::Result<()> {
+    let metadata = fs::symlink_metadata(from)?;
+    if metadata.is_dir() {
+        fs::create_dir(to)?;
+        for entry in fs::read_dir(from)? {
+            let entry = entry?;
+            copy_recursive(&entry.path(), &to.join(entry.file_name()))?;
+        }
+    } else if metadata.file_type().is_symlink() {
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(fs::read_link(from)?, to)?;
+    } else {
+        fs::copy(from, to)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+
+    #[tokio::test]
+    async fn test_writes_are_only_visible_once_committed() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = LocalStorage::new(dir.path());
+
+        let mut abandoned = storage.open_write("a.txt").await.unwrap();
+        abandoned.write_all(b"partial").await.unwrap();
+        drop(abandoned);
+        assert!(fs::read_dir(dir.path()).unwrap().next().is_none());
+
+        let mut writer = storage.open_write("a.txt").await.unwrap();
+        writer.write_all(b"hello world").await.unwrap();
+        assert!(matches!(storage.stat("a.txt").await, Err(ApiError::FileNotFound { .. })));
+        let info = writer.commit().await.unwrap();
+        assert_eq!(info.size, 11);
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+
+        let mut reader = storage.open_read("a.txt", Some(6..11)).await.unwrap();
+        let mut text = String::new();
+        reader.read_to_string(&mut text).await.unwrap();
+        assert_eq!(text, "world");
+
+        assert!(matches!(storage.open_write("missing/b.txt").await, Err(ApiError::Conflict { .. })));
+    }
+}
@@ -0,0 +1,315 @@
+use super::{file_name, is_within, StorageBackend, StorageReader, StorageWriter};
+use crate::{errors::ApiError, services::{parent_of, FileInfo}};
+use async_trait::async_trait;
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use std::{
+    collections::BTreeMap,
+    io::Cursor,
+    ops::Range,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+};
+use tokio::io::AsyncWrite;
+
+#[derive(Clone)]
+enum Entry {
+    File { data: Bytes, modified: DateTime<Utc> },
+    Directory { modified: DateTime<Utc> },
+}
+
+type Entries = BTreeMap<String, Entry>;
+
+/// Files kept in memory, for tests
+pub struct MemoryStorage {
+    entries: Arc<Mutex<Entries>>,
+}
+
+impl Default for MemoryStorage {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        let mut entries = Entries::new();
+        entries.insert(String::new(), Entry::Directory { modified: Utc::now() });
+        Self {
+            entries: Arc::new(Mutex::new(entries)),
+        }
+    }
+
+    fn entries(&self) -> std::sync::MutexGuard<'_, Entries> {
+        self.entries.lock().unwrap()
+    }
+}
+
+fn info(path: &str, entry: &Entry) -> FileInfo {
+    let name = file_name(path).to_string();
+    match entry {
+        Entry::File { data, modified } => FileInfo {
+            mime_type: mime_guess::from_path(&name).first().map(|mime| mime.to_string()),
+            name,
+            path: path.to_string(),
+            size: data.len() as u64,
+            modified: *modified,
+            is_directory: false,
+        },
+        Entry::Directory { modified } => FileInfo {
+            name,
+            path: path.to_string(),
+            size: 0,
+            modified: *modified,
+            is_directory: true,
+            mime_type: None,
+        },
+    }
+}
+
+fn get<'a>(entries: &'a Entries, path: &str) -> Result<&'a Entry, ApiError> {
+    entries.get(path).ok_or_else(|| ApiError::FileNotFound {
+        path: path.to_string(),
+    })
+}
+
+fn is_directory(entries: &Entries, path: &str) -> bool {
+    matches!(entries.get(path), Some(Entry::Directory { .. }))
+}
+
+/// The paths of `path` and everything below it
+fn subtree(entries: &Entries, path: &str) -> Vec<String> {
+    entries
+        .keys()
+        .filter(|key| is_within(key, path))
+        .cloned()
+        .collect()
+}
+
+fn check_destination(entries: &Entries, path: &str) -> Result<(), ApiError> {
+    if entries.contains_key(path) {
+        return Err(ApiError::FileExists {
+            path: path.to_string(),
+        });
+    }
+    if !is_directory(entries, &parent_of(path)) {
+        return Err(ApiError::Conflict {
+            message: "Parent directory does not exist".to_string(),
+        });
+    }
+    Ok(())
+}
+
+/// Copy `from` and everything below it to `to`
+fn copy_subtree(entries: &mut Entries, from: &str, to: &str) -> Result<(), ApiError> {
+    get(entries, from)?;
+    check_destination(entries, to)?;
+    if is_within(to, from) {
+        return Err(ApiError::BadRequest {
+            message: "Cannot copy a directory into itself".to_string(),
+        });
+    }
+
+    for key in subtree(entries, from) {
+        let entry = entries[&key].clone();
+        entries.insert(format!("{}{}", to, &key[from.len()..]), entry);
+    }
+    Ok(())
+}
+
+#[async_trait]
+impl StorageBackend for MemoryStorage {
+    async fn stat(&self, path: &str) -> Result<FileInfo, ApiError> {
+        let entries = self.entries();
+        Ok(info(path, get(&entries, path)?))
+    }
+
+    async fn list(&self, path: &str) -> Result<Vec<FileInfo>, ApiError> {
+        let entries = self.entries();
+        if !matches!(get(&entries, path)?, Entry::Directory { .. }) {
+            return Err(ApiError::BadRequest {
+                message: "Not a directory".to_string(),
+            });
+        }
+
+        Ok(entries
+            .iter()
+            .filter(|(key, _)| !key.is_empty() && parent_of(key) == path)
+            .map(|(key, entry)| info(key, entry))
+            .collect())
+    }
+
+    async fn open_read(&self, path: &str, range: Option<Range<u64>>) -> Result<StorageReader, ApiError> {
+        let entries = self.entries();
+        let Entry::File { data, .. } = get(&entries, path)? else {
+            return Err(ApiError::BadRequest {
+                message: "Cannot read a directory".to_string(),
+            });
+        };
+
+        let data = match range {
+            Some(range) => {
+                let end = (range.end as usize).min(data.len());
+                data.slice((range.start as usize).min(end)..end)
+            }
+            None => data.clone(),
+        };
+        Ok(Box::new(Cursor::new(data)))
+    }
+
+    async fn open_write(&self, path: &str) -> Result<Box<dyn StorageWriter>, ApiError> {
+        let entries = self.entries();
+        if is_directory(&entries, path) {
+            return Err(ApiError::Conflict {
+                message: "A directory with this name already exists".to_string(),
+            });
+        }
+        if path.is_empty() || !is_directory(&entries, &parent_of(path)) {
+            return Err(ApiError::Conflict {
+                message: "Parent directory does not exist".to_string(),
+            });
+        }
+
+        Ok(Box::new(MemoryWriter {
+            buffer: Vec::new(),
+            path: path.to_string(),
+            entries: self.entries.clone(),
+        }))
+    }
+
+    async fn rename(&self, from: &str, to: &str) -> Result<(), ApiError> {
+        let mut entries = self.entries();
+        copy_subtree(&mut entries, from, to)?;
+        for key in subtree(&entries, from) {
+            entries.remove(&key);
+        }
+        Ok(())
+    }
+
+    async fn copy(&self, from: &str, to: &str) -> Result<(), ApiError> {
+        copy_subtree(&mut self.entries(), from, to)
+    }
+
+    async fn delete(&self, path: &str) -> Result<(), ApiError> {
+        let mut entries = self.entries();
+        get(&entries, path)?;
+        for key in subtree(&entries, path) {
+            // The root itself always exists
+            if !key.is_empty() {
+                entries.remove(&key);
+            }
+        }
+        Ok(())
+    }
+
+    async fn mkdir(&self, path: &str, recursive: bool) -> Result<(), ApiError> {
+        let mut entries = self.entries();
+        let mut missing = Vec::new();
+        let mut current = path.to_string();
+        loop {
+            match entries.get(&current) {
+                Some(Entry::Directory { .. }) => break,
+                Some(Entry::File { .. }) => {
+                    return Err(ApiError::Conflict {
+                        message: "A file exists where a directory is needed".to_string(),
+                    })
+                }
+                None => missing.push(current.clone()),
+            }
+            current = parent_of(&current);
+        }
+
+        if missing.is_empty() && !recursive {
+            return Err(ApiError::FileExists {
+                path: path.to_string(),
+            });
+        }
+        if missing.len() > 1 && !recursive {
+            return Err(ApiError::Conflict {
+                message: "Parent directory does not exist".to_string(),
+            });
+        }
+        for directory in missing {
+            entries.insert(directory, Entry::Directory { modified: Utc::now() });
+        }
+        Ok(())
+    }
+}
+
+struct MemoryWriter {
+    buffer: Vec<u8>,
+    path: String,
+    entries: Arc<Mutex<Entries>>,
+}
+
+impl AsyncWrite for MemoryWriter {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.buffer).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.buffer).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.buffer).poll_shutdown(cx)
+    }
+}
+
+#[async_trait]
+impl StorageWriter for MemoryWriter {
+    async fn commit(self: Box<Self>) -> Result<FileInfo, ApiError> {
+        let mut entries = self.entries.lock().unwrap();
+        if !is_directory(&entries, &parent_of(&self.path)) || is_directory(&entries, &self.path) {
+            return Err(ApiError::Conflict {
+                message: "The file's directory changed while it was being written".to_string(),
+            });
+        }
+
+        let entry = Entry::File {
+            data: Bytes::from(self.buffer),
+            modified: Utc::now(),
+        };
+        let info = info(&self.path, &entry);
+        entries.insert(self.path, entry);
+        Ok(info)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+
+    async fn write(storage: &MemoryStorage, path: &str, data: &[u8]) {
+        let mut writer = storage.open_write(path).await.unwrap();
+        writer.write_all(data).await.unwrap();
+        writer.commit().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_directory_trees_move_and_copy_together() {
+        let storage = MemoryStorage::new();
+        storage.mkdir("a/b", true).await.unwrap();
+        write(&storage, "a/b/c.txt", b"abc").await;
+        write(&storage, "ab.txt", b"not inside a").await;
+
+        storage.copy("a", "copy").await.unwrap();
+        storage.rename("a", "moved").await.unwrap();
+        assert!(storage.copy("moved", "moved/b/inner").await.is_err());
+
+        let names = |infos: Vec<FileInfo>| {
+            let mut names: Vec<String> = infos.into_iter().map(|info| info.path).collect();
+            names.sort();
+            names
+        };
+        assert_eq!(names(storage.list("").await.unwrap()), vec!["ab.txt", "copy", "moved"]);
+        assert_eq!(names(storage.list("copy/b").await.unwrap()), vec!["copy/b/c.txt"]);
+        assert_eq!(storage.stat("moved/b/c.txt").await.unwrap().size, 3);
+
+        storage.delete("moved").await.unwrap();
+        assert!(matches!(storage.stat("moved/b").await, Err(ApiError::FileNotFound { .. })));
+        assert!(storage.stat("copy/b/c.txt").await.is_ok());
+    }
+}
@@ -0,0 +1,196 @@
+pub mod dedup;
+pub mod encrypted;
+pub mod local;
+pub mod memory;
+pub mod mounts;
+pub mod s3;
+
+pub use dedup::{CollectedGarbage, DedupStorage};
+pub use encrypted::{EncryptedStorage, Keyring};
+pub use local::LocalStorage;
+pub use memory::MemoryStorage;
+pub use mounts::{Mount, MountedStorage};
+pub use s3::S3Storage;
+
+use crate::{
+    config::{StorageBackendConfig, StorageConfig},
+    db::Database,
+    errors::ApiError,
+    services::FileInfo,
+};
+use async_trait::async_trait;
+use std::{
+    collections::HashSet,
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+
+/// A stream of file contents from a backend
+pub type StorageReader = Box<dyn AsyncRead + Send + Unpin>;
+
+/// A file being written. Nothing appears at the target path until `commit`, and
+/// dropping the writer without committing discards what was written.
+#[async_trait]
+pub trait StorageWriter: AsyncWrite + Send + Unpin {
+    /// Put the written contents in place, replacing any existing file
+    async fn commit(self: Box<Self>) -> Result<FileInfo, ApiError>;
+}
+
+/// Where file contents are kept. Paths are relative to the storage root, `/`-separated and
+/// already normalized, with `""` for the root itself; a missing path is `FileNotFound`.
+#[async_trait]
+pub trait StorageBackend: Send + Sync {
+    /// Information about a file or directory
+    async fn stat(&self, path: &str) -> Result<FileInfo, ApiError>;
+
+    /// The entries directly inside a directory, in no particular order
+    async fn list(&self, path: &str) -> Result<Vec<FileInfo>, ApiError>;
+
+    /// Read a file, or only the bytes in `range`
+    async fn open_read(&self, path: &str, range: Option<Range<u64>>) -> Result<StorageReader, ApiError>;
+
+    /// Start writing a file. Its parent directory must exist and it can't replace a directory.
+    async fn open_write(&self, path: &str) -> Result<Box<dyn StorageWriter>, ApiError>;
+
+    /// Move a file or directory tree. `to` must not exist and its parent must.
+    async fn rename(&self, from: &str, to: &str) -> Result<(), ApiError>;
+
+    /// Copy a file or directory tree. `to` must not exist and its parent must.
+    async fn copy(&self, from: &str, to: &str) -> Result<(), ApiError>;
+
+    /// Delete a file or a directory and everything in it
+    async fn delete(&self, path: &str) -> Result<(), ApiError>;
+
+    /// Create a directory. With `recursive`, missing parents are created and an existing
+    /// directory is not an error.
+    async fn mkdir(&self, path: &str, recursive: bool) -> Result<(), ApiError>;
+
+    /// Move a finished file from the local filesystem into storage at `path`, as
+    /// `open_write` would write it. `source` is gone afterwards.
+    async fn import(&self, path: &str, source: &Path) -> Result<FileInfo, ApiError> {
+        import_by_copy(self, path, source).await
+    }
+
+    /// Rewrite a file under the current encryption key if it is stored unencrypted or under
+    /// an earlier key, returning whether it was rewritten
+    async fn reencrypt(&self, _path: &str) -> Result<bool, ApiError> {
+        Ok(false)
+    }
+
+    /// Create a file at `path` from contents already stored under their SHA-256, without
+    /// transferring them again. `None` when the backend doesn't have them.
+    async fn link(&self, _path: &str, _sha256: &str) -> Result<Option<FileInfo>, ApiError> {
+        Ok(None)
+    }
+
+    /// Delete stored contents that no file refers to any more
+    async fn collect_garbage(&self) -> Result<CollectedGarbage, ApiError> {
+        Ok(CollectedGarbage::default())
+    }
+
+    /// Check that `path` may be changed, for changes made outside the backend, such as
+    /// linking files on the local disk
+    fn check_writable(&self, _path: &str) -> Result<(), ApiError> {
+        Ok(())
+    }
+
+    /// Where `path` is kept on the local disk, for changes and details only the filesystem
+    /// has. `None` when the backend doesn't keep it as a file of its own.
+    fn local_path(&self, _path: &str) -> Option<PathBuf> {
+        None
+    }
+}
+
+/// The storage described by the configuration: the mounts if there are any, otherwise a
+/// single backend. Deduplicated trees are kept in `db`.
+pub fn from_config(config: &StorageConfig, db: &Database) -> Result<Arc<dyn StorageBackend>, ApiError> {
+    let keys = match config.encryption_in_use() {
+        true => Some(Arc::new(Keyring::from_config(&config.encryption)?)),
+        false => None,
+    };
+    let encrypted = |backend: Arc<dyn StorageBackend>, enabled: bool| -> Arc<dyn StorageBackend> {
+        match &keys {
+            Some(keys) if enabled => Arc::new(EncryptedStorage::new(backend, keys.clone())),
+            _ => backend,
+        }
+    };
+
+    // Contents are encrypted after they are hashed, so identical files still match
+    let deduplicated = |blobs: Arc<dyn StorageBackend>, root: &str, enabled: bool| -> Arc<dyn StorageBackend> {
+        match enabled {
+            true => Arc::new(DedupStorage::new(db.clone(), root, blobs)),
+            false => blobs,
+        }
+    };
+
+    if config.mounts.is_empty() {
+        let backend = backend(&config.backend, &config.home_directory)?;
+        let backend = encrypted(backend, config.encryption.enabled);
+        return Ok(deduplicated(backend, "", config.dedup.enabled));
+    }
+
+    let mut names = HashSet::new();
+    let mut mounts = Vec::new();
+    for mount in &config.mounts {
+        let name = mount.name.as_str();
+        if name.is_empty() || name == "." || name == ".." || name.contains(['/', '\\']) || !names.insert(name) {
+            return Err(ApiError::InternalServerError {
+                message: format!("Invalid or duplicate mount name: {:?}", name),
+            });
+        }
+        if matches!(mount.backend, StorageBackendConfig::Local) && mount.path.as_os_str().is_empty() {
+            return Err(ApiError::InternalServerError {
+                message: format!("Mount {} needs a path", name),
+            });
+        }
+        mounts.push(Mount {
+            name: name.to_string(),
+            backend: deduplicated(
+                encrypted(
+                    backend(&mount.backend, &mount.path)?,
+                    mount.encrypted.unwrap_or(config.encryption.enabled),
+                ),
+                name,
+                mount.deduplicated.unwrap_or(config.dedup.enabled),
+            ),
+            read_only: mount.read_only,
+        });
+    }
+    Ok(Arc::new(MountedStorage::new(mounts)))
+}
+
+fn backend(config: &StorageBackendConfig, directory: &Path) -> Result<Arc<dyn StorageBackend>, ApiError> {
+    Ok(match config {
+        StorageBackendConfig::Local => Arc::new(LocalStorage::new(PathBuf::from(directory))),
+        StorageBackendConfig::S3(object_store) => Arc::new(S3Storage::new(object_store)?),
+    })
+}
+
+/// `StorageBackend::import` for backends that can't take over the file directly
+pub(crate) async fn import_by_copy<B>(backend: &B, path: &str, source: &Path) -> Result<FileInfo, ApiError>
+where
+    B: StorageBackend + ?Sized,
+{
+    let mut file = tokio::fs::File::open(source).await?;
+    let mut writer = backend.open_write(path).await?;
+    tokio::io::copy(&mut file, &mut writer).await?;
+    writer.flush().await?;
+    let info = writer.commit().await?;
+
+    let _ = tokio::fs::remove_file(source).await;
+    Ok(info)
+}
+
+/// The last component of a storage path
+pub(crate) fn file_name(path: &str) -> &str {
+    path.rsplit('/').next().unwrap_or_default()
+}
+
+/// Whether `path` is `ancestor` or somewhere below it
+pub(crate) fn is_within(path: &str, ancestor: &str) -> bool {
+    ancestor.is_empty()
+        || path == ancestor
+        || path.strip_prefix(ancestor).is_some_and(|rest| rest.starts_with('/'))
+}
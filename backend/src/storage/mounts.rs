@@ -0,0 +1,283 @@
+use super::{file_name, CollectedGarbage, StorageBackend, StorageReader, StorageWriter};
+use crate::{errors::ApiError, services::FileInfo};
+use async_trait::async_trait;
+use chrono::Utc;
+use std::{
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tokio::io::AsyncWriteExt;
+
+/// A named storage root
+pub struct Mount {
+    pub name: String,
+    pub backend: Arc<dyn StorageBackend>,
+    pub read_only: bool,
+}
+
+/// Several backends under one tree, each mount appearing as a top-level directory.
+/// Nothing but the mounts themselves lives at the top level.
+pub struct MountedStorage {
+    mounts: Vec<Mount>,
+}
+
+impl MountedStorage {
+    pub fn new(mounts: Vec<Mount>) -> Self {
+        Self { mounts }
+    }
+
+    /// The mount holding `path` and the path within it
+    fn locate<'a>(&self, path: &'a str) -> Result<(&Mount, &'a str), ApiError> {
+        let (name, rest) = path.split_once('/').unwrap_or((path, ""));
+        self.mounts
+            .iter()
+            .find(|mount| mount.name == name)
+            .map(|mount| (mount, rest))
+            .ok_or_else(|| ApiError::FileNotFound {
+                path: path.to_string(),
+            })
+    }
+
+    /// Like `locate`, for changes: the mount must be writable and `path` inside it
+    fn locate_writable<'a>(&self, path: &'a str) -> Result<(&Mount, &'a str), ApiError> {
+        if !path.contains('/') {
+            let message = if self.locate(path).is_ok() {
+                "Mounts can't be replaced or removed"
+            } else {
+                "Files and directories must be inside a mount"
+            };
+            return Err(ApiError::Forbidden {
+                message: message.to_string(),
+            });
+        }
+        let (mount, rest) = self.locate(path)?;
+        if mount.read_only {
+            return Err(ApiError::Forbidden {
+                message: format!("The {} mount is read-only", mount.name),
+            });
+        }
+        Ok((mount, rest))
+    }
+}
+
+/// Report an entry of a mount at its path in the whole tree
+fn in_mount(mount: &Mount, info: FileInfo) -> FileInfo {
+    let path = if info.path.is_empty() {
+        mount.name.clone()
+    } else {
+        format!("{}/{}", mount.name, info.path)
+    };
+    FileInfo {
+        name: file_name(&path).to_string(),
+        path,
+        ..info
+    }
+}
+
+#[async_trait]
+impl StorageBackend for MountedStorage {
+    async fn stat(&self, path: &str) -> Result<FileInfo, ApiError> {
+        if path.is_empty() {
+            return Ok(FileInfo {
+                name: String::new(),
+                path: String::new(),
+                size: 0,
+                modified: Utc::now(),
+                is_directory: true,
+                mime_type: None,
+            });
+        }
+        let (mount, rest) = self.locate(path)?;
+        Ok(in_mount(mount, mount.backend.stat(rest).await?))
+    }
+
+    async fn list(&self, path: &str) -> Result<Vec<FileInfo>, ApiError> {
+        if !path.is_empty() {
+            let (mount, rest) = self.locate(path)?;
+            let files = mount.backend.list(rest).await?;
+            return Ok(files.into_iter().map(|info| in_mount(mount, info)).collect());
+        }
+
+        let mut files = Vec::new();
+        for mount in &self.mounts {
+            match mount.backend.stat("").await {
+                Ok(info) => files.push(in_mount(mount, info)),
+                // An unavailable mount shouldn't hide the others
+                Err(e) => tracing::warn!("Mount {} is unavailable: {}", mount.name, e),
+            }
+        }
+        Ok(files)
+    }
+
+    async fn open_read(&self, path: &str, range: Option<Range<u64>>) -> Result<StorageReader, ApiError> {
+        let (mount, rest) = self.locate(path)?;
+        mount.backend.open_read(rest, range).await
+    }
+
+    async fn open_write(&self, path: &str) -> Result<Box<dyn StorageWriter>, ApiError> {
+        let (mount, rest) = self.locate_writable(path)?;
+        mount.backend.open_write(rest).await
+    }
+
+    async fn rename(&self, from: &str, to: &str) -> Result<(), ApiError> {
+        let (from_mount, from_rest) = self.locate_writable(from)?;
+        let (to_mount, to_rest) = self.locate_writable(to)?;
+        if from_mount.name == to_mount.name {
+            return from_mount.backend.rename(from_rest, to_rest).await;
+        }
+
+        // Nothing moves between backends, so copy the tree and then remove the original
+        copy_between(from_mount.backend.as_ref(), from_rest, to_mount.backend.as_ref(), to_rest).await?;
+        from_mount.backend.delete(from_rest).await
+    }
+
+    async fn copy(&self, from: &str, to: &str) -> Result<(), ApiError> {
+        let (from_mount, from_rest) = self.locate(from)?;
+        let (to_mount, to_rest) = self.locate_writable(to)?;
+        if from_mount.name == to_mount.name {
+            return from_mount.backend.copy(from_rest, to_rest).await;
+        }
+        copy_between(from_mount.backend.as_ref(), from_rest, to_mount.backend.as_ref(), to_rest).await
+    }
+
+    async fn delete(&self, path: &str) -> Result<(), ApiError> {
+        let (mount, rest) = self.locate_writable(path)?;
+        mount.backend.delete(rest).await
+    }
+
+    async fn mkdir(&self, path: &str, recursive: bool) -> Result<(), ApiError> {
+        // The root and the mounts always exist, and nothing else can be created beside them
+        if !path.contains('/') {
+            return match self.stat(path).await {
+                Ok(_) if recursive => Ok(()),
+                Ok(_) => Err(ApiError::FileExists {
+                    path: path.to_string(),
+                }),
+                Err(ApiError::FileNotFound { .. }) => self.locate_writable(path).map(|_| ()),
+                Err(e) => Err(e),
+            };
+        }
+        let (mount, rest) = self.locate_writable(path)?;
+        mount.backend.mkdir(rest, recursive).await
+    }
+
+    async fn import(&self, path: &str, source: &Path) -> Result<FileInfo, ApiError> {
+        let (mount, rest) = self.locate_writable(path)?;
+        Ok(in_mount(mount, mount.backend.import(rest, source).await?))
+    }
+
+    async fn reencrypt(&self, path: &str) -> Result<bool, ApiError> {
+        let (mount, rest) = self.locate(path)?;
+        if mount.read_only {
+            return Ok(false);
+        }
+        mount.backend.reencrypt(rest).await
+    }
+
+    async fn link(&self, path: &str, sha256: &str) -> Result<Option<FileInfo>, ApiError> {
+        let (mount, rest) = self.locate_writable(path)?;
+        let info = mount.backend.link(rest, sha256).await?;
+        Ok(info.map(|info| in_mount(mount, info)))
+    }
+
+    async fn collect_garbage(&self) -> Result<CollectedGarbage, ApiError> {
+        let mut collected = CollectedGarbage::default();
+        for mount in &self.mounts {
+            collected += mount.backend.collect_garbage().await?;
+        }
+        Ok(collected)
+    }
+
+    fn check_writable(&self, path: &str) -> Result<(), ApiError> {
+        self.locate_writable(path).map(|_| ())
+    }
+
+    fn local_path(&self, path: &str) -> Option<PathBuf> {
+        let (mount, rest) = self.locate(path).ok()?;
+        mount.backend.local_path(rest)
+    }
+}
+
+/// Copy a file or directory tree from one backend to another. `to` must not exist and
+/// its parent must.
+async fn copy_between(
+    from_backend: &dyn StorageBackend,
+    from: &str,
+    to_backend: &dyn StorageBackend,
+    to: &str,
+) -> Result<(), ApiError> {
+    match to_backend.stat(to).await {
+        Ok(_) => {
+            return Err(ApiError::FileExists {
+                path: to.to_string(),
+            })
+        }
+        Err(ApiError::FileNotFound { .. }) => {}
+        Err(e) => return Err(e),
+    }
+
+    let mut pending = vec![(from.to_string(), to.to_string(), from_backend.stat(from).await?)];
+    while let Some((from, to, info)) = pending.pop() {
+        if info.is_directory {
+            to_backend.mkdir(&to, false).await?;
+            for child in from_backend.list(&from).await? {
+                let target = format!("{}/{}", to, child.name);
+                pending.push((child.path.clone(), target, child));
+            }
+            continue;
+        }
+
+        let mut reader = from_backend.open_read(&from, None).await?;
+        let mut writer = to_backend.open_write(&to).await?;
+        tokio::io::copy(&mut reader, &mut writer).await?;
+        writer.flush().await?;
+        writer.commit().await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+    use tokio::io::AsyncReadExt;
+
+    #[tokio::test]
+    async fn test_mounts_form_the_top_level() {
+        let mount = |name: &str, read_only| Mount {
+            name: name.to_string(),
+            backend: Arc::new(MemoryStorage::new()),
+            read_only,
+        };
+        let storage = MountedStorage::new(vec![mount("work", false), mount("archive", true)]);
+
+        let names: Vec<_> = storage.list("").await.unwrap().into_iter().map(|info| info.path).collect();
+        assert_eq!(names, vec!["work", "archive"]);
+        assert!(matches!(storage.mkdir("other", false).await, Err(ApiError::Forbidden { .. })));
+        assert!(matches!(storage.delete("work").await, Err(ApiError::Forbidden { .. })));
+        assert!(storage.mkdir("work", true).await.is_ok());
+        assert!(matches!(storage.mkdir("archive/new", false).await, Err(ApiError::Forbidden { .. })));
+        assert!(matches!(storage.check_writable("archive/a.txt"), Err(ApiError::Forbidden { .. })));
+        assert!(storage.check_writable("work/a.txt").is_ok());
+
+        storage.mkdir("work/docs", false).await.unwrap();
+        let mut writer = storage.open_write("work/docs/a.txt").await.unwrap();
+        writer.write_all(b"hello").await.unwrap();
+        assert_eq!(writer.commit().await.unwrap().size, 5);
+        assert_eq!(storage.stat("work/docs/a.txt").await.unwrap().path, "work/docs/a.txt");
+
+        // Moving out of a read-only mount is refused, into a writable one copies across
+        assert!(matches!(storage.rename("work/docs", "archive/docs").await, Err(ApiError::Forbidden { .. })));
+        let second = MountedStorage::new(vec![mount("work", false), mount("spare", false)]);
+        second.mkdir("work/docs", false).await.unwrap();
+        let mut writer = second.open_write("work/docs/a.txt").await.unwrap();
+        writer.write_all(b"hello").await.unwrap();
+        writer.commit().await.unwrap();
+        second.rename("work/docs", "spare/moved").await.unwrap();
+        assert!(matches!(second.stat("work/docs").await, Err(ApiError::FileNotFound { .. })));
+        let mut text = String::new();
+        second.open_read("spare/moved/a.txt", None).await.unwrap().read_to_string(&mut text).await.unwrap();
+        assert_eq!(text, "hello");
+    }
+}
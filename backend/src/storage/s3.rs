@@ -0,0 +1,955 @@
+use super::{file_name, is_within, StorageBackend, StorageReader, StorageWriter};
+use crate::{
+    config::ObjectStoreConfig,
+    errors::ApiError,
+    services::{parent_of, FileInfo},
+    utils::{
+        sigv4::{uri_encode, RequestSigner, EMPTY_SHA256},
+        xml::{self, XmlElement},
+    },
+};
+use async_trait::async_trait;
+use axum::http::{HeaderMap, HeaderValue, Method, StatusCode};
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use quick_xml::escape::escape;
+use reqwest::{Response, Url};
+use sha2::{Digest, Sha256};
+use std::{
+    fs,
+    io::Cursor,
+    ops::Range,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+use tokio::{
+    fs as async_fs,
+    io::{AsyncReadExt, AsyncWrite},
+};
+use tokio_util::io::StreamReader;
+use uuid::Uuid;
+
+/// Files kept as objects in an S3-compatible bucket. Directories are key prefixes: one
+/// exists while anything is stored below it, and `mkdir` writes an empty `<dir>/` marker
+/// object so that empty directories survive.
+pub struct S3Storage {
+    client: Arc<S3Client>,
+}
+
+impl S3Storage {
+    pub fn new(config: &ObjectStoreConfig) -> Result<Self, ApiError> {
+        Ok(Self {
+            client: Arc::new(S3Client::new(config)?),
+        })
+    }
+
+    /// The file or directory at `path`, if there is one
+    async fn lookup(&self, path: &str) -> Result<Option<FileInfo>, ApiError> {
+        if path.is_empty() {
+            return Ok(Some(directory_info(path, Utc::now())));
+        }
+        if let Some(info) = self.client.head(path).await? {
+            return Ok(Some(info));
+        }
+
+        let page = self.client.list_page(&self.client.dir_prefix(path), false, None, Some(1)).await?;
+        Ok(page
+            .objects
+            .first()
+            .map(|object| directory_info(path, object.modified)))
+    }
+
+    async fn is_directory(&self, path: &str) -> Result<bool, ApiError> {
+        Ok(self.lookup(path).await?.is_some_and(|info| info.is_directory))
+    }
+
+    /// Check that a file can be written at `path`
+    async fn check_writable(&self, path: &str) -> Result<(), ApiError> {
+        if path.is_empty() || self.is_directory(path).await? {
+            return Err(ApiError::Conflict {
+                message: "A directory with this name already exists".to_string(),
+            });
+        }
+        if !self.is_directory(&parent_of(path)).await? {
+            return Err(ApiError::Conflict {
+                message: "Parent directory does not exist".to_string(),
+            });
+        }
+        Ok(())
+    }
+
+    async fn check_destination(&self, path: &str) -> Result<(), ApiError> {
+        if self.lookup(path).await?.is_some() {
+            return Err(ApiError::FileExists {
+                path: path.to_string(),
+            });
+        }
+        if !self.is_directory(&parent_of(path)).await? {
+            return Err(ApiError::Conflict {
+                message: "Parent directory does not exist".to_string(),
+            });
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl StorageBackend for S3Storage {
+    async fn stat(&self, path: &str) -> Result<FileInfo, ApiError> {
+        self.lookup(path).await?.ok_or_else(|| ApiError::FileNotFound {
+            path: path.to_string(),
+        })
+    }
+
+    async fn list(&self, path: &str) -> Result<Vec<FileInfo>, ApiError> {
+        let prefix = self.client.dir_prefix(path);
+        let (objects, prefixes) = self.client.list_all(&prefix, true).await?;
+
+        let mut files: Vec<FileInfo> = prefixes
+            .iter()
+            .map(|prefix| directory_info(&self.client.path_of(prefix), Utc::now()))
+            .collect();
+        files.extend(
+            objects
+                .iter()
+                // The directory's own marker
+                .filter(|object| object.key != prefix)
+                .map(|object| file_info(&self.client.path_of(&object.key), object.size, object.modified)),
+        );
+
+        // A directory without anything in it is indistinguishable from a missing one
+        // unless it has a marker, so only then is it worth checking what is there
+        if files.is_empty() && !path.is_empty() {
+            match self.lookup(path).await? {
+                Some(info) if info.is_directory => {}
+                Some(_) => {
+                    return Err(ApiError::BadRequest {
+                        message: "Not a directory".to_string(),
+                    })
+                }
+                None => {
+                    return Err(ApiError::FileNotFound {
+                        path: path.to_string(),
+                    })
+                }
+            }
+        }
+        Ok(files)
+    }
+
+    async fn open_read(&self, path: &str, range: Option<Range<u64>>) -> Result<StorageReader, ApiError> {
+        if range.as_ref().is_some_and(|range| range.start >= range.end) {
+            self.stat(path).await?;
+            return Ok(Box::new(Cursor::new(Bytes::new())));
+        }
+
+        let mut headers = HeaderMap::new();
+        if let Some(range) = &range {
+            headers.insert(
+                "range",
+                header_value(&format!("bytes={}-{}", range.start, range.end - 1))?,
+            );
+        }
+        let response = self
+            .client
+            .send(Method::GET, Some(&self.client.key(path)), &[], headers, Bytes::new())
+            .await?;
+
+        match response.status() {
+            // The range starts past the end of the file
+            StatusCode::RANGE_NOT_SATISFIABLE => return Ok(Box::new(Cursor::new(Bytes::new()))),
+            status if !status.is_success() => {
+                if status == StatusCode::NOT_FOUND && self.is_directory(path).await? {
+                    return Err(ApiError::BadRequest {
+                        message: "Cannot read a directory".to_string(),
+                    });
+                }
+                return Err(S3Client::error(response, path).await);
+            }
+            _ => {}
+        }
+
+        let chunks = futures::stream::unfold(Some(response), |response| async move {
+            let mut response = response?;
+            match response.chunk().await {
+                Ok(Some(chunk)) => Some((Ok(chunk), Some(response))),
+                Ok(None) => None,
+                Err(e) => Some((Err(std::io::Error::other(e)), None)),
+            }
+        });
+        Ok(Box::new(StreamReader::new(Box::pin(chunks))))
+    }
+
+    async fn open_write(&self, path: &str) -> Result<Box<dyn StorageWriter>, ApiError> {
+        self.check_writable(path).await?;
+
+        // Spooled to disk so that large files can be uploaded in parts once complete
+        let temp = std::env::temp_dir().join(format!("filedash-{}.partial", Uuid::new_v4().simple()));
+        let file = async_fs::File::create(&temp).await?;
+
+        Ok(Box::new(S3Writer {
+            file,
+            temp: Some(temp),
+            client: self.client.clone(),
+            path: path.to_string(),
+        }))
+    }
+
+    async fn rename(&self, from: &str, to: &str) -> Result<(), ApiError> {
+        // Objects can't be moved, only copied
+        self.copy(from, to).await?;
+        self.delete(from).await
+    }
+
+    async fn copy(&self, from: &str, to: &str) -> Result<(), ApiError> {
+        let source = self.stat(from).await?;
+        self.check_destination(to).await?;
+        if is_within(to, from) {
+            return Err(ApiError::BadRequest {
+                message: "Cannot copy a directory into itself".to_string(),
+            });
+        }
+
+        if !source.is_directory {
+            return self.client.copy(&self.client.key(from), &self.client.key(to)).await;
+        }
+
+        let (from_prefix, to_prefix) = (self.client.dir_prefix(from), self.client.dir_prefix(to));
+        self.client.put(&to_prefix, Bytes::new()).await?;
+        let (objects, _) = self.client.list_all(&from_prefix, false).await?;
+        for object in objects.iter().filter(|object| object.key != from_prefix) {
+            let target = format!("{}{}", to_prefix, &object.key[from_prefix.len()..]);
+            self.client.copy(&object.key, &target).await?;
+        }
+        Ok(())
+    }
+
+    async fn delete(&self, path: &str) -> Result<(), ApiError> {
+        if !self.stat(path).await?.is_directory {
+            return self.client.delete(&self.client.key(path)).await;
+        }
+
+        // Services that derive markers from their own directories only report one once the
+        // directory is empty, so keep going until nothing is left
+        let prefix = self.client.dir_prefix(path);
+        loop {
+            let (objects, _) = self.client.list_all(&prefix, false).await?;
+            if objects.is_empty() {
+                return Ok(());
+            }
+            for object in objects {
+                self.client.delete(&object.key).await?;
+            }
+        }
+    }
+
+    async fn mkdir(&self, path: &str, recursive: bool) -> Result<(), ApiError> {
+        let mut missing = Vec::new();
+        let mut current = path.to_string();
+        loop {
+            match self.lookup(&current).await? {
+                Some(info) if info.is_directory => break,
+                Some(_) => {
+                    return Err(ApiError::Conflict {
+                        message: "A file exists where a directory is needed".to_string(),
+                    })
+                }
+                None => missing.push(current.clone()),
+            }
+            current = parent_of(&current);
+        }
+
+        if missing.is_empty() && !recursive {
+            return Err(ApiError::FileExists {
+                path: path.to_string(),
+            });
+        }
+        if missing.len() > 1 && !recursive {
+            return Err(ApiError::Conflict {
+                message: "Parent directory does not exist".to_string(),
+            });
+        }
+        // Every new directory gets a marker so that it outlives its contents
+        for directory in missing.iter().rev() {
+            self.client.put(&self.client.dir_prefix(directory), Bytes::new()).await?;
+        }
+        Ok(())
+    }
+
+    async fn import(&self, path: &str, source: &Path) -> Result<FileInfo, ApiError> {
+        self.check_writable(path).await?;
+        self.client.upload(&self.client.key(path), source).await?;
+        let _ = async_fs::remove_file(source).await;
+        self.stat(path).await
+    }
+}
+
+struct S3Writer {
+    file: async_fs::File,
+    /// The spooled contents, until they are uploaded
+    temp: Option<PathBuf>,
+    client: Arc<S3Client>,
+    path: String,
+}
+
+impl AsyncWrite for S3Writer {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.file).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.file).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.file).poll_shutdown(cx)
+    }
+}
+
+#[async_trait]
+impl StorageWriter for S3Writer {
+    async fn commit(mut self: Box<Self>) -> Result<FileInfo, ApiError> {
+        use tokio::io::AsyncWriteExt;
+
+        self.file.flush().await?;
+        if let Some(temp) = self.temp.take() {
+            let result = self.client.upload(&self.client.key(&self.path), &temp).await;
+            let _ = async_fs::remove_file(&temp).await;
+            result?;
+        }
+        self.client.head(&self.path).await?.ok_or_else(|| ApiError::FileNotFound {
+            path: self.path.clone(),
+        })
+    }
+}
+
+impl Drop for S3Writer {
+    fn drop(&mut self) {
+        if let Some(temp) = &self.temp {
+            let _ = fs::remove_file(temp);
+        }
+    }
+}
+
+struct ObjectEntry {
+    key: String,
+    size: u64,
+    modified: DateTime<Utc>,
+}
+
+struct ListPage {
+    objects: Vec<ObjectEntry>,
+    prefixes: Vec<String>,
+    next: Option<String>,
+}
+
+/// Signed requests against one bucket, addressed by path
+struct S3Client {
+    http: reqwest::Client,
+    /// Scheme, host and port of the endpoint
+    origin: String,
+    host: String,
+    bucket: String,
+    /// Decoded path of the bucket, below any path of the endpoint
+    bucket_path: String,
+    /// Key prefix, empty or ending in `/`
+    prefix: String,
+    part_size: u64,
+    signer: RequestSigner,
+}
+
+impl S3Client {
+    fn new(config: &ObjectStoreConfig) -> Result<Self, ApiError> {
+        let invalid = |message: &str| ApiError::InternalServerError {
+            message: format!("Invalid object storage endpoint {}: {}", config.endpoint, message),
+        };
+        let endpoint = Url::parse(&config.endpoint).map_err(|e| invalid(&e.to_string()))?;
+        let host = endpoint.host_str().ok_or_else(|| invalid("no host"))?;
+        let host = match endpoint.port() {
+            Some(port) => format!("{}:{}", host, port),
+            None => host.to_string(),
+        };
+        if config.bucket.is_empty() || config.bucket.contains('/') {
+            return Err(ApiError::InternalServerError {
+                message: format!("Invalid bucket name: {}", config.bucket),
+            });
+        }
+
+        let base_path = percent_encoding::percent_decode_str(endpoint.path())
+            .decode_utf8_lossy()
+            .trim_end_matches('/')
+            .to_string();
+        let prefix = config.prefix.trim_matches('/');
+        Ok(Self {
+            http: reqwest::Client::new(),
+            origin: format!("{}://{}", endpoint.scheme(), host),
+            host,
+            bucket: config.bucket.clone(),
+            bucket_path: format!("{}/{}", base_path, config.bucket),
+            prefix: if prefix.is_empty() {
+                String::new()
+            } else {
+                format!("{}/", prefix)
+            },
+            part_size: config.part_size.max(1),
+            signer: RequestSigner::new(&config.access_key_id, &config.secret_access_key, &config.region, "s3"),
+        })
+    }
+
+    fn key(&self, path: &str) -> String {
+        format!("{}{}", self.prefix, path)
+    }
+
+    /// The prefix of every key inside the directory at `path`
+    fn dir_prefix(&self, path: &str) -> String {
+        if path.is_empty() {
+            self.prefix.clone()
+        } else {
+            format!("{}{}/", self.prefix, path)
+        }
+    }
+
+    /// The storage path of a key or common prefix
+    fn path_of(&self, key: &str) -> String {
+        key[self.prefix.len()..].trim_end_matches('/').to_string()
+    }
+
+    /// Send a signed request for an object, or for the bucket itself when `key` is `None`
+    async fn send(
+        &self,
+        method: Method,
+        key: Option<&str>,
+        query: &[(&str, &str)],
+        mut headers: HeaderMap,
+        body: Bytes,
+    ) -> Result<Response, ApiError> {
+        let path = match key {
+            Some(key) => format!("{}/{}", self.bucket_path, key),
+            None => self.bucket_path.clone(),
+        };
+        let query: Vec<(String, String)> = query
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        let mut url = format!("{}{}", self.origin, uri_encode(&path, true));
+        if !query.is_empty() {
+            let query: Vec<String> = query
+                .iter()
+                .map(|(name, value)| format!("{}={}", uri_encode(name, false), uri_encode(value, false)))
+                .collect();
+            url = format!("{}?{}", url, query.join("&"));
+        }
+
+        headers.insert("host", header_value(&self.host)?);
+        let payload_sha256 = if body.is_empty() {
+            EMPTY_SHA256.to_string()
+        } else {
+            hex::encode(Sha256::digest(&body))
+        };
+        self.signer
+            .sign(&method, &path, &query, &mut headers, &payload_sha256, Utc::now());
+
+        self.http
+            .request(method, url)
+            .headers(headers)
+            .body(body)
+            .send()
+            .await
+            .map_err(|e| ApiError::InternalServerError {
+                message: format!("Object storage request failed: {}", e),
+            })
+    }
+
+    /// Turn a failed response into an error; a missing object is `FileNotFound` for `path`
+    async fn error(response: Response, path: &str) -> ApiError {
+        let status = response.status();
+        if status == StatusCode::NOT_FOUND {
+            return ApiError::FileNotFound {
+                path: path.to_string(),
+            };
+        }
+        let body = response.bytes().await.unwrap_or_default();
+        let detail = xml::parse(&body)
+            .ok()
+            .and_then(|error| {
+                let code = text(&error, "Code")?.to_string();
+                Some(match text(&error, "Message") {
+                    Some(message) => format!("{}: {}", code, message),
+                    None => code,
+                })
+            })
+            .unwrap_or_else(|| status.to_string());
+        ApiError::InternalServerError {
+            message: format!("Object storage error: {}", detail),
+        }
+    }
+
+    /// Send a request that must succeed and return its XML body
+    async fn send_xml(
+        &self,
+        method: Method,
+        key: Option<&str>,
+        query: &[(&str, &str)],
+        headers: HeaderMap,
+        body: Bytes,
+    ) -> Result<XmlElement, ApiError> {
+        let path = key.map(|key| self.path_of(key)).unwrap_or_default();
+        let response = self.send(method, key, query, headers, body).await?;
+        if !response.status().is_success() {
+            return Err(Self::error(response, &path).await);
+        }
+        let body = response.bytes().await.map_err(|e| ApiError::InternalServerError {
+            message: format!("Object storage request failed: {}", e),
+        })?;
+        let document = xml::parse(&body).map_err(|message| ApiError::InternalServerError { message })?;
+
+        // Copies and completed uploads can fail after the status has been sent
+        if document.name == "Error" {
+            return Err(ApiError::InternalServerError {
+                message: format!("Object storage error: {}", text(&document, "Code").unwrap_or_default()),
+            });
+        }
+        Ok(document)
+    }
+
+    /// Send a request that must succeed, ignoring its body
+    async fn send_ok(&self, method: Method, key: &str, query: &[(&str, &str)], body: Bytes) -> Result<Response, ApiError> {
+        let response = self.send(method, Some(key), query, HeaderMap::new(), body).await?;
+        if !response.status().is_success() {
+            return Err(Self::error(response, &self.path_of(key)).await);
+        }
+        Ok(response)
+    }
+
+    async fn head(&self, path: &str) -> Result<Option<FileInfo>, ApiError> {
+        let response = self
+            .send(Method::HEAD, Some(&self.key(path)), &[], HeaderMap::new(), Bytes::new())
+            .await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        if !response.status().is_success() {
+            return Err(Self::error(response, path).await);
+        }
+
+        let header = |name: &str| response.headers().get(name).and_then(|value| value.to_str().ok());
+        let size = header("content-length").and_then(|size| size.parse().ok()).unwrap_or(0);
+        let modified = header("last-modified")
+            .and_then(|date| httpdate::parse_http_date(date).ok())
+            .map(DateTime::<Utc>::from)
+            .unwrap_or_else(Utc::now);
+        Ok(Some(file_info(path, size, modified)))
+    }
+
+    /// One page of ListObjectsV2 below `prefix`, stopping at the next `/` with `delimited`
+    async fn list_page(
+        &self,
+        prefix: &str,
+        delimited: bool,
+        token: Option<&str>,
+        max_keys: Option<usize>,
+    ) -> Result<ListPage, ApiError> {
+        let max_keys = max_keys.map(|max_keys| max_keys.to_string());
+        let mut query = vec![("list-type", "2"), ("prefix", prefix)];
+        if delimited {
+            query.push(("delimiter", "/"));
+        }
+        if let Some(token) = token {
+            query.push(("continuation-token", token));
+        }
+        if let Some(max_keys) = &max_keys {
+            query.push(("max-keys", max_keys));
+        }
+
+        let result = self
+            .send_xml(Method::GET, None, &query, HeaderMap::new(), Bytes::new())
+            .await?;
+        let objects = children(&result, "Contents")
+            .filter_map(|object| {
+                Some(ObjectEntry {
+                    key: text(object, "Key")?.to_string(),
+                    size: text(object, "Size").and_then(|size| size.parse().ok()).unwrap_or(0),
+                    modified: text(object, "LastModified")
+                        .and_then(|date| DateTime::parse_from_rfc3339(date).ok())
+                        .map(|date| date.with_timezone(&Utc))
+                        .unwrap_or_else(Utc::now),
+                })
+            })
+            .collect();
+        let prefixes = children(&result, "CommonPrefixes")
+            .filter_map(|prefix| text(prefix, "Prefix").map(str::to_string))
+            .collect();
+        let next = (text(&result, "IsTruncated") == Some("true"))
+            .then(|| text(&result, "NextContinuationToken").map(str::to_string))
+            .flatten();
+        Ok(ListPage { objects, prefixes, next })
+    }
+
+    /// Every object below `prefix` and, with `delimited`, the common prefixes one level down
+    async fn list_all(&self, prefix: &str, delimited: bool) -> Result<(Vec<ObjectEntry>, Vec<String>), ApiError> {
+        let (mut objects, mut prefixes) = (Vec::new(), Vec::new());
+        let mut token = None;
+        loop {
+            let page = self.list_page(prefix, delimited, token.as_deref(), None).await?;
+            objects.extend(page.objects);
+            prefixes.extend(page.prefixes);
+            match page.next {
+                Some(next) => token = Some(next),
+                None => break,
+            }
+        }
+        Ok((objects, prefixes))
+    }
+
+    async fn put(&self, key: &str, body: Bytes) -> Result<(), ApiError> {
+        self.send_ok(Method::PUT, key, &[], body).await.map(|_| ())
+    }
+
+    async fn copy(&self, from: &str, to: &str) -> Result<(), ApiError> {
+        let mut headers = HeaderMap::new();
+        let source = format!("{}/{}", self.bucket, from);
+        headers.insert("x-amz-copy-source", header_value(&uri_encode(&source, true))?);
+        self.send_xml(Method::PUT, Some(to), &[], headers, Bytes::new())
+            .await
+            .map(|_| ())
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), ApiError> {
+        self.send_ok(Method::DELETE, key, &[], Bytes::new()).await.map(|_| ())
+    }
+
+    /// Upload a local file, in parts if it is larger than the part size
+    async fn upload(&self, key: &str, source: &Path) -> Result<(), ApiError> {
+        let mut file = async_fs::File::open(source).await?;
+        let size = file.metadata().await?.len();
+        if size <= self.part_size {
+            let mut data = Vec::with_capacity(size as usize);
+            file.read_to_end(&mut data).await?;
+            return self.put(key, Bytes::from(data)).await;
+        }
+
+        let created = self
+            .send_xml(Method::POST, Some(key), &[("uploads", "")], HeaderMap::new(), Bytes::new())
+            .await?;
+        let upload_id = text(&created, "UploadId")
+            .ok_or_else(|| ApiError::InternalServerError {
+                message: "Object storage did not return an upload id".to_string(),
+            })?
+            .to_string();
+
+        let result = self.upload_parts(key, &upload_id, file).await;
+        if result.is_err() {
+            let _ = self
+                .send(Method::DELETE, Some(key), &[("uploadId", &upload_id)], HeaderMap::new(), Bytes::new())
+                .await;
+        }
+        result
+    }
+
+    async fn upload_parts(&self, key: &str, upload_id: &str, mut file: async_fs::File) -> Result<(), ApiError> {
+        let mut parts = String::new();
+        for number in 1.. {
+            let mut data = Vec::with_capacity(self.part_size as usize);
+            (&mut file).take(self.part_size).read_to_end(&mut data).await?;
+            if data.is_empty() {
+                break;
+            }
+
+            let number = number.to_string();
+            let response = self
+                .send_ok(
+                    Method::PUT,
+                    key,
+                    &[("partNumber", &number), ("uploadId", upload_id)],
+                    Bytes::from(data),
+                )
+                .await?;
+            let etag = response
+                .headers()
+                .get("etag")
+                .and_then(|etag| etag.to_str().ok())
+                .unwrap_or_default();
+            parts.push_str(&format!(
+                "<Part><PartNumber>{}</PartNumber><ETag>{}</ETag></Part>",
+                number,
+                escape(etag)
+            ));
+        }
+
+        let body = format!("<CompleteMultipartUpload>{}</CompleteMultipartUpload>", parts);
+        self.send_xml(Method::POST, Some(key), &[("uploadId", upload_id)], HeaderMap::new(), Bytes::from(body))
+            .await
+            .map(|_| ())
+    }
+}
+
+fn file_info(path: &str, size: u64, modified: DateTime<Utc>) -> FileInfo {
+    let name = file_name(path).to_string();
+    FileInfo {
+        mime_type: mime_guess::from_path(&name).first().map(|mime| mime.to_string()),
+        name,
+        path: path.to_string(),
+        size,
+        modified,
+        is_directory: false,
+    }
+}
+
+/// Directories have no timestamp of their own unless a marker or object stands in for one
+fn directory_info(path: &str, modified: DateTime<Utc>) -> FileInfo {
+    FileInfo {
+        name: file_name(path).to_string(),
+        path: path.to_string(),
+        size: 0,
+        modified,
+        is_directory: true,
+        mime_type: None,
+    }
+}
+
+fn header_value(value: &str) -> Result<HeaderValue, ApiError> {
+    HeaderValue::from_str(value).map_err(|_| ApiError::BadRequest {
+        message: format!("Invalid header value: {}", value),
+    })
+}
+
+/// S3 answers in its own namespace, or none at all for some compatible services
+fn children<'a>(element: &'a XmlElement, name: &'a str) -> impl Iterator<Item = &'a XmlElement> {
+    element.children.iter().filter(move |child| child.name == name)
+}
+
+fn text<'a>(element: &'a XmlElement, name: &str) -> Option<&'a str> {
+    element
+        .children
+        .iter()
+        .find(|child| child.name == name)
+        .map(|child| child.text.as_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::sigv4::SignedRequest;
+    use axum::{
+        extract::State,
+        http::Uri,
+        response::{IntoResponse, Response as AxumResponse},
+        Router,
+    };
+    use std::{
+        collections::{BTreeMap, BTreeSet, HashMap},
+        sync::Mutex,
+    };
+    use tokio::io::AsyncWriteExt;
+
+    const SECRET: &str = "stand-in-secret";
+
+    /// Just enough of S3 for the backend, listing two entries per page so that
+    /// pagination gets exercised
+    #[derive(Default)]
+    struct Bucket {
+        objects: BTreeMap<String, Bytes>,
+        uploads: HashMap<String, BTreeMap<u32, Bytes>>,
+    }
+
+    type SharedBucket = Arc<Mutex<Bucket>>;
+
+    async fn stand_in(
+        State(bucket): State<SharedBucket>,
+        method: Method,
+        uri: Uri,
+        headers: HeaderMap,
+        body: Bytes,
+    ) -> AxumResponse {
+        let path = percent_encoding::percent_decode_str(uri.path()).decode_utf8_lossy().to_string();
+        let query: Vec<(String, String)> = uri
+            .query()
+            .unwrap_or_default()
+            .split('&')
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| {
+                let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+                let decode = |value: &str| percent_encoding::percent_decode_str(value).decode_utf8_lossy().to_string();
+                (decode(name), decode(value))
+            })
+            .collect();
+        let signed = SignedRequest::parse(&headers, &query).ok().flatten();
+        if !signed.is_some_and(|signed| signed.verify(SECRET, &method, &path, &query, &headers)) {
+            return StatusCode::FORBIDDEN.into_response();
+        }
+
+        let param = |name: &str| query.iter().find(|(key, _)| key == name).map(|(_, value)| value.as_str());
+        let mut bucket = bucket.lock().unwrap();
+        let Some(key) = path.strip_prefix("/api/test/") else {
+            return list(&bucket, param("prefix").unwrap_or_default(), param("delimiter"), param("continuation-token"))
+                .into_response();
+        };
+        let key = key.to_string();
+
+        match (method, param("uploadId")) {
+            (Method::POST, None) => {
+                let id = Uuid::new_v4().to_string();
+                bucket.uploads.insert(id.clone(), BTreeMap::new());
+                format!("<InitiateMultipartUploadResult><UploadId>{}</UploadId></InitiateMultipartUploadResult>", id)
+                    .into_response()
+            }
+            (Method::POST, Some(id)) => {
+                let parts = bucket.uploads.remove(id).unwrap();
+                let data: Vec<u8> = parts.into_values().flat_map(|part| part.to_vec()).collect();
+                bucket.objects.insert(key, Bytes::from(data));
+                "<CompleteMultipartUploadResult/>".into_response()
+            }
+            (Method::PUT, Some(id)) => {
+                let number = param("partNumber").unwrap().parse().unwrap();
+                bucket.uploads.get_mut(id).unwrap().insert(number, body);
+                ([("etag", format!("\"{}\"", number))], "").into_response()
+            }
+            (Method::PUT, None) => {
+                if let Some(source) = headers.get("x-amz-copy-source") {
+                    let source = percent_encoding::percent_decode(source.as_bytes()).decode_utf8_lossy();
+                    let data = bucket.objects[source.strip_prefix("test/").unwrap()].clone();
+                    bucket.objects.insert(key, data);
+                    return "<CopyObjectResult/>".into_response();
+                }
+                bucket.objects.insert(key, body);
+                StatusCode::OK.into_response()
+            }
+            (Method::DELETE, Some(id)) => {
+                bucket.uploads.remove(id);
+                StatusCode::NO_CONTENT.into_response()
+            }
+            (Method::DELETE, None) => {
+                bucket.objects.remove(&key);
+                StatusCode::NO_CONTENT.into_response()
+            }
+            (method, None) => {
+                let Some(data) = bucket.objects.get(&key).cloned() else {
+                    return StatusCode::NOT_FOUND.into_response();
+                };
+                let last_modified = httpdate::fmt_http_date(std::time::SystemTime::now());
+                if method == Method::HEAD {
+                    return ([("content-length", data.len().to_string()), ("last-modified", last_modified)], "")
+                        .into_response();
+                }
+                let range = headers.get("range").and_then(|range| {
+                    let (start, end) = range.to_str().ok()?.strip_prefix("bytes=")?.split_once('-')?;
+                    Some(start.parse::<usize>().ok()?..end.parse::<usize>().ok()? + 1)
+                });
+                match range {
+                    Some(range) if range.start >= data.len() => StatusCode::RANGE_NOT_SATISFIABLE.into_response(),
+                    Some(range) => (StatusCode::PARTIAL_CONTENT, data.slice(range.start..range.end.min(data.len())))
+                        .into_response(),
+                    None => data.into_response(),
+                }
+            }
+            _ => StatusCode::NOT_IMPLEMENTED.into_response(),
+        }
+    }
+
+    fn list(bucket: &Bucket, prefix: &str, delimiter: Option<&str>, token: Option<&str>) -> String {
+        let mut entries = BTreeSet::new();
+        for (key, data) in bucket.objects.range(prefix.to_string()..) {
+            let Some(rest) = key.strip_prefix(prefix) else { break };
+            match rest.find('/').filter(|_| delimiter.is_some()) {
+                Some(slash) => entries.insert((format!("{}{}", prefix, &rest[..=slash]), None)),
+                None => entries.insert((key.clone(), Some(data.len()))),
+            };
+        }
+
+        let mut page = entries.into_iter().filter(|(key, _)| token.is_none_or(|token| key.as_str() > token));
+        let mut xml = String::from("<ListBucketResult xmlns=\"http://s3.amazonaws.com/doc/2006-03-01/\">");
+        let mut last = None;
+        for (key, size) in page.by_ref().take(2) {
+            match size {
+                Some(size) => xml.push_str(&format!(
+                    "<Contents><Key>{}</Key><Size>{}</Size><LastModified>2024-01-02T03:04:05.000Z</LastModified></Contents>",
+                    key, size
+                )),
+                None => xml.push_str(&format!("<CommonPrefixes><Prefix>{}</Prefix></CommonPrefixes>", key)),
+            }
+            last = Some(key);
+        }
+        match (page.next(), last) {
+            (Some(_), Some(last)) => xml.push_str(&format!(
+                "<IsTruncated>true</IsTruncated><NextContinuationToken>{}</NextContinuationToken>",
+                last
+            )),
+            _ => xml.push_str("<IsTruncated>false</IsTruncated>"),
+        }
+        xml + "</ListBucketResult>"
+    }
+
+    async fn start_stand_in(bucket: SharedBucket) -> String {
+        let app = Router::new().fallback(stand_in).with_state(bucket);
+        let server = axum::Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(app.into_make_service());
+        let address = server.local_addr();
+        tokio::spawn(server);
+        format!("http://{}/api", address)
+    }
+
+    async fn read(storage: &S3Storage, path: &str, range: Option<Range<u64>>) -> Vec<u8> {
+        let mut data = Vec::new();
+        storage.open_read(path, range).await.unwrap().read_to_end(&mut data).await.unwrap();
+        data
+    }
+
+    #[tokio::test]
+    async fn test_round_trips_through_an_s3_endpoint() {
+        let bucket = SharedBucket::default();
+        bucket.lock().unwrap().objects.insert("other/keep.txt".to_string(), Bytes::from("not ours"));
+        let storage = S3Storage::new(&ObjectStoreConfig {
+            endpoint: start_stand_in(bucket.clone()).await,
+            bucket: "test".to_string(),
+            region: "us-east-1".to_string(),
+            access_key_id: "stand-in".to_string(),
+            secret_access_key: SECRET.to_string(),
+            prefix: "/files/".to_string(),
+            part_size: 5,
+        })
+        .unwrap();
+
+        storage.mkdir("docs/a", true).await.unwrap();
+        assert!(matches!(storage.mkdir("docs", false).await, Err(ApiError::FileExists { .. })));
+        assert!(matches!(storage.open_write("missing/x.txt").await, Err(ApiError::Conflict { .. })));
+
+        let big: Vec<u8> = (0..23).collect();
+        for (path, data) in [("docs/a/small.txt", &b"hello"[..]), ("docs/big.bin", &big), ("docs/c.txt", b"c")] {
+            let mut writer = storage.open_write(path).await.unwrap();
+            writer.write_all(data).await.unwrap();
+            writer.commit().await.unwrap();
+        }
+        assert!(bucket.lock().unwrap().uploads.is_empty());
+
+        let mut names: Vec<(String, bool)> = storage
+            .list("docs")
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|info| (info.path, info.is_directory))
+            .collect();
+        names.sort();
+        assert_eq!(
+            names,
+            vec![
+                ("docs/a".to_string(), true),
+                ("docs/big.bin".to_string(), false),
+                ("docs/c.txt".to_string(), false)
+            ]
+        );
+        assert_eq!(storage.stat("docs/big.bin").await.unwrap().size, 23);
+        assert_eq!(read(&storage, "docs/big.bin", Some(5..12)).await, &big[5..12]);
+        assert!(read(&storage, "docs/big.bin", Some(30..40)).await.is_empty());
+
+        storage.rename("docs", "moved").await.unwrap();
+        assert!(matches!(storage.stat("docs").await, Err(ApiError::FileNotFound { .. })));
+        assert_eq!(read(&storage, "moved/big.bin", None).await, big);
+        assert!(storage.list("moved/a").await.unwrap().len() == 1);
+
+        storage.delete("").await.unwrap();
+        assert!(storage.list("").await.unwrap().is_empty());
+        let keys: Vec<String> = bucket.lock().unwrap().objects.keys().cloned().collect();
+        assert_eq!(keys, vec!["other/keep.txt"]);
+    }
+}
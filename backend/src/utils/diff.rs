@@ -0,0 +1,176 @@
+/// Lines of unchanged context shown around each change
+const CONTEXT: usize = 3;
+
+/// Beyond this many differing lines the shortest edit isn't searched for, and the differing
+/// middle is shown as removed and added in full
+const MAX_EDIT_DISTANCE: usize = 2000;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Same,
+    Removed,
+    Added,
+}
+
+/// A unified diff of two texts by line, empty when they are the same
+pub fn unified(old: &str, new: &str, old_label: &str, new_label: &str) -> String {
+    let (a, b): (Vec<&str>, Vec<&str>) = (old.lines().collect(), new.lines().collect());
+
+    // Lines the texts start and end with in common need no search
+    let prefix = a.iter().zip(&b).take_while(|(x, y)| x == y).count();
+    let suffix = a[prefix..]
+        .iter()
+        .rev()
+        .zip(b[prefix..].iter().rev())
+        .take_while(|(x, y)| x == y)
+        .count();
+    let (middle_a, middle_b) = (&a[prefix..a.len() - suffix], &b[prefix..b.len() - suffix]);
+
+    let mut ops: Vec<(Kind, &str)> = a[..prefix].iter().map(|line| (Kind::Same, *line)).collect();
+    match shortest_edit(middle_a, middle_b) {
+        Some(edit) => ops.extend(edit),
+        None => {
+            ops.extend(middle_a.iter().map(|line| (Kind::Removed, *line)));
+            ops.extend(middle_b.iter().map(|line| (Kind::Added, *line)));
+        }
+    }
+    ops.extend(a[a.len() - suffix..].iter().map(|line| (Kind::Same, *line)));
+
+    // Changes whose context touches are shown as one hunk
+    let mut hunks: Vec<(usize, usize)> = Vec::new();
+    for (position, _) in ops.iter().enumerate().filter(|(_, (kind, _))| *kind != Kind::Same) {
+        let (start, end) = (position.saturating_sub(CONTEXT), (position + CONTEXT + 1).min(ops.len()));
+        match hunks.last_mut() {
+            Some(last) if start <= last.1 => last.1 = end,
+            _ => hunks.push((start, end)),
+        }
+    }
+    if hunks.is_empty() {
+        return String::new();
+    }
+
+    // Lines of each text before every op, for the hunk headers
+    let mut lines_before = Vec::with_capacity(ops.len());
+    let (mut old_line, mut new_line) = (0, 0);
+    for (kind, _) in &ops {
+        lines_before.push((old_line, new_line));
+        old_line += usize::from(*kind != Kind::Added);
+        new_line += usize::from(*kind != Kind::Removed);
+    }
+
+    let mut out = format!("--- {}\n+++ {}\n", old_label, new_label);
+    for (start, end) in hunks {
+        let hunk = &ops[start..end];
+        let old_count = hunk.iter().filter(|(kind, _)| *kind != Kind::Added).count();
+        let new_count = hunk.iter().filter(|(kind, _)| *kind != Kind::Removed).count();
+        let (old_start, new_start) = lines_before[start];
+        out.push_str(&format!(
+            "@@ -{},{} +{},{} @@\n",
+            old_start + usize::from(old_count > 0),
+            old_count,
+            new_start + usize::from(new_count > 0),
+            new_count
+        ));
+        for (kind, line) in hunk {
+            out.push(match kind {
+                Kind::Same => ' ',
+                Kind::Removed => '-',
+                Kind::Added => '+',
+            });
+            out.push_str(line);
+            out.push('\n');
+        }
+    }
+    out
+}
+
+/// The shortest way from `a` to `b` by removing and adding lines (Myers' algorithm), or
+/// `None` if it takes more than `MAX_EDIT_DISTANCE` of them
+fn shortest_edit<'a>(a: &[&'a str], b: &[&'a str]) -> Option<Vec<(Kind, &'a str)>> {
+    let (n, m) = (a.len() as isize, b.len() as isize);
+    let max = (a.len() + b.len()).min(MAX_EDIT_DISTANCE) as isize;
+
+    // The furthest x reached on each diagonal k = x - y, indexed by k + offset
+    let offset = max + 1;
+    let mut v = vec![0isize; 2 * offset as usize + 1];
+    // v before each round, for diagonals -(d + 1)..=d + 1
+    let mut trace: Vec<Vec<isize>> = Vec::new();
+
+    let mut reached = false;
+    'search: for d in 0..=max {
+        trace.push(v[(offset - d - 1) as usize..=(offset + d + 1) as usize].to_vec());
+        for k in (-d..=d).step_by(2) {
+            let mut x = match k == -d || (k != d && v[(offset + k - 1) as usize] < v[(offset + k + 1) as usize]) {
+                true => v[(offset + k + 1) as usize],
+                false => v[(offset + k - 1) as usize] + 1,
+            };
+            let mut y = x - k;
+            while x < n && y < m && a[x as usize] == b[y as usize] {
+                x += 1;
+                y += 1;
+            }
+            v[(offset + k) as usize] = x;
+            if x >= n && y >= m {
+                reached = true;
+                break 'search;
+            }
+        }
+    }
+    if !reached {
+        return None;
+    }
+
+    // Walk back from the end through the rounds
+    let mut edit = Vec::new();
+    let (mut x, mut y) = (n, m);
+    for (d, v) in trace.iter().enumerate().rev() {
+        let d = d as isize;
+        let at = |k: isize| v[(k + d + 1) as usize];
+        let k = x - y;
+        let prev_k = match k == -d || (k != d && at(k - 1) < at(k + 1)) {
+            true => k + 1,
+            false => k - 1,
+        };
+        let prev_x = at(prev_k);
+        let prev_y = prev_x - prev_k;
+        while x > prev_x && y > prev_y {
+            x -= 1;
+            y -= 1;
+            edit.push((Kind::Same, a[x as usize]));
+        }
+        if d > 0 {
+            match x == prev_x {
+                true => edit.push((Kind::Added, b[prev_y as usize])),
+                false => edit.push((Kind::Removed, a[prev_x as usize])),
+            }
+        }
+        (x, y) = (prev_x, prev_y);
+    }
+    edit.reverse();
+    Some(edit)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unified_diff() {
+        assert_eq!(unified("a\nb\n", "a\nb\n", "old", "new"), "");
+
+        let old = "1\n2\n3\n4\n5\n6\n7\n8\n9\n10\n11\n12\n";
+        let new = "1\n2\nthree\n4\n5\n6\n7\n8\n9\n10\n11\n12\n13\n";
+        assert_eq!(
+            unified(old, new, "old", "new"),
+            "--- old\n+++ new\n\
+             @@ -1,6 +1,6 @@\n 1\n 2\n-3\n+three\n 4\n 5\n 6\n\
+             @@ -10,3 +10,4 @@\n 10\n 11\n 12\n+13\n"
+        );
+
+        assert_eq!(unified("", "x\n", "old", "new"), "--- old\n+++ new\n@@ -0,0 +1,1 @@\n+x\n");
+        // Reordered lines keep the longest run in common
+        let reordered = unified("a\nb\nc\n", "c\nb\na\n", "old", "new");
+        let changed = reordered.lines().skip(3).filter(|line| !line.starts_with(' ')).count();
+        assert_eq!(changed, 4);
+    }
+}
@@ -1,3 +1,9 @@
+pub mod diff;
+pub mod owners;
+pub mod preview;
+pub mod range;
 pub mod security;
+pub mod sigv4;
+pub mod xml;
 
 pub use security::*;
@@ -0,0 +1,114 @@
+use std::collections::HashMap;
+
+/// Names of Unix users and groups by id, looked up through the system's user database and
+/// remembered, so that listing a directory asks once per owner rather than once per file
+#[derive(Default)]
+pub struct OwnerNames {
+    users: HashMap<u32, Option<String>>,
+    groups: HashMap<u32, Option<String>>,
+}
+
+impl OwnerNames {
+    pub fn user(&mut self, uid: u32) -> Option<String> {
+        self.users.entry(uid).or_insert_with(|| user_name(uid)).clone()
+    }
+
+    pub fn group(&mut self, gid: u32) -> Option<String> {
+        self.groups.entry(gid).or_insert_with(|| group_name(gid)).clone()
+    }
+}
+
+/// The id of the user called `name`, or `name` itself when it is a number
+pub fn user_id(name: &str) -> Option<u32> {
+    name.parse().ok().or_else(|| find_user(name))
+}
+
+/// The id of the group called `name`, or `name` itself when it is a number
+pub fn group_id(name: &str) -> Option<u32> {
+    name.parse().ok().or_else(|| find_group(name))
+}
+
+/// Call a reentrant `getpw*_r`/`getgr*_r` style lookup with a buffer that grows until the
+/// entry fits, and read what is wanted from the entry found
+#[cfg(unix)]
+fn lookup<T, R>(
+    call: impl Fn(*mut T, *mut libc::c_char, usize, *mut *mut T) -> libc::c_int,
+    read: impl Fn(&T) -> R,
+) -> Option<R> {
+    let mut buffer: Vec<libc::c_char> = vec![0; 1024];
+    loop {
+        // SAFETY: the entry is plain old data that the call fills in
+        let mut entry: T = unsafe { std::mem::zeroed() };
+        let mut found = std::ptr::null_mut();
+        match call(&mut entry, buffer.as_mut_ptr(), buffer.len(), &mut found) {
+            libc::ERANGE if buffer.len() < 1 << 20 => buffer.resize(buffer.len() * 2, 0),
+            0 if !found.is_null() => return Some(read(&entry)),
+            _ => return None,
+        }
+    }
+}
+
+/// Copy a name out of an entry found by `lookup`
+#[cfg(unix)]
+fn name_of(name: *const libc::c_char) -> String {
+    // SAFETY: names in a found entry are NUL-terminated strings inside the lookup's buffer
+    unsafe { std::ffi::CStr::from_ptr(name) }.to_string_lossy().into_owned()
+}
+
+#[cfg(unix)]
+fn user_name(uid: u32) -> Option<String> {
+    lookup(
+        // SAFETY: every pointer is valid for the call and `len` is the buffer's length
+        |entry, buffer, len, found| unsafe { libc::getpwuid_r(uid, entry, buffer, len, found) },
+        |entry: &libc::passwd| name_of(entry.pw_name),
+    )
+}
+
+#[cfg(unix)]
+fn group_name(gid: u32) -> Option<String> {
+    lookup(
+        // SAFETY: every pointer is valid for the call and `len` is the buffer's length
+        |entry, buffer, len, found| unsafe { libc::getgrgid_r(gid, entry, buffer, len, found) },
+        |entry: &libc::group| name_of(entry.gr_name),
+    )
+}
+
+#[cfg(unix)]
+fn find_user(name: &str) -> Option<u32> {
+    let name = std::ffi::CString::new(name).ok()?;
+    lookup(
+        // SAFETY: every pointer is valid for the call and `len` is the buffer's length
+        |entry, buffer, len, found| unsafe { libc::getpwnam_r(name.as_ptr(), entry, buffer, len, found) },
+        |entry: &libc::passwd| entry.pw_uid,
+    )
+}
+
+#[cfg(unix)]
+fn find_group(name: &str) -> Option<u32> {
+    let name = std::ffi::CString::new(name).ok()?;
+    lookup(
+        // SAFETY: every pointer is valid for the call and `len` is the buffer's length
+        |entry, buffer, len, found| unsafe { libc::getgrnam_r(name.as_ptr(), entry, buffer, len, found) },
+        |entry: &libc::group| entry.gr_gid,
+    )
+}
+
+#[cfg(not(unix))]
+fn user_name(_uid: u32) -> Option<String> {
+    None
+}
+
+#[cfg(not(unix))]
+fn group_name(_gid: u32) -> Option<String> {
+    None
+}
+
+#[cfg(not(unix))]
+fn find_user(_name: &str) -> Option<u32> {
+    None
+}
+
+#[cfg(not(unix))]
+fn find_group(_name: &str) -> Option<u32> {
+    None
+}
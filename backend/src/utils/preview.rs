@@ -0,0 +1,148 @@
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+
+/// Policy for previews: nothing is loaded from elsewhere and no script runs. Media and
+/// images may come from the file itself, inline styles are allowed for plain text.
+pub const SANDBOX_POLICY: &str =
+    "sandbox; default-src 'none'; img-src 'self' data:; media-src 'self'; style-src 'unsafe-inline'";
+
+/// As `SANDBOX_POLICY` but without `sandbox`, which browsers' PDF viewers refuse to run under
+pub const PDF_POLICY: &str = "default-src 'none'; object-src 'self'; plugin-types application/pdf";
+
+/// Bytes inspected to decide whether a file of unknown type is text
+pub const SNIFF_LENGTH: usize = 8192;
+
+/// How a file is shown when previewed
+#[derive(Debug, PartialEq, Eq)]
+pub enum Preview {
+    /// Served as it is with this content type
+    Inline(String),
+    /// Served as UTF-8 plain text, whatever its own type
+    Text,
+    /// Might be text; look at its first bytes to decide
+    Unknown,
+    /// Could run script in our origin, so it's only offered as a download
+    Attachment,
+}
+
+/// Types a browser renders as documents able to run script or load other resources
+const ACTIVE_TYPES: [&str; 5] = [
+    "text/html",
+    "application/xhtml+xml",
+    "image/svg+xml",
+    "text/xml",
+    "application/xml",
+];
+
+/// Types of source and data files that aren't `text/*` but read fine as plain text
+const TEXT_TYPES: [&str; 8] = [
+    "application/json",
+    "application/javascript",
+    "application/x-javascript",
+    "application/x-sh",
+    "application/toml",
+    "application/x-yaml",
+    "application/yaml",
+    "application/sql",
+];
+
+/// Characters escaped in the `filename*` parameter: everything but RFC 5987's `attr-char`
+const FILENAME_ESCAPES: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'!')
+    .remove(b'#')
+    .remove(b'$')
+    .remove(b'&')
+    .remove(b'+')
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'^')
+    .remove(b'_')
+    .remove(b'`')
+    .remove(b'|')
+    .remove(b'~');
+
+/// A `Content-Disposition` value naming the file as RFC 6266 describes: a plain ASCII
+/// `filename` for old clients, and the exact name in `filename*`. Always a valid header.
+pub fn content_disposition(disposition: &str, name: &str) -> String {
+    let fallback: String = name
+        .chars()
+        .map(|c| match c {
+            ' '..='~' if c != '"' && c != '\\' => c,
+            _ => '_',
+        })
+        .collect();
+    format!(
+        "{}; filename=\"{}\"; filename*=UTF-8''{}",
+        disposition,
+        fallback,
+        utf8_percent_encode(name, FILENAME_ESCAPES)
+    )
+}
+
+/// Decide how to preview a file from its MIME type, as guessed from its name
+pub fn classify(mime_type: Option<&str>) -> Preview {
+    let Some(mime_type) = mime_type else {
+        return Preview::Unknown;
+    };
+    let essence = mime_type.split(';').next().unwrap_or_default().trim().to_ascii_lowercase();
+    let (kind, subtype) = essence.split_once('/').unwrap_or((essence.as_str(), ""));
+
+    if ACTIVE_TYPES.contains(&essence.as_str()) || subtype.ends_with("+xml") {
+        Preview::Attachment
+    } else if kind == "text" || TEXT_TYPES.contains(&essence.as_str()) || subtype.ends_with("+json") {
+        Preview::Text
+    } else if matches!(kind, "image" | "audio" | "video") || essence == "application/pdf" {
+        Preview::Inline(essence)
+    } else if essence == "application/octet-stream" {
+        Preview::Unknown
+    } else {
+        Preview::Attachment
+    }
+}
+
+/// Whether the first bytes of a file look like text: valid UTF-8, allowing a character cut
+/// off at the end, and no NUL bytes
+pub fn looks_like_text(data: &[u8]) -> bool {
+    if data.contains(&0) {
+        return false;
+    }
+    match std::str::from_utf8(data) {
+        Ok(_) => true,
+        Err(e) => e.error_len().is_none(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_classify_previews() {
+        assert_eq!(classify(Some("image/png")), Preview::Inline("image/png".to_string()));
+        assert_eq!(classify(Some("application/pdf")), Preview::Inline("application/pdf".to_string()));
+        assert_eq!(classify(Some("text/markdown")), Preview::Text);
+        assert_eq!(classify(Some("application/json")), Preview::Text);
+        assert_eq!(classify(Some("text/html")), Preview::Attachment);
+        assert_eq!(classify(Some("image/svg+xml")), Preview::Attachment);
+        assert_eq!(classify(Some("application/zip")), Preview::Attachment);
+        assert_eq!(classify(None), Preview::Unknown);
+
+        assert!(looks_like_text("plain text, naïve".as_bytes()));
+        assert!(looks_like_text(&"é".as_bytes()[..1]));
+        assert!(!looks_like_text(b"\x7fELF\x02\x01\x01\0"));
+    }
+
+    #[test]
+    fn test_content_disposition() {
+        assert_eq!(
+            content_disposition("inline", "notes.txt"),
+            "inline; filename=\"notes.txt\"; filename*=UTF-8''notes.txt"
+        );
+        assert_eq!(
+            content_disposition("attachment", "résumé \"final\".pdf"),
+            "attachment; filename=\"r_sum_ _final_.pdf\"; filename*=UTF-8''r%C3%A9sum%C3%A9%20%22final%22.pdf"
+        );
+        let value = content_disposition("inline", "a\r\nSet-Cookie: x\\y.txt");
+        assert_eq!(value, "inline; filename=\"a__Set-Cookie: x_y.txt\"; filename*=UTF-8''a%0D%0ASet-Cookie%3A%20x%5Cy.txt");
+        assert!(axum::http::HeaderValue::from_str(&value).is_ok());
+    }
+}
@@ -0,0 +1,42 @@
+/// Parse a single `bytes=` range into inclusive bounds. `Some(None)` means the header is
+/// ignored and the whole file is sent (other units, multiple ranges); `None` is unsatisfiable.
+pub fn parse_byte_range(value: &str, size: u64) -> Option<Option<(u64, u64)>> {
+    let Some(spec) = value.trim().strip_prefix("bytes=") else {
+        return Some(None);
+    };
+    if spec.contains(',') {
+        return Some(None);
+    }
+    let (start, end) = spec.split_once('-')?;
+    let (start, end) = match (start.trim(), end.trim()) {
+        ("", suffix) => {
+            let suffix: u64 = suffix.parse().ok()?;
+            if suffix == 0 {
+                return None;
+            }
+            (size.saturating_sub(suffix), size.checked_sub(1)?)
+        }
+        (start, "") => (start.parse().ok()?, size.checked_sub(1)?),
+        (start, end) => (start.parse().ok()?, end.parse::<u64>().ok()?.min(size.checked_sub(1)?)),
+    };
+    if start > end || start >= size {
+        return None;
+    }
+    Some(Some((start, end)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_byte_range() {
+        assert_eq!(parse_byte_range("bytes=0-9", 100), Some(Some((0, 9))));
+        assert_eq!(parse_byte_range("bytes=90-", 100), Some(Some((90, 99))));
+        assert_eq!(parse_byte_range("bytes=-10", 100), Some(Some((90, 99))));
+        assert_eq!(parse_byte_range("bytes=50-500", 100), Some(Some((50, 99))));
+        assert_eq!(parse_byte_range("bytes=0-1,5-6", 100), Some(None));
+        assert_eq!(parse_byte_range("bytes=100-", 100), None);
+        assert_eq!(parse_byte_range("bytes=0-0", 0), None);
+    }
+}
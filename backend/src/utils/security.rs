@@ -1,4 +1,4 @@
-use crate::errors::ApiError;
+use crate::{config::StorageConfig, errors::ApiError};
 use std::path::{Path, PathBuf};
 
 /// Validates and normalizes a file path to prevent directory traversal attacks
@@ -31,11 +31,7 @@ pub fn validate_path(path: &str) -> Result<PathBuf, ApiError> {
     }
     
     // Ensure path doesn't start with / to make it relative
-    let clean_path = if path_str.starts_with('/') {
-        &path_str[1..]
-    } else {
-        &path_str
-    };
+    let clean_path = path_str.strip_prefix('/').unwrap_or(&path_str);
     
     Ok(PathBuf::from(clean_path))
 }
@@ -55,6 +51,26 @@ pub fn resolve_path(storage_root: &Path, user_path: &str) -> Result<PathBuf, Api
     Ok(full_path)
 }
 
+/// Resolves a storage path to the local disk, on whichever mount it belongs to
+pub fn resolve_storage_path(storage: &StorageConfig, user_path: &str) -> Result<PathBuf, ApiError> {
+    let path = crate::services::normalize(user_path)?;
+    let root = storage
+        .local_roots()
+        .into_iter()
+        .filter(|root| crate::storage::is_within(&path, &root.path))
+        .max_by_key(|root| root.path.len());
+
+    match root {
+        Some(root) => resolve_path(&root.directory, &path[root.path.len()..]),
+        None if storage.mounts.is_empty() || storage.mount(&path).is_some() => Err(ApiError::BadRequest {
+            message: format!("{} is not kept on the local disk", user_path),
+        }),
+        None => Err(ApiError::FileNotFound {
+            path: user_path.to_string(),
+        }),
+    }
+}
+
 /// Validates file extension against allowed list
 pub fn validate_file_extension(filename: &str, allowed_extensions: &[String]) -> Result<(), ApiError> {
     // Allow all files if wildcard is present
@@ -88,6 +104,7 @@ pub fn validate_file_size(size: u64, max_size: u64) -> Result<(), ApiError> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::config::Config;
     use std::path::PathBuf;
 
     #[test]
@@ -117,6 +134,25 @@ mod tests {
         assert_eq!(result.unwrap(), PathBuf::from("/app/files/documents/test.txt"));
     }
 
+    #[test]
+    fn test_resolve_storage_path_needs_a_local_backend() {
+        let mut storage = Config::for_tests("/app/files").storage;
+        assert_eq!(resolve_storage_path(&storage, "/a.txt").unwrap(), PathBuf::from("/app/files/a.txt"));
+
+        storage.backend = toml::from_str(
+            r#"
+            type = "s3"
+            endpoint = "http://localhost:9000"
+            bucket = "files"
+            access_key_id = "key"
+            secret_access_key = "secret"
+            "#,
+        )
+        .unwrap();
+        assert!(storage.local_roots().is_empty());
+        assert!(matches!(resolve_storage_path(&storage, "a.txt"), Err(ApiError::BadRequest { .. })));
+    }
+
     #[test]
     fn test_validate_file_extension_allowed() {
         let allowed = vec!["txt".to_string(), "pdf".to_string()];
@@ -0,0 +1,160 @@
+use quick_xml::{
+    escape::escape,
+    events::{BytesStart, Event},
+    name::{Namespace, ResolveResult},
+    reader::NsReader,
+};
+
+/// A parsed XML element with its namespace resolved
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct XmlElement {
+    pub namespace: String,
+    pub name: String,
+    pub children: Vec<XmlElement>,
+    pub text: String,
+}
+
+impl XmlElement {
+    pub fn is(&self, namespace: &str, name: &str) -> bool {
+        self.namespace == namespace && self.name == name
+    }
+
+    pub fn child(&self, namespace: &str, name: &str) -> Option<&XmlElement> {
+        self.children.iter().find(|child| child.is(namespace, name))
+    }
+
+    /// The element's content serialized back to XML, with namespaces declared inline
+    pub fn inner_xml(&self) -> String {
+        let mut xml = escape(&self.text).to_string();
+        for child in &self.children {
+            child.write(&mut xml);
+        }
+        xml
+    }
+
+    fn write(&self, xml: &mut String) {
+        xml.push_str(&element(&self.namespace, &self.name, Some(&self.inner_xml())));
+    }
+}
+
+/// Parse a small XML document into a tree. Text is kept as-is, but mixed content
+/// loses its interleaving: an element's text always comes before its children.
+pub fn parse(input: &[u8]) -> Result<XmlElement, String> {
+    let mut reader = NsReader::from_reader(input);
+
+    let mut buf = Vec::new();
+    let mut stack: Vec<XmlElement> = Vec::new();
+    let mut root = None;
+
+    loop {
+        let (namespace, event) = reader
+            .read_resolved_event_into(&mut buf)
+            .map_err(|e| format!("Invalid XML: {}", e))?;
+        match event {
+            Event::Start(start) => stack.push(new_element(namespace, &start)?),
+            Event::Empty(start) => {
+                let element = new_element(namespace, &start)?;
+                attach(&mut stack, &mut root, element)?;
+            }
+            Event::End(_) => {
+                let element = stack.pop().ok_or("Unbalanced XML")?;
+                attach(&mut stack, &mut root, element)?;
+            }
+            Event::Text(text) => {
+                if let Some(current) = stack.last_mut() {
+                    let text = text.unescape().map_err(|e| format!("Invalid XML: {}", e))?;
+                    current.text.push_str(&text);
+                }
+            }
+            Event::CData(data) => {
+                if let Some(current) = stack.last_mut() {
+                    current.text.push_str(&String::from_utf8_lossy(&data));
+                }
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+        buf.clear();
+    }
+
+    if !stack.is_empty() {
+        return Err("Unexpected end of XML".to_string());
+    }
+    root.ok_or_else(|| "Empty XML document".to_string())
+}
+
+fn new_element(namespace: ResolveResult, start: &BytesStart) -> Result<XmlElement, String> {
+    let namespace = match namespace {
+        ResolveResult::Bound(Namespace(namespace)) => String::from_utf8_lossy(namespace).to_string(),
+        ResolveResult::Unbound => String::new(),
+        ResolveResult::Unknown(prefix) => {
+            return Err(format!(
+                "Undeclared namespace prefix: {}",
+                String::from_utf8_lossy(&prefix)
+            ))
+        }
+    };
+    Ok(XmlElement {
+        namespace,
+        name: String::from_utf8_lossy(start.local_name().as_ref()).to_string(),
+        children: Vec::new(),
+        text: String::new(),
+    })
+}
+
+fn attach(
+    stack: &mut [XmlElement],
+    root: &mut Option<XmlElement>,
+    element: XmlElement,
+) -> Result<(), String> {
+    match stack.last_mut() {
+        Some(parent) => parent.children.push(element),
+        None if root.is_none() => *root = Some(element),
+        None => return Err("XML has more than one root element".to_string()),
+    }
+    Ok(())
+}
+
+/// Write an element in any namespace, declaring it inline. `content` must already be XML.
+pub fn element(namespace: &str, name: &str, content: Option<&str>) -> String {
+    let open = if namespace == "DAV:" {
+        format!("D:{}", name)
+    } else {
+        format!("{} xmlns=\"{}\"", name, escape(namespace))
+    };
+    let close = if namespace == "DAV:" {
+        format!("D:{}", name)
+    } else {
+        name.to_string()
+    };
+    match content {
+        Some(content) if !content.is_empty() => format!("<{}>{}</{}>", open, content, close),
+        _ => format!("<{}/>", open),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_resolves_namespaces() {
+        let xml = br#"<?xml version="1.0"?>
+            <D:propertyupdate xmlns:D="DAV:" xmlns:Z="urn:example">
+              <D:set><D:prop><Z:author>Ann &amp; <Z:b>Bob</Z:b></Z:author></D:prop></D:set>
+            </D:propertyupdate>"#;
+        let root = parse(xml).unwrap();
+        assert!(root.is("DAV:", "propertyupdate"));
+
+        let author = &root.child("DAV:", "set").unwrap().child("DAV:", "prop").unwrap().children[0];
+        assert!(author.is("urn:example", "author"));
+        assert_eq!(author.inner_xml(), r#"Ann &amp; <b xmlns="urn:example">Bob</b>"#);
+    }
+
+    #[test]
+    fn test_parse_rejects_bad_documents() {
+        assert!(parse(b"").is_err());
+        assert!(parse(b"<a><b></a>").is_err());
+        assert!(parse(b"<x:a/>").is_err());
+    }
+}